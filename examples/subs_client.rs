@@ -60,6 +60,8 @@ async fn main() -> std::io::Result<()> {
                 log::warn!("Server closed connection: {:?}", msg);
                 Ready::Ok(msg.ack())
             }
+            v5::client::ControlMessage::Connected(msg) => Ready::Ok(msg.ack()),
+            v5::client::ControlMessage::Reconnecting(msg) => Ready::Ok(msg.ack()),
         }
     })));
 
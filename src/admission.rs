@@ -0,0 +1,211 @@
+//! Connection admission control
+use std::{fmt, net::IpAddr, sync::Arc, sync::Mutex, time::Instant};
+
+use ntex::io::{types::PeerAddr, IoRef, OnDisconnect};
+use ntex::util::HashMap;
+
+#[derive(Clone, Default)]
+/// Connection admission control
+///
+/// Limits number of concurrent connections, connect rate and number of
+/// concurrent connections per peer ip address. Limits are checked after CONNECT
+/// packet is received, before handshake service is called. Rejected mqtt v5
+/// clients receive CONNACK with `ServerBusy` or `ConnectionRateExceeded` reason
+/// code, mqtt v3.1.1 clients receive CONNACK with `ServiceUnavailable` return code.
+///
+/// State is shared between clones. Configure limits before cloning and use
+/// the same instance for all workers and servers to enforce server-wide limits.
+pub struct Admission {
+    config: Config,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Config {
+    max_connections: usize,
+    max_per_ip: usize,
+    max_rate: u32,
+}
+
+#[derive(Default)]
+struct State {
+    connections: usize,
+    peers: HashMap<IpAddr, usize>,
+    // connect rate bucket
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+/// Reason of rejected connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    /// Connections limit is reached
+    Busy,
+    /// Connect rate is exceeded
+    Rate,
+}
+
+impl Admission {
+    /// Create admission control without limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set max number of concurrent connections
+    ///
+    /// To disable limit set value to 0. By default number of connections is not limited.
+    pub fn max_connections(mut self, val: usize) -> Self {
+        self.config.max_connections = val;
+        self
+    }
+
+    /// Set max number of concurrent connections from one ip address
+    ///
+    /// To disable limit set value to 0. By default number of connections is not limited.
+    pub fn max_connections_per_ip(mut self, val: usize) -> Self {
+        self.config.max_per_ip = val;
+        self
+    }
+
+    /// Set max number of accepted connections per second
+    ///
+    /// To disable limit set value to 0. By default connect rate is not limited.
+    pub fn max_connect_rate(mut self, val: u32) -> Self {
+        self.config.max_rate = val;
+        self
+    }
+
+    /// Number of admitted connections
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// Admit new connection
+    pub(crate) fn acquire(&self, io: &IoRef) -> Result<Permit, Rejected> {
+        let peer = if self.config.max_per_ip != 0 {
+            io.query::<PeerAddr>().get().map(|addr| addr.0.ip())
+        } else {
+            None
+        };
+        self.admit(peer, Instant::now())
+    }
+
+    fn admit(&self, peer: Option<IpAddr>, now: Instant) -> Result<Permit, Rejected> {
+        let mut state = self.state.lock().unwrap();
+        let config = &self.config;
+
+        if config.max_connections != 0 && state.connections >= config.max_connections {
+            return Err(Rejected::Busy);
+        }
+        if let Some(ip) = peer {
+            if state.peers.get(&ip).copied().unwrap_or(0) >= config.max_per_ip {
+                return Err(Rejected::Busy);
+            }
+        }
+        if config.max_rate != 0 {
+            // bucket capacity is one second of connects
+            let rate = config.max_rate as f64;
+            let tokens = match state.updated {
+                Some(updated) => {
+                    let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+                    (state.tokens + elapsed * rate).min(rate)
+                }
+                None => rate,
+            };
+            state.updated = Some(now);
+            state.tokens = tokens;
+            if tokens < 1.0 {
+                return Err(Rejected::Rate);
+            }
+            state.tokens -= 1.0;
+        }
+
+        state.connections += 1;
+        if let Some(ip) = peer {
+            *state.peers.entry(ip).or_default() += 1;
+        }
+        Ok(Permit { state: self.state.clone(), peer })
+    }
+}
+
+impl fmt::Debug for Admission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admission")
+            .field("max_connections", &self.config.max_connections)
+            .field("max_connections_per_ip", &self.config.max_per_ip)
+            .field("max_connect_rate", &self.config.max_rate)
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+/// Admitted connection, connection slot is released on drop
+pub(crate) struct Permit {
+    state: Arc<Mutex<State>>,
+    peer: Option<IpAddr>,
+}
+
+impl Permit {
+    /// Release connection slot when connection is closed
+    pub(crate) fn release_on(self, on_disconnect: OnDisconnect) {
+        ntex::rt::spawn(async move {
+            on_disconnect.await;
+            drop(self);
+        });
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.connections -= 1;
+        if let Some(ip) = self.peer {
+            if let Some(count) = state.peers.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    state.peers.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_connections() {
+        let admission = Admission::new().max_connections(3).max_connections_per_ip(2);
+        let now = Instant::now();
+        let ip1 = Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let ip2 = Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+
+        let p1 = admission.admit(ip1, now).unwrap();
+        let _p2 = admission.admit(ip1, now).unwrap();
+        assert_eq!(admission.admit(ip1, now).err(), Some(Rejected::Busy));
+        let _p3 = admission.admit(ip2, now).unwrap();
+        assert_eq!(admission.admit(ip2, now).err(), Some(Rejected::Busy));
+        assert_eq!(admission.connections(), 3);
+
+        drop(p1);
+        assert_eq!(admission.connections(), 2);
+        let _p4 = admission.admit(ip1, now).unwrap();
+    }
+
+    #[test]
+    fn test_connect_rate() {
+        let admission = Admission::new().max_connect_rate(2);
+        let now = Instant::now();
+
+        let _p1 = admission.admit(None, now).unwrap();
+        let _p2 = admission.admit(None, now).unwrap();
+        assert_eq!(admission.admit(None, now).err(), Some(Rejected::Rate));
+        let now = now + Duration::from_millis(500);
+        let _p3 = admission.admit(None, now).unwrap();
+        assert_eq!(admission.admit(None, now).err(), Some(Rejected::Rate));
+        assert_eq!(admission.connections(), 3);
+    }
+}
@@ -0,0 +1,294 @@
+//! Topic authorization
+//!
+//! [`Authorizer`] decides whether session is allowed to publish to a topic
+//! or subscribe to a topic filter. Authorizer is applied to publish and
+//! control services with `v3::Authorization` or `v5::Authorization`.
+use std::{convert::TryFrom, fmt, sync::Arc};
+
+use ntex::util::ByteString;
+
+use crate::topic::{SharedTopicFilter, TopicFilter};
+use crate::types::QoS;
+
+/// Topic authorization
+pub trait Authorizer<St>: 'static {
+    /// Per-session authorization context
+    type Context: 'static;
+
+    /// Create authorization context for the session
+    ///
+    /// Context is created once for publish service and once for control
+    /// service of the session.
+    fn context(&self, session: &St) -> Self::Context;
+
+    /// Check if session is allowed to publish message to the topic
+    fn publish(&self, ctx: &Self::Context, topic: &str, qos: QoS) -> bool;
+
+    /// Check if session is allowed to subscribe to the topic filter
+    fn subscribe(&self, ctx: &Self::Context, filter: &str, qos: QoS) -> bool;
+}
+
+/// Publish to not authorized topic
+///
+/// Returned by `v3::Authorization` publish service, publish service error
+/// type must implement `From<NotAuthorized>`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Publish is not authorized")]
+pub struct NotAuthorized;
+
+/// Identity of the session, used by [`Acl`] for pattern substitution
+pub trait Identity {
+    /// Client identifier
+    fn client_id(&self) -> &str;
+
+    /// User name, if client is authenticated
+    fn username(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Kind of access granted or denied by acl rule
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// Publish messages to matching topics
+    Publish,
+    /// Subscribe to matching topic filters
+    Subscribe,
+    /// Publish and subscribe
+    All,
+}
+
+impl Access {
+    fn contains(self, other: Access) -> bool {
+        self == Access::All || self == other
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    access: Access,
+    pattern: String,
+}
+
+/// Pattern based access control list
+///
+/// Rules are topic filters, `%u` is substituted with user name and `%c`
+/// with client id of the session. Rules are checked in order of definition,
+/// first matching rule decides. If no rule matches, access is denied.
+///
+/// Rule that refers to `%u` never matches session without user name. Also rule
+/// never matches if substituted value starts with `$` or contains `/`, `+`
+/// or `#` characters.
+/// Subscription is allowed if requested filter is a subset of rule's filter,
+/// deny rule also matches requested filters that include rule's filter.
+/// Group name of shared subscription is not checked.
+///
+/// ```rust
+/// use ntex_mqtt::auth::{Access, Acl};
+///
+/// let acl = Acl::new()
+///     .deny(Access::All, "devices/%c/secret")
+///     .allow(Access::All, "devices/%c/#")
+///     .allow(Access::Subscribe, "users/%u/+");
+/// ```
+#[derive(Clone, Default)]
+pub struct Acl {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl Acl {
+    /// Create acl without rules, access is denied for all topics
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow access to topics that match the pattern
+    ///
+    /// Panics if pattern is not a valid topic filter.
+    pub fn allow(self, access: Access, pattern: &str) -> Self {
+        self.rule(true, access, pattern)
+    }
+
+    /// Deny access to topics that match the pattern
+    ///
+    /// Panics if pattern is not a valid topic filter.
+    pub fn deny(self, access: Access, pattern: &str) -> Self {
+        self.rule(false, access, pattern)
+    }
+
+    fn rule(mut self, allow: bool, access: Access, pattern: &str) -> Self {
+        let filter = pattern.replace("%c", "c").replace("%u", "u");
+        if let Err(e) = TopicFilter::try_from(ByteString::from(filter)) {
+            panic!("Invalid acl pattern {:?}: {:?}", pattern, e)
+        }
+        Arc::make_mut(&mut self.rules).push(Rule {
+            allow,
+            access,
+            pattern: pattern.to_string(),
+        });
+        self
+    }
+}
+
+impl fmt::Debug for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Acl").field("rules", &self.rules.len()).finish()
+    }
+}
+
+/// Acl rules of the session with substituted patterns
+#[derive(Debug)]
+pub struct AclContext {
+    rules: Vec<(bool, Access, TopicFilter)>,
+}
+
+impl AclContext {
+    /// Check if session is allowed to publish to the topic
+    pub fn publish(&self, topic: &str) -> bool {
+        self.check(Access::Publish, |_, filter| filter.matches_topic(topic))
+    }
+
+    /// Check if session is allowed to subscribe to the topic filter
+    pub fn subscribe(&self, filter: &str) -> bool {
+        let requested = if SharedTopicFilter::is_shared(filter) {
+            SharedTopicFilter::try_from(ByteString::from(filter)).map(|f| f.into_parts().1)
+        } else {
+            TopicFilter::try_from(ByteString::from(filter))
+        };
+        match requested {
+            Ok(requested) => self.check(Access::Subscribe, |allow, filter| {
+                // deny rule also applies to filters that include denied topics
+                filter.matches_filter(&requested)
+                    || (!allow && requested.matches_filter(filter))
+            }),
+            Err(_) => false,
+        }
+    }
+
+    fn check<F>(&self, access: Access, f: F) -> bool
+    where
+        F: Fn(bool, &TopicFilter) -> bool,
+    {
+        self.rules
+            .iter()
+            .find(|(allow, acc, filter)| acc.contains(access) && f(*allow, filter))
+            .map(|(allow, _, _)| *allow)
+            .unwrap_or(false)
+    }
+}
+
+impl<St: Identity> Authorizer<St> for Acl {
+    type Context = AclContext;
+
+    fn context(&self, session: &St) -> AclContext {
+        let client_id = session.client_id();
+        let username = session.username();
+
+        let rules = self
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                let mut pattern = rule.pattern.clone();
+                if pattern.contains("%c") {
+                    pattern = pattern.replace("%c", valid_level(client_id)?);
+                }
+                if pattern.contains("%u") {
+                    pattern = pattern.replace("%u", valid_level(username?)?);
+                }
+                TopicFilter::try_from(ByteString::from(pattern))
+                    .ok()
+                    .map(|filter| (rule.allow, rule.access, filter))
+            })
+            .collect();
+        AclContext { rules }
+    }
+
+    fn publish(&self, ctx: &AclContext, topic: &str, _: QoS) -> bool {
+        ctx.publish(topic)
+    }
+
+    fn subscribe(&self, ctx: &AclContext, filter: &str, _: QoS) -> bool {
+        ctx.subscribe(filter)
+    }
+}
+
+fn valid_level(val: &str) -> Option<&str> {
+    // leading `$` could make pattern match system topics
+    if val.is_empty() || val.starts_with('$') || val.contains(['/', '+', '#']) {
+        None
+    } else {
+        Some(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Id(&'static str, Option<&'static str>);
+
+    impl Identity for Id {
+        fn client_id(&self) -> &str {
+            self.0
+        }
+
+        fn username(&self) -> Option<&str> {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl::new()
+            .deny(Access::All, "devices/%c/secret")
+            .allow(Access::All, "devices/%c/#")
+            .allow(Access::Subscribe, "users/%u/+")
+            .allow(Access::Publish, "events");
+
+        let ctx = acl.context(&Id("dev1", Some("user1")));
+        assert!(ctx.publish("devices/dev1/temp"));
+        assert!(!ctx.publish("devices/dev1/secret"));
+        assert!(!ctx.publish("devices/dev2/temp"));
+        assert!(ctx.publish("events"));
+        assert!(!ctx.subscribe("events"));
+
+        assert!(ctx.subscribe("devices/dev1/temp/#"));
+        assert!(!ctx.subscribe("devices/dev1/#"));
+        assert!(ctx.subscribe("$share/group/devices/dev1/temp/+"));
+        assert!(!ctx.subscribe("$share/group/devices/dev1/+"));
+        assert!(!ctx.subscribe("devices/+/temp"));
+        assert!(ctx.subscribe("users/user1/inbox"));
+        assert!(!ctx.subscribe("users/user1/#"));
+        assert!(!ctx.publish("users/user1/inbox"));
+        assert!(!ctx.subscribe("users/+/#/"));
+    }
+
+    #[test]
+    fn test_acl_substitution() {
+        let acl = Acl::new().allow(Access::All, "devices/%c/#").allow(Access::All, "users/%u");
+
+        // no user name
+        let ctx = acl.context(&Id("dev1", None));
+        assert!(ctx.publish("devices/dev1/temp"));
+        assert!(!ctx.publish("users/"));
+
+        // wildcards in client id
+        let ctx = acl.context(&Id("#", Some("+")));
+        assert!(!ctx.publish("devices/dev1/temp"));
+        assert!(!ctx.publish("users/user1"));
+
+        // system topics
+        let acl = Acl::new().allow(Access::All, "%c/#").allow(Access::All, "%u/#");
+        let ctx = acl.context(&Id("$SYS", Some("$share")));
+        assert!(!ctx.publish("$SYS/broker/load"));
+        assert!(!ctx.subscribe("$SYS/#"));
+        assert!(!ctx.subscribe("$share/group/topic"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_acl_invalid_pattern() {
+        let _ = Acl::new().allow(Access::All, "devices/#/%c");
+    }
+}
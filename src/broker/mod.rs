@@ -0,0 +1,924 @@
+//! Subscription routing for v3 and v5 sessions.
+//!
+//! `Broker` keeps subscription table of connected sessions and routes
+//! publishes to every matching session, downgrading QoS to the granted level.
+//! Messages matching shared subscriptions (`$share/{group}/{filter}`) are
+//! delivered to one session of the share group, according to `SharedPolicy`.
+//! Retained messages are kept in optional `RetainedStore` and delivered
+//! to new subscriptions according to subscription's retain handling option.
+//! Last will of a session is published when session's connection is closed
+//! without DISCONNECT packet, after will delay interval.
+//! Deliveries to v5 sessions are added to session's outbound queue,
+//! deliveries to v3 sessions are queued by broker while sink is not ready.
+//!
+//! Broker uses session sinks, which are bound to a worker thread. Broker
+//! instance routes messages only between sessions of the same worker.
+
+mod retained;
+
+pub use self::retained::{InMemoryRetainedStore, RetainedMessage, RetainedStore};
+
+use std::{cell::Cell, cell::RefCell, convert::TryFrom, fmt, num::NonZeroU32, rc::Rc};
+use std::{collections::VecDeque, time::Duration};
+
+use ntex::time::sleep;
+use ntex::util::{ByteString, Bytes, HashMap};
+
+use crate::error::SendPacketError;
+use crate::topic::{SharedTopicFilter, TopicFilter, TopicTrie};
+use crate::{types::QoS, utils::random, v3, v5};
+use v5::codec::{RetainHandling, SubscribeAckReason};
+
+/// Sink of broker session
+#[derive(Clone, Debug)]
+pub enum BrokerSink {
+    V3(v3::MqttSink),
+    V5(v5::MqttSink),
+}
+
+impl From<v3::MqttSink> for BrokerSink {
+    fn from(sink: v3::MqttSink) -> Self {
+        BrokerSink::V3(sink)
+    }
+}
+
+impl From<v5::MqttSink> for BrokerSink {
+    fn from(sink: v5::MqttSink) -> Self {
+        BrokerSink::V5(sink)
+    }
+}
+
+impl BrokerSink {
+    fn is_open(&self) -> bool {
+        match self {
+            BrokerSink::V3(sink) => sink.is_open(),
+            BrokerSink::V5(sink) => sink.is_open(),
+        }
+    }
+
+    fn is_same(&self, other: &BrokerSink) -> bool {
+        match (self, other) {
+            (BrokerSink::V3(sink), BrokerSink::V3(other)) => sink.is_same(other),
+            (BrokerSink::V5(sink), BrokerSink::V5(other)) => sink.is_same(other),
+            _ => false,
+        }
+    }
+}
+
+// max duration of single sleep of delayed will publication
+const MAX_SLEEP: Duration = Duration::from_secs(86_400);
+// default max number of queued deliveries of v3 session
+const MAX_QUEUE: usize = 1024;
+
+/// Message routed by broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Topic name of the message
+    pub topic: ByteString,
+    /// Message payload
+    pub payload: Bytes,
+    /// QoS of the message, delivery QoS is limited by granted QoS of subscription
+    pub qos: QoS,
+    /// Retain flag, message is stored in retained store if set
+    pub retain: bool,
+    /// Publish properties, used only for v5 sessions
+    pub properties: v5::codec::PublishProperties,
+}
+
+impl Message {
+    /// Create new message
+    pub fn new(topic: ByteString, payload: Bytes, qos: QoS) -> Self {
+        Message { topic, payload, qos, retain: false, properties: Default::default() }
+    }
+}
+
+impl<'a> From<&'a Message> for RetainedMessage {
+    fn from(msg: &'a Message) -> Self {
+        RetainedMessage {
+            topic: msg.topic.clone(),
+            payload: msg.payload.clone(),
+            qos: msg.qos,
+            properties: msg.properties.clone(),
+        }
+    }
+}
+
+impl From<RetainedMessage> for Message {
+    fn from(msg: RetainedMessage) -> Self {
+        Message {
+            topic: msg.topic,
+            payload: msg.payload,
+            qos: msg.qos,
+            retain: true,
+            properties: msg.properties,
+        }
+    }
+}
+
+impl<'a> From<&'a v3::Publish> for Message {
+    fn from(p: &'a v3::Publish) -> Self {
+        let pkt = p.packet();
+        Message {
+            topic: pkt.topic.clone(),
+            payload: pkt.payload.clone(),
+            qos: pkt.qos,
+            retain: pkt.retain,
+            properties: Default::default(),
+        }
+    }
+}
+
+impl<'a> From<&'a v5::Publish> for Message {
+    fn from(p: &'a v5::Publish) -> Self {
+        let pkt = p.packet();
+        let mut properties = pkt.properties.clone();
+        properties.topic_alias = None;
+        properties.subscription_ids.clear();
+
+        Message {
+            properties,
+            topic: pkt.topic.clone(),
+            payload: pkt.payload.clone(),
+            qos: pkt.qos,
+            retain: pkt.retain,
+        }
+    }
+}
+
+/// Deliveries of v3 session waiting for sink readiness
+struct Queue {
+    sink: v3::MqttSink,
+    msgs: RefCell<VecDeque<v3::codec::Publish>>,
+}
+
+/// Last will of a session
+#[derive(Debug)]
+struct Will {
+    msg: Message,
+    delay: Duration,
+    // session expiry interval, will is published when session ends
+    expiry: Duration,
+    // id of scheduled publication
+    scheduled: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Subscription {
+    qos: QoS,
+    no_local: bool,
+    retain_as_published: bool,
+    id: Option<NonZeroU32>,
+}
+
+impl Subscription {
+    fn v3(qos: QoS) -> Self {
+        Subscription { qos, no_local: false, retain_as_published: false, id: None }
+    }
+}
+
+/// Delivery policy of shared subscriptions
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SharedPolicy {
+    /// Group members receive messages in turn
+    #[default]
+    RoundRobin,
+    /// Message is delivered to random group member
+    Random,
+    /// Messages are delivered to the same member while it is connected
+    Sticky,
+}
+
+/// Members of shared subscription group
+#[derive(Debug, Default)]
+struct SharedGroup {
+    members: Vec<(ByteString, Subscription)>,
+    // position of the next member for round-robin and sticky policies
+    next: Cell<usize>,
+}
+
+impl SharedGroup {
+    /// Select group member for delivery, skips unavailable members
+    fn select<F>(
+        &self,
+        policy: SharedPolicy,
+        available: F,
+    ) -> Option<&(ByteString, Subscription)>
+    where
+        F: Fn(&ByteString) -> bool,
+    {
+        let len = self.members.len();
+        let start = match policy {
+            SharedPolicy::Random => (random() * len as f64) as usize,
+            SharedPolicy::RoundRobin | SharedPolicy::Sticky => self.next.get(),
+        };
+        for idx in (start..start + len).map(|idx| idx % len) {
+            let member = &self.members[idx];
+            if available(&member.0) {
+                if policy == SharedPolicy::RoundRobin {
+                    self.next.set(idx + 1);
+                } else {
+                    self.next.set(idx);
+                }
+                return Some(member);
+            }
+        }
+        None
+    }
+
+    fn remove(&mut self, client_id: &ByteString) -> bool {
+        let len = self.members.len();
+        self.members.retain(|(id, _)| id != client_id);
+        self.members.len() != len
+    }
+}
+
+#[derive(Clone, Default)]
+/// Subscription router for v3 and v5 sessions
+pub struct Broker(Rc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    max_qos: Cell<Option<QoS>>,
+    max_queue: Cell<Option<usize>>,
+    shared_disabled: Cell<bool>,
+    shared_policy: Cell<SharedPolicy>,
+    sessions: RefCell<HashMap<ByteString, BrokerSink>>,
+    filters: RefCell<TopicTrie<HashMap<ByteString, Subscription>>>,
+    shared: RefCell<TopicTrie<HashMap<ByteString, SharedGroup>>>,
+    retained: RefCell<Option<Rc<dyn RetainedStore>>>,
+    wills: RefCell<HashMap<ByteString, Will>>,
+    will_id: Cell<u64>,
+    queues: RefCell<HashMap<ByteString, Rc<Queue>>>,
+}
+
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("sessions", &self.0.sessions.borrow().len())
+            .field("filters", &self.0.filters.borrow().len())
+            .field("shared", &self.0.shared.borrow().len())
+            .finish()
+    }
+}
+
+impl Broker {
+    /// Create new broker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set max granted qos.
+    ///
+    /// By default requested qos is granted.
+    pub fn max_qos(self, qos: QoS) -> Self {
+        self.0.max_qos.set(Some(qos));
+        self
+    }
+
+    /// Set max number of queued deliveries of v3 session.
+    ///
+    /// Deliveries are queued while v3 session sink is not ready, new
+    /// deliveries to full queue are dropped. Deliveries to v5 sessions use
+    /// outbound queue of the session, see `v5::MqttServer::outbound_queue()`.
+    ///
+    /// By default queue holds up to 1024 messages.
+    pub fn max_queue(self, len: usize) -> Self {
+        self.0.max_queue.set(Some(len));
+        self
+    }
+
+    /// Enable or disable shared subscriptions.
+    ///
+    /// If disabled, shared subscriptions are rejected with
+    /// `SharedSubscriptionNotSupported` reason. By default shared
+    /// subscriptions are enabled.
+    pub fn shared_subscriptions(self, enabled: bool) -> Self {
+        self.0.shared_disabled.set(!enabled);
+        self
+    }
+
+    /// Set retained messages store.
+    ///
+    /// By default retained messages are not stored.
+    pub fn retained_store<S: RetainedStore + 'static>(self, store: S) -> Self {
+        *self.0.retained.borrow_mut() = Some(Rc::new(store));
+        self
+    }
+
+    /// Set delivery policy of shared subscriptions.
+    ///
+    /// By default policy is set to `SharedPolicy::RoundRobin`
+    pub fn shared_policy(self, policy: SharedPolicy) -> Self {
+        self.0.shared_policy.set(policy);
+        self
+    }
+
+    /// Register session sink.
+    ///
+    /// Sink of existing session with the same client id get replaced,
+    /// session subscriptions are preserved. Pending last will of the session
+    /// is cancelled, will of new connection must be registered after connect.
+    pub fn connect<T: Into<BrokerSink>>(&self, client_id: ByteString, sink: T) {
+        self.0.wills.borrow_mut().remove(&client_id);
+        self.0.sessions.borrow_mut().insert(client_id, sink.into());
+    }
+
+    /// Remove session and its subscriptions.
+    ///
+    /// Last will of the session get published.
+    pub fn disconnect(&self, client_id: &ByteString) {
+        self.remove_session(client_id, None);
+    }
+
+    /// Handle closed connection of the session.
+    ///
+    /// Session and its subscriptions are removed only if `sink` is still
+    /// the sink of the session. Connection that is closed after session
+    /// takeover does not affect the session that took over.
+    pub fn closed<T: Into<BrokerSink>>(&self, client_id: &ByteString, sink: T) {
+        self.remove_session(client_id, Some(&sink.into()));
+    }
+
+    fn remove_session(&self, client_id: &ByteString, sink: Option<&BrokerSink>) {
+        {
+            let mut sessions = self.0.sessions.borrow_mut();
+            if let Some(sink) = sink {
+                if sessions.get(client_id).is_some_and(|s| !s.is_same(sink)) {
+                    log::trace!("Session {:?} is taken over, keep subscriptions", client_id);
+                    return;
+                }
+            }
+            sessions.remove(client_id);
+        }
+        self.publish_will(client_id);
+
+        self.0.filters.borrow_mut().retain(|subscribers| {
+            subscribers.remove(client_id);
+            !subscribers.is_empty()
+        });
+        self.0.shared.borrow_mut().retain(|groups| {
+            groups.retain(|_, group| {
+                group.remove(client_id);
+                !group.members.is_empty()
+            });
+            !groups.is_empty()
+        });
+    }
+
+    /// Number of connected sessions
+    pub fn sessions(&self) -> usize {
+        self.0.sessions.borrow().len()
+    }
+
+    /// Register last will of v3 session
+    pub fn will_v3(&self, client_id: &ByteString, will: &v3::codec::LastWill) {
+        let mut msg = Message::new(will.topic.clone(), will.message.clone(), will.qos);
+        msg.retain = will.retain;
+        self.set_will(client_id, msg, Duration::ZERO, Duration::ZERO);
+    }
+
+    /// Register last will of v5 session
+    ///
+    /// Will delay interval is limited by session expiry interval of
+    /// connect packet, will is published when session ends (MQTT5 3.1.3.2.2).
+    pub fn will_v5(&self, client_id: &ByteString, pkt: &v5::codec::Connect) {
+        let will = if let Some(ref will) = pkt.last_will { will } else { return };
+        let mut msg = Message::new(will.topic.clone(), will.message.clone(), will.qos);
+        msg.retain = will.retain;
+        msg.properties.correlation_data = will.correlation_data.clone();
+        msg.properties.message_expiry_interval = will.message_expiry_interval;
+        msg.properties.content_type = will.content_type.clone();
+        msg.properties.user_properties = will.user_properties.clone();
+        msg.properties.is_utf8_payload = will.is_utf8_payload.unwrap_or(false);
+        msg.properties.response_topic = will.response_topic.clone();
+
+        let delay = Duration::from_secs(will.will_delay_interval_sec.unwrap_or(0) as u64);
+        let expiry = Duration::from_secs(pkt.session_expiry_interval_secs as u64);
+        self.set_will(client_id, msg, delay, expiry);
+    }
+
+    /// Handle DISCONNECT packet of v3 session, last will is discarded
+    pub fn peer_disconnect_v3(&self, client_id: &ByteString) {
+        self.0.wills.borrow_mut().remove(client_id);
+    }
+
+    /// Handle DISCONNECT packet of v5 session.
+    ///
+    /// Last will is discarded unless reason code is `DisconnectWithWillMessage`.
+    /// Session expiry interval of the packet updates will delay limit.
+    pub fn peer_disconnect_v5(&self, client_id: &ByteString, msg: &v5::control::Disconnect) {
+        let pkt = msg.packet();
+        let mut wills = self.0.wills.borrow_mut();
+        if pkt.reason_code != v5::codec::DisconnectReasonCode::DisconnectWithWillMessage {
+            wills.remove(client_id);
+        } else if let (Some(will), Some(secs)) =
+            (wills.get_mut(client_id), pkt.session_expiry_interval_secs)
+        {
+            will.expiry = Duration::from_secs(secs as u64);
+        }
+    }
+
+    /// Add subscriptions of v3 subscribe message.
+    ///
+    /// Each subscription get confirmed with granted qos, or failed if topic filter is invalid
+    /// or shared subscriptions are disabled. Matching retained messages are sent to the session.
+    pub fn subscribe_v3(&self, client_id: &ByteString, msg: &mut v3::control::Subscribe) {
+        for mut sub in msg.iter_mut() {
+            let qos = self.granted_qos(sub.qos());
+            let item = Subscription::v3(qos);
+            if self.add(client_id, sub.topic(), item).is_ok() {
+                self.send_retained(client_id, sub.topic(), item);
+                sub.confirm(qos);
+            } else {
+                sub.fail();
+            }
+        }
+    }
+
+    /// Add subscriptions of v5 subscribe message.
+    ///
+    /// Each subscription get confirmed with granted qos, or failed with reason
+    /// of `SubscriptionOptions::validate()`. Matching retained messages are sent
+    /// to the session according to retain handling option.
+    pub fn subscribe_v5(&self, client_id: &ByteString, msg: &mut v5::control::Subscribe) {
+        let id = msg.packet().id;
+        for mut sub in msg.iter_mut() {
+            let opts = sub.options();
+            let qos = self.granted_qos(opts.qos);
+            let item = Subscription {
+                qos,
+                id,
+                no_local: opts.no_local,
+                retain_as_published: opts.retain_as_published,
+            };
+            let res = opts
+                .validate(sub.topic(), !self.0.shared_disabled.get())
+                .and_then(|_| self.add(client_id, sub.topic(), item));
+            match res {
+                Ok(is_new) => {
+                    let retained = match opts.retain_handling {
+                        RetainHandling::AtSubscribe => true,
+                        RetainHandling::AtSubscribeNew => is_new,
+                        RetainHandling::NoAtSubscribe => false,
+                    };
+                    if retained {
+                        self.send_retained(client_id, sub.topic(), item);
+                    }
+                    sub.confirm(qos)
+                }
+                Err(reason) => sub.fail(reason),
+            }
+        }
+    }
+
+    /// Remove subscriptions of v3 unsubscribe message
+    pub fn unsubscribe_v3(&self, client_id: &ByteString, msg: &v3::control::Unsubscribe) {
+        for topic in msg.iter() {
+            self.remove(client_id, topic);
+        }
+    }
+
+    /// Remove subscriptions of v5 unsubscribe message
+    pub fn unsubscribe_v5(&self, client_id: &ByteString, msg: &mut v5::control::Unsubscribe) {
+        for mut item in msg.iter_mut() {
+            if self.remove(client_id, item.topic()) {
+                item.success();
+            } else {
+                item.fail(v5::codec::UnsubscribeAckReason::NoSubscriptionExisted);
+            }
+        }
+    }
+
+    /// Route v3 publish to subscribed sessions
+    pub fn publish_v3(&self, client_id: &ByteString, publish: &v3::Publish) -> usize {
+        self.publish(Some(client_id), Message::from(publish))
+    }
+
+    /// Route v5 publish to subscribed sessions
+    pub fn publish_v5(&self, client_id: &ByteString, publish: &v5::Publish) -> usize {
+        self.publish(Some(client_id), Message::from(publish))
+    }
+
+    /// Route message to subscribed sessions.
+    ///
+    /// `from` is client id of the publisher, it is used for `no_local`
+    /// subscription option. Returns number of sessions message is sent to.
+    pub fn publish(&self, from: Option<&ByteString>, msg: Message) -> usize {
+        if msg.retain {
+            if let Some(ref store) = *self.0.retained.borrow() {
+                store.retain(RetainedMessage::from(&msg));
+            }
+        }
+        let sessions = self.0.sessions.borrow();
+
+        // collect matching subscriptions per session
+        let mut matched: HashMap<ByteString, (QoS, bool, Vec<NonZeroU32>)> = HashMap::default();
+        let mut add = |client_id: &ByteString, sub: &Subscription| {
+            let entry = matched
+                .entry(client_id.clone())
+                .or_insert_with(|| (QoS::AtMostOnce, false, Vec::new()));
+            entry.0 = entry.0.max(sub.qos);
+            entry.1 |= sub.retain_as_published;
+            if let Some(id) = sub.id {
+                entry.2.push(id);
+            }
+        };
+        for subscribers in self.0.filters.borrow().matches(&msg.topic) {
+            for (client_id, sub) in subscribers {
+                if !(sub.no_local && Some(client_id) == from) {
+                    add(client_id, sub);
+                }
+            }
+        }
+
+        // one member of each matching share group
+        let policy = self.0.shared_policy.get();
+        let available = |id: &ByteString| sessions.get(id).is_some_and(BrokerSink::is_open);
+        for groups in self.0.shared.borrow().matches(&msg.topic) {
+            for group in groups.values() {
+                if let Some((client_id, sub)) = group.select(policy, available) {
+                    add(client_id, sub);
+                }
+            }
+        }
+
+        let mut count = 0;
+        for (client_id, (qos, retain_as_published, ids)) in matched {
+            if let Some(sink) = sessions.get(&client_id).filter(|sink| sink.is_open()) {
+                let qos = qos.min(msg.qos);
+                self.send(&client_id, sink, &msg, qos, msg.retain && retain_as_published, ids);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn set_will(
+        &self,
+        client_id: &ByteString,
+        msg: Message,
+        delay: Duration,
+        expiry: Duration,
+    ) {
+        let will = Will { msg, delay, expiry, scheduled: None };
+        self.0.wills.borrow_mut().insert(client_id.clone(), will);
+    }
+
+    /// Publish last will, or schedule publication after will delay interval
+    fn publish_will(&self, client_id: &ByteString) {
+        let mut wills = self.0.wills.borrow_mut();
+        let will = match wills.get_mut(client_id) {
+            Some(will) if will.scheduled.is_none() => will,
+            _ => return,
+        };
+
+        let mut delay = will.delay.min(will.expiry);
+        if delay.is_zero() {
+            let will = wills.remove(client_id).unwrap();
+            drop(wills);
+            log::trace!("Publish last will of {:?}", client_id);
+            self.publish(Some(client_id), will.msg);
+        } else {
+            let id = self.0.will_id.get() + 1;
+            self.0.will_id.set(id);
+            will.scheduled = Some(id);
+
+            let broker = self.clone();
+            let client_id = client_id.clone();
+            ntex::rt::spawn(async move {
+                while !delay.is_zero() {
+                    let step = delay.min(MAX_SLEEP);
+                    sleep(step).await;
+                    delay -= step;
+                }
+                let will = {
+                    let mut wills = broker.0.wills.borrow_mut();
+                    match wills.get(&client_id) {
+                        Some(will) if will.scheduled == Some(id) => wills.remove(&client_id),
+                        _ => None,
+                    }
+                };
+                if let Some(will) = will {
+                    log::trace!("Publish last will of {:?}", client_id);
+                    broker.publish(Some(&client_id), will.msg);
+                }
+            });
+        }
+    }
+
+    fn granted_qos(&self, qos: QoS) -> QoS {
+        self.0.max_qos.get().map(|max| qos.min(max)).unwrap_or(qos)
+    }
+
+    /// Add subscription, returns `true` if subscription is new
+    fn add(
+        &self,
+        client_id: &ByteString,
+        topic: &ByteString,
+        sub: Subscription,
+    ) -> Result<bool, SubscribeAckReason> {
+        if SharedTopicFilter::is_shared(topic) {
+            return self.add_shared(client_id, topic, sub);
+        }
+        let filter = TopicFilter::try_from(topic.clone())
+            .map_err(|_| SubscribeAckReason::TopicFilterInvalid)?;
+
+        let mut filters = self.0.filters.borrow_mut();
+        if let Some(subscribers) = filters.get_mut(&filter) {
+            Ok(subscribers.insert(client_id.clone(), sub).is_none())
+        } else {
+            let mut subscribers = HashMap::default();
+            subscribers.insert(client_id.clone(), sub);
+            filters.insert(&filter, subscribers);
+            Ok(true)
+        }
+    }
+
+    fn add_shared(
+        &self,
+        client_id: &ByteString,
+        topic: &ByteString,
+        sub: Subscription,
+    ) -> Result<bool, SubscribeAckReason> {
+        if self.0.shared_disabled.get() {
+            return Err(SubscribeAckReason::SharedSubscriptionNotSupported);
+        }
+        let (group, filter) = SharedTopicFilter::try_from(topic.clone())
+            .map_err(|_| SubscribeAckReason::TopicFilterInvalid)?
+            .into_parts();
+
+        let mut shared = self.0.shared.borrow_mut();
+        if shared.get(&filter).is_none() {
+            shared.insert(&filter, HashMap::default());
+        }
+        let group = shared.get_mut(&filter).unwrap().entry(group).or_default();
+        if let Some(member) = group.members.iter_mut().find(|(id, _)| id == client_id) {
+            member.1 = sub;
+            Ok(false)
+        } else {
+            group.members.push((client_id.clone(), sub));
+            Ok(true)
+        }
+    }
+
+    /// Send retained messages matching topic filter to the session.
+    ///
+    /// Retained messages are not sent for shared subscriptions.
+    fn send_retained(&self, client_id: &ByteString, topic: &ByteString, sub: Subscription) {
+        let msgs = match (&*self.0.retained.borrow(), TopicFilter::try_from(topic.clone())) {
+            (Some(store), Ok(filter)) if !SharedTopicFilter::is_shared(topic) => {
+                store.matches(&filter)
+            }
+            _ => return,
+        };
+        let sink = match self.0.sessions.borrow().get(client_id) {
+            Some(sink) if !msgs.is_empty() && sink.is_open() => sink.clone(),
+            _ => return,
+        };
+
+        // messages are sent after subscribe ack
+        let broker = self.clone();
+        let client_id = client_id.clone();
+        ntex::rt::spawn(async move {
+            for msg in msgs {
+                let qos = sub.qos.min(msg.qos);
+                let ids = sub.id.into_iter().collect();
+                broker.send(&client_id, &sink, &Message::from(msg), qos, true, ids);
+            }
+        });
+    }
+
+    /// Send message to the session
+    ///
+    /// Deliveries to v5 session are added to outbound queue of the session,
+    /// deliveries to v3 session are queued while session sink is not ready.
+    fn send(
+        &self,
+        client_id: &ByteString,
+        sink: &BrokerSink,
+        msg: &Message,
+        qos: QoS,
+        retain: bool,
+        ids: Vec<NonZeroU32>,
+    ) {
+        let result = match sink {
+            BrokerSink::V3(sink) => {
+                let pkt = v3::codec::Publish {
+                    qos,
+                    retain,
+                    dup: false,
+                    topic: msg.topic.clone(),
+                    packet_id: None,
+                    payload: msg.payload.clone(),
+                };
+                self.send_v3(client_id, sink, pkt)
+            }
+            BrokerSink::V5(sink) => {
+                let mut properties = msg.properties.clone();
+                properties.subscription_ids = ids;
+                sink.publish_pkt(v5::codec::Publish {
+                    qos,
+                    retain,
+                    properties,
+                    dup: false,
+                    topic: msg.topic.clone(),
+                    packet_id: None,
+                    payload: msg.payload.clone(),
+                })
+                .try_enqueue(qos)
+            }
+        };
+        if let Err(e) = result {
+            log::trace!("Routed publish to {:?} is dropped: {:?}", client_id, e);
+        }
+    }
+
+    fn send_v3(
+        &self,
+        client_id: &ByteString,
+        sink: &v3::MqttSink,
+        pkt: v3::codec::Publish,
+    ) -> Result<(), SendPacketError> {
+        let mut queues = self.0.queues.borrow_mut();
+        if let Some(queue) = queues.get(client_id).filter(|queue| queue.sink.is_same(sink)) {
+            let mut msgs = queue.msgs.borrow_mut();
+            if msgs.len() >= self.0.max_queue.get().unwrap_or(MAX_QUEUE) {
+                return Err(SendPacketError::QueueFull);
+            }
+            msgs.push_back(pkt);
+            return Ok(());
+        }
+        if sink.is_ready() {
+            return sink.publish_pkt(pkt).send_no_wait();
+        }
+
+        // wait for sink readiness, single task per session
+        let queue =
+            Rc::new(Queue { sink: sink.clone(), msgs: RefCell::new(VecDeque::from([pkt])) });
+        queues.insert(client_id.clone(), queue.clone());
+
+        let broker = self.clone();
+        let client_id = client_id.clone();
+        ntex::rt::spawn(async move {
+            while queue.sink.ready().await {
+                let pkt = queue.msgs.borrow_mut().pop_front();
+                if let Some(pkt) = pkt {
+                    if let Err(e) = queue.sink.publish_pkt(pkt).send_no_wait() {
+                        log::trace!("Routed publish to {:?} is dropped: {:?}", client_id, e);
+                    }
+                } else {
+                    break;
+                }
+            }
+            let mut queues = broker.0.queues.borrow_mut();
+            if queues.get(&client_id).is_some_and(|item| Rc::ptr_eq(item, &queue)) {
+                queues.remove(&client_id);
+            }
+        });
+        Ok(())
+    }
+
+    fn remove(&self, client_id: &ByteString, topic: &ByteString) -> bool {
+        if SharedTopicFilter::is_shared(topic) {
+            return self.remove_shared(client_id, topic);
+        }
+        let filter = if let Ok(filter) = TopicFilter::try_from(topic.clone()) {
+            filter
+        } else {
+            return false;
+        };
+
+        let mut filters = self.0.filters.borrow_mut();
+        if let Some(subscribers) = filters.get_mut(&filter) {
+            let removed = subscribers.remove(client_id).is_some();
+            if subscribers.is_empty() {
+                filters.remove(&filter);
+            }
+            removed
+        } else {
+            false
+        }
+    }
+
+    fn remove_shared(&self, client_id: &ByteString, topic: &ByteString) -> bool {
+        let (group, filter) = if let Ok(filter) = SharedTopicFilter::try_from(topic.clone()) {
+            filter.into_parts()
+        } else {
+            return false;
+        };
+
+        let mut shared = self.0.shared.borrow_mut();
+        let groups = if let Some(groups) = shared.get_mut(&filter) {
+            groups
+        } else {
+            return false;
+        };
+        let removed = if let Some(members) = groups.get_mut(&group) {
+            let removed = members.remove(client_id);
+            if members.members.is_empty() {
+                groups.remove(&group);
+            }
+            removed
+        } else {
+            false
+        };
+        if groups.is_empty() {
+            shared.remove(&filter);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscriptions() {
+        let broker = Broker::new().max_qos(QoS::AtLeastOnce);
+        let client = ByteString::from_static("client");
+
+        let mut msg = v3::control::Subscribe::new(
+            std::num::NonZeroU16::new(1).unwrap(),
+            0,
+            vec![
+                ("a/+".into(), QoS::ExactlyOnce),
+                ("a/#/b".into(), QoS::AtMostOnce),
+                ("b".into(), QoS::AtMostOnce),
+            ],
+        );
+        broker.subscribe_v3(&client, &mut msg);
+        assert_eq!(broker.0.filters.borrow().len(), 2);
+        assert_eq!(
+            broker.0.filters.borrow().get(&"a/+".parse().unwrap()).unwrap()[&client].qos,
+            QoS::AtLeastOnce
+        );
+
+        let msg = v3::control::Unsubscribe::new(
+            std::num::NonZeroU16::new(1).unwrap(),
+            0,
+            vec!["b".into()],
+        );
+        broker.unsubscribe_v3(&client, &msg);
+        assert_eq!(broker.0.filters.borrow().len(), 1);
+
+        broker.disconnect(&client);
+        assert!(broker.0.filters.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_shared_subscriptions() {
+        let broker = Broker::new();
+        let (c1, c2) = (ByteString::from_static("c1"), ByteString::from_static("c2"));
+
+        for client in [&c1, &c2] {
+            let mut msg = v3::control::Subscribe::new(
+                std::num::NonZeroU16::new(1).unwrap(),
+                0,
+                vec![
+                    ("$share/g/a/+".into(), QoS::AtLeastOnce),
+                    ("$share//a".into(), QoS::AtMostOnce),
+                ],
+            );
+            broker.subscribe_v3(client, &mut msg);
+        }
+        assert_eq!(broker.0.shared.borrow().len(), 1);
+        assert!(broker.0.filters.borrow().is_empty());
+
+        let shared = broker.0.shared.borrow();
+        let group = &shared.get(&"a/+".parse().unwrap()).unwrap()["g"];
+        let select = |policy| group.select(policy, |_| true).unwrap().0.clone();
+        assert_eq!(select(SharedPolicy::RoundRobin), c1);
+        assert_eq!(select(SharedPolicy::RoundRobin), c2);
+        assert_eq!(select(SharedPolicy::RoundRobin), c1);
+        assert_eq!(select(SharedPolicy::Sticky), c2);
+        assert_eq!(select(SharedPolicy::Sticky), c2);
+        assert_eq!(group.select(SharedPolicy::Sticky, |id| *id == c1).unwrap().0, c1);
+        assert_eq!(select(SharedPolicy::Sticky), c1);
+        assert!(group.select(SharedPolicy::Random, |_| false).is_none());
+        for _ in 0..10 {
+            assert_eq!(group.select(SharedPolicy::Random, |id| *id == c2).unwrap().0, c2);
+        }
+        drop(shared);
+
+        let msg = v3::control::Unsubscribe::new(
+            std::num::NonZeroU16::new(1).unwrap(),
+            0,
+            vec!["$share/g/a/+".into()],
+        );
+        broker.unsubscribe_v3(&c1, &msg);
+        assert_eq!(broker.0.shared.borrow().len(), 1);
+        broker.disconnect(&c2);
+        assert!(broker.0.shared.borrow().is_empty());
+
+        // shared subscriptions are disabled
+        let broker = Broker::new().shared_subscriptions(false);
+        let mut msg = v3::control::Subscribe::new(
+            std::num::NonZeroU16::new(1).unwrap(),
+            0,
+            vec![("$share/g/a/+".into(), QoS::AtLeastOnce)],
+        );
+        broker.subscribe_v3(&c1, &mut msg);
+        assert!(broker.0.shared.borrow().is_empty());
+    }
+}
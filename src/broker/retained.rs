@@ -0,0 +1,243 @@
+//! Retained messages storage
+use std::sync::{Arc, Mutex};
+use std::{fmt, time::Instant};
+
+use ntex::util::{ByteString, Bytes, HashMap};
+
+use crate::topic::{TopicFilter, TopicFilterLevel};
+use crate::{types::QoS, v5::codec::PublishProperties, v5::expiry};
+
+/// Storage for retained messages
+///
+/// Store keeps last retained message for each topic. Broker delivers
+/// retained messages to new subscriptions with matching topic filters.
+pub trait RetainedStore {
+    /// Store retained message.
+    ///
+    /// Message with empty payload removes retained message of the topic.
+    fn retain(&self, msg: RetainedMessage);
+
+    /// Get retained messages matching topic filter.
+    ///
+    /// Expired messages must not be returned, message expiry interval
+    /// of returned message is set to remaining lifetime.
+    fn matches(&self, filter: &TopicFilter) -> Vec<RetainedMessage>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Retained message
+pub struct RetainedMessage {
+    /// Topic name of the message
+    pub topic: ByteString,
+    /// Message payload, never empty
+    pub payload: Bytes,
+    /// QoS of the message, delivery QoS is limited by granted QoS of subscription
+    pub qos: QoS,
+    /// Publish properties, `message_expiry_interval` limits message lifetime
+    pub properties: PublishProperties,
+}
+
+#[derive(Clone, Default)]
+/// In-memory retained message store
+///
+/// Messages are indexed by topic levels. Store could be cloned and
+/// shared between server workers.
+pub struct InMemoryRetainedStore {
+    root: Arc<Mutex<RetainedNode>>,
+}
+
+#[derive(Default)]
+struct RetainedNode {
+    msg: Option<(RetainedMessage, Option<Instant>)>,
+    children: HashMap<ByteString, RetainedNode>,
+}
+
+impl InMemoryRetainedStore {
+    /// Create new in-memory store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of retained messages, including expired ones
+    pub fn len(&self) -> usize {
+        self.root.lock().unwrap().len()
+    }
+
+    /// Check if store is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl RetainedStore for InMemoryRetainedStore {
+    fn retain(&self, msg: RetainedMessage) {
+        let mut root = self.root.lock().unwrap();
+        if msg.payload.is_empty() {
+            root.remove(&mut msg.topic.split('/'));
+        } else {
+            let deadline = expiry::deadline(&msg.properties, Instant::now());
+            let node = msg.topic.split('/').fold(&mut *root, |node, level| {
+                node.children.entry(ByteString::from(level)).or_default()
+            });
+            node.msg = Some((msg, deadline));
+        }
+    }
+
+    fn matches(&self, filter: &TopicFilter) -> Vec<RetainedMessage> {
+        let mut result = Vec::new();
+        self.root.lock().unwrap().matches(filter.levels(), true, Instant::now(), &mut result);
+        result
+    }
+}
+
+impl fmt::Debug for InMemoryRetainedStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemoryRetainedStore").field("messages", &self.len()).finish()
+    }
+}
+
+impl RetainedNode {
+    fn len(&self) -> usize {
+        self.children.values().map(|node| node.len()).sum::<usize>()
+            + self.msg.is_some() as usize
+    }
+
+    /// Remove message of the topic and empty nodes
+    fn remove(&mut self, levels: &mut std::str::Split<'_, char>) {
+        if let Some(level) = levels.next() {
+            if let Some(node) = self.children.get_mut(level) {
+                node.remove(levels);
+                if node.msg.is_none() && node.children.is_empty() {
+                    self.children.remove(level);
+                }
+            }
+        } else {
+            self.msg = None;
+        }
+    }
+
+    fn matches(
+        &mut self,
+        levels: &[TopicFilterLevel],
+        root: bool,
+        now: Instant,
+        result: &mut Vec<RetainedMessage>,
+    ) {
+        let (level, rest) = if let Some(item) = levels.split_first() {
+            item
+        } else {
+            return self.take(now, result);
+        };
+
+        match level {
+            TopicFilterLevel::Normal(s) | TopicFilterLevel::System(s) => {
+                if let Some(node) = self.children.get_mut(s) {
+                    node.matches(rest, false, now, result);
+                }
+            }
+            TopicFilterLevel::Blank => {
+                if let Some(node) = self.children.get_mut("") {
+                    node.matches(rest, false, now, result);
+                }
+            }
+            TopicFilterLevel::SingleWildcard => {
+                for (name, node) in self.children.iter_mut() {
+                    // wildcards do not match topics starting with `$`
+                    if !(root && name.starts_with('$')) {
+                        node.matches(rest, false, now, result);
+                    }
+                }
+            }
+            TopicFilterLevel::MultiWildcard => {
+                // `#` matches parent level as well
+                if !root {
+                    self.take(now, result);
+                }
+                for (name, node) in self.children.iter_mut() {
+                    if !(root && name.starts_with('$')) {
+                        node.matches(levels, false, now, result);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add message to the result, expired message is removed
+    fn take(&mut self, now: Instant, result: &mut Vec<RetainedMessage>) {
+        match self.msg {
+            Some((_, Some(deadline))) if deadline <= now => self.msg = None,
+            Some((ref msg, deadline)) => {
+                let mut msg = msg.clone();
+                if expiry::refresh(&mut msg.properties, deadline, now) {
+                    result.push(msg);
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+
+    fn retained(topic: &'static str, payload: &'static str) -> RetainedMessage {
+        RetainedMessage {
+            topic: ByteString::from_static(topic),
+            payload: Bytes::from_static(payload.as_bytes()),
+            qos: QoS::AtLeastOnce,
+            properties: PublishProperties::default(),
+        }
+    }
+
+    fn matches(store: &InMemoryRetainedStore, filter: &'static str) -> Vec<ByteString> {
+        let mut topics: Vec<_> =
+            store.matches(&filter.parse().unwrap()).into_iter().map(|msg| msg.topic).collect();
+        topics.sort();
+        topics
+    }
+
+    #[test]
+    fn test_retained() {
+        let store = InMemoryRetainedStore::new();
+        for topic in ["a", "a/b", "a/c", "a/b/c", "/a", "$SYS/a"] {
+            store.retain(retained(topic, "data"));
+        }
+        assert_eq!(store.len(), 6);
+        assert_eq!(matches(&store, "a/b"), vec!["a/b"]);
+        assert_eq!(matches(&store, "a/+"), vec!["a/b", "a/c"]);
+        assert_eq!(matches(&store, "a/#"), vec!["a", "a/b", "a/b/c", "a/c"]);
+        assert_eq!(matches(&store, "+/a"), vec!["/a"]);
+        assert_eq!(matches(&store, "#"), vec!["/a", "a", "a/b", "a/b/c", "a/c"]);
+        assert_eq!(matches(&store, "$SYS/#"), vec!["$SYS/a"]);
+        assert!(matches(&store, "b/#").is_empty());
+
+        // replace message
+        store.retain(retained("a/b", "data2"));
+        assert_eq!(store.len(), 6);
+        assert_eq!(store.matches(&"a/b".parse().unwrap())[0].payload, "data2");
+
+        // empty payload removes retained message
+        store.retain(retained("a/b/c", ""));
+        store.retain(retained("a/d", ""));
+        assert_eq!(store.len(), 5);
+        assert_eq!(matches(&store, "a/b/#"), vec!["a/b"]);
+        assert!(!store.root.lock().unwrap().children["a"].children["b"]
+            .children
+            .contains_key("c"));
+
+        // message expiry
+        let mut msg = retained("e", "data");
+        msg.properties.message_expiry_interval = NonZeroU32::new(10);
+        store.retain(msg);
+        let interval =
+            store.matches(&"e".parse().unwrap())[0].properties.message_expiry_interval;
+        assert!(interval == NonZeroU32::new(10) || interval == NonZeroU32::new(9));
+
+        store.root.lock().unwrap().children.get_mut("e").unwrap().msg.as_mut().unwrap().1 =
+            Some(Instant::now());
+        assert!(matches(&store, "e").is_empty());
+        assert_eq!(store.clone().len(), 5);
+    }
+}
@@ -1,8 +1,8 @@
 use std::{fmt, io, num::NonZeroU16};
 
-use ntex::util::Either;
+use ntex::util::{ByteString, Either};
 
-use crate::v5::codec::DisconnectReasonCode;
+use crate::v5::codec::{DisconnectReasonCode, PublishAckReason, SubscribeAckReason};
 
 /// Errors which can occur when attempting to handle mqtt connection.
 #[derive(Debug, thiserror::Error)]
@@ -176,6 +176,49 @@ pub enum SendPacketError {
     /// Peer disconnected
     #[error("Peer is disconnected")]
     Disconnected,
+    /// Offline queue is full
+    #[error("Offline queue is full")]
+    QueueFull,
+    /// Message expiry interval elapsed before message is sent
+    #[error("Message is expired")]
+    Expired,
+}
+
+/// Request/response error
+#[derive(Debug, PartialEq, Eq, Copy, Clone, thiserror::Error)]
+pub enum RequestError {
+    /// Cannot send packet
+    #[error("Send packet error {:?}", _0)]
+    Send(#[from] SendPacketError),
+    /// Subscription to response topic is rejected
+    #[error("Subscription to response topic is rejected: {:?}", _0)]
+    Subscribe(SubscribeAckReason),
+    /// Request publish is rejected
+    #[error("Request is rejected: {:?}", _0)]
+    Rejected(PublishAckReason),
+    /// Response is not received within timeout
+    #[error("Response timeout")]
+    Timeout,
+}
+
+/// Enhanced authentication error
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Authentication error: {0}")]
+pub struct AuthError(ByteString);
+
+impl AuthError {
+    /// Create authentication error with reason
+    pub fn new<T>(reason: T) -> Self
+    where
+        ByteString: From<T>,
+    {
+        AuthError(reason.into())
+    }
+
+    /// Error reason
+    pub fn reason(&self) -> &ByteString {
+        &self.0
+    }
 }
 
 /// Errors which can occur when attempting to handle mqtt client connection.
@@ -196,6 +239,9 @@ pub enum ClientError<T: fmt::Debug> {
     /// Connect error
     #[error("Connect error: {}", _0)]
     Connect(#[from] ntex::connect::ConnectError),
+    /// Enhanced authentication error
+    #[error("{}", _0)]
+    Auth(#[from] AuthError),
 }
 
 impl<T: fmt::Debug> From<EncodeError> for ClientError<T> {
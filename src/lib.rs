@@ -7,22 +7,37 @@ mod topic;
 #[macro_use]
 mod utils;
 
+pub mod auth;
+#[cfg(feature = "broker")]
+pub mod broker;
 pub mod error;
+pub mod metrics;
+pub mod store;
 pub mod v3;
 pub mod v5;
 
+mod admission;
 mod inflight;
 mod io;
+mod rate;
+mod reconnect;
+mod registry;
 mod server;
 mod service;
 mod session;
+mod trace;
 mod types;
 mod version;
 
+pub use self::admission::Admission;
 pub use self::error::{HandshakeError, MqttError, ProtocolError};
+pub use self::rate::RateLimit;
+pub use self::registry::SessionRegistry;
 pub use self::server::MqttServer;
 pub use self::session::Session;
-pub use self::topic::{TopicFilter, TopicFilterError, TopicFilterLevel};
+pub use self::topic::{
+    SharedTopicFilter, TopicFilter, TopicFilterError, TopicFilterLevel, TopicTrie,
+};
 pub use types::QoS;
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
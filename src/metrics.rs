@@ -0,0 +1,457 @@
+//! Connection and packet metrics
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{cell::RefCell, fmt, fmt::Write, rc::Rc, sync::Arc, time::Duration};
+
+/// Mqtt protocol version
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// Mqtt v3.1.1
+    V3,
+    /// Mqtt v5
+    V5,
+}
+
+impl Protocol {
+    /// Protocol name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::V3 => "v3",
+            Protocol::V5 => "v5",
+        }
+    }
+}
+
+/// Mqtt control packet type
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PacketType {
+    Connect,
+    ConnectAck,
+    Publish,
+    PublishAck,
+    PublishReceived,
+    PublishRelease,
+    PublishComplete,
+    Subscribe,
+    SubscribeAck,
+    Unsubscribe,
+    UnsubscribeAck,
+    PingRequest,
+    PingResponse,
+    Disconnect,
+    Auth,
+}
+
+const PACKET_TYPES: [PacketType; 15] = [
+    PacketType::Connect,
+    PacketType::ConnectAck,
+    PacketType::Publish,
+    PacketType::PublishAck,
+    PacketType::PublishReceived,
+    PacketType::PublishRelease,
+    PacketType::PublishComplete,
+    PacketType::Subscribe,
+    PacketType::SubscribeAck,
+    PacketType::Unsubscribe,
+    PacketType::UnsubscribeAck,
+    PacketType::PingRequest,
+    PacketType::PingResponse,
+    PacketType::Disconnect,
+    PacketType::Auth,
+];
+
+impl PacketType {
+    /// Packet type from the first byte of fixed header
+    pub(crate) fn from_first_byte(byte: u8) -> Option<Self> {
+        match byte >> 4 {
+            0 => None,
+            idx => Some(PACKET_TYPES[(idx - 1) as usize]),
+        }
+    }
+
+    /// Packet type name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PacketType::Connect => "connect",
+            PacketType::ConnectAck => "connack",
+            PacketType::Publish => "publish",
+            PacketType::PublishAck => "puback",
+            PacketType::PublishReceived => "pubrec",
+            PacketType::PublishRelease => "pubrel",
+            PacketType::PublishComplete => "pubcomp",
+            PacketType::Subscribe => "subscribe",
+            PacketType::SubscribeAck => "suback",
+            PacketType::Unsubscribe => "unsubscribe",
+            PacketType::UnsubscribeAck => "unsuback",
+            PacketType::PingRequest => "pingreq",
+            PacketType::PingResponse => "pingresp",
+            PacketType::Disconnect => "disconnect",
+            PacketType::Auth => "auth",
+        }
+    }
+
+    fn idx(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Metrics observer
+///
+/// Observer could be registered on server and client connector, all
+/// methods have empty default implementations.
+pub trait Metrics {
+    /// Connection is established, handshake is completed
+    fn connection_opened(&self, _protocol: Protocol) {}
+
+    /// Connection is closed
+    fn connection_closed(&self, _protocol: Protocol) {}
+
+    /// Packet is received from the peer, `size` is a size of the whole packet in bytes
+    fn packet_received(&self, _protocol: Protocol, _packet: PacketType, _size: usize) {}
+
+    /// Packet is sent to the peer, `size` is a size of the whole packet in bytes
+    fn packet_sent(&self, _protocol: Protocol, _packet: PacketType, _size: usize) {}
+
+    /// Handshake is completed
+    ///
+    /// For server `latency` is a time between start of the handshake and
+    /// CONNACK packet, for client between CONNECT and CONNACK packets.
+    fn handshake(&self, _protocol: Protocol, _latency: Duration) {}
+
+    /// Number of in-flight messages of a connection is changed
+    fn inflight(&self, _protocol: Protocol, _depth: usize) {}
+
+    /// Protocol error occurred
+    fn protocol_error(&self, _protocol: Protocol) {}
+}
+
+/// Metrics observer of a connection
+pub(crate) struct MetricsRef {
+    protocol: Protocol,
+    metrics: RefCell<Option<Rc<dyn Metrics>>>,
+}
+
+impl MetricsRef {
+    pub(crate) fn new(protocol: Protocol) -> Self {
+        Self { protocol, metrics: RefCell::new(None) }
+    }
+
+    pub(crate) fn set(&self, metrics: Option<Rc<dyn Metrics>>) {
+        *self.metrics.borrow_mut() = metrics;
+    }
+
+    #[inline]
+    pub(crate) fn with<F: FnOnce(&dyn Metrics, Protocol)>(&self, f: F) {
+        if let Some(ref metrics) = *self.metrics.borrow() {
+            f(metrics.as_ref(), self.protocol)
+        }
+    }
+
+    /// Record decoded packet
+    pub(crate) fn received(&self, first_byte: u8, remaining_length: u32) {
+        self.with(|metrics, protocol| {
+            if let Some(tp) = PacketType::from_first_byte(first_byte) {
+                let size = fixed_header_len(remaining_length) + remaining_length as usize;
+                metrics.packet_received(protocol, tp, size);
+            }
+        })
+    }
+
+    /// Record encoded packet
+    pub(crate) fn sent(&self, packet_type: u8, size: usize) {
+        self.with(|metrics, protocol| {
+            if let Some(tp) = PacketType::from_first_byte(packet_type) {
+                metrics.packet_sent(protocol, tp, size);
+            }
+        })
+    }
+}
+
+/// Size of fixed header for specified remaining length
+fn fixed_header_len(remaining_length: u32) -> usize {
+    match remaining_length {
+        0..=127 => 2,
+        128..=16_383 => 3,
+        16_384..=2_097_151 => 4,
+        _ => 5,
+    }
+}
+
+impl Clone for MetricsRef {
+    fn clone(&self) -> Self {
+        Self { protocol: self.protocol, metrics: RefCell::new(self.metrics.borrow().clone()) }
+    }
+}
+
+impl fmt::Debug for MetricsRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsRef")
+            .field("protocol", &self.protocol)
+            .field("enabled", &self.metrics.borrow().is_some())
+            .finish()
+    }
+}
+
+const HANDSHAKE_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+const INFLIGHT_BUCKETS: [f64; 8] = [0.0, 1.0, 4.0, 16.0, 64.0, 256.0, 1024.0, 4096.0];
+
+#[derive(Clone, Default)]
+/// Prometheus metrics exporter
+///
+/// Collects metrics of all connections that share exporter, `render()`
+/// returns metrics in prometheus text exposition format.
+///
+/// ```rust
+/// use ntex_mqtt::metrics::PrometheusMetrics;
+///
+/// let metrics = PrometheusMetrics::new();
+/// // register `metrics.clone()` on servers and clients
+/// let text = metrics.render();
+/// assert!(text.contains("mqtt_connections_opened_total"));
+/// ```
+pub struct PrometheusMetrics(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    opened: [AtomicU64; 2],
+    closed: [AtomicU64; 2],
+    received: [[AtomicU64; 15]; 2],
+    sent: [[AtomicU64; 15]; 2],
+    received_bytes: [AtomicU64; 2],
+    sent_bytes: [AtomicU64; 2],
+    errors: [AtomicU64; 2],
+    handshake: [Histogram; 2],
+    inflight: [Histogram; 2],
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; 8],
+    count: AtomicU64,
+    // sum of observed values in micro units
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, bounds: &[f64; 8], val: f64) {
+        for (bucket, bound) in self.buckets.iter().zip(bounds.iter()) {
+            if val <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add((val * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, proto: Protocol, bounds: &[f64; 8]) {
+        let proto = proto.as_str();
+        for (bucket, bound) in self.buckets.iter().zip(bounds.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{protocol=\"{}\",le=\"{}\"}} {}",
+                name,
+                proto,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ =
+            writeln!(out, "{}_bucket{{protocol=\"{}\",le=\"+Inf\"}} {}", name, proto, count);
+        let _ = writeln!(out, "{}_sum{{protocol=\"{}\"}} {}", name, proto, sum);
+        let _ = writeln!(out, "{}_count{{protocol=\"{}\"}} {}", name, proto, count);
+    }
+}
+
+impl PrometheusMetrics {
+    /// Create new exporter
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render metrics in prometheus text format
+    pub fn render(&self) -> String {
+        let c = &self.0;
+        let mut out = String::new();
+
+        counter(&mut out, "mqtt_connections_opened_total", "Opened connections", &c.opened);
+        counter(&mut out, "mqtt_connections_closed_total", "Closed connections", &c.closed);
+        header(&mut out, "mqtt_connections_active", "Active connections", "gauge");
+        for proto in [Protocol::V3, Protocol::V5] {
+            let idx = proto_idx(proto);
+            let active = c.opened[idx]
+                .load(Ordering::Relaxed)
+                .saturating_sub(c.closed[idx].load(Ordering::Relaxed));
+            let _ = writeln!(
+                out,
+                "mqtt_connections_active{{protocol=\"{}\"}} {}",
+                proto.as_str(),
+                active
+            );
+        }
+
+        packets(&mut out, "mqtt_packets_received_total", "Received packets", &c.received);
+        packets(&mut out, "mqtt_packets_sent_total", "Sent packets", &c.sent);
+        counter(&mut out, "mqtt_received_bytes_total", "Received bytes", &c.received_bytes);
+        counter(&mut out, "mqtt_sent_bytes_total", "Sent bytes", &c.sent_bytes);
+        counter(&mut out, "mqtt_protocol_errors_total", "Protocol errors", &c.errors);
+
+        let name = "mqtt_handshake_duration_seconds";
+        header(&mut out, name, "Handshake latency", "histogram");
+        for proto in [Protocol::V3, Protocol::V5] {
+            c.handshake[proto_idx(proto)].render(&mut out, name, proto, &HANDSHAKE_BUCKETS);
+        }
+        let name = "mqtt_inflight_messages";
+        header(&mut out, name, "In-flight messages per connection", "histogram");
+        for proto in [Protocol::V3, Protocol::V5] {
+            c.inflight[proto_idx(proto)].render(&mut out, name, proto, &INFLIGHT_BUCKETS);
+        }
+        out
+    }
+}
+
+impl fmt::Debug for PrometheusMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrometheusMetrics").finish()
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn connection_opened(&self, protocol: Protocol) {
+        self.0.opened[proto_idx(protocol)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self, protocol: Protocol) {
+        self.0.closed[proto_idx(protocol)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn packet_received(&self, protocol: Protocol, packet: PacketType, size: usize) {
+        let idx = proto_idx(protocol);
+        self.0.received[idx][packet.idx()].fetch_add(1, Ordering::Relaxed);
+        self.0.received_bytes[idx].fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    fn packet_sent(&self, protocol: Protocol, packet: PacketType, size: usize) {
+        let idx = proto_idx(protocol);
+        self.0.sent[idx][packet.idx()].fetch_add(1, Ordering::Relaxed);
+        self.0.sent_bytes[idx].fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    fn handshake(&self, protocol: Protocol, latency: Duration) {
+        self.0.handshake[proto_idx(protocol)]
+            .observe(&HANDSHAKE_BUCKETS, latency.as_secs_f64());
+    }
+
+    fn inflight(&self, protocol: Protocol, depth: usize) {
+        self.0.inflight[proto_idx(protocol)].observe(&INFLIGHT_BUCKETS, depth as f64);
+    }
+
+    fn protocol_error(&self, protocol: Protocol) {
+        self.0.errors[proto_idx(protocol)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn proto_idx(protocol: Protocol) -> usize {
+    match protocol {
+        Protocol::V3 => 0,
+        Protocol::V5 => 1,
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, tp: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, tp);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, values: &[AtomicU64; 2]) {
+    header(out, name, help, "counter");
+    for proto in [Protocol::V3, Protocol::V5] {
+        let _ = writeln!(
+            out,
+            "{}{{protocol=\"{}\"}} {}",
+            name,
+            proto.as_str(),
+            values[proto_idx(proto)].load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn packets(out: &mut String, name: &str, help: &str, values: &[[AtomicU64; 15]; 2]) {
+    header(out, name, help, "counter");
+    for proto in [Protocol::V3, Protocol::V5] {
+        for tp in PACKET_TYPES.iter() {
+            let val = values[proto_idx(proto)][tp.idx()].load(Ordering::Relaxed);
+            if val != 0 {
+                let _ = writeln!(
+                    out,
+                    "{}{{protocol=\"{}\",type=\"{}\"}} {}",
+                    name,
+                    proto.as_str(),
+                    tp.as_str(),
+                    val
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::packet_type;
+
+    #[test]
+    fn test_packet_type() {
+        assert_eq!(PacketType::from_first_byte(0), None);
+        assert_eq!(
+            PacketType::from_first_byte(packet_type::CONNECT),
+            Some(PacketType::Connect)
+        );
+        assert_eq!(
+            PacketType::from_first_byte(packet_type::PUBLISH_END),
+            Some(PacketType::Publish)
+        );
+        assert_eq!(
+            PacketType::from_first_byte(packet_type::PUBREL),
+            Some(PacketType::PublishRelease)
+        );
+        assert_eq!(PacketType::from_first_byte(packet_type::AUTH), Some(PacketType::Auth));
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let metrics = PrometheusMetrics::new();
+        metrics.connection_opened(Protocol::V5);
+        metrics.connection_opened(Protocol::V5);
+        metrics.connection_closed(Protocol::V5);
+        metrics.packet_received(Protocol::V5, PacketType::Connect, 20);
+        metrics.packet_sent(Protocol::V5, PacketType::ConnectAck, 5);
+        metrics.packet_sent(Protocol::V3, PacketType::Publish, 10);
+        metrics.packet_sent(Protocol::V3, PacketType::Publish, 12);
+        metrics.handshake(Protocol::V3, Duration::from_millis(20));
+        metrics.inflight(Protocol::V5, 3);
+        metrics.protocol_error(Protocol::V3);
+
+        let text = metrics.render();
+        for line in [
+            "# TYPE mqtt_connections_opened_total counter",
+            "mqtt_connections_opened_total{protocol=\"v5\"} 2",
+            "mqtt_connections_active{protocol=\"v5\"} 1",
+            "mqtt_connections_active{protocol=\"v3\"} 0",
+            "mqtt_packets_received_total{protocol=\"v5\",type=\"connect\"} 1",
+            "mqtt_packets_sent_total{protocol=\"v3\",type=\"publish\"} 2",
+            "mqtt_sent_bytes_total{protocol=\"v3\"} 22",
+            "mqtt_received_bytes_total{protocol=\"v5\"} 20",
+            "mqtt_protocol_errors_total{protocol=\"v3\"} 1",
+            "mqtt_handshake_duration_seconds_bucket{protocol=\"v3\",le=\"0.01\"} 0",
+            "mqtt_handshake_duration_seconds_bucket{protocol=\"v3\",le=\"0.05\"} 1",
+            "mqtt_handshake_duration_seconds_count{protocol=\"v3\"} 1",
+            "mqtt_handshake_duration_seconds_sum{protocol=\"v3\"} 0.02",
+            "mqtt_inflight_messages_bucket{protocol=\"v5\",le=\"4\"} 1",
+            "mqtt_inflight_messages_bucket{protocol=\"v5\",le=\"+Inf\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not found in:\n{}", line, text);
+        }
+        assert!(!text.contains("type=\"pingreq\""));
+    }
+}
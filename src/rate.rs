@@ -0,0 +1,229 @@
+//! Inbound message rate limiting
+use std::{cell::Cell, cell::RefCell, task::Context, task::Poll, time::Instant};
+
+use ntex::time::{Millis, Seconds, Sleep};
+
+/// Inbound rate limit configuration
+///
+/// Limits apply to PUBLISH packets received from the peer and are enforced
+/// with token buckets. Reads from the peer are paused until buckets refill.
+/// Bucket capacity is equal to the number of tokens refilled during burst
+/// period, if bytes limit is lower than size of a single message, every
+/// such message exceeds the rate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    messages: u32,
+    bytes: u32,
+    burst: Seconds,
+    disconnect_after: u16,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self { messages: 0, bytes: 0, burst: Seconds(1), disconnect_after: 0 }
+    }
+}
+
+impl RateLimit {
+    /// Create rate limit configuration without limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set max number of messages per second
+    ///
+    /// To disable limit set value to 0.
+    pub fn messages(mut self, val: u32) -> Self {
+        self.messages = val;
+        self
+    }
+
+    /// Set max number of message bytes per second
+    ///
+    /// To disable limit set value to 0.
+    pub fn bytes(mut self, val: u32) -> Self {
+        self.bytes = val;
+        self
+    }
+
+    /// Set burst period
+    ///
+    /// Peer can send messages at unlimited rate until tokens accumulated during
+    /// burst period are consumed. By default burst period is set to 1 second.
+    /// Panics if period is `0`.
+    pub fn burst(mut self, val: Seconds) -> Self {
+        if val.is_zero() {
+            panic!("Burst period must be greater than 0")
+        }
+        self.burst = val;
+        self
+    }
+
+    /// Disconnect peer after number of consecutive messages exceed the rate
+    ///
+    /// v5 peer is disconnected with `MessageRateTooHigh` reason code, v3 connection
+    /// is closed. By default peer is never disconnected.
+    pub fn disconnect_after(mut self, val: u16) -> Self {
+        self.disconnect_after = val;
+        self
+    }
+
+    fn is_limited(&self) -> bool {
+        self.messages != 0 || self.bytes != 0
+    }
+}
+
+/// Token buckets of a connection
+pub(crate) struct RateLimiter {
+    config: RateLimit,
+    // available tokens, negative value is a debt
+    messages: Cell<f64>,
+    bytes: Cell<f64>,
+    updated: Cell<Instant>,
+    violations: Cell<u16>,
+    sleep: RefCell<Option<Sleep>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimit) -> Option<Self> {
+        if config.is_limited() {
+            let burst = config.burst.seconds() as f64;
+            Some(Self {
+                config,
+                messages: Cell::new(config.messages as f64 * burst),
+                bytes: Cell::new(config.bytes as f64 * burst),
+                updated: Cell::new(Instant::now()),
+                violations: Cell::new(0),
+                sleep: RefCell::new(None),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Consume tokens for received message
+    ///
+    /// Returns `false` if peer must be disconnected.
+    pub(crate) fn consume(&self, size: u32) -> bool {
+        self.consume_at(size, Instant::now())
+    }
+
+    /// Check buckets state, timer is armed if rate is exceeded
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let wait = self.wait_at(Instant::now());
+        if wait == 0 {
+            Poll::Ready(())
+        } else {
+            log::trace!("Message rate is exceeded, pause reads for {} ms", wait);
+            let mut sleep = self.sleep.borrow_mut();
+            let sleep = sleep.get_or_insert_with(|| Sleep::new(Millis(wait)));
+            sleep.reset(Millis(wait));
+            sleep.poll_elapsed(cx)
+        }
+    }
+
+    fn consume_at(&self, size: u32, now: Instant) -> bool {
+        self.refill(now);
+
+        let mut exceeded = false;
+        if self.config.messages != 0 {
+            self.messages.set(self.messages.get() - 1.0);
+            exceeded |= self.messages.get() < 0.0;
+        }
+        if self.config.bytes != 0 {
+            self.bytes.set(self.bytes.get() - size as f64);
+            exceeded |= self.bytes.get() < 0.0;
+        }
+
+        if exceeded {
+            let violations = self.violations.get().saturating_add(1);
+            self.violations.set(violations);
+            self.config.disconnect_after == 0 || violations < self.config.disconnect_after
+        } else {
+            self.violations.set(0);
+            true
+        }
+    }
+
+    /// Milliseconds until all buckets are paid off
+    fn wait_at(&self, now: Instant) -> u32 {
+        self.refill(now);
+
+        let mut wait = 0.0_f64;
+        if self.messages.get() < 0.0 {
+            wait = wait.max(-self.messages.get() / self.config.messages as f64);
+        }
+        if self.bytes.get() < 0.0 {
+            wait = wait.max(-self.bytes.get() / self.config.bytes as f64);
+        }
+        (wait * 1000.0).ceil() as u32
+    }
+
+    fn refill(&self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated.get()).as_secs_f64();
+        self.updated.set(now);
+
+        let burst = self.config.burst.seconds() as f64;
+        if self.config.messages != 0 {
+            let rate = self.config.messages as f64;
+            self.messages.set((self.messages.get() + elapsed * rate).min(rate * burst));
+        }
+        if self.config.bytes != 0 {
+            let rate = self.config.bytes as f64;
+            self.bytes.set((self.bytes.get() + elapsed * rate).min(rate * burst));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        assert!(RateLimiter::new(RateLimit::new().disconnect_after(1)).is_none());
+    }
+
+    #[test]
+    fn test_messages() {
+        let limiter = RateLimiter::new(RateLimit::new().messages(10)).unwrap();
+        let now = limiter.updated.get();
+
+        // burst capacity
+        for _ in 0..10 {
+            assert!(limiter.consume_at(100, now));
+        }
+        assert_eq!(limiter.wait_at(now), 0);
+        assert!(limiter.consume_at(100, now));
+        assert_eq!(limiter.violations.get(), 1);
+        assert_eq!(limiter.wait_at(now), 100);
+
+        // refill
+        let now = now + Duration::from_millis(250);
+        assert_eq!(limiter.wait_at(now), 0);
+        assert!(limiter.consume_at(100, now));
+        assert_eq!(limiter.violations.get(), 0);
+
+        // bucket is capped by burst
+        let now = now + Duration::from_secs(10);
+        limiter.refill(now);
+        assert_eq!(limiter.messages.get(), 10.0);
+    }
+
+    #[test]
+    fn test_bytes_disconnect() {
+        let limiter = RateLimiter::new(
+            RateLimit::new().bytes(1000).burst(Seconds(2)).disconnect_after(3),
+        )
+        .unwrap();
+        let now = limiter.updated.get();
+
+        assert!(limiter.consume_at(2000, now));
+        assert!(limiter.consume_at(500, now));
+        assert_eq!(limiter.wait_at(now), 500);
+        assert!(limiter.consume_at(500, now));
+        assert!(!limiter.consume_at(500, now));
+    }
+}
@@ -0,0 +1,202 @@
+//! Helpers for reconnecting clients.
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use ntex::channel::condition::Condition;
+use ntex::service::{Service, ServiceCtx};
+use ntex::time::Millis;
+
+use crate::utils;
+
+/// Reconnect backoff policy.
+///
+/// Delay before attempt `n` is `initial * multiplier^(n-1)`, capped by `max`.
+/// Jitter randomly reduces each delay by up to the given fraction.
+/// Attempts counter is reset only after connection stays up for
+/// the stable period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    initial: Millis,
+    max: Millis,
+    multiplier: f64,
+    jitter: f64,
+    stable: Millis,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Millis(500),
+            max: Millis(30_000),
+            multiplier: 2.0,
+            jitter: 0.2,
+            stable: Millis(10_000),
+        }
+    }
+}
+
+impl Backoff {
+    /// Create backoff policy with initial and max delays
+    pub fn new(initial: Millis, max: Millis) -> Self {
+        Backoff { initial, max, ..Default::default() }
+    }
+
+    /// Set delay multiplier.
+    ///
+    /// By default multiplier is set to 2.0
+    pub fn multiplier(mut self, val: f64) -> Self {
+        self.multiplier = val.max(1.0);
+        self
+    }
+
+    /// Set jitter, fraction of the delay in range `[0.0, 1.0]`.
+    ///
+    /// By default jitter is set to 0.2
+    pub fn jitter(mut self, val: f64) -> Self {
+        self.jitter = val.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set period connection must stay up to reset attempts counter.
+    ///
+    /// By default period is set to 10 seconds
+    pub fn stable_period(mut self, val: Millis) -> Self {
+        self.stable = val;
+        self
+    }
+
+    /// Check if connection that stayed up for `uptime` is stable
+    pub(crate) fn is_stable(&self, uptime: std::time::Duration) -> bool {
+        uptime >= self.stable.into()
+    }
+
+    /// Delay before reconnect attempt, attempts start from 1
+    pub fn delay(&self, attempt: u32) -> Millis {
+        let exp = attempt.saturating_sub(1).min(64) as i32;
+        let delay = (self.initial.0 as f64 * self.multiplier.powi(exp)).min(self.max.0 as f64);
+        let jitter = delay * self.jitter * utils::random();
+        Millis((delay - jitter) as u32)
+    }
+}
+
+/// Offline queue overflow policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Reject the new message with `SendPacketError::QueueFull` error
+    Error,
+}
+
+/// Connection state shared between reconnecting client and its sink
+pub(crate) struct Connection<T> {
+    sink: RefCell<Option<T>>,
+    stopped: Cell<bool>,
+    cond: Condition,
+}
+
+impl<T: Clone> Connection<T> {
+    pub(crate) fn new() -> Self {
+        Connection {
+            sink: RefCell::new(None),
+            stopped: Cell::new(false),
+            cond: Condition::new(),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<T> {
+        self.sink.borrow().clone()
+    }
+
+    pub(crate) fn set(&self, sink: T) {
+        *self.sink.borrow_mut() = Some(sink);
+        self.cond.notify();
+    }
+
+    pub(crate) fn take(&self) -> Option<T> {
+        self.sink.borrow_mut().take()
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.get()
+    }
+
+    pub(crate) fn stop(&self) -> Option<T> {
+        self.stopped.set(true);
+        self.cond.notify();
+        self.get()
+    }
+
+    /// Wait for connection state change
+    pub(crate) async fn changed(&self) {
+        self.cond.wait().ready().await
+    }
+}
+
+/// Service that could be shared between several connections.
+///
+/// Shutdown is not propagated to the inner service.
+pub(crate) struct SharedService<S>(Rc<S>);
+
+impl<S> SharedService<S> {
+    pub(crate) fn new(service: S) -> Self {
+        SharedService(Rc::new(service))
+    }
+}
+
+impl<S> Clone for SharedService<S> {
+    fn clone(&self) -> Self {
+        SharedService(self.0.clone())
+    }
+}
+
+impl<S, R> Service<R> for SharedService<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future<'f> = S::Future<'f> where Self: 'f, R: 'f;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, _: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    #[inline]
+    fn call<'a>(&'a self, req: R, ctx: ServiceCtx<'a, Self>) -> S::Future<'a> {
+        ctx.call_nowait(self.0.as_ref(), req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::new(Millis(100), Millis(1000)).jitter(0.0);
+        assert_eq!(backoff.delay(1), Millis(100));
+        assert_eq!(backoff.delay(2), Millis(200));
+        assert_eq!(backoff.delay(4), Millis(800));
+        assert_eq!(backoff.delay(5), Millis(1000));
+        assert_eq!(backoff.delay(u32::MAX), Millis(1000));
+        assert!(!backoff.is_stable(Duration::from_secs(1)));
+        assert!(backoff.is_stable(Duration::from_secs(10)));
+
+        let backoff = Backoff::new(Millis(100), Millis(1000)).multiplier(3.0).jitter(0.5);
+        for _ in 0..100 {
+            let delay = backoff.delay(2);
+            assert!(delay >= Millis(150) && delay <= Millis(300));
+        }
+    }
+}
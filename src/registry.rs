@@ -0,0 +1,130 @@
+//! Registry of connected clients
+use std::{cell::Cell, cell::RefCell, fmt, rc::Rc};
+
+use ntex::io::OnDisconnect;
+use ntex::util::{ByteString, Either, HashMap};
+
+use crate::{v3, v5};
+
+#[derive(Clone, Default)]
+/// Registry of connected clients, keyed by client id.
+///
+/// Server registers every connection after successful handshake. If client
+/// with the same client id is already connected, existing connection is taken
+/// over: mqtt v5 connection receives DISCONNECT packet with `SessionTakenOver`
+/// reason code, mqtt v3.1.1 connection get closed. Session state persisted by
+/// previous connection is inherited by the new connection.
+///
+/// Registry uses connection sinks, which are bound to a worker thread. Registry
+/// instance tracks only connections of the same worker.
+pub struct SessionRegistry(Rc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    id: Cell<u64>,
+    clients: RefCell<HashMap<ByteString, (u64, Either<v3::MqttSink, v5::MqttSink>)>>,
+}
+
+impl SessionRegistry {
+    /// Create new registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of connected clients
+    pub fn len(&self) -> usize {
+        self.0.clients.borrow().len()
+    }
+
+    /// Check if registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check if client is connected
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.0.clients.borrow().contains_key(client_id)
+    }
+
+    /// Get sink of mqtt v3.1.1 client
+    pub fn get_v3(&self, client_id: &str) -> Option<v3::MqttSink> {
+        match self.0.clients.borrow().get(client_id) {
+            Some((_, Either::Left(sink))) => Some(sink.clone()),
+            _ => None,
+        }
+    }
+
+    /// Get sink of mqtt v5 client
+    pub fn get_v5(&self, client_id: &str) -> Option<v5::MqttSink> {
+        match self.0.clients.borrow().get(client_id) {
+            Some((_, Either::Right(sink))) => Some(sink.clone()),
+            _ => None,
+        }
+    }
+
+    /// Register mqtt v3.1.1 connection, returns `true` if existing connection is taken over
+    pub(crate) fn register_v3(
+        &self,
+        client_id: ByteString,
+        sink: v3::MqttSink,
+        on_disconnect: OnDisconnect,
+    ) -> bool {
+        self.register(client_id, Either::Left(sink), on_disconnect)
+    }
+
+    /// Register mqtt v5 connection, returns `true` if existing connection is taken over
+    pub(crate) fn register_v5(
+        &self,
+        client_id: ByteString,
+        sink: v5::MqttSink,
+        on_disconnect: OnDisconnect,
+    ) -> bool {
+        self.register(client_id, Either::Right(sink), on_disconnect)
+    }
+
+    fn register(
+        &self,
+        client_id: ByteString,
+        sink: Either<v3::MqttSink, v5::MqttSink>,
+        on_disconnect: OnDisconnect,
+    ) -> bool {
+        let id = self.0.id.get() + 1;
+        self.0.id.set(id);
+
+        let prev = self.0.clients.borrow_mut().insert(client_id.clone(), (id, sink));
+        let taken_over = match prev {
+            Some((_, Either::Left(sink))) if sink.is_open() => {
+                sink.force_close();
+                true
+            }
+            Some((_, Either::Right(sink))) if sink.is_open() => {
+                sink.close_with_reason(v5::codec::Disconnect {
+                    reason_code: v5::codec::DisconnectReasonCode::SessionTakenOver,
+                    ..Default::default()
+                });
+                true
+            }
+            _ => false,
+        };
+        if taken_over {
+            log::trace!("Connection of {:?} is taken over", client_id);
+        }
+
+        // remove client on disconnect, unless it is taken over
+        let registry = self.clone();
+        ntex::rt::spawn(async move {
+            on_disconnect.await;
+            let mut clients = registry.0.clients.borrow_mut();
+            if clients.get(&client_id).is_some_and(|item| item.0 == id) {
+                clients.remove(&client_id);
+            }
+        });
+        taken_over
+    }
+}
+
+impl fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionRegistry").field("clients", &self.len()).finish()
+    }
+}
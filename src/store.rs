@@ -0,0 +1,469 @@
+//! Persistent session storage
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, io, num::NonZeroU16, path::PathBuf, rc::Rc};
+
+use ntex::util::{ByteString, Bytes, HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::types::QoS;
+
+/// Storage for persistent mqtt sessions
+///
+/// Server stores session state when connection with `clean_start=false`
+/// (`clean_session=false` for mqtt v3.1.1) is closed and restores it on
+/// next connect with the same client id.
+///
+/// Store methods are called synchronously from the connection's worker
+/// thread, so slow store blocks all connections of the worker.
+pub trait SessionStore {
+    /// Load session state for specified client id.
+    ///
+    /// Expired sessions must not be returned.
+    fn load(&self, client_id: &str) -> Option<SessionState>;
+
+    /// Store session state.
+    ///
+    /// `expiry` is a session expiry interval, `None` means session never expires.
+    fn save(&self, client_id: &str, state: SessionState, expiry: Option<Duration>);
+
+    /// Remove session state for specified client id
+    fn remove(&self, client_id: &str);
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Persisted session state
+pub struct SessionState {
+    /// Client subscriptions
+    ///
+    /// Subscriptions are not re-applied on session restore, application
+    /// must re-install them from `Handshake::session_state()`.
+    pub subscriptions: Vec<Subscription>,
+    /// Outbound QoS 1 and QoS 2 publishes that are not acknowledged by client
+    pub publishes: Vec<StoredPublish>,
+    /// Packet ids of QoS 2 publishes that are received by client
+    /// but PUBCOMP is not received yet
+    pub released: Vec<NonZeroU16>,
+}
+
+impl SessionState {
+    /// Check if session state is empty
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty() && self.publishes.is_empty() && self.released.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Subscription of a client
+pub struct Subscription {
+    /// Topic filter
+    pub filter: ByteString,
+    /// Granted QoS
+    pub qos: QoS,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Outbound publish message
+///
+/// Mqtt v5 specific properties are ignored by mqtt v3.1.1 connections.
+pub struct StoredPublish {
+    /// Packet id of the publish, it is re-sent with the same packet id
+    pub packet_id: NonZeroU16,
+    /// QoS of the publish, QoS 1 or QoS 2
+    pub qos: QoS,
+    /// Retain flag
+    pub retain: bool,
+    /// Topic name
+    pub topic: ByteString,
+    /// Message payload
+    pub payload: Bytes,
+    /// Correlation data of request/response message
+    #[serde(default)]
+    pub correlation_data: Option<Bytes>,
+    /// Message expiration time, seconds since unix epoch
+    #[serde(default)]
+    pub message_expiry: Option<u64>,
+    /// Content type of the payload
+    #[serde(default)]
+    pub content_type: Option<ByteString>,
+    /// User properties
+    #[serde(default)]
+    pub user_properties: Vec<(ByteString, ByteString)>,
+    /// Payload format indicator, payload is UTF-8 encoded character data
+    #[serde(default)]
+    pub is_utf8_payload: bool,
+    /// Topic name for response message
+    #[serde(default)]
+    pub response_topic: Option<ByteString>,
+}
+
+#[derive(Clone, Default)]
+/// In-memory session store
+///
+/// Store could be cloned and shared between server workers.
+pub struct InMemorySessionStore {
+    sessions: Arc<Mutex<HashMap<String, (SessionState, Option<Instant>)>>>,
+}
+
+impl InMemorySessionStore {
+    /// Create new in-memory store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of stored sessions, including expired ones
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Check if store is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn load(&self, client_id: &str) -> Option<SessionState> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(client_id) {
+            Some((_, Some(deadline))) if *deadline <= Instant::now() => {
+                sessions.remove(client_id);
+                None
+            }
+            Some((state, _)) => Some(state.clone()),
+            None => None,
+        }
+    }
+
+    fn save(&self, client_id: &str, state: SessionState, expiry: Option<Duration>) {
+        let deadline = expiry.and_then(|exp| Instant::now().checked_add(exp));
+        self.sessions.lock().unwrap().insert(client_id.to_string(), (state, deadline));
+    }
+
+    fn remove(&self, client_id: &str) {
+        self.sessions.lock().unwrap().remove(client_id);
+    }
+}
+
+impl fmt::Debug for InMemorySessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemorySessionStore").field("sessions", &self.len()).finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+/// File-backed session store
+///
+/// Each session is stored in a separate json file in the store directory.
+/// Errors are logged and treated as missing session.
+///
+/// File operations are blocking, store blocks worker's event loop while
+/// session file is read or written. Store is suitable for small number of
+/// persistent sessions on local disk, use custom [`SessionStore`] with
+/// in-memory cache otherwise.
+pub struct FileSessionStore {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileEntry {
+    /// Expiration time, seconds since unix epoch
+    expires: Option<u64>,
+    state: SessionState,
+}
+
+/// Current time, seconds since unix epoch
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl FileSessionStore {
+    /// Create file store, directory is created if it does not exist
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, client_id: &str) -> PathBuf {
+        // client id could contain any utf-8 chars, use hex encoded file name
+        let mut name = String::with_capacity(client_id.len() * 2 + 5);
+        for b in client_id.as_bytes() {
+            name.push_str(&format!("{:02x}", b));
+        }
+        name.push_str(".json");
+        self.dir.join(name)
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, client_id: &str) -> Option<SessionState> {
+        let path = self.path(client_id);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::error!("Cannot read session file {:?}: {}", path, e);
+                }
+                return None;
+            }
+        };
+        match serde_json::from_slice::<FileEntry>(&data) {
+            Ok(entry) => {
+                if entry.expires.map(|exp| exp <= unix_time()).unwrap_or(false) {
+                    let _ = fs::remove_file(&path);
+                    None
+                } else {
+                    Some(entry.state)
+                }
+            }
+            Err(e) => {
+                log::error!("Cannot parse session file {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn save(&self, client_id: &str, state: SessionState, expiry: Option<Duration>) {
+        let path = self.path(client_id);
+        let entry = FileEntry {
+            expires: expiry.map(|exp| unix_time().saturating_add(exp.as_secs())),
+            state,
+        };
+
+        let data = match serde_json::to_vec(&entry) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("Cannot serialize session state: {}", e);
+                return;
+            }
+        };
+
+        // write to temp file first, so partially written state is never loaded
+        let tmp = path.with_extension("tmp");
+        if let Err(e) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, &path)) {
+            log::error!("Cannot write session file {:?}: {}", path, e);
+        }
+    }
+
+    fn remove(&self, client_id: &str) {
+        let path = self.path(client_id);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::error!("Cannot remove session file {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+enum Pending {
+    Subscribe(Vec<(ByteString, QoS)>),
+    Unsubscribe(Vec<ByteString>),
+}
+
+/// Session persistence state of a connection
+pub(crate) struct SessionPersist {
+    store: Rc<dyn SessionStore>,
+    client_id: ByteString,
+    expiry: Option<Duration>,
+    restored: Option<SessionState>,
+    subscriptions: Vec<Subscription>,
+    pending: HashMap<NonZeroU16, Pending>,
+}
+
+impl SessionPersist {
+    /// Load stored session, or remove it for clean session
+    pub(crate) fn open(
+        store: Rc<dyn SessionStore>,
+        client_id: ByteString,
+        clean: bool,
+    ) -> Self {
+        let restored = if clean {
+            store.remove(&client_id);
+            None
+        } else {
+            store.load(&client_id)
+        };
+        let subscriptions =
+            restored.as_ref().map(|st| st.subscriptions.clone()).unwrap_or_default();
+        Self {
+            store,
+            client_id,
+            restored,
+            subscriptions,
+            expiry: None,
+            pending: HashMap::default(),
+        }
+    }
+
+    pub(crate) fn restored(&self) -> Option<&SessionState> {
+        self.restored.as_ref()
+    }
+
+    pub(crate) fn take_restored(&mut self) -> Option<SessionState> {
+        self.restored.take()
+    }
+
+    /// Set session expiry interval from mqtt v5 property
+    pub(crate) fn set_expiry_secs(&mut self, secs: u32) {
+        self.expiry =
+            if secs == u32::MAX { None } else { Some(Duration::from_secs(secs as u64)) };
+    }
+
+    pub(crate) fn subscribe(&mut self, id: NonZeroU16, filters: Vec<(ByteString, QoS)>) {
+        self.pending.insert(id, Pending::Subscribe(filters));
+    }
+
+    pub(crate) fn unsubscribe(&mut self, id: NonZeroU16, filters: Vec<ByteString>) {
+        self.pending.insert(id, Pending::Unsubscribe(filters));
+    }
+
+    /// Apply subscribe result, `granted` is a granted qos for each topic filter
+    pub(crate) fn subscribed<I>(&mut self, id: NonZeroU16, granted: I)
+    where
+        I: IntoIterator<Item = Option<QoS>>,
+    {
+        if let Some(Pending::Subscribe(filters)) = self.pending.remove(&id) {
+            for ((filter, _), qos) in filters.into_iter().zip(granted) {
+                if let Some(qos) = qos {
+                    if let Some(sub) =
+                        self.subscriptions.iter_mut().find(|s| s.filter == filter)
+                    {
+                        sub.qos = qos;
+                    } else {
+                        self.subscriptions.push(Subscription { filter, qos });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply unsubscribe result, `removed` is a result for each topic filter
+    pub(crate) fn unsubscribed<I>(&mut self, id: NonZeroU16, removed: I)
+    where
+        I: IntoIterator<Item = bool>,
+    {
+        if let Some(Pending::Unsubscribe(filters)) = self.pending.remove(&id) {
+            for (filter, removed) in filters.into_iter().zip(removed) {
+                if removed {
+                    self.subscriptions.retain(|s| s.filter != filter);
+                }
+            }
+        }
+    }
+
+    /// Store session state, session with zero expiry interval is removed
+    pub(crate) fn save(self, publishes: Vec<StoredPublish>, released: Vec<NonZeroU16>) {
+        if self.expiry == Some(Duration::ZERO) {
+            self.store.remove(&self.client_id);
+        } else {
+            log::trace!("Store session state for {:?}", self.client_id);
+            let state = SessionState { subscriptions: self.subscriptions, publishes, released };
+            self.store.save(&self.client_id, state, self.expiry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> SessionState {
+        SessionState {
+            subscriptions: vec![Subscription {
+                filter: ByteString::from_static("topic/#"),
+                qos: QoS::AtLeastOnce,
+            }],
+            publishes: vec![StoredPublish {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                qos: QoS::ExactlyOnce,
+                retain: false,
+                topic: ByteString::from_static("topic/1"),
+                payload: Bytes::from_static(b"data"),
+                correlation_data: None,
+                message_expiry: Some(1_700_000_000),
+                content_type: Some(ByteString::from_static("text/plain")),
+                user_properties: vec![(
+                    ByteString::from_static("key"),
+                    ByteString::from_static("value"),
+                )],
+                is_utf8_payload: true,
+                response_topic: None,
+            }],
+            released: vec![NonZeroU16::new(2).unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let store = InMemorySessionStore::new();
+        assert!(store.load("client").is_none());
+
+        store.save("client", state(), None);
+        assert_eq!(store.load("client"), Some(state()));
+        assert_eq!(store.clone().len(), 1);
+
+        store.remove("client");
+        assert!(store.load("client").is_none());
+
+        store.save("client", state(), Some(Duration::ZERO));
+        assert!(store.load("client").is_none());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = std::env::temp_dir().join(format!("ntex-mqtt-store-{}", std::process::id()));
+        let store = FileSessionStore::new(&dir).unwrap();
+        assert!(store.load("client/1").is_none());
+
+        store.save("client/1", state(), Some(Duration::from_secs(60)));
+        assert_eq!(store.load("client/1"), Some(state()));
+        assert_eq!(FileSessionStore::new(&dir).unwrap().load("client/1"), Some(state()));
+
+        store.remove("client/1");
+        assert!(store.load("client/1").is_none());
+
+        store.save("client/1", state(), Some(Duration::ZERO));
+        assert!(store.load("client/1").is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_persist() {
+        let store = Rc::new(InMemorySessionStore::new());
+        store.save("client", state(), None);
+
+        let mut persist =
+            SessionPersist::open(store.clone(), ByteString::from_static("client"), false);
+        assert_eq!(persist.restored(), Some(&state()));
+        let id1 = NonZeroU16::new(1).unwrap();
+        let id2 = NonZeroU16::new(2).unwrap();
+        persist.subscribe(
+            id1,
+            vec![
+                (ByteString::from_static("a"), QoS::ExactlyOnce),
+                (ByteString::from_static("b"), QoS::AtLeastOnce),
+            ],
+        );
+        persist.subscribed(id1, vec![Some(QoS::AtLeastOnce), None]);
+        persist.unsubscribe(id2, vec![ByteString::from_static("topic/#")]);
+        persist.unsubscribed(id2, vec![true]);
+        persist.save(Vec::new(), Vec::new());
+
+        let st = store.load("client").unwrap();
+        assert_eq!(
+            st.subscriptions,
+            vec![Subscription { filter: ByteString::from_static("a"), qos: QoS::AtLeastOnce }]
+        );
+        assert!(st.publishes.is_empty());
+
+        // clean session removes stored state
+        let mut persist =
+            SessionPersist::open(store.clone(), ByteString::from_static("client"), true);
+        assert!(persist.restored().is_none());
+        persist.set_expiry_secs(0);
+        persist.save(Vec::new(), Vec::new());
+        assert!(store.is_empty());
+    }
+}
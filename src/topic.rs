@@ -1,7 +1,7 @@
 use std::fmt::{self, Write};
-use std::{convert::TryFrom, io};
+use std::{convert::TryFrom, io, str::Split};
 
-use ntex::util::ByteString;
+use ntex::util::{ByteString, HashMap};
 
 pub(crate) fn is_valid(topic: &str) -> bool {
     if topic.is_empty() {
@@ -269,6 +269,305 @@ impl fmt::Display for TopicFilter {
     }
 }
 
+const SHARE_PREFIX: &str = "$share/";
+
+/// Shared subscription topic filter, `$share/{ShareName}/{filter}`
+///
+/// Messages matching shared subscription are delivered to only one
+/// subscriber of the share group.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SharedTopicFilter {
+    group: ByteString,
+    filter: TopicFilter,
+}
+
+impl SharedTopicFilter {
+    /// Create shared topic filter, share name must not be empty
+    /// and must not contain `/`, `+` or `#` chars
+    pub fn new(group: ByteString, filter: TopicFilter) -> Result<Self, TopicFilterError> {
+        if group.is_empty() || group.contains(['/', '+', '#']) {
+            Err(TopicFilterError::InvalidLevel)
+        } else {
+            Ok(SharedTopicFilter { group, filter })
+        }
+    }
+
+    /// Check if topic filter is a shared subscription filter
+    pub fn is_shared<S: AsRef<str> + ?Sized>(topic: &S) -> bool {
+        topic.as_ref().starts_with(SHARE_PREFIX)
+    }
+
+    /// Share name
+    pub fn group(&self) -> &ByteString {
+        &self.group
+    }
+
+    /// Topic filter of the subscription
+    pub fn filter(&self) -> &TopicFilter {
+        &self.filter
+    }
+
+    /// Convert to share name and topic filter
+    pub fn into_parts(self) -> (ByteString, TopicFilter) {
+        (self.group, self.filter)
+    }
+}
+
+impl TryFrom<ByteString> for SharedTopicFilter {
+    type Error = TopicFilterError;
+
+    fn try_from(value: ByteString) -> Result<Self, Self::Error> {
+        let rest = value.strip_prefix(SHARE_PREFIX).ok_or(TopicFilterError::InvalidTopic)?;
+        let (group, filter) = rest.split_once('/').ok_or(TopicFilterError::InvalidTopic)?;
+        let group = recover_bstr(&value, group);
+        let filter = TopicFilter::try_from(recover_bstr(&value, filter))?;
+        SharedTopicFilter::new(group, filter)
+    }
+}
+
+impl std::str::FromStr for SharedTopicFilter {
+    type Err = TopicFilterError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let s: ByteString = value.into();
+        SharedTopicFilter::try_from(s)
+    }
+}
+
+impl fmt::Display for SharedTopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}/{}", SHARE_PREFIX, self.group, self.filter)
+    }
+}
+
+/// Index of topic filters for matching topic names against many filters.
+///
+/// Trie stores one value per topic filter. Matching a topic name visits
+/// only the branches of matching levels instead of checking every filter.
+#[derive(Debug, Clone)]
+pub struct TopicTrie<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node<V> {
+    value: Option<V>,
+    // value of filter that ends with `#` level
+    multi: Option<V>,
+    single: Option<Box<Node<V>>>,
+    levels: HashMap<ByteString, Node<V>>,
+}
+
+impl<V> Default for TopicTrie<V> {
+    fn default() -> Self {
+        TopicTrie { root: Node::default(), len: 0 }
+    }
+}
+
+impl<V> TopicTrie<V> {
+    /// Create empty trie
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of topic filters in trie
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if trie is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert value for topic filter.
+    ///
+    /// Returns previous value of the topic filter.
+    pub fn insert(&mut self, filter: &TopicFilter, value: V) -> Option<V> {
+        let (levels, multi) = split_multi(filter);
+        let node = levels.iter().fold(&mut self.root, |node, level| node.child_mut(level));
+        let prev = if multi { node.multi.replace(value) } else { node.value.replace(value) };
+        if prev.is_none() {
+            self.len += 1;
+        }
+        prev
+    }
+
+    /// Remove topic filter, returns value of the topic filter
+    pub fn remove(&mut self, filter: &TopicFilter) -> Option<V> {
+        let value = self.root.remove(filter.levels());
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    /// Get value of topic filter
+    pub fn get(&self, filter: &TopicFilter) -> Option<&V> {
+        let (levels, multi) = split_multi(filter);
+        let mut node = &self.root;
+        for level in levels {
+            node = match level {
+                TopicFilterLevel::SingleWildcard => node.single.as_deref()?,
+                _ => node.levels.get(level_key(level))?,
+            };
+        }
+        if multi {
+            node.multi.as_ref()
+        } else {
+            node.value.as_ref()
+        }
+    }
+
+    /// Get mutable reference to value of topic filter
+    pub fn get_mut(&mut self, filter: &TopicFilter) -> Option<&mut V> {
+        let (levels, multi) = split_multi(filter);
+        let mut node = &mut self.root;
+        for level in levels {
+            node = match level {
+                TopicFilterLevel::SingleWildcard => node.single.as_deref_mut()?,
+                _ => node.levels.get_mut(level_key(level))?,
+            };
+        }
+        if multi {
+            node.multi.as_mut()
+        } else {
+            node.value.as_mut()
+        }
+    }
+
+    /// Retain only values for which predicate returns `true`
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut V) -> bool,
+    {
+        self.len -= self.root.retain(&mut f);
+    }
+
+    /// Get values of all topic filters that match topic name
+    pub fn matches<S: AsRef<str> + ?Sized>(&self, topic: &S) -> Vec<&V> {
+        let mut values = Vec::new();
+        self.root.matches(topic.as_ref().split('/'), 0, &mut values);
+        values
+    }
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Node { value: None, multi: None, single: None, levels: HashMap::default() }
+    }
+}
+
+impl<V> Node<V> {
+    fn is_empty(&self) -> bool {
+        self.value.is_none()
+            && self.multi.is_none()
+            && self.single.is_none()
+            && self.levels.is_empty()
+    }
+
+    fn child_mut(&mut self, level: &TopicFilterLevel) -> &mut Node<V> {
+        match level {
+            TopicFilterLevel::SingleWildcard => self.single.get_or_insert_with(Box::default),
+            _ => self.levels.entry(ByteString::from(level_key(level))).or_default(),
+        }
+    }
+
+    fn remove(&mut self, levels: &[TopicFilterLevel]) -> Option<V> {
+        match levels.split_first() {
+            None => self.value.take(),
+            Some((TopicFilterLevel::MultiWildcard, _)) => self.multi.take(),
+            Some((TopicFilterLevel::SingleWildcard, rest)) => {
+                let child = self.single.as_mut()?;
+                let value = child.remove(rest);
+                if child.is_empty() {
+                    self.single = None;
+                }
+                value
+            }
+            Some((level, rest)) => {
+                let key = level_key(level);
+                let child = self.levels.get_mut(key)?;
+                let value = child.remove(rest);
+                if child.is_empty() {
+                    self.levels.remove(key);
+                }
+                value
+            }
+        }
+    }
+
+    /// Returns number of removed values
+    fn retain<F>(&mut self, f: &mut F) -> usize
+    where
+        F: FnMut(&mut V) -> bool,
+    {
+        let mut removed = 0;
+        for slot in [&mut self.value, &mut self.multi] {
+            if slot.as_mut().map(|v| !f(v)).unwrap_or(false) {
+                *slot = None;
+                removed += 1;
+            }
+        }
+        if let Some(ref mut child) = self.single {
+            removed += child.retain(f);
+            if child.is_empty() {
+                self.single = None;
+            }
+        }
+        self.levels.retain(|_, child| {
+            removed += child.retain(f);
+            !child.is_empty()
+        });
+        removed
+    }
+
+    fn matches<'a>(
+        &'a self,
+        mut topic: Split<'_, char>,
+        index: usize,
+        values: &mut Vec<&'a V>,
+    ) {
+        let level = if let Some(level) = topic.next() {
+            level
+        } else {
+            // `a/#` matches `a` topic
+            values.extend(self.value.as_ref());
+            values.extend(self.multi.as_ref());
+            return;
+        };
+
+        // wildcards do not match topics that start with `$`
+        if !(index == 0 && is_system(level)) {
+            values.extend(self.multi.as_ref());
+            if let Some(ref child) = self.single {
+                child.matches(topic.clone(), index + 1, values);
+            }
+        }
+        if let Some(child) = self.levels.get(level) {
+            child.matches(topic, index + 1, values);
+        }
+    }
+}
+
+/// Split trailing `#` level from topic filter levels
+fn split_multi(filter: &TopicFilter) -> (&[TopicFilterLevel], bool) {
+    match filter.levels().split_last() {
+        Some((TopicFilterLevel::MultiWildcard, levels)) => (levels, true),
+        _ => (filter.levels(), false),
+    }
+}
+
+fn level_key(level: &TopicFilterLevel) -> &str {
+    match level {
+        TopicFilterLevel::Normal(s) | TopicFilterLevel::System(s) => s.as_str(),
+        TopicFilterLevel::Blank => "",
+        TopicFilterLevel::SingleWildcard => "+",
+        TopicFilterLevel::MultiWildcard => "#",
+    }
+}
+
 pub(crate) trait WriteTopicExt: io::Write {
     fn write_level(&mut self, level: &TopicFilterLevel) -> io::Result<usize> {
         match *level {
@@ -478,4 +777,114 @@ mod tests {
     fn matches_filter(superset_filter: &'static str, subset_filter: &'static str) -> bool {
         topic(superset_filter).matches_filter(&topic(subset_filter))
     }
+
+    #[test]
+    fn test_trie_matches() {
+        let filters = [
+            "sport/tennis/player1/#",
+            "sport/#",
+            "sport/tennis/+",
+            "sport/+",
+            "+/+",
+            "/+",
+            "+",
+            "#",
+            "+/monitor/Clients",
+            "$SYS/#",
+            "$SYS/monitor/+",
+            "+/#",
+            "a/+/#",
+            "a//b",
+            "a/b",
+        ];
+        let topics = [
+            "sport",
+            "sport/",
+            "sport/tennis/player1",
+            "sport/tennis/player1/score/wimbledon",
+            "sport/tennis/player1/ranking",
+            "/finance",
+            "$SYS",
+            "$SYS/",
+            "$SYS/monitor/Clients",
+            "/$SYS/monitor/Clients",
+            "a/b",
+            "a//b",
+            "",
+        ];
+
+        let mut trie = TopicTrie::new();
+        for (idx, filter) in filters.iter().enumerate() {
+            assert_eq!(trie.insert(&topic(filter), idx), None);
+        }
+        assert_eq!(trie.len(), filters.len());
+
+        for t in topics {
+            let mut matched: Vec<_> = trie.matches(t).into_iter().copied().collect();
+            matched.sort_unstable();
+            let expected: Vec<_> = (0..filters.len())
+                .filter(|idx| topic(filters[*idx]).matches_topic(t))
+                .collect();
+            assert_eq!(matched, expected, "topic: {:?}", t);
+        }
+    }
+
+    #[test]
+    fn test_trie() {
+        let mut trie = TopicTrie::new();
+        assert!(trie.is_empty());
+        assert_eq!(trie.insert(&topic("a/+/#"), 1), None);
+        assert_eq!(trie.insert(&topic("a/+"), 2), None);
+        assert_eq!(trie.insert(&topic("a/+"), 3), Some(2));
+        assert_eq!(trie.insert(&topic("$a/b"), 4), None);
+        assert_eq!(trie.len(), 3);
+        assert_eq!(trie.get(&topic("a/+")), Some(&3));
+        assert_eq!(trie.get(&topic("a/#")), None);
+        assert_eq!(trie.get(&topic("a")), None);
+        *trie.get_mut(&topic("a/+/#")).unwrap() += 10;
+        assert_eq!(trie.matches("a/b"), vec![&3, &11]);
+
+        assert_eq!(trie.remove(&topic("a/+")), Some(3));
+        assert_eq!(trie.remove(&topic("a/+")), None);
+        assert_eq!(trie.remove(&topic("a/b")), None);
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.matches("a/b"), vec![&11]);
+
+        trie.retain(|v| *v != 11);
+        assert_eq!(trie.len(), 1);
+        assert!(trie.matches("a/b").is_empty());
+        assert!(trie.root.single.is_none());
+        assert_eq!(trie.matches("$a/b"), vec![&4]);
+
+        assert_eq!(trie.remove(&topic("$a/b")), Some(4));
+        assert!(trie.is_empty());
+        assert!(trie.root.levels.is_empty());
+    }
+
+    #[test_case("$share/g/a/+" => Ok(("g".to_string(), "a/+".to_string())) ; "1")]
+    #[test_case("$share/g/#" => Ok(("g".to_string(), "#".to_string())) ; "2")]
+    #[test_case("$share/g/$SYS/#" => Ok(("g".to_string(), "$SYS/#".to_string())) ; "3")]
+    #[test_case("$share/g" => Err(TopicFilterError::InvalidTopic) ; "4")]
+    #[test_case("$share/g/" => Err(TopicFilterError::InvalidTopic) ; "5")]
+    #[test_case("$share//a" => Err(TopicFilterError::InvalidLevel) ; "6")]
+    #[test_case("$share/g+/a" => Err(TopicFilterError::InvalidLevel) ; "7")]
+    #[test_case("$share/g/a/#/b" => Err(TopicFilterError::InvalidTopic) ; "8")]
+    #[test_case("$SYS/g/a" => Err(TopicFilterError::InvalidTopic) ; "9")]
+    #[test_case("a/b" => Err(TopicFilterError::InvalidTopic) ; "10")]
+    fn parsing_shared(input: &str) -> Result<(String, String), TopicFilterError> {
+        SharedTopicFilter::try_from(ByteString::from(input))
+            .map(|t| (t.group().to_string(), t.filter().to_string()))
+    }
+
+    #[test]
+    fn test_shared() {
+        assert!(SharedTopicFilter::is_shared("$share/g/a"));
+        assert!(!SharedTopicFilter::is_shared("$share"));
+        assert!(!SharedTopicFilter::is_shared("a/$share/g"));
+
+        let filter: SharedTopicFilter = "$share/group/a/+".parse().unwrap();
+        assert_eq!(filter.to_string(), "$share/group/a/+");
+        assert!(filter.filter().matches_topic("a/b"));
+        assert_eq!(filter.into_parts(), ("group".into(), topic("a/+")));
+    }
 }
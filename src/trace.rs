@@ -0,0 +1,195 @@
+//! Tracing spans
+//!
+//! Spans are created only if `tracing` feature is enabled, otherwise
+//! all helpers compile to no-ops.
+use std::num::NonZeroU16;
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::io::{DispatchItem, IoRef};
+
+use crate::{metrics::PacketType, types::QoS};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{instrument::Instrumented, Span};
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) type Instrumented<F> = F;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug)]
+pub(crate) struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    #[inline]
+    pub(crate) fn enter(&self) -> Entered {
+        Entered
+    }
+}
+
+/// Attach span to a future
+#[inline]
+pub(crate) fn instrument<F>(fut: F, span: Span) -> Instrumented<F> {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::Instrument::instrument(fut, span)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = span;
+        fut
+    }
+}
+
+/// Connection span, client id is recorded after handshake
+#[inline]
+pub(crate) fn connection(protocol: &'static str, io: &IoRef) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        let peer_addr = io.query::<ntex::io::types::PeerAddr>().get().map(|addr| addr.0);
+        match peer_addr {
+            Some(addr) => tracing::info_span!(
+                "mqtt",
+                protocol,
+                client_id = tracing::field::Empty,
+                peer_addr = %addr
+            ),
+            None => tracing::info_span!(
+                "mqtt",
+                protocol,
+                client_id = tracing::field::Empty,
+                peer_addr = tracing::field::Empty
+            ),
+        }
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (protocol, io);
+        Span
+    }
+}
+
+/// Record client id of the connection
+#[inline]
+pub(crate) fn record_client_id(span: &Span, client_id: &str) {
+    #[cfg(feature = "tracing")]
+    span.record("client_id", client_id);
+    #[cfg(not(feature = "tracing"))]
+    let _ = (span, client_id);
+}
+
+/// Handshake span
+#[inline]
+pub(crate) fn handshake(parent: &Span) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::debug_span!(parent: parent, "handshake")
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = parent;
+        Span
+    }
+}
+
+/// Span of received publish packet
+#[inline]
+pub(crate) fn publish(
+    parent: &Span,
+    topic: &str,
+    qos: QoS,
+    packet_id: Option<NonZeroU16>,
+) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::debug_span!(
+            parent: parent,
+            "publish",
+            topic,
+            qos = u8::from(qos),
+            packet_id = packet_id.map(|id| id.get())
+        )
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (parent, topic, qos, packet_id);
+        Span
+    }
+}
+
+/// Span of received control packet or dispatcher event
+#[inline]
+pub(crate) fn control(parent: &Span, kind: &'static str) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::debug_span!(parent: parent, "control", kind)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (parent, kind);
+        Span
+    }
+}
+
+/// Span of sink's outgoing publish packet
+#[inline]
+pub(crate) fn send_publish(parent: &Span, topic: &str, qos: QoS) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::debug_span!(parent: parent, "send", kind = "publish", topic, qos = u8::from(qos))
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (parent, topic, qos);
+        Span
+    }
+}
+
+/// Span of sink's outgoing control packet
+#[inline]
+pub(crate) fn send(parent: &Span, kind: &'static str) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::debug_span!(parent: parent, "send", kind)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (parent, kind);
+        Span
+    }
+}
+
+/// Name of the packet type
+pub(crate) fn packet_kind(packet_type: u8) -> &'static str {
+    PacketType::from_first_byte(packet_type).map(|tp| tp.as_str()).unwrap_or("unknown")
+}
+
+/// Name of the dispatcher event
+pub(crate) fn event_kind<U: Encoder + Decoder>(item: &DispatchItem<U>) -> &'static str {
+    match item {
+        DispatchItem::Item(_) => "packet",
+        DispatchItem::WBackPressureEnabled => "backpressure_enabled",
+        DispatchItem::WBackPressureDisabled => "backpressure_disabled",
+        DispatchItem::KeepAliveTimeout => "keepalive_timeout",
+        DispatchItem::ReadTimeout => "read_timeout",
+        DispatchItem::DecoderError(_) => "decoder_error",
+        DispatchItem::EncoderError(_) => "encoder_error",
+        DispatchItem::Disconnect(_) => "disconnect",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinds() {
+        assert_eq!(packet_kind(0b0011_0000), "publish");
+        assert_eq!(packet_kind(0b1100_0000), "pingreq");
+        assert_eq!(packet_kind(0), "unknown");
+    }
+}
@@ -1,4 +1,5 @@
-use std::{convert::TryFrom, io::Cursor, num::NonZeroU16, num::NonZeroU32};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{cell::Cell, convert::TryFrom, io::Cursor, num::NonZeroU16, num::NonZeroU32};
 
 use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
 
@@ -17,6 +18,16 @@ macro_rules! ensure {
     };
 }
 
+/// Trace level event, emitted with `tracing` if feature is enabled
+macro_rules! trace {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::trace!($($arg)+);
+    }};
+}
+
 macro_rules! prim_enum {
     (
         $( #[$enum_attr:meta] )*
@@ -294,6 +305,28 @@ pub(crate) fn write_variable_length(len: u32, dst: &mut BytesMut) {
     }
 }
 
+/// Returns pseudo-random value in range `[0.0, 1.0)`
+pub(crate) fn random() -> f64 {
+    thread_local! {
+        static SEED: Cell<u64> = Cell::new(seed());
+    }
+
+    SEED.with(|seed| {
+        // xorshift64*
+        let mut x = seed.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        seed.set(x);
+        (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+fn seed() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64);
+    nanos.unwrap_or(0) | 1
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
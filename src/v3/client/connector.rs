@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::{rc::Rc, time::Instant};
 
 use ntex::connect::{self, Address, Connect, Connector};
 use ntex::io::{DispatcherConfig, IoBoxed};
@@ -7,7 +7,7 @@ use ntex::time::{timeout_checked, Seconds};
 use ntex::util::{ByteString, Bytes, PoolId};
 
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
-use crate::v3::shared::{MqttShared, MqttSinkPool};
+use crate::{metrics::Metrics, trace, v3::shared::MqttShared, v3::shared::MqttSinkPool};
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
@@ -20,6 +20,7 @@ pub struct MqttConnector<A, T> {
     handshake_timeout: Seconds,
     config: DispatcherConfig,
     pool: Rc<MqttSinkPool>,
+    metrics: Option<Rc<dyn Metrics>>,
 }
 
 impl<A> MqttConnector<A, ()>
@@ -42,6 +43,7 @@ where
             max_packet_size: 64 * 1024,
             handshake_timeout: Seconds::ZERO,
             pool: Rc::new(MqttSinkPool::default()),
+            metrics: None,
         }
     }
 }
@@ -172,6 +174,15 @@ where
         self
     }
 
+    /// Set metrics observer.
+    ///
+    /// Observer is called for connection and packet events, see
+    /// `metrics::Metrics` for details.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(Rc::new(metrics));
+        self
+    }
+
     /// Use custom connector
     pub fn connector<U, F>(self, connector: F) -> MqttConnector<A, U>
     where
@@ -189,6 +200,7 @@ where
             max_packet_size: self.max_packet_size,
             handshake_timeout: self.handshake_timeout,
             pool: self.pool,
+            metrics: self.metrics,
         }
     }
 }
@@ -217,21 +229,27 @@ where
         let pool = self.pool.clone();
         let codec = codec::Codec::new();
         codec.set_max_size(self.max_packet_size);
-
-        io.encode(pkt.into(), &codec)?;
-
-        let packet = io.recv(&codec).await.map_err(ClientError::from)?.ok_or_else(|| {
-            log::trace!("Mqtt server is disconnected during handshake");
-            ClientError::Disconnected(None)
-        })?;
+        codec.metrics().set(self.metrics.clone());
 
         let shared = Rc::new(MqttShared::new(io.get_ref(), codec, true, pool));
+        trace::record_client_id(shared.span(), &pkt.client_id);
+
+        let started = Instant::now();
+        let handshake = async {
+            io.encode(pkt.into(), &shared.codec)?;
+            io.recv(&shared.codec).await.map_err(ClientError::from)?.ok_or_else(|| {
+                log::trace!("Mqtt server is disconnected during handshake");
+                ClientError::<codec::ConnectAck>::Disconnected(None)
+            })
+        };
+        let packet = trace::instrument(handshake, trace::handshake(shared.span())).await?;
 
         match packet {
             (codec::Packet::ConnectAck(pkt), _) => {
                 log::trace!("Connect ack response from server: session: present: {:?}, return code: {:?}", pkt.session_present, pkt.return_code);
                 if pkt.return_code == codec::ConnectAckReason::ConnectionAccepted {
                     shared.set_cap(max_send);
+                    shared.codec.metrics().with(|m, p| m.handshake(p, started.elapsed()));
                     Ok(Client::new(
                         io,
                         shared,
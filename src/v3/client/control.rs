@@ -1,9 +1,11 @@
 use std::io;
 
+use ntex::time::Millis;
+
 pub use crate::v3::control::{
     Closed, ControlResult, Disconnect, Error, PeerGone, ProtocolError,
 };
-use crate::v3::{codec, control::ControlResultKind, error};
+use crate::v3::{codec, control::ControlResultKind, error, error::ClientError};
 
 #[derive(Debug)]
 pub enum ControlMessage<E> {
@@ -17,6 +19,10 @@ pub enum ControlMessage<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Connection is established by reconnecting client
+    Connected(Connected),
+    /// Connect attempt of reconnecting client failed, next attempt is scheduled
+    Reconnecting(Reconnecting),
 }
 
 impl<E> ControlMessage<E> {
@@ -40,6 +46,18 @@ impl<E> ControlMessage<E> {
         ControlMessage::PeerGone(PeerGone(err))
     }
 
+    pub(super) fn connected(session_present: bool) -> Self {
+        ControlMessage::Connected(Connected(session_present))
+    }
+
+    pub(super) fn reconnecting(
+        attempt: u32,
+        delay: Millis,
+        err: ClientError<codec::ConnectAck>,
+    ) -> Self {
+        ControlMessage::Reconnecting(Reconnecting { attempt, delay, err })
+    }
+
     /// Initiate clean disconnect
     pub fn disconnect(&self) -> ControlResult {
         ControlResult { result: ControlResultKind::Disconnect }
@@ -76,3 +94,47 @@ impl Publish {
         }
     }
 }
+
+#[derive(Debug)]
+pub struct Connected(bool);
+
+impl Connected {
+    /// Indicates whether there is already stored Session state
+    pub fn session_present(&self) -> bool {
+        self.0
+    }
+
+    /// Ack Connected message
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Nothing }
+    }
+}
+
+#[derive(Debug)]
+pub struct Reconnecting {
+    attempt: u32,
+    delay: Millis,
+    err: ClientError<codec::ConnectAck>,
+}
+
+impl Reconnecting {
+    /// Number of connect attempts since last stable connection
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before next connect attempt
+    pub fn delay(&self) -> Millis {
+        self.delay
+    }
+
+    /// Returns connect error
+    pub fn error(&self) -> &ClientError<codec::ConnectAck> {
+        &self.err
+    }
+
+    /// Ack Reconnecting message
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Nothing }
+    }
+}
@@ -4,11 +4,12 @@ use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc
 
 use ntex::io::DispatchItem;
 use ntex::service::{Pipeline, Service, ServiceCall, ServiceCtx};
-use ntex::util::{inflight::InFlightService, BoxFuture, Either, HashSet, Ready};
+use ntex::util::{inflight::InFlightService, BoxFuture, Either, HashMap, HashSet, Ready};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
 use crate::v3::shared::{Ack, MqttShared};
 use crate::v3::{codec, control::ControlResultKind, publish::Publish};
+use crate::{trace, types::QoS};
 
 use super::control::{ControlMessage, ControlResult};
 
@@ -42,7 +43,21 @@ pub(crate) struct Dispatcher<T, C: Service<ControlMessage<E>>, E> {
 struct Inner<C> {
     control: C,
     sink: Rc<MqttShared>,
-    inflight: RefCell<HashSet<NonZeroU16>>,
+    // packet ids and qos of publishes that are being handled
+    inflight: RefCell<HashMap<NonZeroU16, QoS>>,
+    // QoS 2 packet ids, PUBREL is not received yet
+    unreleased: RefCell<HashSet<NonZeroU16>>,
+}
+
+impl<C> Inner<C> {
+    fn publish_ack(&self, packet_id: NonZeroU16) -> codec::Packet {
+        if self.inflight.borrow_mut().remove(&packet_id) == Some(QoS::ExactlyOnce) {
+            self.unreleased.borrow_mut().insert(packet_id);
+            codec::Packet::PublishReceived { packet_id }
+        } else {
+            codec::Packet::PublishAck { packet_id }
+        }
+    }
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -51,10 +66,16 @@ where
     C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
 {
     pub(crate) fn new(sink: Rc<MqttShared>, publish: T, control: C) -> Self {
+        sink.codec.metrics().with(|m, p| m.connection_opened(p));
         Self {
             publish,
             shutdown: RefCell::new(None),
-            inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
+            inner: Rc::new(Inner {
+                sink,
+                control,
+                inflight: RefCell::new(HashMap::default()),
+                unreleased: RefCell::new(HashSet::default()),
+            }),
             _t: PhantomData,
         }
     }
@@ -68,9 +89,11 @@ where
 {
     type Response = Option<codec::Packet>;
     type Error = MqttError<E>;
-    type Future<'f> = Either<
-        PublishResponse<'f, T, C, E>,
-        Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, C, E>>,
+    type Future<'f> = trace::Instrumented<
+        Either<
+            PublishResponse<'f, T, C, E>,
+            Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, C, E>>,
+        >,
     > where Self: 'f;
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -87,6 +110,7 @@ where
     fn poll_shutdown(&self, cx: &mut Context<'_>) -> Poll<()> {
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
+            self.inner.sink.codec.metrics().with(|m, p| m.connection_closed(p));
             self.inner.sink.close();
             let inner = self.inner.clone();
             *shutdown = Some(Box::pin(async move {
@@ -109,16 +133,58 @@ where
         packet: DispatchItem<Rc<MqttShared>>,
         ctx: ServiceCtx<'a, Self>,
     ) -> Self::Future<'a> {
-        log::trace!("Dispatch packet: {:#?}", packet);
+        trace!("Dispatch packet: {:#?}", packet);
+
+        let parent = self.inner.sink.span();
+        let span = match packet {
+            DispatchItem::Item((codec::Packet::Publish(ref pkt), _)) => {
+                trace::publish(parent, &pkt.topic, pkt.qos, pkt.packet_id)
+            }
+            DispatchItem::Item((ref pkt, _)) => {
+                trace::control(parent, trace::packet_kind(pkt.packet_type()))
+            }
+            ref item => trace::control(parent, trace::event_kind(item)),
+        };
+        trace::instrument(self.dispatch(packet, ctx), span)
+    }
+}
+
+impl<T, C, E> Dispatcher<T, C, E>
+where
+    T: Service<Publish, Response = Either<(), Publish>, Error = E>,
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>> + 'static,
+    E: 'static,
+{
+    fn dispatch<'a>(
+        &'a self,
+        packet: DispatchItem<Rc<MqttShared>>,
+        ctx: ServiceCtx<'a, Self>,
+    ) -> Either<
+        PublishResponse<'a, T, C, E>,
+        Either<Ready<Option<codec::Packet>, MqttError<E>>, ControlResponse<'a, C, E>>,
+    > {
         match packet {
             DispatchItem::Item((codec::Packet::Publish(publish), size)) => {
                 let inner = self.inner.as_ref();
                 let packet_id = publish.packet_id;
 
-                // check for duplicated packet id
                 if let Some(pid) = packet_id {
-                    if !inner.inflight.borrow_mut().insert(pid) {
-                        log::trace!("Duplicated packet id for publish packet: {:?}", pid);
+                    // QoS 2 publish is already handled, PUBREL is not received yet
+                    if publish.qos == QoS::ExactlyOnce
+                        && inner.unreleased.borrow().contains(&pid)
+                        && !inner.inflight.borrow().contains_key(&pid)
+                    {
+                        trace!("Re-delivered QoS 2 publish packet: {:?}", pid);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishReceived { packet_id: pid },
+                        ))));
+                    }
+
+                    // check for duplicated packet id
+                    if inner.unreleased.borrow().contains(&pid)
+                        || inner.inflight.borrow_mut().insert(pid, publish.qos).is_some()
+                    {
+                        trace!("Duplicated packet id for publish packet: {:?}", pid);
                         return Either::Right(Either::Left(Ready::Err(MqttError::Handshake(
                             HandshakeError::Protocol(
                                 ProtocolError::generic_violation("PUBLISH received with packet id that is already in use [MQTT-2.2.1-3]"))
@@ -142,6 +208,30 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item((codec::Packet::PublishReceived { packet_id }, _)) => {
+                if let Err(e) = self.inner.sink.pkt_ack(Ack::Receive(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Handshake(
+                        HandshakeError::Protocol(e),
+                    ))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishComplete { packet_id }, _)) => {
+                if let Err(e) = self.inner.sink.pkt_ack(Ack::Complete(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Handshake(
+                        HandshakeError::Protocol(e),
+                    ))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishRelease { packet_id }, _)) => {
+                self.inner.unreleased.borrow_mut().remove(&packet_id);
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete {
+                    packet_id,
+                }))))
+            }
             DispatchItem::Item((codec::Packet::SubscribeAck { packet_id, status }, _)) => {
                 if let Err(e) = self.inner.sink.pkt_ack(Ack::Subscribe { packet_id, status }) {
                     Either::Right(Either::Left(Ready::Err(MqttError::Handshake(
@@ -261,11 +351,10 @@ where
 
         match res {
             Either::Left(_) => {
-                log::trace!("Publish result for packet {:?} is ready", this.packet_id);
+                trace!("Publish result for packet {:?} is ready", this.packet_id);
 
                 if let Some(packet_id) = this.packet_id {
-                    this.inner.inflight.borrow_mut().remove(packet_id);
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck { packet_id: *packet_id })))
+                    Poll::Ready(Ok(Some(this.inner.publish_ack(*packet_id))))
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -302,6 +391,9 @@ where
         inner: &'f Inner<C>,
         ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
     ) -> Self {
+        if let ControlMessage::ProtocolError(_) = msg {
+            inner.sink.codec.metrics().with(|m, p| m.protocol_error(p));
+        }
         Self { fut: ctx.call(&inner.control, msg), inner }
     }
 }
@@ -318,10 +410,7 @@ where
         let packet = match this.fut.poll(cx)? {
             Poll::Ready(item) => match item.result {
                 ControlResultKind::Ping => Some(codec::Packet::PingResponse),
-                ControlResultKind::PublishAck(id) => {
-                    this.inner.inflight.borrow_mut().remove(&id);
-                    Some(codec::Packet::PublishAck { packet_id: id })
-                }
+                ControlResultKind::PublishAck(id) => Some(this.inner.publish_ack(id)),
                 ControlResultKind::Subscribe(_) => unreachable!(),
                 ControlResultKind::Unsubscribe(_) => unreachable!(),
                 ControlResultKind::Disconnect => {
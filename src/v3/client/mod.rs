@@ -3,11 +3,14 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod reconnect;
 
 pub use self::connection::{Client, ClientRouter};
 pub use self::connector::MqttConnector;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::reconnect::{Delivery, ReconnectClient, ReconnectSink};
 
+pub use crate::reconnect::{Backoff, OverflowPolicy};
 pub use crate::topic::{TopicFilter, TopicFilterError};
 pub use crate::types::QoS;
 pub use crate::v3::{codec, error, error::ClientError, sink::MqttSink};
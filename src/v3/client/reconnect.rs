@@ -0,0 +1,471 @@
+use std::task::{Context, Poll};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, fmt, future::Future};
+use std::{num::NonZeroU16, pin::Pin, rc::Rc, time::Instant};
+
+use ntex::channel::oneshot;
+use ntex::connect::{self, Address, Connect};
+use ntex::io::IoBoxed;
+use ntex::service::{IntoService, Pipeline, Service};
+use ntex::time::sleep;
+use ntex::util::{select, ByteString, Bytes, Either, HashMap};
+
+use crate::error::{MqttError, SendPacketError};
+use crate::reconnect::{Backoff, Connection, OverflowPolicy, SharedService};
+use crate::v3::{codec, sink::InflightMessages, sink::MqttSink};
+
+use super::{connector::MqttConnector, control::ControlMessage, control::ControlResult};
+
+/// Mqtt client that re-establishes connection to the server
+///
+/// Client reconnects with exponential backoff, re-sends configured
+/// subscriptions and re-delivers unacknowledged messages if server
+/// keeps session state. Publishes made while client is disconnected
+/// are stored in bounded offline queue and sent after reconnect.
+/// Publish acknowledgements are tracked across reconnects, see [`Delivery`].
+pub struct ReconnectClient<A, T> {
+    connector: MqttConnector<A, T>,
+    backoff: Backoff,
+    subscriptions: Vec<(ByteString, codec::QoS)>,
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    conn: Connection<MqttSink>,
+    queue: RefCell<VecDeque<(codec::Publish, Option<DeliveryTx>)>>,
+    // unacknowledged publishes by packet id
+    pending: RefCell<HashMap<NonZeroU16, DeliveryTx>>,
+    max_queue: Cell<usize>,
+    policy: Cell<OverflowPolicy>,
+    flushing: Cell<bool>,
+}
+
+impl<A, T> fmt::Debug for ReconnectClient<A, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v3::ReconnectClient")
+            .field("backoff", &self.backoff)
+            .field("subscriptions", &self.subscriptions)
+            .field("max_queue", &self.inner.max_queue.get())
+            .field("policy", &self.inner.policy.get())
+            .finish()
+    }
+}
+
+impl<A, T> ReconnectClient<A, T>
+where
+    A: Address + Clone,
+    T: Service<Connect<A>, Error = connect::ConnectError>,
+    IoBoxed: From<T::Response>,
+{
+    /// Create reconnecting client
+    pub fn new(connector: MqttConnector<A, T>) -> Self {
+        ReconnectClient {
+            connector,
+            backoff: Backoff::default(),
+            subscriptions: Vec::new(),
+            inner: Rc::new(Inner {
+                conn: Connection::new(),
+                queue: RefCell::new(VecDeque::new()),
+                pending: RefCell::new(HashMap::default()),
+                max_queue: Cell::new(256),
+                policy: Cell::new(OverflowPolicy::Error),
+                flushing: Cell::new(false),
+            }),
+        }
+    }
+
+    /// Set reconnect backoff policy
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Subscribe to a topic filter.
+    ///
+    /// Subscriptions are sent after each connect, unless server reports
+    /// existing session state.
+    pub fn subscribe(mut self, filter: ByteString, qos: codec::QoS) -> Self {
+        self.subscriptions.push((filter, qos));
+        self
+    }
+
+    /// Set offline queue size and overflow policy.
+    ///
+    /// By default queue size is 256 messages and `OverflowPolicy::Error` is used.
+    pub fn offline_queue(self, size: usize, policy: OverflowPolicy) -> Self {
+        self.inner.max_queue.set(size);
+        self.inner.policy.set(policy);
+        self
+    }
+
+    /// Get client sink
+    pub fn sink(&self) -> ReconnectSink {
+        ReconnectSink(self.inner.clone())
+    }
+
+    /// Run client and handle control messages
+    ///
+    /// Client runs until `ReconnectSink::close()` get called or
+    /// control service returns an error. Control service receives
+    /// `ControlMessage::Connected` on each connect and
+    /// `ControlMessage::Reconnecting` on each failed connect attempt.
+    /// Client reconnects after backoff delay, attempts counter is reset
+    /// once connection stays up for backoff's stable period.
+    pub async fn start<F, S, E>(self, service: F) -> Result<(), MqttError<E>>
+    where
+        E: 'static,
+        F: IntoService<S, ControlMessage<E>> + 'static,
+        S: Service<ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
+    {
+        let service = SharedService::new(service.into_service());
+        let control = Pipeline::new(service.clone());
+        let mut inflight = InflightMessages::default();
+        let mut attempt = 0;
+
+        while !self.inner.conn.is_stopped() {
+            let client = match self.connector.connect().await {
+                Ok(client) => client,
+                Err(err) => {
+                    attempt += 1;
+                    let delay = self.backoff.delay(attempt);
+                    log::debug!(
+                        "Connect attempt {} failed: {:?}, retry in {:?}",
+                        attempt,
+                        err,
+                        delay
+                    );
+
+                    control
+                        .call(ControlMessage::reconnecting(attempt, delay, err))
+                        .await
+                        .map_err(MqttError::Service)?;
+                    if !self.inner.conn.is_stopped() {
+                        let _ = select(sleep(delay), self.inner.conn.changed()).await;
+                    }
+                    continue;
+                }
+            };
+            let sink = client.sink();
+            let session_present = client.session_present();
+            sink.track_inflight();
+            let inner = Rc::downgrade(&self.inner);
+            sink.publish_ack_cb(move |id, disconnected| {
+                // unacknowledged publishes are re-sent on next connection
+                if !disconnected {
+                    if let Some(inner) = inner.upgrade() {
+                        inner.ack(id);
+                    }
+                }
+            });
+
+            control
+                .call(ControlMessage::connected(session_present))
+                .await
+                .map_err(MqttError::Service)?;
+
+            if session_present {
+                if let Err(e) = sink.replay_inflight(std::mem::take(&mut inflight)) {
+                    log::error!("Cannot re-deliver in-flight messages: {:?}", e);
+                    self.inner.fail_pending();
+                }
+            } else {
+                if !inflight.is_empty() {
+                    log::warn!("Session is not present, drop in-flight messages");
+                }
+                self.inner.fail_pending();
+                if !self.subscriptions.is_empty() {
+                    ntex::rt::spawn(subscribe(sink.clone(), self.subscriptions.clone()));
+                }
+            }
+
+            if self.inner.conn.is_stopped() {
+                sink.close();
+            }
+            self.inner.conn.set(sink.clone());
+            if !self.inner.queue.borrow().is_empty() {
+                start_flush(&self.inner);
+            }
+
+            let connected = Instant::now();
+            let res = client.start(service.clone()).await;
+            self.inner.conn.take();
+            inflight = sink.take_inflight();
+
+            match res {
+                Err(MqttError::Service(err)) => return Err(MqttError::Service(err)),
+                Err(MqttError::Handshake(err)) => log::debug!("Connection failed: {}", err),
+                Ok(()) => log::debug!("Connection is closed"),
+            }
+
+            if self.backoff.is_stable(connected.elapsed()) {
+                attempt = 0;
+            }
+            attempt += 1;
+            if !self.inner.conn.is_stopped() {
+                let delay = self.backoff.delay(attempt);
+                log::debug!("Reconnect in {:?}", delay);
+                let _ = select(sleep(delay), self.inner.conn.changed()).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A, T> Drop for ReconnectClient<A, T> {
+    fn drop(&mut self) {
+        // publishes could not be delivered without client
+        self.inner.fail_pending();
+        for (_, tx) in self.inner.queue.borrow_mut().iter_mut() {
+            if let Some(tx) = tx.take() {
+                let _ = tx.send(Err(SendPacketError::Disconnected));
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn wait_ack(&self, id: Option<NonZeroU16>, tx: DeliveryTx) {
+        if let Some(id) = id {
+            self.pending.borrow_mut().insert(id, tx);
+        } else {
+            let _ = tx.send(Ok(()));
+        }
+    }
+
+    fn ack(&self, id: NonZeroU16) {
+        if let Some(tx) = self.pending.borrow_mut().remove(&id) {
+            let _ = tx.send(Ok(()));
+        }
+    }
+
+    fn fail_pending(&self) {
+        for (_, tx) in self.pending.borrow_mut().drain() {
+            let _ = tx.send(Err(SendPacketError::Disconnected));
+        }
+    }
+}
+
+type DeliveryTx = oneshot::Sender<Result<(), SendPacketError>>;
+
+/// Delivery of reconnecting client publish
+///
+/// Resolves after QoS 1 publish is acknowledged with PUBACK or QoS 2 publish
+/// is completed with PUBCOMP, QoS 0 publish resolves after it is sent.
+/// Unacknowledged publish is re-sent on next connection if server keeps
+/// session state, otherwise delivery fails with `SendPacketError::Disconnected`.
+/// Dropping `Delivery` does not cancel publish.
+pub struct Delivery(oneshot::Receiver<Result<(), SendPacketError>>);
+
+impl Future for Delivery {
+    type Output = Result<(), SendPacketError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|res| res.unwrap_or(Err(SendPacketError::Disconnected)))
+    }
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v3::Delivery").finish()
+    }
+}
+
+async fn subscribe(sink: MqttSink, filters: Vec<(ByteString, codec::QoS)>) {
+    let mut builder = sink.subscribe();
+    for (filter, qos) in filters {
+        builder = builder.topic_filter(filter, qos);
+    }
+    match builder.send().await {
+        Ok(codes) => log::trace!("Subscriptions are restored: {:?}", codes),
+        Err(e) => log::error!("Cannot restore subscriptions: {:?}", e),
+    }
+}
+
+fn start_flush(inner: &Rc<Inner>) {
+    if !inner.flushing.replace(true) {
+        ntex::rt::spawn(flush(inner.clone()));
+    }
+}
+
+/// Send queued publishes in order
+async fn flush(inner: Rc<Inner>) {
+    while let Some(sink) = inner.conn.get() {
+        if inner.queue.borrow().is_empty() {
+            break;
+        }
+        if !sink.ready().await {
+            // continue only if new connection is established
+            if inner.conn.get().map(|s| s.is_open()).unwrap_or(false) {
+                continue;
+            }
+            break;
+        }
+        if !sink.is_ready() {
+            continue;
+        }
+        let item = inner.queue.borrow_mut().pop_front();
+        if let Some((pkt, tx)) = item {
+            match sink.publish_pkt(pkt).send_no_block() {
+                Ok(id) => {
+                    if let Some(tx) = tx {
+                        inner.wait_ack(id, tx);
+                    }
+                }
+                Err(e) => {
+                    log::error!("Cannot send queued publish: {:?}", e);
+                    if let Some(tx) = tx {
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            }
+        }
+    }
+    inner.flushing.set(false);
+}
+
+#[derive(Clone)]
+/// Sink of reconnecting client
+///
+/// Sink stays valid across reconnects.
+pub struct ReconnectSink(Rc<Inner>);
+
+impl fmt::Debug for ReconnectSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v3::ReconnectSink")
+            .field("connected", &self.is_connected())
+            .field("queued", &self.queued())
+            .finish()
+    }
+}
+
+impl ReconnectSink {
+    #[inline]
+    /// Check if client is connected to the server
+    pub fn is_connected(&self) -> bool {
+        self.0.conn.get().map(|sink| sink.is_open()).unwrap_or(false)
+    }
+
+    #[inline]
+    /// Get sink of current connection
+    pub fn sink(&self) -> Option<MqttSink> {
+        self.0.conn.get().filter(|sink| sink.is_open())
+    }
+
+    #[inline]
+    /// Number of publishes in offline queue
+    pub fn queued(&self) -> usize {
+        self.0.queue.borrow().len()
+    }
+
+    /// Get notification when packet could be send to the peer.
+    ///
+    /// Waits for connection if client is disconnected. Result is `false`
+    /// if client is closed.
+    pub fn ready(&self) -> impl Future<Output = bool> {
+        let inner = self.0.clone();
+        async move {
+            loop {
+                if inner.conn.is_stopped() {
+                    return false;
+                }
+                if let Some(sink) = inner.conn.get() {
+                    let ready = sink.ready();
+                    let changed = inner.conn.changed();
+                    match select(ready, changed).await {
+                        Either::Left(true) => return true,
+                        Either::Left(false) => (),
+                        Either::Right(_) => continue,
+                    }
+                }
+                inner.conn.changed().await;
+            }
+        }
+    }
+
+    /// Publish message with specified QoS.
+    ///
+    /// Message is sent immediately if client is connected, otherwise
+    /// it is stored in offline queue. Returned [`Delivery`] resolves
+    /// when message is acknowledged.
+    pub fn publish<U>(
+        &self,
+        topic: U,
+        payload: Bytes,
+        qos: codec::QoS,
+    ) -> Result<Delivery, SendPacketError>
+    where
+        ByteString: From<U>,
+    {
+        self.publish_pkt(codec::Publish {
+            qos,
+            payload,
+            dup: false,
+            retain: false,
+            topic: topic.into(),
+            packet_id: None,
+        })
+    }
+
+    /// Publish packet.
+    ///
+    /// Packet is sent immediately if client is connected, otherwise
+    /// it is stored in offline queue. Returned [`Delivery`] resolves
+    /// when packet is acknowledged, delivery of publish dropped from
+    /// offline queue fails with `SendPacketError::QueueFull`.
+    pub fn publish_pkt(&self, packet: codec::Publish) -> Result<Delivery, SendPacketError> {
+        let inner = &self.0;
+        if inner.conn.is_stopped() {
+            return Err(SendPacketError::Disconnected);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let sink = self.sink();
+        if let Some(ref sink) = sink {
+            if !inner.flushing.get() && inner.queue.borrow().is_empty() && sink.is_ready() {
+                let id = sink.publish_pkt(packet).send_no_block()?;
+                inner.wait_ack(id, tx);
+                return Ok(Delivery(rx));
+            }
+        }
+
+        {
+            let mut queue = inner.queue.borrow_mut();
+            if queue.len() >= inner.max_queue.get() {
+                match inner.policy.get() {
+                    OverflowPolicy::DropOldest => match queue.pop_front() {
+                        Some((_, tx)) => {
+                            log::trace!("Offline queue is full, drop oldest publish");
+                            if let Some(tx) = tx {
+                                let _ = tx.send(Err(SendPacketError::QueueFull));
+                            }
+                        }
+                        None => {
+                            let _ = tx.send(Err(SendPacketError::QueueFull));
+                            return Ok(Delivery(rx));
+                        }
+                    },
+                    OverflowPolicy::DropNewest => {
+                        log::trace!("Offline queue is full, drop publish");
+                        let _ = tx.send(Err(SendPacketError::QueueFull));
+                        return Ok(Delivery(rx));
+                    }
+                    OverflowPolicy::Error => return Err(SendPacketError::QueueFull),
+                }
+            }
+            queue.push_back((packet, Some(tx)));
+        }
+
+        if sink.is_some() {
+            start_flush(inner);
+        }
+        Ok(Delivery(rx))
+    }
+
+    /// Close client. Client does not reconnect after this call.
+    pub fn close(&self) {
+        if let Some(sink) = self.0.conn.stop() {
+            sink.close();
+        }
+    }
+}
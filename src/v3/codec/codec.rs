@@ -5,6 +5,7 @@ use ntex::util::{Buf, BytesMut};
 
 use super::{decode, encode, Packet, Publish};
 use crate::error::{DecodeError, EncodeError};
+use crate::metrics::{MetricsRef, Protocol};
 use crate::types::{FixedHeader, QoS};
 use crate::utils::decode_variable_length;
 
@@ -13,6 +14,7 @@ use crate::utils::decode_variable_length;
 pub struct Codec {
     state: Cell<DecodeState>,
     max_size: Cell<u32>,
+    metrics: MetricsRef,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -24,7 +26,11 @@ enum DecodeState {
 impl Codec {
     /// Create `Codec` instance
     pub fn new() -> Self {
-        Codec { state: Cell::new(DecodeState::FrameHeader), max_size: Cell::new(0) }
+        Codec {
+            state: Cell::new(DecodeState::FrameHeader),
+            max_size: Cell::new(0),
+            metrics: MetricsRef::new(Protocol::V3),
+        }
     }
 
     /// Set max inbound frame size.
@@ -34,6 +40,10 @@ impl Codec {
     pub fn set_max_size(&self, size: u32) {
         self.max_size.set(size);
     }
+
+    pub(crate) fn metrics(&self) -> &MetricsRef {
+        &self.metrics
+    }
 }
 
 impl Default for Codec {
@@ -88,6 +98,7 @@ impl Decoder for Codec {
                     let packet = decode::decode_packet(packet_buf.freeze(), fixed.first_byte)?;
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(2);
+                    self.metrics.received(fixed.first_byte, fixed.remaining_length);
                     return Ok(Some((packet, fixed.remaining_length)));
                 }
             }
@@ -107,7 +118,9 @@ impl Encoder for Codec {
         }
         let content_size = encode::get_encoded_size(&item);
         dst.reserve(content_size + 5);
+        let len = dst.len();
         encode::encode(&item, dst, content_size as u32)?;
+        self.metrics.sent(item.packet_type(), dst.len() - len);
         Ok(())
     }
 }
@@ -173,6 +173,7 @@ pub struct Subscribe {
     packet_size: u32,
     topics: Vec<(ByteString, QoS)>,
     codes: Vec<codec::SubscribeReturnCode>,
+    hidden: Vec<bool>,
 }
 
 /// Result of a subscribe message
@@ -194,7 +195,8 @@ impl Subscribe {
         let mut codes = Vec::with_capacity(topics.len());
         (0..topics.len()).for_each(|_| codes.push(codec::SubscribeReturnCode::Failure));
 
-        Self { packet_id, packet_size, topics, codes }
+        let hidden = vec![false; topics.len()];
+        Self { packet_id, packet_size, topics, codes, hidden }
     }
 
     /// Returns size of the packet
@@ -240,11 +242,17 @@ impl<'a> SubscribeIter<'a> {
     fn next_unsafe(&mut self) -> Option<Subscription<'a>> {
         let subs = unsafe { &mut *self.subs };
 
+        // skip subscriptions that are already handled
+        while self.entry < subs.hidden.len() && subs.hidden[self.entry] {
+            self.entry += 1;
+        }
+
         if self.entry < subs.topics.len() {
             let s = Subscription {
                 topic: &subs.topics[self.entry].0,
                 qos: subs.topics[self.entry].1,
                 code: &mut subs.codes[self.entry],
+                hidden: &mut subs.hidden[self.entry],
             };
             self.entry += 1;
             Some(s)
@@ -269,6 +277,7 @@ pub struct Subscription<'a> {
     topic: &'a ByteString,
     qos: QoS,
     code: &'a mut codec::SubscribeReturnCode,
+    hidden: &'a mut bool,
 }
 
 impl<'a> Subscription<'a> {
@@ -302,6 +311,11 @@ impl<'a> Subscription<'a> {
     pub fn subscribe(&mut self, qos: QoS) {
         self.confirm(qos)
     }
+
+    /// Hide subscription from next iterations
+    pub(crate) fn hide(&mut self) {
+        *self.hidden = true
+    }
 }
 
 /// Unsubscribe message
@@ -8,7 +8,7 @@ use ntex::util::buffer::{BufferService, BufferServiceError};
 use ntex::util::{inflight::InFlightService, join, BoxFuture, Either, HashSet, Ready};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::types::QoS;
+use crate::{rate::RateLimiter, trace, types::QoS, v5::codec::DisconnectReasonCode};
 
 use super::control::{
     ControlMessage, ControlResult, ControlResultKind, Subscribe, Unsubscribe,
@@ -88,6 +88,7 @@ pub(crate) struct Dispatcher<T, C: Service<ControlMessage<E>>, E> {
     publish: T,
     max_qos: QoS,
     shutdown: RefCell<Option<BoxFuture<'static, ()>>>,
+    rate: Option<RateLimiter>,
     inner: Rc<Inner<C>>,
     _t: PhantomData<(E,)>,
 }
@@ -96,6 +97,8 @@ struct Inner<C> {
     control: C,
     sink: Rc<MqttShared>,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    // QoS 2 packet ids, PUBREL is not received yet
+    unreleased: RefCell<HashSet<NonZeroU16>>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -105,11 +108,18 @@ where
     C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
 {
     pub(crate) fn new(sink: Rc<MqttShared>, publish: T, control: C, max_qos: QoS) -> Self {
+        sink.codec.metrics().with(|m, p| m.connection_opened(p));
         Self {
             publish,
             max_qos,
             shutdown: RefCell::new(None),
-            inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
+            rate: sink.rate_limit().and_then(RateLimiter::new),
+            inner: Rc::new(Inner {
+                sink,
+                control,
+                inflight: RefCell::new(HashSet::default()),
+                unreleased: RefCell::new(HashSet::default()),
+            }),
             _t: PhantomData,
         }
     }
@@ -123,16 +133,21 @@ where
 {
     type Response = Option<codec::Packet>;
     type Error = MqttError<E>;
-    type Future<'f> = Either<
-        PublishResponse<'f, T, C, E>,
-        Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, T, C, E>>,
+    type Future<'f> = trace::Instrumented<
+        Either<
+            PublishResponse<'f, T, C, E>,
+            Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, T, C, E>>,
+        >,
     > where Self: 'f;
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let res1 = self.publish.poll_ready(cx).map_err(|e| MqttError::Service(e.into()))?;
         let res2 = self.inner.control.poll_ready(cx)?;
+        // pause reads while inbound rate is exceeded
+        let res3 =
+            self.rate.as_ref().map(|rate| rate.poll_ready(cx)).unwrap_or(Poll::Ready(()));
 
-        if res1.is_pending() || res2.is_pending() {
+        if res1.is_pending() || res2.is_pending() || res3.is_pending() {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -142,6 +157,7 @@ where
     fn poll_shutdown(&self, cx: &mut Context<'_>) -> Poll<()> {
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
+            self.inner.sink.codec.metrics().with(|m, p| m.connection_closed(p));
             self.inner.sink.close();
             let inner = self.inner.clone();
             *shutdown = Some(Box::pin(async move {
@@ -164,8 +180,36 @@ where
         req: DispatchItem<Rc<MqttShared>>,
         ctx: ServiceCtx<'a, Self>,
     ) -> Self::Future<'a> {
-        log::trace!("Dispatch v3 packet: {:#?}", req);
+        trace!("Dispatch v3 packet: {:#?}", req);
+
+        let parent = self.inner.sink.span();
+        let span = match req {
+            DispatchItem::Item((codec::Packet::Publish(ref pkt), _)) => {
+                trace::publish(parent, &pkt.topic, pkt.qos, pkt.packet_id)
+            }
+            DispatchItem::Item((ref pkt, _)) => {
+                trace::control(parent, trace::packet_kind(pkt.packet_type()))
+            }
+            ref item => trace::control(parent, trace::event_kind(item)),
+        };
+        trace::instrument(self.dispatch(req, ctx), span)
+    }
+}
 
+impl<T, C, E> Dispatcher<T, C, E>
+where
+    E: From<T::Error> + 'static,
+    T: Service<Publish, Response = ()>,
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>> + 'static,
+{
+    fn dispatch<'a>(
+        &'a self,
+        req: DispatchItem<Rc<MqttShared>>,
+        ctx: ServiceCtx<'a, Self>,
+    ) -> Either<
+        PublishResponse<'a, T, C, E>,
+        Either<Ready<Option<codec::Packet>, MqttError<E>>, ControlResponse<'a, T, C, E>>,
+    > {
         match req {
             DispatchItem::Item((codec::Packet::Publish(publish), size)) => {
                 if publish.topic.contains(['#', '+']) {
@@ -180,13 +224,41 @@ where
                     )));
                 }
 
+                // check inbound rate
+                if let Some(ref rate) = self.rate {
+                    if !rate.consume(size) {
+                        trace!("Message rate is too high, disconnecting");
+                        return Either::Right(Either::Right(ControlResponse::new(
+                            ControlMessage::proto_error(ProtocolError::violation(
+                                DisconnectReasonCode::MessageRateTooHigh,
+                                "Message rate is too high",
+                            )),
+                            &self.inner,
+                            ctx,
+                        )));
+                    }
+                }
+
                 let inner = self.inner.as_ref();
                 let packet_id = publish.packet_id;
 
-                // check for duplicated packet id
                 if let Some(pid) = packet_id {
-                    if !inner.inflight.borrow_mut().insert(pid) {
-                        log::trace!("Duplicated packet id for publish packet: {:?}", pid);
+                    // QoS 2 publish is already handled, PUBREL is not received yet
+                    if publish.qos == QoS::ExactlyOnce
+                        && inner.unreleased.borrow().contains(&pid)
+                        && !inner.inflight.borrow().contains(&pid)
+                    {
+                        trace!("Re-delivered QoS 2 publish packet: {:?}", pid);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishReceived { packet_id: pid },
+                        ))));
+                    }
+
+                    // check for duplicated packet id
+                    if inner.unreleased.borrow().contains(&pid)
+                        || !inner.inflight.borrow_mut().insert(pid)
+                    {
+                        trace!("Duplicated packet id for publish packet: {:?}", pid);
                         return Either::Right(Either::Right(ControlResponse::new(
                             ControlMessage::proto_error(
                                 ProtocolError::generic_violation("PUBLISH received with packet id that is already in use [MQTT-2.2.1-3]")
@@ -199,7 +271,7 @@ where
 
                 // check max allowed qos
                 if publish.qos > self.max_qos {
-                    log::trace!(
+                    trace!(
                         "Max allowed QoS is violated, max {:?} provided {:?}",
                         self.max_qos,
                         publish.qos
@@ -223,6 +295,7 @@ where
 
                 Either::Left(PublishResponse {
                     packet_id,
+                    qos: publish.qos,
                     inner,
                     ctx,
                     state: PublishResponseState::Publish {
@@ -241,6 +314,34 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item((codec::Packet::PublishReceived { packet_id }, _)) => {
+                if let Err(e) = self.inner.sink.pkt_ack(Ack::Receive(packet_id)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(e),
+                        &self.inner,
+                        ctx,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishComplete { packet_id }, _)) => {
+                if let Err(e) = self.inner.sink.pkt_ack(Ack::Complete(packet_id)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(e),
+                        &self.inner,
+                        ctx,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishRelease { packet_id }, _)) => {
+                self.inner.unreleased.borrow_mut().remove(&packet_id);
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete {
+                    packet_id,
+                }))))
+            }
             DispatchItem::Item((codec::Packet::PingRequest, _)) => Either::Right(
                 Either::Right(ControlResponse::new(ControlMessage::ping(), &self.inner, ctx)),
             ),
@@ -263,7 +364,7 @@ where
                 }
 
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
-                    log::trace!("Duplicated packet id for subscribe packet: {:?}", packet_id);
+                    trace!("Duplicated packet id for subscribe packet: {:?}", packet_id);
                     return Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(ProtocolError::generic_violation(
                             "SUBSCRIBE received with packet id that is already in use [MQTT-2.2.1-3]"
@@ -273,6 +374,7 @@ where
                     )));
                 }
 
+                self.inner.sink.session_subscribe(packet_id, &topic_filters);
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::subscribe(Subscribe::new(packet_id, size, topic_filters)),
                     &self.inner,
@@ -298,7 +400,7 @@ where
                 }
 
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
-                    log::trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
+                    trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
                     return Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(ProtocolError::generic_violation(
                             "UNSUBSCRIBE received with packet id that is already in use [MQTT-2.2.1-3]"
@@ -308,6 +410,7 @@ where
                     )));
                 }
 
+                self.inner.sink.session_unsubscribe(packet_id, &topic_filters);
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::unsubscribe(Unsubscribe::new(
                         packet_id,
@@ -371,6 +474,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<'f, T, C, E>,
         packet_id: Option<NonZeroU16>,
+        qos: QoS,
         inner: &'f Inner<C>,
         ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
     }
@@ -400,13 +504,20 @@ where
         match this.state.as_mut().project() {
             PublishResponseStateProject::Publish { fut } => match ready!(fut.poll(cx)) {
                 Ok(_) => {
-                    log::trace!("Publish result for packet {:?} is ready", this.packet_id);
+                    trace!("Publish result for packet {:?} is ready", this.packet_id);
 
                     if let Some(packet_id) = this.packet_id {
                         this.inner.inflight.borrow_mut().remove(packet_id);
-                        Poll::Ready(Ok(Some(codec::Packet::PublishAck {
-                            packet_id: *packet_id,
-                        })))
+                        if *this.qos == QoS::ExactlyOnce {
+                            this.inner.unreleased.borrow_mut().insert(*packet_id);
+                            Poll::Ready(Ok(Some(codec::Packet::PublishReceived {
+                                packet_id: *packet_id,
+                            })))
+                        } else {
+                            Poll::Ready(Ok(Some(codec::Packet::PublishAck {
+                                packet_id: *packet_id,
+                            })))
+                        }
                     } else {
                         Poll::Ready(Ok(None))
                     }
@@ -450,6 +561,9 @@ where
         ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
     ) -> Self {
         let error = matches!(pkt, ControlMessage::Error(_) | ControlMessage::ProtocolError(_));
+        if let ControlMessage::ProtocolError(_) = pkt {
+            inner.sink.codec.metrics().with(|m, p| m.protocol_error(p));
+        }
         let fut = ctx.call(&inner.control, pkt);
         Self { error, inner, ctx, fut }
     }
@@ -487,6 +601,9 @@ where
                     }
                     ControlResultKind::PublishAck(_) => unreachable!(),
                 };
+                if let Some(ref pkt) = packet {
+                    this.inner.sink.session_ack(pkt);
+                }
                 Poll::Ready(Ok(packet))
             }
             Err(err) => {
@@ -602,4 +719,54 @@ mod tests {
         assert!(lazy(|cx| rx.poll_recv(cx).is_ready()).await);
         assert!(!lazy(|cx| rx2.poll_recv(cx).is_ready()).await);
     }
+
+    #[ntex::test]
+    async fn test_qos2() {
+        let io = Io::new(IoTest::create().0);
+        let codec = codec::Codec::default();
+        let shared = Rc::new(MqttShared::new(io.get_ref(), codec, false, Default::default()));
+        let delivered = Rc::new(RefCell::new(0));
+        let delivered2 = delivered.clone();
+
+        let disp = Pipeline::new(Dispatcher::<_, _, ()>::new(
+            shared.clone(),
+            fn_service(move |_| {
+                *delivered2.borrow_mut() += 1;
+                Ready::Ok(())
+            }),
+            fn_service(|_| Ready::Ok(ControlResult { result: ControlResultKind::Nothing })),
+            QoS::ExactlyOnce,
+        ));
+
+        let packet_id = NonZeroU16::new(1).unwrap();
+        let publish = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::ExactlyOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: Some(packet_id),
+            payload: Bytes::new(),
+        };
+
+        let res = disp.call(DispatchItem::Item((codec::Packet::Publish(publish.clone()), 0)));
+        assert_eq!(res.await.unwrap(), Some(codec::Packet::PublishReceived { packet_id }));
+        assert_eq!(*delivered.borrow(), 1);
+
+        // re-delivered publish is acknowledged but not handled
+        let res = disp.call(DispatchItem::Item((
+            codec::Packet::Publish(codec::Publish { dup: true, ..publish.clone() }),
+            0,
+        )));
+        assert_eq!(res.await.unwrap(), Some(codec::Packet::PublishReceived { packet_id }));
+        assert_eq!(*delivered.borrow(), 1);
+
+        let res =
+            disp.call(DispatchItem::Item((codec::Packet::PublishRelease { packet_id }, 0)));
+        assert_eq!(res.await.unwrap(), Some(codec::Packet::PublishComplete { packet_id }));
+
+        // packet id is released, publish is handled again
+        let res = disp.call(DispatchItem::Item((codec::Packet::Publish(publish), 0)));
+        assert_eq!(res.await.unwrap(), Some(codec::Packet::PublishReceived { packet_id }));
+        assert_eq!(*delivered.borrow(), 2);
+    }
 }
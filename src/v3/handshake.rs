@@ -5,6 +5,8 @@ use ntex::{io::IoBoxed, time::Seconds};
 use super::codec as mqtt;
 use super::shared::MqttShared;
 use super::sink::MqttSink;
+use crate::store::{SessionState, SessionStore};
+use crate::RateLimit;
 
 const DEFAULT_KEEPALIVE: Seconds = Seconds(30);
 const DEFAULT_OUTGOING_INFLIGHT: u16 = 16;
@@ -14,7 +16,8 @@ pub struct Handshake {
     io: IoBoxed,
     pkt: Box<mqtt::Connect>,
     pkt_size: u32,
-    shared: Rc<MqttShared>,
+    pub(super) shared: Rc<MqttShared>,
+    pub(super) store: Option<Rc<dyn SessionStore>>,
 }
 
 impl Handshake {
@@ -24,7 +27,7 @@ impl Handshake {
         io: IoBoxed,
         shared: Rc<MqttShared>,
     ) -> Self {
-        Self { io, pkt, pkt_size, shared }
+        Self { io, pkt, pkt_size, shared, store: None }
     }
 
     #[inline]
@@ -52,7 +55,29 @@ impl Handshake {
         MqttSink::new(self.shared.clone())
     }
 
+    /// Returns session state persisted in server's session store
+    ///
+    /// State is loaded from the store on each call, so it should be called
+    /// after client is authenticated. Returns `None` for clean session.
+    /// Session is restored and its unacknowledged messages are re-sent
+    /// only if handshake succeeds.
+    ///
+    /// Subscriptions of the session are not re-applied by the server,
+    /// handshake service must re-install them, for example into its
+    /// broker or router.
+    pub fn session_state(&self) -> Option<SessionState> {
+        match self.store {
+            Some(ref store) if !self.pkt.clean_session && !self.pkt.client_id.is_empty() => {
+                store.load(&self.pkt.client_id)
+            }
+            _ => None,
+        }
+    }
+
     /// Ack handshake message and set state
+    ///
+    /// `session_present` flag is always set if session state is restored
+    /// from server's session store.
     pub fn ack<St>(self, st: St, session_present: bool) -> HandshakeAck<St> {
         let Handshake { io, shared, pkt, .. } = self;
         // [MQTT-3.1.2-24].
@@ -158,4 +183,12 @@ impl<St> HandshakeAck<St> {
         self.inflight = val;
         self
     }
+
+    /// Set inbound rate limit for the connection
+    ///
+    /// Overrides rate limit configured for the server.
+    pub fn rate_limit(self, limit: RateLimit) -> Self {
+        self.shared.set_rate_limit(limit);
+        self
+    }
 }
@@ -1,5 +1,6 @@
 //! MQTT 3.1.1 Client/Server framework
 
+mod auth;
 pub mod client;
 pub mod codec;
 pub mod control;
@@ -15,13 +16,16 @@ mod sink;
 
 pub type Session<St> = crate::Session<MqttSink, St>;
 
+pub use self::auth::Authorization;
 pub use self::control::{ControlMessage, ControlResult};
 pub use self::handshake::{Handshake, HandshakeAck};
 pub use self::publish::Publish;
 pub use self::router::Router;
 pub use self::selector::Selector;
 pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+pub use self::sink::{
+    InflightMessages, MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder,
+};
 
 pub use crate::error::{self, MqttError};
 pub use crate::topic::{TopicFilter, TopicFilterError};
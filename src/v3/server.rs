@@ -1,12 +1,14 @@
-use std::{fmt, future::Future, marker::PhantomData, rc::Rc};
+use std::{fmt, future::Future, marker::PhantomData, rc::Rc, time::Instant};
 
 use ntex::io::{DispatchItem, DispatcherConfig, IoBoxed};
 use ntex::service::{IntoServiceFactory, Service, ServiceCtx, ServiceFactory};
 use ntex::time::{timeout_checked, Millis, Seconds};
-use ntex::util::{BoxFuture, Either};
+use ntex::util::{BoxFuture, ByteString, Either};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::{io::Dispatcher, service, types::QoS};
+use crate::types::QoS;
+use crate::{admission::Rejected, service, store::SessionStore, trace, Admission, RateLimit};
+use crate::{io::Dispatcher, metrics::Metrics, registry::SessionRegistry};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
@@ -51,6 +53,11 @@ pub struct MqttServer<St, H, C, P> {
     max_inflight_size: usize,
     connect_timeout: Seconds,
     config: DispatcherConfig,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -80,6 +87,11 @@ where
             max_inflight: 16,
             max_inflight_size: 65535,
             connect_timeout: Seconds::ZERO,
+            store: None,
+            registry: None,
+            metrics: None,
+            admission: None,
+            rate_limit: None,
             pool: Default::default(),
             _t: PhantomData,
         }
@@ -167,6 +179,55 @@ where
         self
     }
 
+    /// Set session store.
+    ///
+    /// Subscriptions and unacknowledged outbound messages of a connection
+    /// with `clean_session=false` are persisted in the store and restored on reconnect.
+    ///
+    /// By default sessions are not persisted.
+    pub fn session_store<S: SessionStore + 'static>(mut self, store: S) -> Self {
+        self.store = Some(Rc::new(store));
+        self
+    }
+
+    /// Set registry of connected clients.
+    ///
+    /// Existing connection of a client is taken over by a new connection
+    /// with the same client id, see `SessionRegistry` for details.
+    ///
+    /// By default connections are not registered.
+    pub fn registry(mut self, registry: SessionRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Set metrics observer.
+    ///
+    /// Observer is called for connection and packet events of all connections,
+    /// see `metrics::Metrics` for details.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(Rc::new(metrics));
+        self
+    }
+
+    /// Set inbound rate limit.
+    ///
+    /// Limit applies to each connection and can be overridden
+    /// with `HandshakeAck::rate_limit()`. By default rate is not limited.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Set connection admission control.
+    ///
+    /// Connections are admitted after CONNECT packet is received,
+    /// before handshake service is called.
+    pub fn admission(mut self, admission: Admission) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -188,6 +249,11 @@ where
             max_inflight: self.max_inflight,
             max_inflight_size: self.max_inflight_size,
             connect_timeout: self.connect_timeout,
+            store: self.store,
+            registry: self.registry,
+            metrics: self.metrics,
+            admission: self.admission,
+            rate_limit: self.rate_limit,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -210,6 +276,11 @@ where
             max_inflight: self.max_inflight,
             max_inflight_size: self.max_inflight_size,
             connect_timeout: self.connect_timeout,
+            store: self.store,
+            registry: self.registry,
+            metrics: self.metrics,
+            admission: self.admission,
+            rate_limit: self.rate_limit,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -240,6 +311,11 @@ where
                 factory: self.handshake,
                 max_size: self.max_size,
                 connect_timeout: self.connect_timeout,
+                store: self.store,
+                registry: self.registry,
+                metrics: self.metrics,
+                admission: self.admission,
+                rate_limit: self.rate_limit,
                 pool: self.pool.clone(),
                 _t: PhantomData,
             },
@@ -280,6 +356,11 @@ where
             )),
             max_size: self.max_size,
             config: self.config,
+            store: self.store,
+            registry: self.registry,
+            metrics: self.metrics,
+            admission: self.admission,
+            rate_limit: self.rate_limit,
             _t: PhantomData,
         }
     }
@@ -289,6 +370,11 @@ struct HandshakeFactory<St, H> {
     factory: H,
     max_size: u32,
     connect_timeout: Seconds,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -310,6 +396,11 @@ where
             Ok(HandshakeService {
                 max_size: self.max_size,
                 pool: self.pool.clone(),
+                store: self.store.clone(),
+                registry: self.registry.clone(),
+                metrics: self.metrics.clone(),
+                admission: self.admission.clone(),
+                rate_limit: self.rate_limit,
                 service: self.factory.create(()).await?,
                 connect_timeout: self.connect_timeout.into(),
                 _t: PhantomData,
@@ -322,6 +413,11 @@ struct HandshakeService<St, H> {
     service: H,
     max_size: u32,
     pool: Rc<MqttSinkPool>,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     connect_timeout: Millis,
     _t: PhantomData<St>,
 }
@@ -340,13 +436,15 @@ where
 
     fn call<'a>(&'a self, io: IoBoxed, ctx: ServiceCtx<'a, Self>) -> Self::Future<'a> {
         log::trace!("Starting mqtt v3 handshake");
+        let started = Instant::now();
 
-        Box::pin(async move {
-            let codec = mqtt::Codec::default();
-            codec.set_max_size(self.max_size);
-            let shared =
-                Rc::new(MqttShared::new(io.get_ref(), codec, false, self.pool.clone()));
+        let codec = mqtt::Codec::default();
+        codec.set_max_size(self.max_size);
+        codec.metrics().set(self.metrics.clone());
+        let shared = Rc::new(MqttShared::new(io.get_ref(), codec, false, self.pool.clone()));
 
+        let span = trace::handshake(shared.span());
+        let fut = async move {
             // read first packet
             let packet = timeout_checked(self.connect_timeout, io.recv(&shared.codec))
                 .await
@@ -362,16 +460,34 @@ where
 
             match packet {
                 (mqtt::Packet::Connect(connect), size) => {
+                    // connection admission control
+                    let permit = match self.admission.as_ref().map(|adm| adm.acquire(&io)) {
+                        Some(Ok(permit)) => Some(permit),
+                        Some(Err(reason)) => return reject(&io, &shared.codec, reason).await,
+                        None => None,
+                    };
+
+                    let client_id = connect.client_id.clone();
+                    trace::record_client_id(shared.span(), &client_id);
+                    let clean_session = connect.clean_session;
+                    if let Some(limit) = self.rate_limit {
+                        shared.set_rate_limit(limit);
+                    }
+
                     // authenticate mqtt connection
-                    let ack = ctx
-                        .call(&self.service, Handshake::new(connect, size, io, shared))
-                        .await
-                        .map_err(MqttError::Service)?;
+                    let mut hnd = Handshake::new(connect, size, io, shared);
+                    hnd.store = self.store.clone();
+                    let ack = ctx.call(&self.service, hnd).await.map_err(MqttError::Service)?;
 
+                    if ack.session.is_some() {
+                        register_session(&self.registry, &ack, &client_id);
+                        open_session(&self.store, &ack.shared, &client_id, clean_session);
+                    }
                     match ack.session {
                         Some(session) => {
                             let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
-                                session_present: ack.session_present,
+                                session_present: ack.session_present
+                                    || ack.shared.session_present(),
                                 return_code: mqtt::ConnectAckReason::ConnectionAccepted,
                             });
 
@@ -379,6 +495,12 @@ where
 
                             ack.shared.set_cap(ack.inflight as usize);
                             ack.io.encode(pkt, &ack.shared.codec)?;
+                            if let Some(permit) = permit {
+                                permit.release_on(ack.io.on_disconnect());
+                            }
+                            let latency = started.elapsed();
+                            ack.shared.codec.metrics().with(|m, p| m.handshake(p, latency));
+                            ack.shared.restore_session();
                             Ok((
                                 ack.io,
                                 ack.shared.clone(),
@@ -410,7 +532,61 @@ where
                     )))
                 }
             }
-        })
+        };
+        Box::pin(trace::instrument(fut, span))
+    }
+}
+
+/// Reject connection that is not admitted
+async fn reject<T, E>(
+    io: &IoBoxed,
+    codec: &mqtt::Codec,
+    reason: Rejected,
+) -> Result<T, MqttError<E>> {
+    log::trace!("Connection is rejected by admission control: {:?}", reason);
+    io.encode(
+        mqtt::Packet::ConnectAck(mqtt::ConnectAck {
+            session_present: false,
+            return_code: mqtt::ConnectAckReason::ServiceUnavailable,
+        }),
+        codec,
+    )?;
+    let _ = io.shutdown().await;
+    Err(MqttError::Handshake(HandshakeError::Disconnected(None)))
+}
+
+/// Load persisted session for connection, or remove it for clean session
+///
+/// Session is opened only for accepted connections.
+fn open_session(
+    store: &Option<Rc<dyn SessionStore>>,
+    shared: &MqttShared,
+    client_id: &ByteString,
+    clean_session: bool,
+) {
+    if let Some(store) = store {
+        if clean_session {
+            store.remove(client_id);
+        } else if !client_id.is_empty() {
+            shared.open_session(store.clone(), client_id.clone());
+        }
+    }
+}
+
+/// Register connection, existing connection of the client is taken over.
+///
+/// Session state of taken over connection is persisted on close, so
+/// session must be opened after registration to inherit it.
+fn register_session<St>(
+    registry: &Option<SessionRegistry>,
+    ack: &HandshakeAck<St>,
+    client_id: &ByteString,
+) {
+    if let Some(registry) = registry {
+        if !client_id.is_empty() {
+            let sink = MqttSink::new(ack.shared.clone());
+            registry.register_v3(client_id.clone(), sink, ack.io.on_disconnect());
+        }
     }
 }
 
@@ -420,6 +596,11 @@ pub(crate) struct ServerSelector<St, H, T, F, R> {
     check: Rc<F>,
     config: DispatcherConfig,
     max_size: u32,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -452,6 +633,11 @@ where
                 check: self.check.clone(),
                 config: self.config.clone(),
                 max_size: self.max_size,
+                store: self.store.clone(),
+                registry: self.registry.clone(),
+                metrics: self.metrics.clone(),
+                admission: self.admission.clone(),
+                rate_limit: self.rate_limit,
                 handshake: self.handshake.create(()).await?,
                 _t: PhantomData,
             })
@@ -465,6 +651,11 @@ pub(crate) struct ServerSelectorImpl<St, H, T, F, R> {
     handler: Rc<T>,
     max_size: u32,
     config: DispatcherConfig,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -491,24 +682,49 @@ where
     ntex::forward_poll_shutdown!(handshake);
 
     #[inline]
-    fn call<'a>(&'a self, hnd: Handshake, ctx: ServiceCtx<'a, Self>) -> Self::Future<'a> {
-        Box::pin(async move {
+    fn call<'a>(&'a self, mut hnd: Handshake, ctx: ServiceCtx<'a, Self>) -> Self::Future<'a> {
+        let span = trace::handshake(hnd.shared.span());
+        let fut = async move {
             log::trace!("Start connection handshake");
+            let started = Instant::now();
 
             let result = (*self.check)(&hnd).await;
             if !result.map_err(|e| MqttError::Handshake(HandshakeError::Service(e)))? {
                 Ok(Either::Left(hnd))
             } else {
+                // connection admission control
+                let permit = match self.admission.as_ref().map(|adm| adm.acquire(hnd.io())) {
+                    Some(Ok(permit)) => Some(permit),
+                    Some(Err(reason)) => {
+                        return reject(hnd.io(), &hnd.shared.codec, reason).await
+                    }
+                    None => None,
+                };
+
+                hnd.shared.codec.metrics().set(self.metrics.clone());
+                if let Some(limit) = self.rate_limit {
+                    hnd.shared.set_rate_limit(limit);
+                }
+                let client_id = hnd.packet().client_id.clone();
+                trace::record_client_id(hnd.shared.span(), &client_id);
+                let clean_session = hnd.packet().clean_session;
+                hnd.store = self.store.clone();
+
                 // authenticate mqtt connection
                 let ack = ctx.call(&self.handshake, hnd).await.map_err(|e| {
                     log::trace!("Connection handshake failed: {:?}", e);
                     MqttError::Handshake(HandshakeError::Service(e))
                 })?;
 
+                if ack.session.is_some() {
+                    register_session(&self.registry, &ack, &client_id);
+                    open_session(&self.store, &ack.shared, &client_id, clean_session);
+                }
                 match ack.session {
                     Some(session) => {
                         let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
-                            session_present: ack.session_present,
+                            session_present: ack.session_present
+                                || ack.shared.session_present(),
                             return_code: mqtt::ConnectAckReason::ConnectionAccepted,
                         });
                         log::trace!(
@@ -519,6 +735,12 @@ where
                         ack.shared.set_cap(ack.inflight as usize);
                         ack.shared.codec.set_max_size(self.max_size);
                         ack.io.encode(pkt, &ack.shared.codec)?;
+                        if let Some(permit) = permit {
+                            permit.release_on(ack.io.on_disconnect());
+                        }
+                        let latency = started.elapsed();
+                        ack.shared.codec.metrics().with(|m, p| m.handshake(p, latency));
+                        ack.shared.restore_session();
 
                         let session = Session::new(session, MqttSink::new(ack.shared.clone()));
                         let handler = self.handler.create(session).await?;
@@ -543,6 +765,7 @@ where
                     }
                 }
             }
-        })
+        };
+        Box::pin(trace::instrument(fut, span))
     }
 }
@@ -3,13 +3,18 @@ use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc:
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
 use ntex::io::IoRef;
-use ntex::util::{BytesMut, HashSet, PoolId, PoolRef};
+use ntex::util::{ByteString, BytesMut, HashMap, HashSet, PoolId, PoolRef};
 
 use crate::error::{DecodeError, EncodeError, ProtocolError, SendPacketError};
-use crate::{types::packet_type, v3::codec};
+use crate::store::{SessionPersist, SessionStore, StoredPublish};
+use crate::{trace, types::packet_type, types::QoS, v3::codec, RateLimit};
+
+use super::sink::InflightMessages;
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
+    Receive(NonZeroU16),
+    Complete(NonZeroU16),
     Subscribe { packet_id: NonZeroU16, status: Vec<codec::SubscribeReturnCode> },
     Unsubscribe(NonZeroU16),
 }
@@ -17,6 +22,7 @@ pub(super) enum Ack {
 #[derive(Copy, Clone)]
 pub(super) enum AckType {
     Publish,
+    Receive,
     Subscribe,
     Unsubscribe,
 }
@@ -43,6 +49,7 @@ bitflags::bitflags! {
         const CLIENT         = 0b1000_0000;
         const WRB_ENABLED    = 0b0100_0000; // write-backpressure
         const ON_PUBLISH_ACK = 0b0010_0000; // on-publish-ack callback
+        const TRACK_INFLIGHT = 0b0001_0000; // keep unacknowledged publishes
     }
 }
 
@@ -54,12 +61,25 @@ pub struct MqttShared {
     pool: Rc<MqttSinkPool>,
     flags: Cell<Flags>,
     on_publish_ack: Cell<Option<Box<dyn Fn(NonZeroU16, bool)>>>,
+    session: RefCell<Option<SessionPersist>>,
+    // copies of unacknowledged publishes
+    unacked: RefCell<HashMap<NonZeroU16, codec::Publish>>,
+    // in-flight messages of closed connection
+    exported: Cell<Option<InflightMessages>>,
+    // connection tracing span
+    span: trace::Span,
+    // inbound rate limit, applied by dispatcher
+    rate_limit: Cell<Option<RateLimit>>,
     pub(super) codec: codec::Codec,
 }
 
 struct MqttSharedQueues {
     inflight: VecDeque<(NonZeroU16, Option<pool::Sender<Ack>>, AckType)>,
     inflight_ids: HashSet<NonZeroU16>,
+    // QoS 2 publishes waiting for PUBCOMP
+    released: VecDeque<(NonZeroU16, Option<pool::Sender<Ack>>)>,
+    // replayed publishes waiting for receive credit
+    replay: VecDeque<codec::Publish>,
     waiters: VecDeque<pool::Sender<()>>,
 }
 
@@ -70,6 +90,7 @@ impl MqttShared {
         client: bool,
         pool: Rc<MqttSinkPool>,
     ) -> Self {
+        let span = trace::connection("v3", &io);
         Self {
             io,
             codec,
@@ -79,13 +100,33 @@ impl MqttShared {
             queues: RefCell::new(MqttSharedQueues {
                 inflight: VecDeque::with_capacity(8),
                 inflight_ids: HashSet::default(),
+                released: VecDeque::new(),
+                replay: VecDeque::new(),
                 waiters: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
             on_publish_ack: Cell::new(None),
+            session: RefCell::new(None),
+            unacked: RefCell::new(HashMap::default()),
+            exported: Cell::new(None),
+            span,
+            rate_limit: Cell::new(None),
         }
     }
 
+    /// Connection tracing span
+    pub(super) fn span(&self) -> &trace::Span {
+        &self.span
+    }
+
+    pub(super) fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.get()
+    }
+
+    pub(super) fn set_rate_limit(&self, limit: RateLimit) {
+        self.rate_limit.set(Some(limit));
+    }
+
     pub(super) fn close(&self) {
         if self.flags.get().contains(Flags::CLIENT) {
             let _ = self.encode_packet(codec::Packet::Disconnect);
@@ -108,7 +149,7 @@ impl MqttShared {
     }
 
     pub(super) fn credit(&self) -> usize {
-        self.cap.get().saturating_sub(self.queues.borrow().inflight.len())
+        self.cap.get().saturating_sub(self.queues.borrow().len())
     }
 
     pub(super) fn next_id(&self) -> NonZeroU16 {
@@ -124,6 +165,12 @@ impl MqttShared {
     }
 
     pub(super) fn set_cap(&self, cap: usize) {
+        self.cap.set(cap);
+
+        // replayed publishes use receive credit first
+        if !self.drain_replay() {
+            return;
+        }
         let mut queues = self.queues.borrow_mut();
 
         // wake up queued request (receive max limit)
@@ -135,7 +182,6 @@ impl MqttShared {
             }
             break;
         }
-        self.cap.set(cap);
     }
 
     pub(super) fn set_publish_ack(&self, f: Box<dyn Fn(NonZeroU16, bool)>) {
@@ -151,7 +197,33 @@ impl MqttShared {
 
     fn clear_queues(&self) {
         let mut queues = self.queues.borrow_mut();
+        let queues = &mut *queues;
+
+        // keep unacknowledged messages
+        if self.flags.get().contains(Flags::TRACK_INFLIGHT) {
+            let mut unacked = self.unacked.take();
+            let msgs = InflightMessages {
+                publishes: queues
+                    .inflight
+                    .iter()
+                    .filter_map(|(id, _, _)| unacked.remove(id))
+                    // replayed publishes are not sent yet
+                    .chain(queues.replay.drain(..))
+                    .collect(),
+                released: queues.released.iter().map(|item| item.0).collect(),
+            };
+
+            if let Some(persist) = self.session.take() {
+                let publishes = msgs.publishes.into_iter().filter_map(stored_publish).collect();
+                persist.save(publishes, msgs.released);
+            } else if !msgs.is_empty() {
+                self.exported.set(Some(msgs));
+            }
+        }
+
         queues.waiters.clear();
+        queues.released.clear();
+        queues.replay.clear();
 
         if let Some(cb) = self.on_publish_ack.take() {
             for (idx, tx, _) in queues.inflight.drain(..) {
@@ -175,10 +247,15 @@ impl MqttShared {
         flags.remove(Flags::WRB_ENABLED);
         self.flags.set(flags);
 
+        // replayed publishes use receive credit first
+        if !self.drain_replay() {
+            return;
+        }
+
         // check if there are waiters
         let mut queues = self.queues.borrow_mut();
-        if queues.inflight.len() < self.cap.get() {
-            let mut num = self.cap.get() - queues.inflight.len();
+        if queues.len() < self.cap.get() {
+            let mut num = self.cap.get() - queues.len();
             while num > 0 {
                 if let Some(tx) = queues.waiters.pop_front() {
                     if tx.send(()).is_ok() {
@@ -195,12 +272,46 @@ impl MqttShared {
         self.pkt_ack_inner(ack).map_err(|e| {
             self.close();
             e
-        })
+        })?;
+        self.drain_replay();
+        self.inflight_metrics(self.queues.borrow().len());
+        Ok(())
+    }
+
+    fn inflight_metrics(&self, depth: usize) {
+        self.codec.metrics().with(|m, p| m.inflight(p, depth));
     }
 
     fn pkt_ack_inner(&self, pkt: Ack) -> Result<(), ProtocolError> {
         let mut queues = self.queues.borrow_mut();
 
+        // PUBCOMP is not ordered with other acks
+        if let Ack::Complete(packet_id) = pkt {
+            return if let Some(pos) =
+                queues.released.iter().position(|item| item.0 == packet_id)
+            {
+                log::trace!("Complete QoS 2 publish with id: {}", packet_id);
+
+                let (_, tx) = queues.released.remove(pos).unwrap();
+                queues.inflight_ids.remove(&packet_id);
+                if let Some(tx) = tx {
+                    let _ = tx.send(pkt);
+                } else if let Some(cb) = self.on_publish_ack.take() {
+                    (*cb)(packet_id, false);
+                    self.on_publish_ack.set(Some(cb));
+                }
+                if queues.replay.is_empty() {
+                    queues.wake_waiter();
+                }
+                Ok(())
+            } else {
+                log::trace!("Unexpected PUBCOMP packet: {:?}", packet_id);
+                Err(ProtocolError::generic_violation(
+                    "Received PUBCOMP packet while there are no unreleased PUBLISH packets",
+                ))
+            };
+        }
+
         // check ack order
         if let Some((idx, tx, tp)) = queues.inflight.pop_front() {
             if idx != pkt.packet_id() {
@@ -213,22 +324,30 @@ impl MqttShared {
             } else {
                 // get publish ack channel
                 log::trace!("Ack packet with id: {}", pkt.packet_id());
-                queues.inflight_ids.remove(&pkt.packet_id());
 
                 if pkt.is_match(tp) {
+                    self.unacked.borrow_mut().remove(&idx);
+
+                    // PUBREC, send PUBREL and wait for PUBCOMP
+                    if let Ack::Receive(packet_id) = pkt {
+                        self.io
+                            .encode(codec::Packet::PublishRelease { packet_id }, &self.codec)?;
+                        queues.released.push_back((idx, tx));
+                        return Ok(());
+                    }
+                    queues.inflight_ids.remove(&pkt.packet_id());
+
                     if let Some(tx) = tx {
                         let _ = tx.send(pkt);
-                    } else {
-                        let cb = self.on_publish_ack.take().unwrap();
+                    } else if let Some(cb) = self.on_publish_ack.take() {
                         (*cb)(pkt.packet_id(), false);
                         self.on_publish_ack.set(Some(cb));
                     }
 
-                    // wake up queued request (receive max limit)
-                    while let Some(tx) = queues.waiters.pop_front() {
-                        if tx.send(()).is_ok() {
-                            break;
-                        }
+                    // wake up queued request (receive max limit),
+                    // replayed publishes are sent first
+                    if queues.replay.is_empty() {
+                        queues.wake_waiter();
                     }
                     Ok(())
                 } else {
@@ -257,6 +376,7 @@ impl MqttShared {
             let (tx, rx) = self.pool.queue.channel();
             queues.inflight.push_back((id, Some(tx), ack));
             queues.inflight_ids.insert(id);
+            self.inflight_metrics(queues.len());
             Ok(rx)
         }
     }
@@ -272,11 +392,16 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
+            let retained = self.retain_publish(&pkt);
             match self.io.encode(pkt, &self.codec) {
                 Ok(_) => {
+                    if let Some(pkt) = retained {
+                        self.unacked.borrow_mut().insert(id, pkt);
+                    }
                     let (tx, rx) = self.pool.queue.channel();
                     queues.inflight.push_back((id, Some(tx), ack));
                     queues.inflight_ids.insert(id);
+                    self.inflight_metrics(queues.len());
                     Ok(rx)
                 }
                 Err(e) => Err(SendPacketError::Encode(e)),
@@ -295,10 +420,15 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
+            let retained = self.retain_publish(&pkt);
             match self.io.encode(pkt, &self.codec) {
                 Ok(_) => {
+                    if let Some(pkt) = retained {
+                        self.unacked.borrow_mut().insert(id, pkt);
+                    }
                     queues.inflight.push_back((id, None, ack));
                     queues.inflight_ids.insert(id);
+                    self.inflight_metrics(queues.len());
                     if !self.flags.get().contains(Flags::ON_PUBLISH_ACK) {
                         panic!("Publish ack callback is not set");
                     }
@@ -309,12 +439,174 @@ impl MqttShared {
         }
     }
 
+    fn retain_publish(&self, pkt: &codec::Packet) -> Option<codec::Publish> {
+        match pkt {
+            codec::Packet::Publish(pkt) if self.flags.get().contains(Flags::TRACK_INFLIGHT) => {
+                Some(pkt.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub(super) fn track_inflight(&self) {
+        let mut flags = self.flags.get();
+        flags.insert(Flags::TRACK_INFLIGHT);
+        self.flags.set(flags);
+    }
+
+    pub(super) fn take_inflight(&self) -> InflightMessages {
+        self.exported.take().unwrap_or_default()
+    }
+
+    /// Re-send in-flight messages of previous connection
+    ///
+    /// Packet ids are checked before anything is sent. Publishes that
+    /// exceed receive credit are sent when credit is released.
+    pub(super) fn replay_inflight(
+        &self,
+        msgs: InflightMessages,
+    ) -> Result<(), SendPacketError> {
+        {
+            let mut queues = self.queues.borrow_mut();
+
+            let mut ids = HashSet::default();
+            let publish_ids = msgs.publishes.iter().filter_map(|pkt| pkt.packet_id);
+            for id in msgs.released.iter().copied().chain(publish_ids) {
+                if queues.inflight_ids.contains(&id) || !ids.insert(id) {
+                    return Err(SendPacketError::PacketIdInUse(id));
+                }
+            }
+            let max_id = ids.iter().map(|id| id.get()).max().unwrap_or(0);
+
+            // PUBREL packets must be re-sent before publishes
+            for packet_id in msgs.released {
+                self.io.encode(codec::Packet::PublishRelease { packet_id }, &self.codec)?;
+                queues.released.push_back((packet_id, None));
+                queues.inflight_ids.insert(packet_id);
+            }
+
+            for mut pkt in msgs.publishes {
+                if let Some(id) = pkt.packet_id {
+                    pkt.dup = true;
+                    queues.replay.push_back(pkt);
+                    queues.inflight_ids.insert(id);
+                }
+            }
+
+            // do not reuse replayed packet ids
+            self.inflight_idx.set(self.inflight_idx.get().max(max_id));
+        }
+        self.drain_replay();
+        Ok(())
+    }
+
+    /// Send replayed publishes while there is receive credit
+    ///
+    /// Returns `true` if all replayed publishes are sent.
+    fn drain_replay(&self) -> bool {
+        let mut queues = self.queues.borrow_mut();
+        while !queues.replay.is_empty() {
+            if queues.len() >= self.cap.get() || self.flags.get().contains(Flags::WRB_ENABLED) {
+                return false;
+            }
+            let pkt = queues.replay.pop_front().unwrap();
+            let id = pkt.packet_id.unwrap();
+            let tp =
+                if pkt.qos == QoS::ExactlyOnce { AckType::Receive } else { AckType::Publish };
+
+            let retained =
+                self.flags.get().contains(Flags::TRACK_INFLIGHT).then(|| pkt.clone());
+            if let Err(err) = self.io.encode(codec::Packet::Publish(pkt), &self.codec) {
+                log::trace!("Cannot send replayed publish: {:?}", err);
+                queues.inflight_ids.remove(&id);
+                continue;
+            }
+            if let Some(pkt) = retained {
+                self.unacked.borrow_mut().insert(id, pkt);
+            }
+            queues.inflight.push_back((id, None, tp));
+        }
+        true
+    }
+
+    /// Load persisted session state
+    pub(super) fn open_session(&self, store: Rc<dyn SessionStore>, client_id: ByteString) {
+        *self.session.borrow_mut() = Some(SessionPersist::open(store, client_id, false));
+        self.track_inflight();
+    }
+
+    /// Check if persisted session state is restored
+    pub(super) fn session_present(&self) -> bool {
+        self.session.borrow().as_ref().map(|p| p.restored().is_some()).unwrap_or(false)
+    }
+
+    /// Resend unacknowledged packets of restored session
+    pub(super) fn restore_session(&self) {
+        let state = if let Some(ref mut persist) = *self.session.borrow_mut() {
+            persist.take_restored()
+        } else {
+            None
+        };
+
+        if let Some(state) = state {
+            let msgs = InflightMessages {
+                publishes: state
+                    .publishes
+                    .into_iter()
+                    .map(|item| codec::Publish {
+                        dup: true,
+                        retain: item.retain,
+                        qos: item.qos,
+                        topic: item.topic,
+                        packet_id: Some(item.packet_id),
+                        payload: item.payload,
+                    })
+                    .collect(),
+                released: state.released,
+            };
+            if let Err(e) = self.replay_inflight(msgs) {
+                log::error!("Cannot re-send messages of restored session: {:?}", e);
+            }
+        }
+    }
+
+    /// Record client subscribe request
+    pub(super) fn session_subscribe(&self, id: NonZeroU16, filters: &[(ByteString, QoS)]) {
+        if let Some(ref mut persist) = *self.session.borrow_mut() {
+            persist.subscribe(id, filters.to_vec());
+        }
+    }
+
+    /// Record client unsubscribe request
+    pub(super) fn session_unsubscribe(&self, id: NonZeroU16, filters: &[ByteString]) {
+        if let Some(ref mut persist) = *self.session.borrow_mut() {
+            persist.unsubscribe(id, filters.to_vec());
+        }
+    }
+
+    /// Apply SUBACK or UNSUBACK to session subscriptions
+    pub(super) fn session_ack(&self, pkt: &codec::Packet) {
+        if let Some(ref mut persist) = *self.session.borrow_mut() {
+            match pkt {
+                codec::Packet::SubscribeAck { packet_id, status } => persist.subscribed(
+                    *packet_id,
+                    status.iter().map(|st| match st {
+                        codec::SubscribeReturnCode::Success(qos) => Some(*qos),
+                        codec::SubscribeReturnCode::Failure => None,
+                    }),
+                ),
+                codec::Packet::UnsubscribeAck { packet_id } => {
+                    persist.unsubscribed(*packet_id, std::iter::repeat(true))
+                }
+                _ => (),
+            }
+        }
+    }
+
     pub(super) fn wait_readiness(&self) -> Option<pool::Receiver<()>> {
         let mut queues = self.queues.borrow_mut();
 
-        if queues.inflight.len() >= self.cap.get()
-            || self.flags.get().contains(Flags::WRB_ENABLED)
-        {
+        if queues.len() >= self.cap.get() || self.flags.get().contains(Flags::WRB_ENABLED) {
             let (tx, rx) = self.pool.waiters.channel();
             queues.waiters.push_back(tx);
             Some(rx)
@@ -324,6 +616,37 @@ impl MqttShared {
     }
 }
 
+fn stored_publish(pkt: codec::Publish) -> Option<StoredPublish> {
+    pkt.packet_id.map(|packet_id| StoredPublish {
+        packet_id,
+        qos: pkt.qos,
+        retain: pkt.retain,
+        topic: pkt.topic,
+        payload: pkt.payload,
+        correlation_data: None,
+        message_expiry: None,
+        content_type: None,
+        user_properties: Vec::new(),
+        is_utf8_payload: false,
+        response_topic: None,
+    })
+}
+
+impl MqttSharedQueues {
+    /// Number of packets that use receive credit
+    fn len(&self) -> usize {
+        self.inflight.len() + self.released.len()
+    }
+
+    fn wake_waiter(&mut self) {
+        while let Some(tx) = self.waiters.pop_front() {
+            if tx.send(()).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
 impl Encoder for MqttShared {
     type Item = codec::Packet;
     type Error = EncodeError;
@@ -348,6 +671,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe { .. } => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -356,6 +681,8 @@ impl Ack {
     pub(super) fn packet_id(&self) -> NonZeroU16 {
         match self {
             Ack::Publish(id) => *id,
+            Ack::Receive(id) => *id,
+            Ack::Complete(id) => *id,
             Ack::Subscribe { packet_id, .. } => *packet_id,
             Ack::Unsubscribe(id) => *id,
         }
@@ -372,6 +699,7 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
             (Ack::Subscribe { .. }, AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -383,6 +711,7 @@ impl AckType {
     pub(super) fn expected_str(&self) -> &'static str {
         match self {
             AckType::Publish => "Expected PUBACK packet",
+            AckType::Receive => "Expected PUBREC packet",
             AckType::Subscribe => "Expected SUBACK packet",
             AckType::Unsubscribe => "Expected UNSUBACK packet",
         }
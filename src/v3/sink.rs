@@ -3,6 +3,7 @@ use std::{fmt, future::ready, future::Future, num::NonZeroU16, rc::Rc};
 use ntex::util::{ByteString, Bytes, Either, Ready};
 
 use super::{codec, error::SendPacketError, shared::AckType, shared::MqttShared};
+use crate::trace;
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -21,6 +22,12 @@ impl MqttSink {
         self.0.clone()
     }
 
+    #[cfg(feature = "broker")]
+    /// Check if both sinks belong to the same connection
+    pub(crate) fn is_same(&self, other: &MqttSink) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
     #[inline]
     /// Check if io stream is open
     pub fn is_open(&self) -> bool {
@@ -101,7 +108,8 @@ impl MqttSink {
     /// Set publish ack callback
     ///
     /// Use non-blocking send, PublishBuilder::send_at_least_once_no_block()
-    /// First argument is packet id, second argument is "disconnected" state
+    /// First argument is packet id, second argument is "disconnected" state.
+    /// QoS 2 publishes are acknowledged after PUBCOMP is received.
     pub fn publish_ack_cb<F>(&self, f: F)
     where
         F: Fn(NonZeroU16, bool) + 'static,
@@ -109,6 +117,38 @@ impl MqttSink {
         self.0.set_publish_ack(Box::new(f));
     }
 
+    /// Keep unacknowledged QoS 1 and QoS 2 messages
+    ///
+    /// After connection is closed, in-flight messages could be taken
+    /// with `MqttSink::take_inflight()` and re-sent on a new connection.
+    pub fn track_inflight(&self) {
+        self.0.track_inflight();
+    }
+
+    /// Take in-flight messages of closed connection
+    ///
+    /// Messages are tracked only if `MqttSink::track_inflight()` is called.
+    pub fn take_inflight(&self) -> InflightMessages {
+        self.0.take_inflight()
+    }
+
+    /// Re-send in-flight messages of previous connection
+    ///
+    /// PUBREL packets are sent first, then publish packets with `dup` flag set,
+    /// in the order they were originally sent. Original packet ids are used.
+    /// Acknowledgements of replayed publishes are delivered to publish ack callback
+    /// if it is set.
+    ///
+    /// Nothing is sent if any packet id is already in use. Publishes that exceed
+    /// peer's receive maximum are sent when in-flight publishes get acknowledged.
+    pub fn replay_inflight(&self, msgs: InflightMessages) -> Result<(), SendPacketError> {
+        if self.0.is_closed() {
+            Err(SendPacketError::Disconnected)
+        } else {
+            self.0.replay_inflight(msgs)
+        }
+    }
+
     #[inline]
     /// Create subscribe packet builder
     ///
@@ -173,6 +213,9 @@ impl PublishBuilder {
     #[inline]
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(mut self) -> Result<(), SendPacketError> {
+        let span =
+            trace::send_publish(self.shared.span(), &self.packet.topic, codec::QoS::AtMostOnce);
+        let _enter = span.enter();
         if !self.shared.is_closed() {
             log::trace!("Publish (QoS-0) to {:?}", self.packet.topic);
             self.packet.qos = codec::QoS::AtMostOnce;
@@ -188,7 +231,12 @@ impl PublishBuilder {
 
     /// Send publish packet with QoS 1
     pub fn send_at_least_once(self) -> impl Future<Output = Result<(), SendPacketError>> {
-        if !self.shared.is_closed() {
+        let span = trace::send_publish(
+            self.shared.span(),
+            &self.packet.topic,
+            codec::QoS::AtLeastOnce,
+        );
+        let fut = if !self.shared.is_closed() {
             let shared = self.shared;
             let mut packet = self.packet;
             packet.qos = codec::QoS::AtLeastOnce;
@@ -206,13 +254,20 @@ impl PublishBuilder {
             }
         } else {
             Either::Right(Ready::Err(SendPacketError::Disconnected))
-        }
+        };
+        trace::instrument(fut, span)
     }
 
     /// Non-blocking send publish packet with QoS 1
     ///
     /// Panics if sink is not ready or publish ack callback is not set
     pub fn send_at_least_once_no_block(self) -> Result<(), SendPacketError> {
+        let span = trace::send_publish(
+            self.shared.span(),
+            &self.packet.topic,
+            codec::QoS::AtLeastOnce,
+        );
+        let _enter = span.enter();
         if !self.shared.is_closed() {
             let shared = self.shared;
 
@@ -261,6 +316,123 @@ impl PublishBuilder {
             shared.wait_packet_response(idx, AckType::Publish, codec::Packet::Publish(packet));
         async move { rx?.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected) }
     }
+
+    #[cfg(feature = "broker")]
+    /// Send publish packet without waiting for acknowledgement
+    ///
+    /// Sink must be ready, acknowledgement is not passed to publish ack callback.
+    pub(crate) fn send_no_wait(self) -> Result<(), SendPacketError> {
+        let ack = match self.packet.qos {
+            codec::QoS::AtMostOnce => return self.send_at_most_once(),
+            codec::QoS::AtLeastOnce => AckType::Publish,
+            codec::QoS::ExactlyOnce => AckType::Receive,
+        };
+        let span = trace::send_publish(self.shared.span(), &self.packet.topic, self.packet.qos);
+        let _enter = span.enter();
+        if self.shared.is_closed() {
+            return Err(SendPacketError::Disconnected);
+        }
+        let mut packet = self.packet;
+        let idx = self.shared.next_id();
+        packet.packet_id = Some(idx);
+        log::trace!("Publish ({:?}) to {:#?}", packet.qos, packet);
+
+        self.shared.wait_packet_response(idx, ack, codec::Packet::Publish(packet)).map(|_| ())
+    }
+
+    /// Non-blocking send publish packet with packet's QoS
+    ///
+    /// Sink must be ready, acknowledgement of QoS 1 and QoS 2 publish is passed
+    /// to publish ack callback. Returns packet id of QoS 1 and QoS 2 publish.
+    pub(crate) fn send_no_block(self) -> Result<Option<NonZeroU16>, SendPacketError> {
+        let ack = match self.packet.qos {
+            codec::QoS::AtMostOnce => return self.send_at_most_once().map(|_| None),
+            codec::QoS::AtLeastOnce => AckType::Publish,
+            codec::QoS::ExactlyOnce => AckType::Receive,
+        };
+        let span = trace::send_publish(self.shared.span(), &self.packet.topic, self.packet.qos);
+        let _enter = span.enter();
+        if self.shared.is_closed() {
+            return Err(SendPacketError::Disconnected);
+        }
+        if !self.shared.is_ready() {
+            panic!("Mqtt sink is not ready");
+        }
+        let mut packet = self.packet;
+        let idx = packet.packet_id.unwrap_or_else(|| self.shared.next_id());
+        packet.packet_id = Some(idx);
+        log::trace!("Publish ({:?}) to {:#?}", packet.qos, packet);
+
+        self.shared
+            .wait_packet_response_no_block(idx, ack, codec::Packet::Publish(packet))
+            .map(|_| Some(idx))
+    }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// Future resolves after PUBCOMP is received from the peer.
+    pub fn send_exactly_once(self) -> impl Future<Output = Result<(), SendPacketError>> {
+        let span = trace::send_publish(
+            self.shared.span(),
+            &self.packet.topic,
+            codec::QoS::ExactlyOnce,
+        );
+        let fut = if !self.shared.is_closed() {
+            let shared = self.shared;
+            let mut packet = self.packet;
+            packet.qos = codec::QoS::ExactlyOnce;
+
+            // handle client receive maximum
+            if let Some(rx) = shared.wait_readiness() {
+                Either::Left(Either::Left(async move {
+                    if rx.await.is_err() {
+                        return Err(SendPacketError::Disconnected);
+                    }
+                    Self::send_exactly_once_inner(packet, shared).await
+                }))
+            } else {
+                Either::Left(Either::Right(Self::send_exactly_once_inner(packet, shared)))
+            }
+        } else {
+            Either::Right(Ready::Err(SendPacketError::Disconnected))
+        };
+        trace::instrument(fut, span)
+    }
+
+    fn send_exactly_once_inner(
+        mut packet: codec::Publish,
+        shared: Rc<MqttShared>,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        // packet id
+        let idx = if let Some(idx) = packet.packet_id {
+            idx
+        } else {
+            let idx = shared.next_id();
+            packet.packet_id = Some(idx);
+            idx
+        };
+        log::trace!("Publish (QoS2) to {:#?}", packet);
+
+        let rx =
+            shared.wait_packet_response(idx, AckType::Receive, codec::Packet::Publish(packet));
+        async move { rx?.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected) }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// Unacknowledged messages of a connection
+pub struct InflightMessages {
+    /// QoS 1 and QoS 2 publishes without PUBACK or PUBREC
+    pub publishes: Vec<codec::Publish>,
+    /// Packet ids of QoS 2 publishes without PUBCOMP
+    pub released: Vec<NonZeroU16>,
+}
+
+impl InflightMessages {
+    /// Check if there are no in-flight messages
+    pub fn is_empty(&self) -> bool {
+        self.publishes.is_empty() && self.released.is_empty()
+    }
 }
 
 /// Subscribe packet builder
@@ -299,6 +471,11 @@ impl SubscribeBuilder {
 
     /// Send subscribe packet
     pub async fn send(self) -> Result<Vec<codec::SubscribeReturnCode>, SendPacketError> {
+        let span = trace::send(self.shared.span(), "subscribe");
+        trace::instrument(self.send_inner(), span).await
+    }
+
+    async fn send_inner(self) -> Result<Vec<codec::SubscribeReturnCode>, SendPacketError> {
         let shared = self.shared;
         let filters = self.topic_filters;
 
@@ -369,6 +546,11 @@ impl UnsubscribeBuilder {
 
     /// Send unsubscribe packet
     pub async fn send(self) -> Result<(), SendPacketError> {
+        let span = trace::send(self.shared.span(), "unsubscribe");
+        trace::instrument(self.send_inner(), span).await
+    }
+
+    async fn send_inner(self) -> Result<(), SendPacketError> {
         let shared = self.shared;
         let filters = self.topic_filters;
 
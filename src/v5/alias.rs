@@ -0,0 +1,163 @@
+//! Outbound topic alias assignment
+use std::{collections::BTreeMap, num::NonZeroU16};
+
+use ntex::util::{ByteString, HashMap};
+
+use super::codec;
+
+/// LRU cache of outbound topic aliases
+#[derive(Debug, Default)]
+pub(super) struct TopicAliases {
+    max: u16,
+    peer_max: u16,
+    tick: u64,
+    topics: HashMap<ByteString, (NonZeroU16, u64)>,
+    lru: BTreeMap<u64, ByteString>,
+}
+
+/// Alias that must be registered after publish packet get sent
+pub(super) struct NewAlias {
+    topic: ByteString,
+    alias: NonZeroU16,
+    // lru entry to evict
+    evict: Option<u64>,
+}
+
+impl TopicAliases {
+    pub(super) fn set_max(&mut self, max: u16) {
+        self.max = max;
+        self.trim();
+    }
+
+    pub(super) fn set_peer_max(&mut self, max: u16) {
+        self.peer_max = max;
+        self.trim();
+    }
+
+    fn size(&self) -> usize {
+        self.max.min(self.peer_max) as usize
+    }
+
+    /// Drop aliases above limits
+    fn trim(&mut self) {
+        let size = self.size();
+        self.topics.retain(|_, (alias, _)| alias.get() as usize <= size);
+        let topics = &self.topics;
+        self.lru.retain(|_, topic| topics.contains_key(topic));
+    }
+
+    /// Set topic alias for publish packet.
+    ///
+    /// Topic is omitted if alias is already established.
+    pub(super) fn apply(&mut self, pkt: &mut codec::Publish) -> Option<NewAlias> {
+        if self.size() == 0 || pkt.topic.is_empty() || pkt.properties.topic_alias.is_some() {
+            return None;
+        }
+
+        self.tick += 1;
+        if let Some((alias, tick)) = self.topics.get_mut(&pkt.topic) {
+            let topic = self.lru.remove(tick).expect("lru entry exists");
+            *tick = self.tick;
+            self.lru.insert(self.tick, topic);
+            pkt.properties.topic_alias = Some(*alias);
+            pkt.topic = ByteString::new();
+            return None;
+        }
+
+        let (alias, evict) = if self.topics.len() < self.size() {
+            (NonZeroU16::new(self.topics.len() as u16 + 1).unwrap(), None)
+        } else {
+            // re-use alias of least recently used topic
+            let (tick, topic) = self.lru.first_key_value().expect("lru is not empty");
+            (self.topics[topic].0, Some(*tick))
+        };
+        pkt.properties.topic_alias = Some(alias);
+        Some(NewAlias { topic: pkt.topic.clone(), alias, evict })
+    }
+
+    /// Register alias of sent packet
+    pub(super) fn register(&mut self, item: NewAlias) {
+        if let Some(tick) = item.evict {
+            if let Some(topic) = self.lru.remove(&tick) {
+                self.topics.remove(&topic);
+            }
+        }
+        self.lru.insert(self.tick, item.topic.clone());
+        self.topics.insert(item.topic, (item.alias, self.tick));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::Bytes;
+
+    use super::*;
+
+    fn publish(topic: &'static str) -> codec::Publish {
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::new(),
+            properties: Default::default(),
+        }
+    }
+
+    fn send(aliases: &mut TopicAliases, topic: &'static str) -> codec::Publish {
+        let mut pkt = publish(topic);
+        if let Some(alias) = aliases.apply(&mut pkt) {
+            aliases.register(alias);
+        }
+        pkt
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut aliases = TopicAliases::default();
+        aliases.set_max(2);
+
+        // peer does not support aliases
+        let pkt = send(&mut aliases, "a");
+        assert_eq!(pkt.topic, "a");
+        assert_eq!(pkt.properties.topic_alias, None);
+
+        aliases.set_peer_max(10);
+        let pkt = send(&mut aliases, "a");
+        assert_eq!(pkt.topic, "a");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(1));
+        let pkt = send(&mut aliases, "a");
+        assert_eq!(pkt.topic, "");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(1));
+
+        let pkt = send(&mut aliases, "b");
+        assert_eq!(pkt.topic, "b");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(2));
+
+        // "b" is least recently used
+        let pkt = send(&mut aliases, "a");
+        assert_eq!(pkt.topic, "");
+        let pkt = send(&mut aliases, "c");
+        assert_eq!(pkt.topic, "c");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(2));
+        let pkt = send(&mut aliases, "b");
+        assert_eq!(pkt.topic, "b");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(1));
+        let pkt = send(&mut aliases, "c");
+        assert_eq!(pkt.topic, "");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(2));
+
+        // alias is not registered if packet is not sent
+        let mut pkt = publish("d");
+        assert!(aliases.apply(&mut pkt).is_some());
+        let pkt = send(&mut aliases, "d");
+        assert_eq!(pkt.topic, "d");
+
+        // limit is lowered
+        aliases.set_peer_max(1);
+        let pkt = send(&mut aliases, "b");
+        assert_eq!(pkt.topic, "b");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(1));
+    }
+}
@@ -0,0 +1,233 @@
+use std::{marker::PhantomData, rc::Rc};
+
+use ntex::service::{IntoServiceFactory, Service, ServiceCall, ServiceCtx, ServiceFactory};
+use ntex::util::{BoxFuture, ByteString, Bytes, Either, Ready};
+
+use crate::{auth::Authorizer, error::AuthError};
+
+use super::control::{ControlMessage, ControlResult};
+use super::publish::{Publish, PublishAck};
+use super::{codec, Session};
+
+/// Client side of enhanced authentication exchange
+///
+/// Authenticator is used for handshake authentication and for
+/// re-authentication started with `MqttSink::reauthenticate()`.
+pub trait Authenticator {
+    /// Authentication method
+    fn method(&self) -> ByteString;
+
+    /// Start authentication.
+    ///
+    /// Returns authentication data for CONNECT packet or for
+    /// re-authentication AUTH packet.
+    fn start(&mut self) -> Result<Option<Bytes>, AuthError>;
+
+    /// Handle server challenge, returns response authentication data
+    fn challenge(&mut self, data: Option<&Bytes>) -> Result<Option<Bytes>, AuthError>;
+
+    /// Authentication is completed by server.
+    ///
+    /// `data` is authentication data of server's CONNACK or AUTH packet.
+    fn complete(&mut self, data: Option<&Bytes>) -> Result<(), AuthError> {
+        let _ = data;
+        Ok(())
+    }
+}
+
+/// Authorization middleware for publish and control services
+///
+/// Publish to not authorized topic is acked with `NotAuthorized` reason code,
+/// control service does not receive subscriptions to not authorized topic
+/// filters, such subscriptions are failed with `NotAuthorized` reason code.
+///
+/// ```rust,ignore
+/// let auth = Authorization::new(acl);
+///
+/// MqttServer::new(handshake)
+///     .publish(auth.publish(publish))
+///     .control(auth.control(control))
+/// ```
+pub struct Authorization<A> {
+    auth: Rc<A>,
+}
+
+impl<A> Authorization<A> {
+    /// Create authorization middleware
+    pub fn new(authorizer: A) -> Self {
+        Self { auth: Rc::new(authorizer) }
+    }
+
+    /// Apply authorization to publish service
+    pub fn publish<St, F, S>(&self, service: F) -> PublishAuthFactory<St, A, S>
+    where
+        A: Authorizer<St>,
+        F: IntoServiceFactory<S, Publish, Session<St>>,
+        S: ServiceFactory<Publish, Session<St>, Response = PublishAck>,
+    {
+        PublishAuthFactory {
+            auth: self.auth.clone(),
+            service: service.into_factory(),
+            _t: PhantomData,
+        }
+    }
+
+    /// Apply authorization to control service
+    pub fn control<St, E, F, S>(&self, service: F) -> ControlAuthFactory<St, A, S>
+    where
+        A: Authorizer<St>,
+        F: IntoServiceFactory<S, ControlMessage<E>, Session<St>>,
+        S: ServiceFactory<ControlMessage<E>, Session<St>, Response = ControlResult>,
+    {
+        ControlAuthFactory {
+            auth: self.auth.clone(),
+            service: service.into_factory(),
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<A> Clone for Authorization<A> {
+    fn clone(&self) -> Self {
+        Self { auth: self.auth.clone() }
+    }
+}
+
+/// Publish service factory with topic authorization
+pub struct PublishAuthFactory<St, A, S> {
+    auth: Rc<A>,
+    service: S,
+    _t: PhantomData<St>,
+}
+
+impl<St, A, S> ServiceFactory<Publish, Session<St>> for PublishAuthFactory<St, A, S>
+where
+    St: 'static,
+    A: Authorizer<St>,
+    S: ServiceFactory<Publish, Session<St>, Response = PublishAck>,
+{
+    type Response = PublishAck;
+    type Error = S::Error;
+    type InitError = S::InitError;
+    type Service = PublishAuthService<St, A, S::Service>;
+    type Future<'f> = BoxFuture<'f, Result<Self::Service, Self::InitError>> where Self: 'f;
+
+    fn create(&self, session: Session<St>) -> Self::Future<'_> {
+        let context = self.auth.context(session.state());
+
+        Box::pin(async move {
+            let service = self.service.create(session).await?;
+            Ok(PublishAuthService { auth: self.auth.clone(), context, service })
+        })
+    }
+}
+
+/// Publish service, acks publishes to not authorized topics with `NotAuthorized`
+pub struct PublishAuthService<St, A: Authorizer<St>, S> {
+    auth: Rc<A>,
+    context: A::Context,
+    service: S,
+}
+
+impl<St, A, S> Service<Publish> for PublishAuthService<St, A, S>
+where
+    A: Authorizer<St>,
+    S: Service<Publish, Response = PublishAck>,
+{
+    type Response = PublishAck;
+    type Error = S::Error;
+    type Future<'f> = Either<Ready<PublishAck, S::Error>, ServiceCall<'f, S, Publish>> where Self: 'f;
+
+    ntex::forward_poll_ready!(service);
+    ntex::forward_poll_shutdown!(service);
+
+    fn call<'a>(&'a self, req: Publish, ctx: ServiceCtx<'a, Self>) -> Self::Future<'a> {
+        if self.auth.publish(&self.context, req.publish_topic(), req.qos()) {
+            Either::Right(ctx.call(&self.service, req))
+        } else {
+            log::trace!("Publish to {:?} is not authorized", req.publish_topic());
+            Either::Left(Ready::Ok(PublishAck::new(codec::PublishAckReason::NotAuthorized)))
+        }
+    }
+}
+
+/// Control service factory with subscription authorization
+pub struct ControlAuthFactory<St, A, S> {
+    auth: Rc<A>,
+    service: S,
+    _t: PhantomData<St>,
+}
+
+impl<St, E, A, S> ServiceFactory<ControlMessage<E>, Session<St>>
+    for ControlAuthFactory<St, A, S>
+where
+    St: 'static,
+    A: Authorizer<St>,
+    S: ServiceFactory<ControlMessage<E>, Session<St>, Response = ControlResult>,
+{
+    type Response = ControlResult;
+    type Error = S::Error;
+    type InitError = S::InitError;
+    type Service = ControlAuthService<St, A, S::Service>;
+    type Future<'f> = BoxFuture<'f, Result<Self::Service, Self::InitError>> where Self: 'f;
+
+    fn create(&self, session: Session<St>) -> Self::Future<'_> {
+        let context = self.auth.context(session.state());
+
+        Box::pin(async move {
+            let service = self.service.create(session).await?;
+            Ok(ControlAuthService { auth: self.auth.clone(), context, service })
+        })
+    }
+}
+
+/// Control service, fails subscriptions to not authorized topic filters
+pub struct ControlAuthService<St, A: Authorizer<St>, S> {
+    auth: Rc<A>,
+    context: A::Context,
+    service: S,
+}
+
+impl<St, E, A, S> Service<ControlMessage<E>> for ControlAuthService<St, A, S>
+where
+    A: Authorizer<St>,
+    S: Service<ControlMessage<E>, Response = ControlResult>,
+{
+    type Response = ControlResult;
+    type Error = S::Error;
+    type Future<'f> = Either<Ready<ControlResult, S::Error>, ServiceCall<'f, S, ControlMessage<E>>>
+    where
+        Self: 'f,
+        E: 'f;
+
+    ntex::forward_poll_ready!(service);
+    ntex::forward_poll_shutdown!(service);
+
+    fn call<'a>(
+        &'a self,
+        req: ControlMessage<E>,
+        ctx: ServiceCtx<'a, Self>,
+    ) -> Self::Future<'a> {
+        match req {
+            ControlMessage::Subscribe(mut subs) => {
+                let mut authorized = false;
+                for mut sub in subs.iter_mut() {
+                    if self.auth.subscribe(&self.context, sub.topic(), sub.options().qos) {
+                        authorized = true;
+                    } else {
+                        log::trace!("Subscription to {:?} is not authorized", sub.topic());
+                        sub.fail(codec::SubscribeAckReason::NotAuthorized);
+                        sub.hide();
+                    }
+                }
+
+                if authorized {
+                    Either::Right(ctx.call(&self.service, ControlMessage::Subscribe(subs)))
+                } else {
+                    Either::Left(Ready::Ok(subs.ack()))
+                }
+            }
+            req => Either::Right(ctx.call(&self.service, req)),
+        }
+    }
+}
@@ -11,8 +11,8 @@ use crate::io::Dispatcher;
 use crate::v5::publish::{Publish, PublishAck};
 use crate::v5::{codec, shared::MqttShared, sink::MqttSink, ControlResult};
 
-use super::control::ControlMessage;
 use super::dispatcher::create_dispatcher;
+use super::{control::ControlMessage, request::Requester};
 
 /// Mqtt client
 pub struct Client {
@@ -74,6 +74,22 @@ impl Client {
         &mut self.pkt
     }
 
+    /// Create request/response helper
+    ///
+    /// Response topic is derived from response information of `ConnectAck` packet,
+    /// returns `None` if server does not provide response information.
+    /// See `MqttConnector::request_response_info()`.
+    pub fn requester(&self) -> Option<Requester> {
+        self.pkt.response_info.as_ref().map(|info| {
+            let topic = if info.is_empty() || info.ends_with('/') {
+                format!("{}response", info)
+            } else {
+                format!("{}/response", info)
+            };
+            Requester::new(self.sink(), ByteString::from(topic))
+        })
+    }
+
     /// Configure mqtt resource for a specific topic
     pub fn resource<T, F, U, E>(self, address: T, service: F) -> ClientRouter<E, U::Error>
     where
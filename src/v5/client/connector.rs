@@ -1,4 +1,4 @@
-use std::{num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::{cell::RefCell, num::NonZeroU16, num::NonZeroU32, rc::Rc, time::Instant};
 
 use ntex::connect::{self, Address, Connect, Connector};
 use ntex::io::{DispatcherConfig, IoBoxed};
@@ -6,17 +6,21 @@ use ntex::service::{IntoService, Pipeline, Service};
 use ntex::time::{timeout_checked, Seconds};
 use ntex::util::{ByteString, Bytes, PoolId};
 
-use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
-use crate::v5::shared::{MqttShared, MqttSinkPool};
+use super::{codec, connection::Client};
+use super::{error::ClientError, error::ProtocolError};
+use crate::v5::{shared::MqttShared, shared::MqttSinkPool, Authenticator};
+use crate::{metrics::Metrics, trace};
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
     address: A,
     connector: Pipeline<T>,
     pkt: codec::Connect,
+    auth: Option<Rc<RefCell<dyn Authenticator>>>,
     handshake_timeout: Seconds,
     config: DispatcherConfig,
     pool: Rc<MqttSinkPool>,
+    metrics: Option<Rc<dyn Metrics>>,
 }
 
 impl<A> MqttConnector<A, ()>
@@ -32,9 +36,11 @@ where
             address,
             config,
             pkt: codec::Connect::default(),
+            auth: None,
             connector: Pipeline::new(Connector::default()),
             handshake_timeout: Seconds::ZERO,
             pool: Rc::new(MqttSinkPool::default()),
+            metrics: None,
         }
     }
 }
@@ -86,6 +92,19 @@ where
         self
     }
 
+    /// Set authenticator for enhanced authentication.
+    ///
+    /// Authenticator answers server's AUTH challenges during handshake
+    /// and is used for re-authentication with `MqttSink::reauthenticate()`.
+    /// Authentication method and data of connect packet are set by authenticator.
+    pub fn authenticator<F>(mut self, auth: F) -> Self
+    where
+        F: Authenticator + 'static,
+    {
+        self.auth = Some(Rc::new(RefCell::new(auth)));
+        self
+    }
+
     #[inline]
     /// Username can be used by the Server for authentication and authorization.
     pub fn username(mut self, val: ByteString) -> Self {
@@ -127,6 +146,16 @@ where
         self
     }
 
+    #[inline]
+    /// Request response information from the server
+    ///
+    /// Response information is used as a base of response topic
+    /// for `Client::requester()`.
+    pub fn request_response_info(mut self) -> Self {
+        self.pkt.request_response_info = true;
+        self
+    }
+
     #[inline]
     /// Update connect user properties
     pub fn properties<F>(mut self, f: F) -> Self
@@ -178,6 +207,15 @@ where
         self
     }
 
+    /// Set metrics observer.
+    ///
+    /// Observer is called for connection and packet events, see
+    /// `metrics::Metrics` for details.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(Rc::new(metrics));
+        self
+    }
+
     /// Use custom connector
     pub fn connector<U, F>(self, connector: F) -> MqttConnector<A, U>
     where
@@ -188,10 +226,12 @@ where
         MqttConnector {
             connector: Pipeline::new(connector.into_service()),
             pkt: self.pkt,
+            auth: self.auth,
             address: self.address,
             config: self.config,
             handshake_timeout: self.handshake_timeout,
             pool: self.pool,
+            metrics: self.metrics,
         }
     }
 }
@@ -212,7 +252,12 @@ where
 
     async fn _connect(&self) -> Result<Client, ClientError<Box<codec::ConnectAck>>> {
         let io: IoBoxed = self.connector.call(Connect::new(self.address.clone())).await?.into();
-        let pkt = self.pkt.clone();
+        let mut pkt = self.pkt.clone();
+        if let Some(ref auth) = self.auth {
+            let mut auth = auth.borrow_mut();
+            pkt.auth_method = Some(auth.method());
+            pkt.auth_data = auth.start()?;
+        }
         let keep_alive = pkt.keep_alive;
         let max_packet_size = pkt.max_packet_size.map(|v| v.get()).unwrap_or(0);
         let max_receive = pkt.receive_max.map(|v| v.get()).unwrap_or(65535);
@@ -220,27 +265,86 @@ where
         codec.set_max_inbound_size(max_packet_size);
         let pool = self.pool.clone();
         let config = self.config.clone();
+        codec.metrics().set(self.metrics.clone());
 
-        io.encode(codec::Packet::Connect(Box::new(pkt)), &codec)?;
+        let shared = Rc::new(MqttShared::new(io.get_ref(), codec, pool));
+        trace::record_client_id(shared.span(), &pkt.client_id);
 
-        let packet = io.recv(&codec).await.map_err(ClientError::from)?.ok_or_else(|| {
-            log::trace!("Mqtt server is disconnected during handshake");
-            ClientError::Disconnected(None)
-        })?;
+        let started = Instant::now();
+        let handshake = async {
+            io.encode(codec::Packet::Connect(Box::new(pkt)), &shared.codec)?;
+
+            loop {
+                let packet =
+                    io.recv(&shared.codec).await.map_err(ClientError::from)?.ok_or_else(
+                        || {
+                            log::trace!("Mqtt server is disconnected during handshake");
+                            ClientError::Disconnected(None)
+                        },
+                    )?;
+
+                // enhanced authentication exchange
+                if let (codec::Packet::Auth(ref pkt), Some(ref auth)) = (&packet.0, &self.auth)
+                {
+                    log::trace!("Auth challenge from server: {:?}", pkt);
+                    let method = auth.borrow().method();
+                    if pkt.reason_code != codec::AuthReasonCode::ContinueAuth
+                        || pkt.auth_method.as_ref() != Some(&method)
+                    {
+                        return Err(ProtocolError::generic_violation(
+                            "Unexpected AUTH packet during enhanced authentication",
+                        )
+                        .into());
+                    }
+                    let res = auth.borrow_mut().challenge(pkt.auth_data.as_ref());
+                    let auth_data = match res {
+                        Ok(data) => data,
+                        Err(e) => {
+                            log::trace!("Server challenge is rejected by authenticator: {}", e);
+                            auth_failed(&io, &shared.codec).await;
+                            return Err(e.into());
+                        }
+                    };
+                    io.encode(
+                        codec::Packet::Auth(codec::Auth {
+                            auth_data,
+                            reason_code: codec::AuthReasonCode::ContinueAuth,
+                            auth_method: Some(method),
+                            reason_string: None,
+                            user_properties: Vec::new(),
+                        }),
+                        &shared.codec,
+                    )?;
+                    continue;
+                }
+                break Ok::<_, ClientError<Box<codec::ConnectAck>>>(packet);
+            }
+        };
+        let packet = trace::instrument(handshake, trace::handshake(shared.span())).await?;
 
-        let shared = Rc::new(MqttShared::new(io.get_ref(), codec, pool));
         match packet {
             (codec::Packet::ConnectAck(pkt), _) => {
                 log::trace!("Connect ack response from server: {:#?}", pkt);
                 if pkt.reason_code == codec::ConnectAckReason::Success {
+                    if let Some(ref auth) = self.auth {
+                        let res = auth.borrow_mut().complete(pkt.auth_data.as_ref());
+                        if let Err(e) = res {
+                            log::trace!("Server is not verified by authenticator: {}", e);
+                            auth_failed(&io, &shared.codec).await;
+                            return Err(ClientError::Auth(e));
+                        }
+                        shared.set_authenticator(auth.clone());
+                    }
                     // set max outbound (encoder) packet size
                     if let Some(size) = pkt.max_packet_size {
                         shared.codec.set_max_outbound_size(size);
                     }
+                    shared.set_peer_topic_alias_max(pkt.topic_alias_max);
                     // server keep-alive
                     let keep_alive = pkt.server_keepalive_sec.unwrap_or(keep_alive);
 
                     shared.set_cap(pkt.receive_max.get() as usize);
+                    shared.codec.metrics().with(|m, p| m.handshake(p, started.elapsed()));
 
                     Ok(Client::new(io, shared, pkt, max_receive, Seconds(keep_alive), config))
                 } else {
@@ -255,3 +359,14 @@ where
         }
     }
 }
+
+/// Send DISCONNECT and wait until connection is closed
+async fn auth_failed(io: &IoBoxed, codec: &codec::Codec) {
+    let _ = io.encode(
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::UnspecifiedError,
+        )),
+        codec,
+    );
+    let _ = io.shutdown().await;
+}
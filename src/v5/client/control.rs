@@ -1,8 +1,8 @@
 use std::io;
 
-use ntex::util::ByteString;
+use ntex::{time::Millis, util::ByteString};
 
-use crate::{error, v5::codec};
+use crate::{error, error::ClientError, v5::codec};
 
 pub use crate::v5::control::{Closed, ControlResult, Disconnect, Error, ProtocolError};
 
@@ -20,6 +20,10 @@ pub enum ControlMessage<E> {
     Closed(Closed),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Connection is established by reconnecting client
+    Connected(Connected),
+    /// Connect attempt of reconnecting client failed, next attempt is scheduled
+    Reconnecting(Reconnecting),
 }
 
 impl<E> ControlMessage<E> {
@@ -47,6 +51,18 @@ impl<E> ControlMessage<E> {
         ControlMessage::PeerGone(PeerGone(err))
     }
 
+    pub(super) fn connected(pkt: Box<codec::ConnectAck>) -> Self {
+        ControlMessage::Connected(Connected(pkt))
+    }
+
+    pub(super) fn reconnecting(
+        attempt: u32,
+        delay: Millis,
+        err: ClientError<Box<codec::ConnectAck>>,
+    ) -> Self {
+        ControlMessage::Reconnecting(Reconnecting { attempt, delay, err })
+    }
+
     pub fn disconnect(&self, pkt: codec::Disconnect) -> ControlResult {
         ControlResult { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
     }
@@ -143,3 +159,52 @@ impl PeerGone {
         ControlResult { packet: None, disconnect: true }
     }
 }
+
+#[derive(Debug)]
+pub struct Connected(Box<codec::ConnectAck>);
+
+impl Connected {
+    /// Returns reference to `ConnectAck` packet
+    pub fn packet(&self) -> &codec::ConnectAck {
+        &self.0
+    }
+
+    /// Indicates whether there is already stored Session state
+    pub fn session_present(&self) -> bool {
+        self.0.session_present
+    }
+
+    /// Ack Connected message
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
+#[derive(Debug)]
+pub struct Reconnecting {
+    attempt: u32,
+    delay: Millis,
+    err: ClientError<Box<codec::ConnectAck>>,
+}
+
+impl Reconnecting {
+    /// Number of connect attempts since last stable connection
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before next connect attempt
+    pub fn delay(&self) -> Millis {
+        self.delay
+    }
+
+    /// Returns connect error
+    pub fn error(&self) -> &ClientError<Box<codec::ConnectAck>> {
+        &self.err
+    }
+
+    /// Ack Reconnecting message
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
@@ -7,7 +7,7 @@ use ntex::service::{Pipeline, Service, ServiceCall, ServiceCtx};
 use ntex::util::{BoxFuture, ByteString, Either, HashMap, HashSet, Ready};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::types::packet_type;
+use crate::trace;
 use crate::v5::codec::DisconnectReasonCode;
 use crate::v5::shared::{Ack, MqttShared};
 use crate::v5::{codec, publish::Publish, publish::PublishAck, sink::MqttSink};
@@ -54,6 +54,8 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<NonZeroU16>,
+    // QoS 2 responses to requests, PUBREL is not received yet
+    unreleased: HashSet<NonZeroU16>,
     aliases: HashMap<NonZeroU16, ByteString>,
 }
 
@@ -69,6 +71,8 @@ where
         publish: T,
         control: C,
     ) -> Self {
+        let sink = sink.shared();
+        sink.codec.metrics().with(|m, p| m.connection_opened(p));
         Self {
             publish,
             max_receive,
@@ -76,10 +80,11 @@ where
             shutdown: RefCell::new(None),
             inner: Rc::new(Inner {
                 control,
-                sink: sink.shared(),
+                sink,
                 info: RefCell::new(PublishInfo {
                     aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    unreleased: HashSet::default(),
                 }),
             }),
             _t: PhantomData,
@@ -94,9 +99,11 @@ where
 {
     type Response = Option<codec::Packet>;
     type Error = MqttError<E>;
-    type Future<'f> = Either<
-        PublishResponse<'f, T, C, E>,
-        Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, T, C, E>>,
+    type Future<'f> = trace::Instrumented<
+        Either<
+            PublishResponse<'f, T, C, E>,
+            Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, T, C, E>>,
+        >,
     > where Self: 'f;
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -113,6 +120,7 @@ where
     fn poll_shutdown(&self, cx: &mut Context<'_>) -> Poll<()> {
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
+            self.inner.sink.codec.metrics().with(|m, p| m.connection_closed(p));
             self.inner.sink.drop_sink();
             let inner = self.inner.clone();
             *shutdown = Some(Box::pin(async move {
@@ -135,8 +143,35 @@ where
         request: DispatchItem<Rc<MqttShared>>,
         ctx: ServiceCtx<'a, Self>,
     ) -> Self::Future<'a> {
-        log::trace!("Dispatch packet: {:#?}", request);
+        trace!("Dispatch packet: {:#?}", request);
 
+        let parent = self.inner.sink.span();
+        let span = match request {
+            DispatchItem::Item((codec::Packet::Publish(ref pkt), _)) => {
+                trace::publish(parent, &pkt.topic, pkt.qos, pkt.packet_id)
+            }
+            DispatchItem::Item((ref pkt, _)) => {
+                trace::control(parent, trace::packet_kind(pkt.packet_type()))
+            }
+            ref item => trace::control(parent, trace::event_kind(item)),
+        };
+        trace::instrument(self.dispatch(request, ctx), span)
+    }
+}
+
+impl<T, C, E> Dispatcher<T, C, E>
+where
+    T: Service<Publish, Response = Either<Publish, PublishAck>, Error = E>,
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>> + 'static,
+{
+    fn dispatch<'a>(
+        &'a self,
+        request: DispatchItem<Rc<MqttShared>>,
+        ctx: ServiceCtx<'a, Self>,
+    ) -> Either<
+        PublishResponse<'a, T, C, E>,
+        Either<Ready<Option<codec::Packet>, MqttError<E>>, ControlResponse<'a, T, C, E>>,
+    > {
         match request {
             DispatchItem::Item((codec::Packet::Publish(mut publish), size)) => {
                 let info = self.inner.as_ref();
@@ -146,12 +181,25 @@ where
                     let mut inner = info.info.borrow_mut();
 
                     if let Some(pid) = packet_id {
-                        // check for receive maximum
-                        if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
-                            log::trace!(
+                        // PUBREL is not received yet, response must not be delivered twice
+                        if publish.qos == codec::QoS::ExactlyOnce
+                            && inner.unreleased.contains(&pid)
+                        {
+                            let ack =
+                                codec::PublishAck { packet_id: pid, ..Default::default() };
+                            return Either::Right(Either::Left(Ready::Ok(Some(
+                                codec::Packet::PublishReceived(ack),
+                            ))));
+                        }
+
+                        // check for receive maximum, QoS 2 responses are counted
+                        // until PUBCOMP is sent
+                        let in_flight = inner.inflight.len() + inner.unreleased.len();
+                        if self.max_receive != 0 && in_flight >= self.max_receive {
+                            trace!(
                                 "Receive maximum exceeded: max: {} inflight: {}",
                                 self.max_receive,
-                                inner.inflight.len()
+                                in_flight
                             );
                             return Either::Right(Either::Right(ControlResponse::new(
                                 ControlMessage::proto_error(
@@ -228,6 +276,26 @@ where
                     }
                 }
 
+                // response to pending request is not passed to publish service
+                let qos = publish.qos;
+                let publish = match self.inner.sink.request_response(publish) {
+                    Some(publish) => publish,
+                    None => {
+                        let ack = packet_id.map(|packet_id| {
+                            let mut inner = info.info.borrow_mut();
+                            inner.inflight.remove(&packet_id);
+                            let ack = codec::PublishAck { packet_id, ..Default::default() };
+                            if qos == codec::QoS::ExactlyOnce {
+                                inner.unreleased.insert(packet_id);
+                                codec::Packet::PublishReceived(ack)
+                            } else {
+                                codec::Packet::PublishAck(ack)
+                            }
+                        });
+                        return Either::Right(Either::Left(Ready::Ok(ack)));
+                    }
+                };
+
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
                     packet_size: size,
@@ -250,6 +318,28 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item((codec::Packet::PublishReceived(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                        ctx,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishComplete(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                        ctx,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
             DispatchItem::Item((codec::Packet::SubscribeAck(packet), _)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Subscribe(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
@@ -272,6 +362,22 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item((codec::Packet::PublishRelease(pkt), _)) => {
+                let reason_code =
+                    if self.inner.info.borrow_mut().unreleased.remove(&pkt.packet_id) {
+                        codec::PublishAck2Reason::Success
+                    } else {
+                        codec::PublishAck2Reason::PacketIdNotFound
+                    };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        packet_id: pkt.packet_id,
+                        reason_code,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item((codec::Packet::Disconnect(pkt), size)) => {
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::dis(pkt, size),
@@ -279,15 +385,15 @@ where
                     ctx,
                 )))
             }
-            DispatchItem::Item((codec::Packet::Auth(_), _)) => {
-                Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::unexpected_packet(
-                        packet_type::AUTH,
-                        "AUTH packet is not supported at this time",
-                    )),
-                    &self.inner,
-                    ctx,
-                )))
+            DispatchItem::Item((codec::Packet::Auth(pkt), _)) => {
+                match self.inner.sink.auth_packet(pkt) {
+                    Ok(res) => Either::Right(Either::Left(Ready::Ok(res))),
+                    Err(err) => Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                        ctx,
+                    ))),
+                }
             }
             DispatchItem::Item((
                 pkt @ (codec::Packet::PingRequest
@@ -415,7 +521,7 @@ where
                     Poll::Pending => return Poll::Pending,
                 };
                 if let Some(id) = NonZeroU16::new(*this.packet_id) {
-                    log::trace!("Sending publish ack for {} id", this.packet_id);
+                    trace!("Sending publish ack for {} id", this.packet_id);
                     this.inner.info.borrow_mut().inflight.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
@@ -458,6 +564,9 @@ where
         ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
     ) -> Self {
         let error = matches!(pkt, ControlMessage::Error(_) | ControlMessage::ProtocolError(_));
+        if let ControlMessage::ProtocolError(_) = pkt {
+            inner.sink.codec.metrics().with(|m, p| m.protocol_error(p));
+        }
         let fut = ctx.call(&inner.control, pkt);
 
         Self { error, inner, fut, ctx, packet_id: 0, _t: PhantomData }
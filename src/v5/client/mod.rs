@@ -4,11 +4,16 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod reconnect;
+mod request;
 
 pub use self::connection::{Client, ClientRouter};
 pub use self::connector::MqttConnector;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::reconnect::{ReconnectClient, ReconnectSink};
+pub use self::request::{RequestBuilder, Requester};
 
+pub use crate::reconnect::Backoff;
 pub use crate::topic::{TopicFilter, TopicFilterError};
 pub use crate::types::QoS;
-pub use crate::v5::{codec, error, sink::MqttSink};
+pub use crate::v5::{codec, error, sink::MqttSink, Authenticator};
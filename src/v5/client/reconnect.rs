@@ -0,0 +1,232 @@
+use std::{fmt, future::Future, rc::Rc, time::Instant};
+
+use ntex::connect::{self, Address, Connect};
+use ntex::io::IoBoxed;
+use ntex::service::{IntoService, Pipeline, Service};
+use ntex::time::sleep;
+use ntex::util::{select, ByteString, Either};
+
+use crate::error::MqttError;
+use crate::reconnect::{Backoff, Connection, SharedService};
+use crate::v5::{codec, sink::InflightMessages, sink::MqttSink};
+
+use super::{connector::MqttConnector, control::ControlMessage, control::ControlResult};
+
+/// Mqtt client that re-establishes connection to the server
+///
+/// Client reconnects with exponential backoff, re-sends configured
+/// subscriptions and re-delivers unacknowledged messages if server
+/// keeps session state.
+pub struct ReconnectClient<A, T> {
+    connector: MqttConnector<A, T>,
+    backoff: Backoff,
+    subscriptions: Vec<(ByteString, codec::SubscriptionOptions)>,
+    conn: Rc<Connection<MqttSink>>,
+}
+
+impl<A, T> fmt::Debug for ReconnectClient<A, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v5::ReconnectClient")
+            .field("backoff", &self.backoff)
+            .field("subscriptions", &self.subscriptions)
+            .finish()
+    }
+}
+
+impl<A, T> ReconnectClient<A, T>
+where
+    A: Address + Clone,
+    T: Service<Connect<A>, Error = connect::ConnectError>,
+    IoBoxed: From<T::Response>,
+{
+    /// Create reconnecting client
+    pub fn new(connector: MqttConnector<A, T>) -> Self {
+        ReconnectClient {
+            connector,
+            backoff: Backoff::default(),
+            subscriptions: Vec::new(),
+            conn: Rc::new(Connection::new()),
+        }
+    }
+
+    /// Set reconnect backoff policy
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Subscribe to a topic filter.
+    ///
+    /// Subscriptions are sent after each connect, unless server reports
+    /// existing session state.
+    pub fn subscribe(mut self, filter: ByteString, opts: codec::SubscriptionOptions) -> Self {
+        self.subscriptions.push((filter, opts));
+        self
+    }
+
+    /// Get client sink
+    pub fn sink(&self) -> ReconnectSink {
+        ReconnectSink(self.conn.clone())
+    }
+
+    /// Run client and handle control messages
+    ///
+    /// Client runs until `ReconnectSink::close()` get called or
+    /// control service returns an error. Control service receives
+    /// `ControlMessage::Connected` on each connect and
+    /// `ControlMessage::Reconnecting` on each failed connect attempt.
+    /// Client reconnects after backoff delay, attempts counter is reset
+    /// once connection stays up for backoff's stable period.
+    pub async fn start<F, S, E>(self, service: F) -> Result<(), MqttError<E>>
+    where
+        E: 'static,
+        F: IntoService<S, ControlMessage<E>> + 'static,
+        S: Service<ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
+    {
+        let service = SharedService::new(service.into_service());
+        let control = Pipeline::new(service.clone());
+        let mut inflight = InflightMessages::default();
+        let mut attempt = 0;
+
+        while !self.conn.is_stopped() {
+            let client = match self.connector.connect().await {
+                Ok(client) => client,
+                Err(err) => {
+                    attempt += 1;
+                    let delay = self.backoff.delay(attempt);
+                    log::debug!(
+                        "Connect attempt {} failed: {:?}, retry in {:?}",
+                        attempt,
+                        err,
+                        delay
+                    );
+
+                    control
+                        .call(ControlMessage::reconnecting(attempt, delay, err))
+                        .await
+                        .map_err(MqttError::Service)?;
+                    if !self.conn.is_stopped() {
+                        let _ = select(sleep(delay), self.conn.changed()).await;
+                    }
+                    continue;
+                }
+            };
+            let sink = client.sink();
+            let session_present = client.session_present();
+            sink.track_inflight();
+
+            control
+                .call(ControlMessage::connected(Box::new(client.packet().clone())))
+                .await
+                .map_err(MqttError::Service)?;
+
+            if session_present {
+                if let Err(e) = sink.replay_inflight(std::mem::take(&mut inflight)) {
+                    log::error!("Cannot re-deliver in-flight messages: {:?}", e);
+                }
+            } else {
+                if !inflight.is_empty() {
+                    log::warn!("Session is not present, drop in-flight messages");
+                }
+                if !self.subscriptions.is_empty() {
+                    ntex::rt::spawn(subscribe(sink.clone(), self.subscriptions.clone()));
+                }
+            }
+
+            if self.conn.is_stopped() {
+                sink.close();
+            }
+            self.conn.set(sink.clone());
+            let connected = Instant::now();
+            let res = client.start(service.clone()).await;
+            self.conn.take();
+            inflight = sink.take_inflight();
+
+            match res {
+                Err(MqttError::Service(err)) => return Err(MqttError::Service(err)),
+                Err(MqttError::Handshake(err)) => log::debug!("Connection failed: {}", err),
+                Ok(()) => log::debug!("Connection is closed"),
+            }
+
+            if self.backoff.is_stable(connected.elapsed()) {
+                attempt = 0;
+            }
+            attempt += 1;
+            if !self.conn.is_stopped() {
+                let delay = self.backoff.delay(attempt);
+                log::debug!("Reconnect in {:?}", delay);
+                let _ = select(sleep(delay), self.conn.changed()).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn subscribe(sink: MqttSink, filters: Vec<(ByteString, codec::SubscriptionOptions)>) {
+    let mut builder = sink.subscribe(None);
+    for (filter, opts) in filters {
+        builder = builder.topic_filter(filter, opts);
+    }
+    match builder.send().await {
+        Ok(ack) => log::trace!("Subscriptions are restored: {:?}", ack),
+        Err(e) => log::error!("Cannot restore subscriptions: {:?}", e),
+    }
+}
+
+#[derive(Clone)]
+/// Sink of reconnecting client
+///
+/// Sink stays valid across reconnects.
+pub struct ReconnectSink(Rc<Connection<MqttSink>>);
+
+impl fmt::Debug for ReconnectSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v5::ReconnectSink").field("connected", &self.is_connected()).finish()
+    }
+}
+
+impl ReconnectSink {
+    #[inline]
+    /// Check if client is connected to the server
+    pub fn is_connected(&self) -> bool {
+        self.0.get().map(|sink| sink.is_open()).unwrap_or(false)
+    }
+
+    #[inline]
+    /// Get sink of current connection
+    pub fn sink(&self) -> Option<MqttSink> {
+        self.0.get().filter(|sink| sink.is_open())
+    }
+
+    /// Get notification when packet could be send to the peer.
+    ///
+    /// Waits for connection if client is disconnected. Result is `false`
+    /// if client is closed.
+    pub fn ready(&self) -> impl Future<Output = bool> {
+        let conn = self.0.clone();
+        async move {
+            loop {
+                if conn.is_stopped() {
+                    return false;
+                }
+                if let Some(sink) = conn.get() {
+                    let ready = sink.ready();
+                    let changed = conn.changed();
+                    match select(ready, changed).await {
+                        Either::Left(true) => return true,
+                        Either::Left(false) => (),
+                        Either::Right(_) => continue,
+                    }
+                }
+                conn.changed().await;
+            }
+        }
+    }
+
+    /// Close client. Client does not reconnect after this call.
+    pub fn close(&self) {
+        if let Some(sink) = self.0.stop() {
+            sink.close();
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{cell::Cell, fmt, rc::Rc};
+
+use ntex::time::{timeout_checked, Millis};
+use ntex::util::{ByteString, Bytes};
+
+use crate::error::{RequestError, SendPacketError};
+use crate::v5::{codec, shared::MqttShared, sink::MqttSink, sink::PublishBuilder};
+
+/// Request/response helper
+///
+/// Request is published with `response_topic` and generated `correlation_data`
+/// properties, responder is expected to publish response to the response topic
+/// with the same correlation data. Requester subscribes to the response topic
+/// before first request.
+///
+/// Responses are handled by client's dispatcher and are not passed to publish
+/// handlers, so client must be started.
+#[derive(Clone)]
+pub struct Requester {
+    sink: MqttSink,
+    topic: ByteString,
+    timeout: Millis,
+    subscribed: Rc<Cell<bool>>,
+}
+
+impl fmt::Debug for Requester {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v5::Requester")
+            .field("topic", &self.topic)
+            .field("timeout", &self.timeout)
+            .field("subscribed", &self.subscribed.get())
+            .finish()
+    }
+}
+
+impl Requester {
+    /// Create requester for specified response topic
+    pub fn new(sink: MqttSink, topic: ByteString) -> Self {
+        Requester {
+            sink,
+            topic,
+            timeout: Millis(30_000),
+            subscribed: Rc::new(Cell::new(false)),
+        }
+    }
+
+    #[inline]
+    /// Response topic
+    pub fn topic(&self) -> &ByteString {
+        &self.topic
+    }
+
+    /// Set response timeout.
+    ///
+    /// Timeout includes subscription to response topic and request publish
+    /// acknowledgement. By default timeout is set to 30 seconds, zero disables timeout.
+    pub fn timeout<T: Into<Millis>>(mut self, timeout: T) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    /// Subscribe to response topic.
+    ///
+    /// Requester subscribes automatically before first request.
+    pub async fn subscribe(&self) -> Result<(), RequestError> {
+        let opts =
+            codec::SubscriptionOptions { qos: codec::QoS::AtLeastOnce, ..Default::default() };
+        let ack =
+            self.sink.subscribe(None).topic_filter(self.topic.clone(), opts).send().await?;
+
+        match ack.status.first() {
+            Some(reason) if u8::from(*reason) < 0x80 => {
+                self.subscribed.set(true);
+                Ok(())
+            }
+            Some(reason) => Err(RequestError::Subscribe(*reason)),
+            None => Err(RequestError::Subscribe(codec::SubscribeAckReason::UnspecifiedError)),
+        }
+    }
+
+    /// Create request builder
+    pub fn request<U>(&self, topic: U, payload: Bytes) -> RequestBuilder
+    where
+        ByteString: From<U>,
+    {
+        RequestBuilder {
+            publish: self.sink.publish(topic, payload),
+            timeout: self.timeout,
+            requester: self.clone(),
+        }
+    }
+}
+
+/// Request builder
+pub struct RequestBuilder {
+    requester: Requester,
+    publish: PublishBuilder,
+    timeout: Millis,
+}
+
+impl RequestBuilder {
+    #[inline]
+    /// Set publish packet properties
+    ///
+    /// `response_topic` and `correlation_data` properties are set by requester.
+    pub fn properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::PublishProperties),
+    {
+        self.publish = self.publish.properties(f);
+        self
+    }
+
+    #[inline]
+    /// Set response timeout for this request
+    pub fn timeout<T: Into<Millis>>(mut self, timeout: T) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    /// Send request with QoS 1 and wait for response
+    pub async fn send(self) -> Result<codec::Publish, RequestError> {
+        let requester = self.requester;
+        let publish = self.publish;
+
+        timeout_checked(self.timeout, async move {
+            if !requester.subscribed.get() {
+                requester.subscribe().await?;
+            }
+
+            let shared = requester.sink.shared();
+            let (data, rx) = shared.wait_request_response(requester.topic.clone());
+            let _guard = PendingRequest(shared, data.clone());
+
+            let ack = publish
+                .properties(|props| {
+                    props.response_topic = Some(requester.topic.clone());
+                    props.correlation_data = Some(data);
+                })
+                .send_at_least_once()
+                .await?;
+            if u8::from(ack.reason_code) >= 0x80 {
+                return Err(RequestError::Rejected(ack.reason_code));
+            }
+
+            rx.await.map_err(|_| RequestError::Send(SendPacketError::Disconnected))
+        })
+        .await
+        .map_err(|_| RequestError::Timeout)?
+    }
+}
+
+/// Remove pending request on timeout or error
+struct PendingRequest(Rc<MqttShared>, Bytes);
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.0.cancel_request(&self.1);
+    }
+}
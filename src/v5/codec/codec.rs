@@ -5,6 +5,7 @@ use ntex::util::{Buf, BytesMut};
 
 use super::{decode::decode_packet, encode::EncodeLtd, Packet};
 use crate::error::{DecodeError, EncodeError};
+use crate::metrics::{MetricsRef, Protocol};
 use crate::types::{FixedHeader, MAX_PACKET_SIZE};
 use crate::utils::decode_variable_length;
 
@@ -14,6 +15,7 @@ pub struct Codec {
     max_in_size: Cell<u32>,
     max_out_size: Cell<u32>,
     flags: Cell<CodecFlags>,
+    metrics: MetricsRef,
 }
 
 bitflags::bitflags! {
@@ -39,6 +41,7 @@ impl Codec {
             max_in_size: Cell::new(0),
             max_out_size: Cell::new(0),
             flags: Cell::new(CodecFlags::empty()),
+            metrics: MetricsRef::new(Protocol::V5),
         }
     }
 
@@ -78,6 +81,10 @@ impl Codec {
         self.max_out_size.set(size);
     }
 
+    pub(crate) fn metrics(&self) -> &MetricsRef {
+        &self.metrics
+    }
+
     pub(crate) fn retain_available(&self) -> bool {
         !self.flags.get().contains(CodecFlags::NO_RETAIN)
     }
@@ -156,6 +163,7 @@ impl Decoder for Codec {
                     let packet = decode_packet(packet_buf, fixed.first_byte)?;
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
+                    self.metrics.received(fixed.first_byte, fixed.remaining_length);
 
                     if let Packet::Connect(ref pkt) = packet {
                         let mut flags = self.flags.get();
@@ -214,7 +222,10 @@ impl Encoder for Codec {
             return Err(EncodeError::OverMaxPacketSize);
         }
         dst.reserve(content_size + 5);
+        let len = dst.len();
+        let packet_type = item.packet_type();
         item.encode(dst, content_size as u32)?; // safe: max_size <= u32 max value
+        self.metrics.sent(packet_type, dst.len() - len);
         Ok(())
     }
 }
@@ -1,9 +1,10 @@
 use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::num::{NonZeroU16, NonZeroU32};
 
 use super::ack_props;
 use crate::error::{DecodeError, EncodeError};
+use crate::topic::{SharedTopicFilter, TopicFilter};
 use crate::types::QoS;
 use crate::utils::{self, write_variable_length, Decode, Encode};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
@@ -39,6 +40,30 @@ impl Default for SubscriptionOptions {
     }
 }
 
+impl SubscriptionOptions {
+    /// Validate subscription options for the topic filter.
+    ///
+    /// `shared` indicates if shared subscriptions are supported. Shared
+    /// subscription must not set `no_local` option.
+    pub fn validate(&self, topic: &ByteString, shared: bool) -> Result<(), SubscribeAckReason> {
+        if SharedTopicFilter::is_shared(topic) {
+            if !shared {
+                return Err(SubscribeAckReason::SharedSubscriptionNotSupported);
+            }
+            if self.no_local {
+                return Err(SubscribeAckReason::TopicFilterInvalid);
+            }
+            SharedTopicFilter::try_from(topic.clone())
+                .map(|_| ())
+                .map_err(|_| SubscribeAckReason::TopicFilterInvalid)
+        } else {
+            TopicFilter::try_from(topic.clone())
+                .map(|_| ())
+                .map_err(|_| SubscribeAckReason::TopicFilterInvalid)
+        }
+    }
+}
+
 prim_enum! {
     pub enum RetainHandling {
         AtSubscribe = 0,
@@ -422,4 +447,30 @@ mod tests {
         ack.encode(&mut buf, size as u32).unwrap();
         assert_eq!(ack, UnsubscribeAck::decode(&mut buf.freeze()).unwrap());
     }
+
+    #[test]
+    fn test_validate() {
+        let opts = SubscriptionOptions::default();
+        assert_eq!(opts.validate(&"a/+".into(), false), Ok(()));
+        assert_eq!(
+            opts.validate(&"a/#/b".into(), false),
+            Err(SubscribeAckReason::TopicFilterInvalid)
+        );
+        assert_eq!(opts.validate(&"$share/g/a/+".into(), true), Ok(()));
+        assert_eq!(
+            opts.validate(&"$share/g/a/+".into(), false),
+            Err(SubscribeAckReason::SharedSubscriptionNotSupported)
+        );
+        assert_eq!(
+            opts.validate(&"$share/g".into(), true),
+            Err(SubscribeAckReason::TopicFilterInvalid)
+        );
+
+        let opts = SubscriptionOptions { no_local: true, ..Default::default() };
+        assert_eq!(opts.validate(&"a/+".into(), true), Ok(()));
+        assert_eq!(
+            opts.validate(&"$share/g/a/+".into(), true),
+            Err(SubscribeAckReason::TopicFilterInvalid)
+        );
+    }
 }
@@ -158,6 +158,7 @@ impl Disconnect {
 pub struct Subscribe {
     packet: codec::Subscribe,
     result: codec::SubscribeAck,
+    hidden: Vec<bool>,
     size: u32,
 }
 
@@ -176,7 +177,8 @@ impl Subscribe {
             reason_string: None,
         };
 
-        Self { packet, result, size }
+        let hidden = vec![false; packet.topic_filters.len()];
+        Self { packet, result, hidden, size }
     }
 
     #[inline]
@@ -242,11 +244,17 @@ impl<'a> SubscribeIter<'a> {
     fn next_unsafe(&mut self) -> Option<Subscription<'a>> {
         let subs = unsafe { &mut *self.subs };
 
+        // skip subscriptions that are already handled
+        while self.entry < subs.hidden.len() && subs.hidden[self.entry] {
+            self.entry += 1;
+        }
+
         if self.entry < subs.packet.topic_filters.len() {
             let s = Subscription {
                 topic: &subs.packet.topic_filters[self.entry].0,
                 options: &subs.packet.topic_filters[self.entry].1,
                 status: &mut subs.result.status[self.entry],
+                hidden: &mut subs.hidden[self.entry],
             };
             self.entry += 1;
             Some(s)
@@ -271,6 +279,7 @@ pub struct Subscription<'a> {
     topic: &'a ByteString,
     options: &'a codec::SubscriptionOptions,
     status: &'a mut codec::SubscribeAckReason,
+    hidden: &'a mut bool,
 }
 
 impl<'a> Subscription<'a> {
@@ -308,6 +317,11 @@ impl<'a> Subscription<'a> {
     pub fn subscribe(&mut self, qos: QoS) {
         self.confirm(qos)
     }
+
+    /// Hide subscription from next iterations
+    pub(crate) fn hide(&mut self) {
+        *self.hidden = true
+    }
 }
 
 /// Unsubscribe message
@@ -8,11 +8,12 @@ use ntex::util::{join, BoxFuture, ByteString, Either, HashMap, HashSet, Ready};
 use ntex::{service, Pipeline, Service, ServiceCall, ServiceCtx, ServiceFactory};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
+use crate::{rate::RateLimiter, trace};
 
 use super::control::{ControlMessage, ControlResult};
 use super::publish::{Publish, PublishAck};
 use super::shared::{Ack, MqttShared};
-use super::{codec, codec::DisconnectReasonCode, Session};
+use super::{codec, codec::DisconnectReasonCode, QoS, Session};
 
 /// MQTT 5 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
@@ -82,6 +83,7 @@ impl crate::inflight::SizedRequest for DispatchItem<Rc<MqttShared>> {
 pub(crate) struct Dispatcher<T, C: Service<ControlMessage<E>>, E> {
     publish: T,
     shutdown: RefCell<Option<BoxFuture<'static, ()>>>,
+    rate: Option<RateLimiter>,
     inner: Rc<Inner<C>>,
     _t: marker::PhantomData<E>,
 }
@@ -94,6 +96,8 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<num::NonZeroU16>,
+    /// QoS 2 packet ids for which PUBREC is sent, but PUBREL is not received yet
+    unreleased: HashSet<num::NonZeroU16>,
     aliases: HashMap<num::NonZeroU16, ByteString>,
 }
 
@@ -105,15 +109,18 @@ where
     C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
 {
     fn new(sink: Rc<MqttShared>, publish: T, control: C) -> Self {
+        sink.codec.metrics().with(|m, p| m.connection_opened(p));
         Self {
             publish,
             shutdown: RefCell::new(None),
+            rate: sink.rate_limit().and_then(RateLimiter::new),
             inner: Rc::new(Inner {
                 sink,
                 control,
                 info: RefCell::new(PublishInfo {
                     aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    unreleased: HashSet::default(),
                 }),
             }),
             _t: marker::PhantomData,
@@ -130,16 +137,21 @@ where
 {
     type Response = Option<codec::Packet>;
     type Error = MqttError<E>;
-    type Future<'f> = Either<
-        PublishResponse<'f, T, C, E>,
-        Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, T, C, E>>,
+    type Future<'f> = trace::Instrumented<
+        Either<
+            PublishResponse<'f, T, C, E>,
+            Either<Ready<Self::Response, MqttError<E>>, ControlResponse<'f, T, C, E>>,
+        >,
     > where Self: 'f;
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let res1 = self.publish.poll_ready(cx).map_err(|e| MqttError::Service(e.into()))?;
         let res2 = self.inner.control.poll_ready(cx)?;
+        // pause reads while inbound rate is exceeded
+        let res3 =
+            self.rate.as_ref().map(|rate| rate.poll_ready(cx)).unwrap_or(Poll::Ready(()));
 
-        if res1.is_pending() || res2.is_pending() {
+        if res1.is_pending() || res2.is_pending() || res3.is_pending() {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -149,6 +161,7 @@ where
     fn poll_shutdown(&self, cx: &mut Context<'_>) -> Poll<()> {
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
+            self.inner.sink.codec.metrics().with(|m, p| m.connection_closed(p));
             self.inner.sink.drop_sink();
             let inner = self.inner.clone();
             *shutdown = Some(Box::pin(async move {
@@ -171,12 +184,42 @@ where
         request: DispatchItem<Rc<MqttShared>>,
         ctx: ServiceCtx<'a, Self>,
     ) -> Self::Future<'a> {
-        log::trace!("Dispatch v5 packet: {:#?}", request);
+        trace!("Dispatch v5 packet: {:#?}", request);
 
+        let parent = self.inner.sink.span();
+        let span = match request {
+            DispatchItem::Item((codec::Packet::Publish(ref pkt), _)) => {
+                trace::publish(parent, &pkt.topic, pkt.qos, pkt.packet_id)
+            }
+            DispatchItem::Item((ref pkt, _)) => {
+                trace::control(parent, trace::packet_kind(pkt.packet_type()))
+            }
+            ref item => trace::control(parent, trace::event_kind(item)),
+        };
+        trace::instrument(self.dispatch(request, ctx), span)
+    }
+}
+
+impl<T, C, E> Dispatcher<T, C, E>
+where
+    E: From<T::Error>,
+    T: Service<Publish, Response = PublishAck>,
+    PublishAck: TryFrom<T::Error, Error = E>,
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>> + 'static,
+{
+    fn dispatch<'a>(
+        &'a self,
+        request: DispatchItem<Rc<MqttShared>>,
+        ctx: ServiceCtx<'a, Self>,
+    ) -> Either<
+        PublishResponse<'a, T, C, E>,
+        Either<Ready<Option<codec::Packet>, MqttError<E>>, ControlResponse<'a, T, C, E>>,
+    > {
         match request {
             DispatchItem::Item((codec::Packet::Publish(mut publish), size)) => {
                 let info = self.inner.as_ref();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
 
                 if publish.topic.contains(['#', '+']) {
                     return Either::Right(Either::Right(ControlResponse::new(
@@ -190,18 +233,55 @@ where
                     )));
                 }
 
+                // check inbound rate
+                if let Some(ref rate) = self.rate {
+                    if !rate.consume(size) {
+                        trace!("Message rate is too high, disconnecting");
+                        return Either::Right(Either::Right(ControlResponse::new(
+                            ControlMessage::proto_error(ProtocolError::violation(
+                                DisconnectReasonCode::MessageRateTooHigh,
+                                "Message rate is too high",
+                            )),
+                            &self.inner,
+                            ctx,
+                        )));
+                    }
+                }
+
                 {
                     let mut inner = info.info.borrow_mut();
                     let state = &self.inner.sink;
 
                     if let Some(pid) = packet_id {
-                        // check for receive maximum
+                        // PUBREL is not received yet, message must not be delivered twice
+                        if inner.unreleased.contains(&pid) {
+                            let reason_code = if qos == QoS::ExactlyOnce {
+                                codec::PublishAckReason::Success
+                            } else {
+                                codec::PublishAckReason::PacketIdentifierInUse
+                            };
+                            let ack = codec::PublishAck {
+                                packet_id: pid,
+                                reason_code,
+                                ..Default::default()
+                            };
+                            let pkt = if qos == QoS::ExactlyOnce {
+                                codec::Packet::PublishReceived(ack)
+                            } else {
+                                codec::Packet::PublishAck(ack)
+                            };
+                            return Either::Right(Either::Left(Ready::Ok(Some(pkt))));
+                        }
+
+                        // check for receive maximum, QoS 2 messages are counted
+                        // until PUBCOMP is sent
                         let receive_max = state.receive_max();
-                        if receive_max != 0 && inner.inflight.len() >= receive_max as usize {
-                            log::trace!(
+                        let in_flight = inner.inflight.len() + inner.unreleased.len();
+                        if receive_max != 0 && in_flight >= receive_max as usize {
+                            trace!(
                                 "Receive maximum exceeded: max: {} in-flight: {}",
                                 receive_max,
-                                inner.inflight.len()
+                                in_flight
                             );
                             return Either::Right(Either::Right(ControlResponse::new(
                                 ControlMessage::proto_error(
@@ -217,7 +297,7 @@ where
 
                         // check max allowed qos
                         if publish.qos > state.max_qos() {
-                            log::trace!(
+                            trace!(
                                 "Max allowed QoS is violated, max {:?} provided {:?}",
                                 state.max_qos(),
                                 publish.qos
@@ -232,7 +312,7 @@ where
                             )));
                         }
                         if publish.retain && !state.codec.retain_available() {
-                            log::trace!("Retain is not available but is set");
+                            trace!("Retain is not available but is set");
                             return Either::Right(Either::Right(ControlResponse::new(
                                 ControlMessage::proto_error(ProtocolError::violation(
                                     DisconnectReasonCode::RetainNotSupported,
@@ -245,13 +325,16 @@ where
 
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
-                            let _ = self.inner.sink.encode_packet(codec::Packet::PublishAck(
-                                codec::PublishAck {
-                                    packet_id: pid,
-                                    reason_code: codec::PublishAckReason::PacketIdentifierInUse,
-                                    ..Default::default()
-                                },
-                            ));
+                            let ack = codec::PublishAck {
+                                packet_id: pid,
+                                reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+                                ..Default::default()
+                            };
+                            let _ = self.inner.sink.encode_packet(if qos == QoS::ExactlyOnce {
+                                codec::Packet::PublishReceived(ack)
+                            } else {
+                                codec::Packet::PublishAck(ack)
+                            });
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
                     }
@@ -312,6 +395,7 @@ where
 
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
+                    qos,
                     inner: info,
                     state: PublishResponseState::Publish {
                         fut: ctx.call(&self.publish, Publish::new(publish, size)),
@@ -330,6 +414,44 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item((codec::Packet::PublishReceived(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                        ctx,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishComplete(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                        ctx,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishRelease(pkt), _)) => {
+                let reason_code =
+                    if self.inner.info.borrow_mut().unreleased.remove(&pkt.packet_id) {
+                        codec::PublishAck2Reason::Success
+                    } else {
+                        codec::PublishAck2Reason::PacketIdNotFound
+                    };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        packet_id: pkt.packet_id,
+                        reason_code,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item((codec::Packet::Auth(pkt), size)) => {
                 if self.inner.sink.is_closed() {
                     return Either::Right(Either::Left(Ready::Ok(None)));
@@ -345,6 +467,9 @@ where
                 Either::Right(ControlResponse::new(ControlMessage::ping(), &self.inner, ctx)),
             ),
             DispatchItem::Item((codec::Packet::Disconnect(pkt), size)) => {
+                if let Some(secs) = pkt.session_expiry_interval_secs {
+                    self.inner.sink.set_session_expiry(secs);
+                }
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::remote_disconnect(pkt, size),
                     &self.inner,
@@ -367,7 +492,7 @@ where
                 }
 
                 if pkt.id.is_some() && !self.inner.sink.codec.sub_ids_available() {
-                    log::trace!("Subscription Identifiers are not supported but was set");
+                    trace!("Subscription Identifiers are not supported but was set");
                     return Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(ProtocolError::violation(
                             DisconnectReasonCode::SubscriptionIdentifiersNotSupported,
@@ -395,6 +520,7 @@ where
                     ));
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
+                self.inner.sink.session_subscribe(&pkt);
                 let id = pkt.packet_id;
                 Either::Right(Either::Right(
                     ControlResponse::new(
@@ -437,6 +563,7 @@ where
                     ));
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
+                self.inner.sink.session_unsubscribe(&pkt);
                 let id = pkt.packet_id;
                 Either::Right(Either::Right(
                     ControlResponse::new(
@@ -497,6 +624,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<'f, T, C, E>,
         packet_id: u16,
+        qos: QoS,
         inner: &'f Inner<C>,
         ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
     }
@@ -557,14 +685,23 @@ where
                     Poll::Pending => return Poll::Pending,
                 };
                 if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
-                    this.inner.info.borrow_mut().inflight.remove(&id);
+                    let mut info = this.inner.info.borrow_mut();
+                    info.inflight.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
                         reason_code: ack.reason_code,
                         reason_string: ack.reason_string,
                         properties: ack.properties,
                     };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    if *this.qos == QoS::ExactlyOnce {
+                        // PUBREC with failure reason code completes exchange
+                        if u8::from(ack.reason_code) < 0x80 {
+                            info.unreleased.insert(id);
+                        }
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived(ack))))
+                    } else {
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -599,6 +736,9 @@ where
         ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
     ) -> Self {
         let error = matches!(pkt, ControlMessage::Error(_) | ControlMessage::ProtocolError(_));
+        if let ControlMessage::ProtocolError(_) = pkt {
+            inner.sink.codec.metrics().with(|m, p| m.protocol_error(p));
+        }
         let fut = ctx.call(&inner.control, pkt);
 
         Self { fut, ctx, error, inner, packet_id: 0, _t: marker::PhantomData }
@@ -656,6 +796,9 @@ where
             }
             Poll::Ready(Ok(None))
         } else {
+            if let Some(ref pkt) = result.packet {
+                self.inner.sink.session_ack(pkt);
+            }
             if result.disconnect {
                 self.inner.sink.drop_sink();
             }
@@ -667,7 +810,7 @@ where
 #[cfg(test)]
 mod tests {
     use ntex::{io::Io, service::fn_service, testing::IoTest, util::lazy};
-    use std::rc::Rc;
+    use std::{cell::Cell, rc::Rc};
 
     use super::*;
     use crate::v5::{codec, MqttSink};
@@ -717,4 +860,87 @@ mod tests {
         assert!(lazy(|cx| rx.poll_recv(cx).is_ready()).await);
         assert!(!lazy(|cx| rx2.poll_recv(cx).is_ready()).await);
     }
+
+    #[ntex::test]
+    async fn test_qos2() {
+        let io = Io::new(IoTest::create().0);
+        let codec = codec::Codec::default();
+        let shared = Rc::new(MqttShared::new(io.get_ref(), codec, Default::default()));
+        shared.set_max_qos(QoS::ExactlyOnce);
+
+        let delivered = Rc::new(Cell::new(0));
+        let delivered2 = delivered.clone();
+        let disp = Pipeline::new(Dispatcher::<_, _, _>::new(
+            shared.clone(),
+            fn_service(move |p: Publish| {
+                delivered2.set(delivered2.get() + 1);
+                Ready::Ok::<_, TestError>(p.ack())
+            }),
+            fn_service(|_| {
+                Ready::Ok::<_, MqttError<TestError>>(ControlResult {
+                    packet: None,
+                    disconnect: false,
+                })
+            }),
+        ));
+
+        let packet_id = num::NonZeroU16::new(1).unwrap();
+        let publish = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::ExactlyOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: Some(packet_id),
+            payload: ntex::util::Bytes::new(),
+            properties: Default::default(),
+        };
+        let pubrec = codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id,
+            ..Default::default()
+        });
+
+        let res =
+            disp.call(DispatchItem::Item((codec::Packet::Publish(publish.clone()), 0))).await;
+        assert_eq!(res.unwrap(), Some(pubrec.clone()));
+        assert_eq!(delivered.get(), 1);
+
+        // re-delivered publish is acknowledged but not handled
+        let res = disp
+            .call(DispatchItem::Item((
+                codec::Packet::Publish(codec::Publish { dup: true, ..publish.clone() }),
+                0,
+            )))
+            .await;
+        assert_eq!(res.unwrap(), Some(pubrec));
+        assert_eq!(delivered.get(), 1);
+
+        let pubrel = codec::PublishAck2 { packet_id, ..Default::default() };
+        let res = disp
+            .call(DispatchItem::Item((codec::Packet::PublishRelease(pubrel.clone()), 0)))
+            .await;
+        assert_eq!(
+            res.unwrap(),
+            Some(codec::Packet::PublishComplete(codec::PublishAck2 {
+                packet_id,
+                ..Default::default()
+            }))
+        );
+
+        // unknown packet id
+        let res =
+            disp.call(DispatchItem::Item((codec::Packet::PublishRelease(pubrel), 0))).await;
+        assert_eq!(
+            res.unwrap(),
+            Some(codec::Packet::PublishComplete(codec::PublishAck2 {
+                packet_id,
+                reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+                ..Default::default()
+            }))
+        );
+
+        // packet id is released, publish is handled again
+        let res = disp.call(DispatchItem::Item((codec::Packet::Publish(publish), 0))).await;
+        assert!(matches!(res.unwrap(), Some(codec::Packet::PublishReceived(_))));
+        assert_eq!(delivered.get(), 2);
+    }
 }
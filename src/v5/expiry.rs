@@ -0,0 +1,95 @@
+//! Message expiry interval handling
+use std::{num::NonZeroU32, time::Duration, time::Instant};
+
+use super::codec;
+
+/// Expiration time of the message
+pub(crate) fn deadline(props: &codec::PublishProperties, now: Instant) -> Option<Instant> {
+    props
+        .message_expiry_interval
+        .and_then(|secs| now.checked_add(Duration::from_secs(secs.get() as u64)))
+}
+
+/// Remaining lifetime of the message, rounded up to whole seconds.
+///
+/// Returns `None` if message is expired.
+pub(crate) fn remaining(deadline: Instant, now: Instant) -> Option<NonZeroU32> {
+    if deadline <= now {
+        None
+    } else {
+        let left = deadline - now;
+        let secs = left.as_secs() as u32 + (left.subsec_nanos() > 0) as u32;
+        NonZeroU32::new(secs)
+    }
+}
+
+/// Rewrite message expiry interval to remaining lifetime.
+///
+/// Returns `false` if message is expired.
+pub(crate) fn refresh(
+    props: &mut codec::PublishProperties,
+    deadline: Option<Instant>,
+    now: Instant,
+) -> bool {
+    if let Some(deadline) = deadline {
+        if let Some(secs) = remaining(deadline, now) {
+            props.message_expiry_interval = Some(secs);
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// Rewrite message expiry interval of in-flight message.
+///
+/// Message that is already sent must be re-sent until it is acknowledged,
+/// even if it is expired, so interval is never less than one second.
+pub(crate) fn refresh_inflight(
+    props: &mut codec::PublishProperties,
+    deadline: Option<Instant>,
+    now: Instant,
+) {
+    if let Some(deadline) = deadline {
+        props.message_expiry_interval = Some(remaining(deadline, now).unwrap_or(ONE_SEC));
+    }
+}
+
+const ONE_SEC: NonZeroU32 = match NonZeroU32::new(1) {
+    Some(secs) => secs,
+    None => unreachable!(),
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let now = Instant::now();
+        let mut props = codec::PublishProperties::default();
+        assert_eq!(deadline(&props, now), None);
+        assert!(refresh(&mut props, None, now));
+        assert_eq!(props.message_expiry_interval, None);
+
+        props.message_expiry_interval = NonZeroU32::new(10);
+        let dl = deadline(&props, now);
+        assert_eq!(dl, Some(now + Duration::from_secs(10)));
+
+        // remaining lifetime is rounded up
+        assert!(refresh(&mut props, dl, now + Duration::from_millis(2500)));
+        assert_eq!(props.message_expiry_interval, NonZeroU32::new(8));
+        assert!(refresh(&mut props, dl, now + Duration::from_secs(9)));
+        assert_eq!(props.message_expiry_interval, NonZeroU32::new(1));
+
+        assert!(!refresh(&mut props, dl, now + Duration::from_secs(10)));
+        assert_eq!(remaining(now, now + Duration::from_secs(1)), None);
+
+        // in-flight message is never expired
+        refresh_inflight(&mut props, dl, now + Duration::from_secs(20));
+        assert_eq!(props.message_expiry_interval, NonZeroU32::new(1));
+        props.message_expiry_interval = None;
+        refresh_inflight(&mut props, None, now);
+        assert_eq!(props.message_expiry_interval, None);
+    }
+}
@@ -1,14 +1,20 @@
 use ntex::io::IoBoxed;
+use ntex::{time::Deadline, util::select, util::Either};
 use std::{fmt, num::NonZeroU16, rc::Rc};
 
 use super::{codec, shared::MqttShared, sink::MqttSink};
+use crate::error::{HandshakeError, ProtocolError};
+use crate::store::{SessionState, SessionStore};
+use crate::RateLimit;
 
 /// Handshake message
 pub struct Handshake {
     io: IoBoxed,
     pkt: Box<codec::Connect>,
     size: u32,
+    deadline: Deadline,
     pub(super) shared: Rc<MqttShared>,
+    pub(super) store: Option<Rc<dyn SessionStore>>,
 }
 
 impl Handshake {
@@ -17,8 +23,9 @@ impl Handshake {
         size: u32,
         io: IoBoxed,
         shared: Rc<MqttShared>,
+        deadline: Deadline,
     ) -> Self {
-        Self { io, pkt, size, shared }
+        Self { io, pkt, size, deadline, shared, store: None }
     }
 
     #[inline]
@@ -47,6 +54,87 @@ impl Handshake {
         MqttSink::new(self.shared.clone())
     }
 
+    /// Returns session state persisted in server's session store
+    ///
+    /// State is loaded from the store on each call, so it should be called
+    /// after client is authenticated. Returns `None` for clean start.
+    /// Session is restored and its unacknowledged messages are re-sent
+    /// only if handshake succeeds.
+    ///
+    /// Subscriptions of the session are not re-applied by the server,
+    /// handshake service must re-install them, for example into its
+    /// broker or router.
+    pub fn session_state(&self) -> Option<SessionState> {
+        match self.store {
+            Some(ref store) if !self.pkt.clean_start && !self.pkt.client_id.is_empty() => {
+                store.load(&self.pkt.client_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Send AUTH challenge to the client and wait for client's AUTH response.
+    ///
+    /// Could be called multiple times for multi-step enhanced authentication.
+    /// Authentication method of CONNECT packet is used if challenge does not
+    /// specify one. Whole exchange is limited by server's `connect_timeout`.
+    pub async fn auth<E>(
+        &mut self,
+        mut auth: codec::Auth,
+    ) -> Result<codec::Auth, HandshakeError<E>> {
+        if self.pkt.auth_method.is_none() {
+            return Err(HandshakeError::Protocol(ProtocolError::generic_violation(
+                "Client did not request enhanced authentication",
+            )));
+        }
+        if auth.auth_method.is_none() {
+            auth.auth_method = self.pkt.auth_method.clone();
+        }
+
+        log::trace!("Sending auth challenge: {:?}", auth);
+        self.io
+            .encode(codec::Packet::Auth(auth), &self.shared.codec)
+            .map_err(|e| HandshakeError::Protocol(e.into()))?;
+
+        let result = match select(&mut self.deadline, self.io.recv(&self.shared.codec)).await {
+            Either::Left(_) => return Err(HandshakeError::Timeout),
+            Either::Right(result) => result.map_err(|err| {
+                log::trace!("Error is received during mqtt auth exchange: {:?}", err);
+                HandshakeError::from(err)
+            })?,
+        };
+
+        match result {
+            Some((codec::Packet::Auth(auth), _)) => {
+                if auth.reason_code != codec::AuthReasonCode::ContinueAuth {
+                    Err(HandshakeError::Protocol(ProtocolError::generic_violation(
+                        "Client AUTH packet must have ContinueAuth reason code during handshake",
+                    )))
+                } else if auth.auth_method != self.pkt.auth_method {
+                    Err(HandshakeError::Protocol(ProtocolError::generic_violation(
+                        "Authentication method of AUTH packet does not match CONNECT packet",
+                    )))
+                } else {
+                    Ok(auth)
+                }
+            }
+            Some((codec::Packet::Disconnect(pkt), _)) => {
+                log::trace!("Client is disconnected during auth exchange: {:?}", pkt);
+                Err(HandshakeError::Disconnected(None))
+            }
+            Some((packet, _)) => {
+                Err(HandshakeError::Protocol(ProtocolError::unexpected_packet(
+                    packet.packet_type(),
+                    "Expected AUTH packet during enhanced authentication",
+                )))
+            }
+            None => {
+                log::trace!("Client is disconnected during auth exchange");
+                Err(HandshakeError::Disconnected(None))
+            }
+        }
+    }
+
     #[inline]
     /// Ack handshake message and set state
     pub fn ack<St>(self, st: St) -> HandshakeAck<St> {
@@ -132,4 +220,12 @@ impl<St> HandshakeAck<St> {
         f(&mut self.packet);
         self
     }
+
+    /// Set inbound rate limit for the connection
+    ///
+    /// Overrides rate limit configured for the server.
+    pub fn rate_limit(self, limit: RateLimit) -> Self {
+        self.shared.set_rate_limit(limit);
+        self
+    }
 }
@@ -1,13 +1,19 @@
 //! MQTT5 Client/Server framework
 
+mod alias;
+mod auth;
 pub mod client;
 pub mod codec;
 pub mod control;
 mod default;
 mod dispatcher;
+pub(crate) mod expiry;
 mod handshake;
 mod publish;
+mod queue;
 mod router;
+#[cfg(feature = "scram")]
+pub mod scram;
 mod selector;
 mod server;
 mod shared;
@@ -17,16 +23,21 @@ pub type Session<St> = crate::Session<MqttSink, St>;
 
 use std::num::NonZeroU16;
 
+pub use self::auth::{Authenticator, Authorization};
 pub use self::control::{ControlMessage, ControlResult};
 pub use self::handshake::{Handshake, HandshakeAck};
 pub use self::publish::{Publish, PublishAck};
+pub use self::queue::{OutboundQueue, QueueMetrics, QueueOverflow};
 pub use self::router::Router;
 pub use self::selector::Selector;
 pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+pub use self::sink::{
+    ExactlyOnceAck, InflightMessages, MqttSink, PublishBuilder, SubscribeBuilder,
+    UnsubscribeBuilder,
+};
 
 pub use crate::error;
-pub use crate::topic::{TopicFilter, TopicFilterError};
+pub use crate::topic::{SharedTopicFilter, TopicFilter, TopicFilterError};
 pub use crate::types::QoS;
 
 const RECEIVE_MAX_DEFAULT: NonZeroU16 = unsafe { NonZeroU16::new_unchecked(65_535) };
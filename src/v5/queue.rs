@@ -0,0 +1,364 @@
+//! Outbound message queue
+use std::{collections::VecDeque, fmt, rc::Rc, time::Instant};
+
+use ntex::channel::oneshot;
+
+use super::{codec, codec::EncodeLtd, expiry};
+use crate::types::QoS;
+
+/// Outbound queue overflow policy
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Drop QoS 0 messages first
+    ///
+    /// New QoS 0 message is dropped if queue is full. QoS 1 and QoS 2 messages
+    /// replace the oldest queued QoS 0 messages, if there are not enough QoS 0
+    /// messages new message is rejected with `SendPacketError::QueueFull` error.
+    #[default]
+    DropQos0,
+    /// Disconnect peer with `QuotaExceeded` reason code
+    Disconnect,
+    /// Wait until queue has room for new message
+    Block,
+}
+
+/// Outbound queue metrics hooks
+///
+/// Hooks are called for all connections that share queue configuration.
+pub trait QueueMetrics {
+    /// Message is added to the queue, `len` and `bytes` are the new queue size
+    fn enqueued(&self, _len: usize, _bytes: usize) {}
+
+    /// Queued message is sent to the peer, `len` and `bytes` are the new queue size
+    fn dequeued(&self, _len: usize, _bytes: usize) {}
+
+    /// Message is dropped because queue is full
+    fn dropped(&self, _qos: QoS) {}
+
+    /// Peer is disconnected because queue is full
+    fn overflow(&self) {}
+}
+
+#[derive(Clone)]
+/// Outbound queue configuration
+///
+/// Publishes sent with `PublishBuilder::enqueue()` are queued while peer's
+/// receive maximum credit is exhausted and are sent in order as in-flight
+/// messages get acknowledged. Queue always accepts at least one message.
+/// Queued messages are dropped when connection is closed.
+pub struct OutboundQueue {
+    max_len: usize,
+    max_bytes: usize,
+    overflow: QueueOverflow,
+    metrics: Option<Rc<dyn QueueMetrics>>,
+}
+
+impl Default for OutboundQueue {
+    fn default() -> Self {
+        Self { max_len: 1024, max_bytes: 0, overflow: QueueOverflow::default(), metrics: None }
+    }
+}
+
+impl OutboundQueue {
+    /// Create queue configuration with default limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set max number of queued messages
+    ///
+    /// By default max number of queued messages is set to 1024
+    pub fn max_len(mut self, val: usize) -> Self {
+        self.max_len = val;
+        self
+    }
+
+    /// Set max total size of queued messages in bytes
+    ///
+    /// By default size of queue is not limited. To disable limit set value to 0.
+    pub fn max_bytes(mut self, val: usize) -> Self {
+        self.max_bytes = val;
+        self
+    }
+
+    /// Set queue overflow policy
+    ///
+    /// By default `QueueOverflow::DropQos0` is used.
+    pub fn overflow(mut self, val: QueueOverflow) -> Self {
+        self.overflow = val;
+        self
+    }
+
+    /// Set metrics hooks
+    pub fn metrics<M: QueueMetrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(Rc::new(metrics));
+        self
+    }
+}
+
+impl fmt::Debug for OutboundQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboundQueue")
+            .field("max_len", &self.max_len)
+            .field("max_bytes", &self.max_bytes)
+            .field("overflow", &self.overflow)
+            .finish()
+    }
+}
+
+/// Result of adding message to the queue
+pub(super) enum Push {
+    Queued,
+    /// New QoS 0 message is dropped
+    Dropped,
+    /// Message is rejected
+    Full,
+    /// Peer must be disconnected
+    Overflow,
+    /// Queue is full, retry after notification
+    Blocked(Box<codec::Publish>, oneshot::Receiver<()>),
+}
+
+/// Outbound queue of a connection
+#[derive(Default)]
+pub(super) struct Outbound {
+    config: OutboundQueue,
+    // queued messages with encoded size and expiration time
+    items: VecDeque<(codec::Publish, usize, Option<Instant>)>,
+    bytes: usize,
+    blocked: VecDeque<oneshot::Sender<()>>,
+}
+
+impl Outbound {
+    pub(super) fn set_config(&mut self, config: OutboundQueue) {
+        self.config = config;
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// QoS of the next queued message
+    pub(super) fn next_qos(&self) -> Option<QoS> {
+        self.items.front().map(|(pkt, _, _)| pkt.qos)
+    }
+
+    fn is_full(&self, size: usize) -> bool {
+        !self.items.is_empty()
+            && (self.items.len() >= self.config.max_len
+                || (self.config.max_bytes != 0 && self.bytes + size > self.config.max_bytes))
+    }
+
+    pub(super) fn push(&mut self, pkt: codec::Publish) -> Push {
+        let size = pkt.encoded_size(u32::MAX);
+        if self.is_full(size) {
+            match self.config.overflow {
+                QueueOverflow::DropQos0 => {
+                    if pkt.qos == QoS::AtMostOnce {
+                        self.dropped(QoS::AtMostOnce);
+                        return Push::Dropped;
+                    }
+                    if !self.drop_qos0(size) {
+                        return Push::Full;
+                    }
+                }
+                QueueOverflow::Disconnect => {
+                    if let Some(ref metrics) = self.config.metrics {
+                        metrics.overflow();
+                    }
+                    return Push::Overflow;
+                }
+                QueueOverflow::Block => {
+                    let (tx, rx) = oneshot::channel();
+                    self.blocked.push_back(tx);
+                    return Push::Blocked(Box::new(pkt), rx);
+                }
+            }
+        }
+
+        let deadline = expiry::deadline(&pkt.properties, Instant::now());
+        self.bytes += size;
+        self.items.push_back((pkt, size, deadline));
+        if let Some(ref metrics) = self.config.metrics {
+            metrics.enqueued(self.items.len(), self.bytes);
+        }
+        Push::Queued
+    }
+
+    /// Add message to the queue, full queue with `Block` policy is not waited for
+    #[cfg(feature = "broker")]
+    pub(super) fn try_push(&mut self, pkt: codec::Publish) -> Push {
+        if self.config.overflow == QueueOverflow::Block
+            && self.is_full(pkt.encoded_size(u32::MAX))
+        {
+            return Push::Full;
+        }
+        self.push(pkt)
+    }
+
+    /// Drop oldest QoS 0 messages to make room for new message
+    fn drop_qos0(&mut self, size: usize) -> bool {
+        // check that new message fits after dropping all QoS 0 messages
+        let (len, bytes) = self
+            .items
+            .iter()
+            .filter(|(pkt, _, _)| pkt.qos != QoS::AtMostOnce)
+            .fold((0, 0), |(len, bytes), (_, size, _)| (len + 1, bytes + size));
+        if len > 0
+            && (len >= self.config.max_len
+                || (self.config.max_bytes != 0 && bytes + size > self.config.max_bytes))
+        {
+            return false;
+        }
+
+        while self.is_full(size) {
+            if let Some(pos) =
+                self.items.iter().position(|(pkt, _, _)| pkt.qos == QoS::AtMostOnce)
+            {
+                let (_, size, _) = self.items.remove(pos).unwrap();
+                self.bytes -= size;
+                self.dropped(QoS::AtMostOnce);
+            } else {
+                break;
+            }
+        }
+        true
+    }
+
+    fn dropped(&self, qos: QoS) {
+        if let Some(ref metrics) = self.config.metrics {
+            metrics.dropped(qos);
+        }
+    }
+
+    pub(super) fn pop(&mut self) -> Option<(codec::Publish, Option<Instant>)> {
+        let (pkt, size, deadline) = self.items.pop_front()?;
+        self.bytes -= size;
+        if let Some(ref metrics) = self.config.metrics {
+            metrics.dequeued(self.items.len(), self.bytes);
+        }
+
+        // wake up blocked publisher
+        while let Some(tx) = self.blocked.pop_front() {
+            if tx.send(()).is_ok() {
+                break;
+            }
+        }
+        Some((pkt, deadline))
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.items.clear();
+        self.blocked.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use ntex::util::lazy;
+
+    use super::*;
+
+    fn publish(topic: &'static str, qos: QoS) -> codec::Publish {
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos,
+            topic: topic.into(),
+            packet_id: None,
+            payload: ntex::util::Bytes::new(),
+            properties: Default::default(),
+        }
+    }
+
+    fn topics(queue: &Outbound) -> Vec<&str> {
+        queue.items.iter().map(|(pkt, _, _)| pkt.topic.as_str()).collect()
+    }
+
+    #[derive(Clone, Default)]
+    struct Metrics(Rc<RefCell<Vec<String>>>);
+
+    impl QueueMetrics for Metrics {
+        fn enqueued(&self, len: usize, _: usize) {
+            self.0.borrow_mut().push(format!("enqueued {}", len));
+        }
+        fn dequeued(&self, len: usize, _: usize) {
+            self.0.borrow_mut().push(format!("dequeued {}", len));
+        }
+        fn dropped(&self, qos: QoS) {
+            self.0.borrow_mut().push(format!("dropped {:?}", qos));
+        }
+        fn overflow(&self) {
+            self.0.borrow_mut().push("overflow".to_string());
+        }
+    }
+
+    #[test]
+    fn test_drop_qos0() {
+        let metrics = Metrics::default();
+        let mut queue = Outbound::default();
+        queue.set_config(OutboundQueue::new().max_len(3).metrics(metrics.clone()));
+
+        assert!(matches!(queue.push(publish("a", QoS::AtMostOnce)), Push::Queued));
+        assert!(matches!(queue.push(publish("b", QoS::AtLeastOnce)), Push::Queued));
+        assert!(matches!(queue.push(publish("c", QoS::AtMostOnce)), Push::Queued));
+        assert!(matches!(queue.push(publish("d", QoS::AtMostOnce)), Push::Dropped));
+        assert!(matches!(queue.push(publish("e", QoS::ExactlyOnce)), Push::Queued));
+        assert_eq!(topics(&queue), vec!["b", "c", "e"]);
+        assert!(matches!(queue.push(publish("f", QoS::AtLeastOnce)), Push::Queued));
+        assert_eq!(topics(&queue), vec!["b", "e", "f"]);
+        assert!(matches!(queue.push(publish("g", QoS::AtLeastOnce)), Push::Full));
+
+        assert_eq!(queue.next_qos(), Some(QoS::AtLeastOnce));
+        assert_eq!(queue.pop().unwrap().0.topic, "b");
+        assert_eq!(
+            &metrics.0.borrow()[..],
+            &[
+                "enqueued 1",
+                "enqueued 2",
+                "enqueued 3",
+                "dropped AtMostOnce",
+                "dropped AtMostOnce",
+                "enqueued 3",
+                "dropped AtMostOnce",
+                "enqueued 3",
+                "dequeued 2"
+            ]
+        );
+    }
+
+    #[ntex::test]
+    async fn test_limits() {
+        let size = publish("a", QoS::AtLeastOnce).encoded_size(u32::MAX);
+        let mut queue = Outbound::default();
+        queue.set_config(
+            OutboundQueue::new().max_bytes(size * 2).overflow(QueueOverflow::Disconnect),
+        );
+
+        // queue accepts at least one message
+        assert!(matches!(queue.push(publish("long/topic", QoS::AtLeastOnce)), Push::Queued));
+        assert!(matches!(queue.push(publish("a", QoS::AtLeastOnce)), Push::Overflow));
+        queue.pop();
+        assert!(matches!(queue.push(publish("a", QoS::AtLeastOnce)), Push::Queued));
+        assert!(matches!(queue.push(publish("b", QoS::AtLeastOnce)), Push::Queued));
+        assert!(matches!(queue.push(publish("c", QoS::AtLeastOnce)), Push::Overflow));
+
+        queue.set_config(OutboundQueue::new().max_len(1).overflow(QueueOverflow::Block));
+        queue.clear();
+        assert!(queue.is_empty());
+        assert!(matches!(queue.push(publish("a", QoS::AtMostOnce)), Push::Queued));
+        let rx = match queue.push(publish("b", QoS::AtMostOnce)) {
+            Push::Blocked(pkt, rx) => {
+                assert_eq!(pkt.topic, "b");
+                rx
+            }
+            _ => panic!(),
+        };
+        assert!(lazy(|cx| rx.poll_recv(cx)).await.is_pending());
+        queue.pop();
+        assert!(lazy(|cx| rx.poll_recv(cx)).await.is_ready());
+    }
+}
@@ -0,0 +1,626 @@
+//! SCRAM-SHA-256 enhanced authentication (RFC 5802, RFC 7677)
+//!
+//! `ScramServer` authenticates clients during v5 handshake, `ScramClient`
+//! is client authenticator for `MqttConnector::authenticator()`.
+//! Channel binding and SASLprep normalization are not supported.
+use std::{fmt, num::NonZeroU32};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ntex::util::{ByteString, Bytes};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+
+use super::{codec, handshake::Handshake, handshake::HandshakeAck, Authenticator};
+use crate::error::{AuthError, HandshakeError};
+
+/// SCRAM-SHA-256 authentication method name
+pub const METHOD: &str = "SCRAM-SHA-256";
+
+// base64 encoded "n,,"
+const GS2_HEADER: &str = "biws";
+const KEY_LEN: usize = 32;
+// iteration count of mock credentials for unknown users
+const MOCK_ITERATIONS: u32 = 4096;
+// max iteration count accepted by client
+const MAX_ITERATIONS: u32 = 100_000;
+
+/// Salted credentials of a user
+///
+/// Credentials do not contain password and could be stored on server side.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    salt: Bytes,
+    iterations: NonZeroU32,
+    stored_key: [u8; KEY_LEN],
+    server_key: [u8; KEY_LEN],
+}
+
+impl ScramCredentials {
+    /// Derive credentials from password
+    pub fn new(password: &str, salt: Bytes, iterations: NonZeroU32) -> Self {
+        let salted = salted_password(password, &salt, iterations);
+        let client_key = hmac(&salted, b"Client Key");
+        ScramCredentials {
+            salt,
+            iterations,
+            stored_key: sha256(&client_key),
+            server_key: hmac(&salted, b"Server Key"),
+        }
+    }
+
+    /// Create credentials from stored keys
+    pub fn from_keys(
+        salt: Bytes,
+        iterations: NonZeroU32,
+        stored_key: [u8; KEY_LEN],
+        server_key: [u8; KEY_LEN],
+    ) -> Self {
+        ScramCredentials { salt, iterations, stored_key, server_key }
+    }
+
+    /// Salt of the password
+    pub fn salt(&self) -> &Bytes {
+        &self.salt
+    }
+
+    /// Number of PBKDF2 iterations
+    pub fn iterations(&self) -> NonZeroU32 {
+        self.iterations
+    }
+
+    /// Stored key, `H(HMAC(SaltedPassword, "Client Key"))`
+    pub fn stored_key(&self) -> &[u8; KEY_LEN] {
+        &self.stored_key
+    }
+
+    /// Server key, `HMAC(SaltedPassword, "Server Key")`
+    pub fn server_key(&self) -> &[u8; KEY_LEN] {
+        &self.server_key
+    }
+}
+
+impl fmt::Debug for ScramCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramCredentials")
+            .field("salt", &self.salt)
+            .field("iterations", &self.iterations)
+            .finish()
+    }
+}
+
+/// Credentials lookup for `ScramServer`
+pub trait CredentialStore {
+    /// Get credentials of the user
+    fn credentials(&self, username: &str) -> Option<ScramCredentials>;
+}
+
+impl<F> CredentialStore for F
+where
+    F: Fn(&str) -> Option<ScramCredentials>,
+{
+    fn credentials(&self, username: &str) -> Option<ScramCredentials> {
+        (self)(username)
+    }
+}
+
+/// SCRAM authentication errors
+#[derive(Debug, thiserror::Error)]
+pub enum ScramError {
+    /// Client uses other authentication method
+    #[error("Unsupported authentication method")]
+    Method,
+    /// Malformed SCRAM message
+    #[error("Malformed SCRAM message: {0}")]
+    Format(&'static str),
+    /// User is not known
+    #[error("Unknown user")]
+    UnknownUser,
+    /// Client proof does not match stored credentials
+    #[error("Invalid client proof")]
+    InvalidProof,
+    /// Authentication exchange failed
+    #[error("Authentication exchange failed: {0}")]
+    Handshake(HandshakeError<()>),
+}
+
+impl ScramError {
+    /// Reason code for CONNACK packet
+    pub fn reason_code(&self) -> codec::ConnectAckReason {
+        match self {
+            ScramError::Method => codec::ConnectAckReason::BadAuthenticationMethod,
+            ScramError::Format(_) => codec::ConnectAckReason::MalformedPacket,
+            ScramError::UnknownUser | ScramError::InvalidProof => {
+                codec::ConnectAckReason::BadUserNameOrPassword
+            }
+            ScramError::Handshake(_) => codec::ConnectAckReason::UnspecifiedError,
+        }
+    }
+}
+
+/// Server side of SCRAM-SHA-256 authentication
+///
+/// Exchange for unknown user is continued with mock credentials and fails
+/// at client proof verification, so user names could not be enumerated
+/// (RFC 5802, section 5.1). Only authentication during handshake is
+/// supported, re-authentication requests are delivered to control service
+/// as `ControlMessage::Auth` and are not handled by `ScramServer`.
+pub struct ScramServer<S> {
+    store: S,
+    mock_key: [u8; KEY_LEN],
+}
+
+impl<S> fmt::Debug for ScramServer<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramServer").finish()
+    }
+}
+
+/// Successful SCRAM authentication
+#[derive(Debug)]
+pub struct ScramSuccess {
+    username: ByteString,
+    server_final: Bytes,
+}
+
+impl ScramSuccess {
+    /// Authenticated user name
+    pub fn username(&self) -> &ByteString {
+        &self.username
+    }
+
+    /// Ack handshake, CONNACK packet carries server signature
+    pub fn ack<St>(self, hnd: Handshake, st: St) -> HandshakeAck<St> {
+        hnd.ack(st).with(|ack| {
+            ack.auth_method = Some(ByteString::from_static(METHOD));
+            ack.auth_data = Some(self.server_final);
+        })
+    }
+}
+
+impl<S: CredentialStore> ScramServer<S> {
+    /// Create server authenticator with credentials store
+    pub fn new(store: S) -> Self {
+        let mut mock_key = [0; KEY_LEN];
+        SystemRandom::new().fill(&mut mock_key).expect("system random source is available");
+        ScramServer { store, mock_key }
+    }
+
+    /// Set secret for mock credentials of unknown users
+    ///
+    /// Mock salt is derived from the secret and user name. By default random
+    /// secret is used, servers that share credentials store should use
+    /// the same secret, so mock salt does not differ between servers.
+    pub fn mock_secret(mut self, secret: &[u8]) -> Self {
+        self.mock_key = sha256(secret);
+        self
+    }
+
+    /// Run SCRAM authentication exchange for handshake
+    pub async fn authenticate(&self, hnd: &mut Handshake) -> Result<ScramSuccess, ScramError> {
+        let pkt = hnd.packet();
+        if pkt.auth_method.as_deref() != Some(METHOD) {
+            return Err(ScramError::Method);
+        }
+        let client_first = utf8(pkt.auth_data.as_ref())?;
+        let (state, server_first) =
+            ServerFirst::new(&self.store, &self.mock_key, client_first, nonce())?;
+
+        let response = hnd
+            .auth(codec::Auth {
+                reason_code: codec::AuthReasonCode::ContinueAuth,
+                auth_method: None,
+                auth_data: Some(Bytes::from(server_first)),
+                reason_string: None,
+                user_properties: Vec::new(),
+            })
+            .await
+            .map_err(ScramError::Handshake)?;
+
+        let server_final = state.finish(utf8(response.auth_data.as_ref())?)?;
+        Ok(ScramSuccess { username: state.username, server_final: Bytes::from(server_final) })
+    }
+}
+
+/// Server state after server-first message
+struct ServerFirst {
+    username: ByteString,
+    nonce: String,
+    credentials: ScramCredentials,
+    // mock credentials of unknown user
+    mock: bool,
+    // client-first-message-bare "," server-first-message
+    messages: String,
+}
+
+impl ServerFirst {
+    fn new<S: CredentialStore>(
+        store: &S,
+        mock_key: &[u8],
+        client_first: &str,
+        server_nonce: String,
+    ) -> Result<(Self, String), ScramError> {
+        let bare = client_first
+            .strip_prefix("n,,")
+            .ok_or(ScramError::Format("Unsupported GS2 header"))?;
+        let mut attrs = Attrs(bare);
+        let username = unescape(attrs.next('n')?)?;
+        let client_nonce = attrs.next('r')?;
+
+        let (credentials, mock) = match store.credentials(&username) {
+            Some(credentials) => (credentials, false),
+            None => (mock_credentials(mock_key, &username), true),
+        };
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            STANDARD.encode(&credentials.salt),
+            credentials.iterations
+        );
+        let messages = format!("{},{}", bare, server_first);
+        Ok((
+            ServerFirst { nonce, credentials, mock, messages, username: username.into() },
+            server_first,
+        ))
+    }
+
+    /// Verify client-final message, returns server-final message
+    fn finish(&self, client_final: &str) -> Result<String, ScramError> {
+        let (without_proof, proof) = client_final
+            .rsplit_once(",p=")
+            .ok_or(ScramError::Format("Client proof is missing"))?;
+        let mut attrs = Attrs(without_proof);
+        if attrs.next('c')? != GS2_HEADER {
+            return Err(ScramError::Format("Unsupported channel binding"));
+        }
+        if attrs.next('r')? != self.nonce {
+            return Err(ScramError::InvalidProof);
+        }
+        let proof =
+            STANDARD.decode(proof).map_err(|_| ScramError::Format("Invalid client proof"))?;
+        if proof.len() != KEY_LEN {
+            return Err(ScramError::InvalidProof);
+        }
+
+        let auth_message = format!("{},{}", self.messages, without_proof);
+        let signature = hmac(&self.credentials.stored_key, auth_message.as_bytes());
+        let mut client_key = [0; KEY_LEN];
+        for (idx, b) in client_key.iter_mut().enumerate() {
+            *b = proof[idx] ^ signature[idx];
+        }
+        if self.mock {
+            return Err(ScramError::UnknownUser);
+        }
+        if !eq(&sha256(&client_key), &self.credentials.stored_key) {
+            return Err(ScramError::InvalidProof);
+        }
+
+        let server_signature = hmac(&self.credentials.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", STANDARD.encode(server_signature)))
+    }
+}
+
+/// Client side of SCRAM-SHA-256 authentication
+pub struct ScramClient {
+    username: String,
+    password: String,
+    max_iterations: u32,
+    state: ClientState,
+}
+
+enum ClientState {
+    Initial,
+    First { nonce: String, bare: String },
+    Final { server_signature: [u8; KEY_LEN] },
+}
+
+impl ScramClient {
+    /// Create client authenticator
+    pub fn new<U, P>(username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        ScramClient {
+            username: username.into(),
+            password: password.into(),
+            max_iterations: MAX_ITERATIONS,
+            state: ClientState::Initial,
+        }
+    }
+
+    /// Set max iteration count accepted from the server
+    ///
+    /// Authentication fails if server requests more iterations.
+    /// By default max iteration count is set to 100_000
+    pub fn max_iterations(mut self, val: u32) -> Self {
+        self.max_iterations = val;
+        self
+    }
+
+    fn client_first(&mut self, nonce: String) -> String {
+        let bare = format!("n={},r={}", escape(&self.username), nonce);
+        let msg = format!("n,,{}", bare);
+        self.state = ClientState::First { nonce, bare };
+        msg
+    }
+
+    fn client_final(&mut self, server_first: &str) -> Result<String, AuthError> {
+        let (nonce, bare) = match self.state {
+            ClientState::First { ref nonce, ref bare } => (nonce, bare),
+            _ => return Err(AuthError::new("Unexpected server-first message")),
+        };
+
+        let mut attrs = Attrs(server_first);
+        let server_nonce = attrs.next('r').map_err(auth_error)?;
+        let salt = attrs.next('s').map_err(auth_error)?;
+        let iterations = attrs.next('i').map_err(auth_error)?;
+        if !server_nonce.starts_with(nonce.as_str()) || server_nonce.len() == nonce.len() {
+            return Err(AuthError::new("Server nonce is invalid"));
+        }
+        let salt = STANDARD.decode(salt).map_err(|_| AuthError::new("Salt is invalid"))?;
+        let iterations = iterations
+            .parse()
+            .ok()
+            .and_then(NonZeroU32::new)
+            .ok_or_else(|| AuthError::new("Iteration count is invalid"))?;
+        if iterations.get() > self.max_iterations {
+            return Err(AuthError::new("Iteration count is too large"));
+        }
+
+        let salted = salted_password(&self.password, &salt, iterations);
+        let client_key = hmac(&salted, b"Client Key");
+        let server_key = hmac(&salted, b"Server Key");
+        let without_proof = format!("c={},r={}", GS2_HEADER, server_nonce);
+        let auth_message = format!("{},{},{}", bare, server_first, without_proof);
+
+        let signature = hmac(&sha256(&client_key), auth_message.as_bytes());
+        let mut proof = client_key;
+        for (idx, b) in proof.iter_mut().enumerate() {
+            *b ^= signature[idx];
+        }
+        self.state =
+            ClientState::Final { server_signature: hmac(&server_key, auth_message.as_bytes()) };
+        Ok(format!("{},p={}", without_proof, STANDARD.encode(proof)))
+    }
+
+    fn verify(&mut self, server_final: &str) -> Result<(), AuthError> {
+        let signature = match std::mem::replace(&mut self.state, ClientState::Initial) {
+            ClientState::Final { server_signature } => server_signature,
+            _ => return Err(AuthError::new("Unexpected server-final message")),
+        };
+        if let Some(err) = server_final.strip_prefix("e=") {
+            return Err(AuthError::new(err.to_string()));
+        }
+        let value = Attrs(server_final).next('v').map_err(auth_error)?;
+        match STANDARD.decode(value) {
+            Ok(value) if eq(&value, &signature) => Ok(()),
+            _ => Err(AuthError::new("Server signature is invalid")),
+        }
+    }
+}
+
+impl fmt::Debug for ScramClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramClient").field("username", &self.username).finish()
+    }
+}
+
+impl Authenticator for ScramClient {
+    fn method(&self) -> ByteString {
+        ByteString::from_static(METHOD)
+    }
+
+    fn start(&mut self) -> Result<Option<Bytes>, AuthError> {
+        Ok(Some(Bytes::from(self.client_first(nonce()))))
+    }
+
+    fn challenge(&mut self, data: Option<&Bytes>) -> Result<Option<Bytes>, AuthError> {
+        let server_first = utf8(data).map_err(auth_error)?;
+        Ok(Some(Bytes::from(self.client_final(server_first)?)))
+    }
+
+    fn complete(&mut self, data: Option<&Bytes>) -> Result<(), AuthError> {
+        self.verify(utf8(data).map_err(auth_error)?)
+    }
+}
+
+/// Iterator over `name=value` attributes of SCRAM message
+struct Attrs<'a>(&'a str);
+
+impl<'a> Attrs<'a> {
+    /// Get value of next attribute, attribute name must match
+    fn next(&mut self, name: char) -> Result<&'a str, ScramError> {
+        let (attr, rest) = self.0.split_once(',').unwrap_or((self.0, ""));
+        self.0 = rest;
+        attr.strip_prefix(name)
+            .and_then(|attr| attr.strip_prefix('='))
+            .ok_or(ScramError::Format("Unexpected attribute"))
+    }
+}
+
+fn utf8(data: Option<&Bytes>) -> Result<&str, ScramError> {
+    data.and_then(|data| std::str::from_utf8(data).ok())
+        .ok_or(ScramError::Format("Authentication data is not valid utf-8 string"))
+}
+
+fn auth_error(err: ScramError) -> AuthError {
+    AuthError::new(err.to_string())
+}
+
+fn escape(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn unescape(name: &str) -> Result<String, ScramError> {
+    if name.split('=').skip(1).all(|s| s.starts_with("2C") || s.starts_with("3D")) {
+        Ok(name.replace("=2C", ",").replace("=3D", "="))
+    } else {
+        Err(ScramError::Format("User name is not escaped"))
+    }
+}
+
+fn nonce() -> String {
+    let mut buf = [0; 18];
+    SystemRandom::new().fill(&mut buf).expect("system random source is available");
+    STANDARD.encode(buf)
+}
+
+/// Deterministic credentials of unknown user, no password matches them
+fn mock_credentials(key: &[u8], username: &str) -> ScramCredentials {
+    let salt = hmac(key, username.as_bytes());
+    ScramCredentials {
+        salt: Bytes::copy_from_slice(&salt[..16]),
+        iterations: NonZeroU32::new(MOCK_ITERATIONS).unwrap(),
+        stored_key: hmac(key, &salt),
+        server_key: hmac(key, &hmac(key, &salt)),
+    }
+}
+
+fn salted_password(password: &str, salt: &[u8], iterations: NonZeroU32) -> [u8; KEY_LEN] {
+    let mut out = [0; KEY_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password.as_bytes(), &mut out);
+    out
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; KEY_LEN] {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data);
+    let mut out = [0; KEY_LEN];
+    out.copy_from_slice(tag.as_ref());
+    out
+}
+
+fn sha256(data: &[u8]) -> [u8; KEY_LEN] {
+    let mut out = [0; KEY_LEN];
+    out.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    out
+}
+
+/// Constant time comparison
+fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOCK_KEY: &[u8] = b"mock";
+
+    fn credentials(name: &str) -> Option<ScramCredentials> {
+        if name == "user" {
+            let salt = STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+            Some(ScramCredentials::new(
+                "pencil",
+                Bytes::from(salt),
+                NonZeroU32::new(4096).unwrap(),
+            ))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn test_rfc7677() {
+        let mut client = ScramClient::new("user", "pencil");
+        let client_first = client.client_first("rOprNGfwEbeRWgbNEkqO".to_string());
+        assert_eq!(client_first, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+
+        let (server, server_first) = ServerFirst::new(
+            &credentials,
+            MOCK_KEY,
+            &client_first,
+            "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            server_first,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+
+        let client_final = client.client_final(&server_first).unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+
+        let server_final = server.finish(&client_final).unwrap();
+        assert_eq!(server_final, "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+        assert!(client.verify(&server_final).is_ok());
+        assert_eq!(server.username, "user");
+    }
+
+    #[test]
+    fn test_failures() {
+        // wrong password
+        let mut client = ScramClient::new("user", "wrong");
+        let client_first = client.client_first(nonce());
+        let (server, server_first) =
+            ServerFirst::new(&credentials, MOCK_KEY, &client_first, nonce()).unwrap();
+        let client_final = client.client_final(&server_first).unwrap();
+        assert!(matches!(server.finish(&client_final), Err(ScramError::InvalidProof)));
+
+        // unknown user fails at proof verification, mock salt is deterministic
+        let mut client = ScramClient::new("unknown", "pencil");
+        let client_first = client.client_first(nonce());
+        let (server, server_first) =
+            ServerFirst::new(&credentials, MOCK_KEY, &client_first, nonce()).unwrap();
+        let client_final = client.client_final(&server_first).unwrap();
+        assert!(matches!(server.finish(&client_final), Err(ScramError::UnknownUser)));
+        let (server2, _) =
+            ServerFirst::new(&credentials, MOCK_KEY, &client_first, nonce()).unwrap();
+        assert_eq!(server.credentials.salt, server2.credentials.salt);
+        assert_eq!(server.credentials.iterations.get(), MOCK_ITERATIONS);
+        let (server3, _) =
+            ServerFirst::new(&credentials, MOCK_KEY, "n,,n=other,r=1", nonce()).unwrap();
+        assert_ne!(server.credentials.salt, server3.credentials.salt);
+
+        // malformed messages
+        assert!(matches!(
+            ServerFirst::new(&credentials, MOCK_KEY, "y,,n=user,r=1", nonce()),
+            Err(ScramError::Format(_))
+        ));
+        assert!(matches!(
+            ServerFirst::new(&credentials, MOCK_KEY, "n,,r=1", nonce()),
+            Err(ScramError::Format(_))
+        ));
+
+        // invalid server signature
+        let mut client = ScramClient::new("user", "pencil");
+        let client_first = client.client_first(nonce());
+        let (_, server_first) =
+            ServerFirst::new(&credentials, MOCK_KEY, &client_first, nonce()).unwrap();
+        client.client_final(&server_first).unwrap();
+        assert!(client.verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").is_err());
+
+        // server error
+        let mut client = ScramClient::new("user", "pencil");
+        let client_first = client.client_first(nonce());
+        let (_, server_first) =
+            ServerFirst::new(&credentials, MOCK_KEY, &client_first, nonce()).unwrap();
+        client.client_final(&server_first).unwrap();
+        let err = client.verify("e=invalid-proof").unwrap_err();
+        assert_eq!(err.reason(), "invalid-proof");
+
+        // server nonce must extend client nonce
+        let mut client = ScramClient::new("user", "pencil");
+        client.client_first("abc".to_string());
+        assert!(client.client_final("r=xyz,s=QSXCR+Q6sek8bf92,i=4096").is_err());
+
+        // iteration count above client limit
+        let mut client = ScramClient::new("user", "pencil").max_iterations(4095);
+        client.client_first("abc".to_string());
+        let err = client.client_final("r=abcxyz,s=QSXCR+Q6sek8bf92,i=4096").unwrap_err();
+        assert_eq!(err.reason(), "Iteration count is too large");
+        let mut client = ScramClient::new("user", "pencil");
+        client.client_first("abc".to_string());
+        assert!(client.client_final("r=abcxyz,s=QSXCR+Q6sek8bf92,i=4294967295").is_err());
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a=b,c"), "a=3Db=2Cc");
+        assert_eq!(unescape("a=3Db=2Cc").unwrap(), "a=b,c");
+        assert!(unescape("a=b").is_err());
+    }
+}
@@ -54,7 +54,8 @@ where
     ///
     /// Defines a timeout for reading `Connect` frame. If a client does not transmit
     /// the entire frame within this time, the connection is terminated with
-    /// Mqtt::Handshake(HandshakeError::Timeout) error.
+    /// Mqtt::Handshake(HandshakeError::Timeout) error. Timeout also applies to
+    /// enhanced authentication exchange, see `Handshake::auth()`.
     ///
     /// By default, connect timeout is disabled.
     pub fn connect_timeout(mut self, timeout: Seconds) -> Self {
@@ -312,7 +313,7 @@ where
             };
 
             // call servers
-            let mut item = Handshake::new(connect, size, io, shared);
+            let mut item = Handshake::new(connect, size, io, shared, timeout);
             for srv in self.servers.iter() {
                 match ctx.call(srv, item).await? {
                     Either::Left(result) => {
@@ -1,17 +1,20 @@
-use std::{convert::TryFrom, fmt, future::Future, marker::PhantomData, rc::Rc};
+use std::{convert::TryFrom, fmt, future::Future, marker::PhantomData, rc::Rc, time::Instant};
 
 use ntex::io::{DispatchItem, DispatcherConfig, IoBoxed};
 use ntex::service::{IntoServiceFactory, Service, ServiceCtx, ServiceFactory};
-use ntex::time::{timeout_checked, Millis, Seconds};
-use ntex::util::{BoxFuture, Either};
+use ntex::time::{Deadline, Millis, Seconds};
+use ntex::util::{select, BoxFuture, ByteString, Either};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::{io::Dispatcher, service, types::QoS};
+use crate::types::QoS;
+use crate::{admission::Rejected, service, store::SessionStore, trace, Admission, RateLimit};
+use crate::{io::Dispatcher, metrics::Metrics, registry::SessionRegistry};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
 use super::handshake::{Handshake, HandshakeAck};
 use super::publish::{Publish, PublishAck};
+use super::queue::OutboundQueue;
 use super::shared::{MqttShared, MqttSinkPool};
 use super::{codec as mqtt, dispatcher::factory, MqttSink, Session};
 
@@ -27,6 +30,12 @@ pub struct MqttServer<St, C, Cn, P> {
     max_topic_alias: u16,
     connect_timeout: Seconds,
     config: DispatcherConfig,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    outbound: Option<OutboundQueue>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -56,6 +65,12 @@ where
             max_inflight_size: 65535,
             max_topic_alias: 32,
             connect_timeout: Seconds::ZERO,
+            store: None,
+            registry: None,
+            outbound: None,
+            metrics: None,
+            admission: None,
+            rate_limit: None,
             pool: Rc::new(MqttSinkPool::default()),
             _t: PhantomData,
         }
@@ -75,7 +90,8 @@ where
     ///
     /// Defines a timeout for reading `Connect` frame. If a client does not transmit
     /// the entire frame within this time, the connection is terminated with
-    /// Mqtt::Handshake(HandshakeError::Timeout) error.
+    /// Mqtt::Handshake(HandshakeError::Timeout) error. Timeout also applies to
+    /// enhanced authentication exchange, see `Handshake::auth()`.
     ///
     /// By default, connect timeout is disabled.
     pub fn connect_timeout(mut self, timeout: Seconds) -> Self {
@@ -150,6 +166,67 @@ where
         self
     }
 
+    /// Set session store.
+    ///
+    /// Subscriptions and unacknowledged outbound messages of a connection
+    /// are persisted in the store and restored on reconnect with `clean_start=false`.
+    /// Session expiry interval of `Connect`, `ConnectAck` and `Disconnect` packets is honoured.
+    ///
+    /// By default sessions are not persisted.
+    pub fn session_store<S: SessionStore + 'static>(mut self, store: S) -> Self {
+        self.store = Some(Rc::new(store));
+        self
+    }
+
+    /// Set registry of connected clients.
+    ///
+    /// Existing connection of a client is taken over by a new connection
+    /// with the same client id, see `SessionRegistry` for details.
+    ///
+    /// By default connections are not registered.
+    pub fn registry(mut self, registry: SessionRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Set outbound queue configuration.
+    ///
+    /// Queue is used by `PublishBuilder::enqueue()` for messages that
+    /// exceed peer's receive maximum, see `OutboundQueue` for details.
+    ///
+    /// By default queue holds up to 1024 messages and drops QoS 0 messages first.
+    pub fn outbound_queue(mut self, queue: OutboundQueue) -> Self {
+        self.outbound = Some(queue);
+        self
+    }
+
+    /// Set metrics observer.
+    ///
+    /// Observer is called for connection and packet events of all connections,
+    /// see `metrics::Metrics` for details.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(Rc::new(metrics));
+        self
+    }
+
+    /// Set inbound rate limit.
+    ///
+    /// Limit applies to each connection and can be overridden
+    /// with `HandshakeAck::rate_limit()`. By default rate is not limited.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Set connection admission control.
+    ///
+    /// Connections are admitted after CONNECT packet is received,
+    /// before handshake service is called.
+    pub fn admission(mut self, admission: Admission) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -172,6 +249,12 @@ where
             max_qos: self.max_qos,
             max_inflight_size: self.max_inflight_size,
             connect_timeout: self.connect_timeout,
+            store: self.store,
+            registry: self.registry,
+            outbound: self.outbound,
+            metrics: self.metrics,
+            admission: self.admission,
+            rate_limit: self.rate_limit,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -197,6 +280,12 @@ where
             max_qos: self.max_qos,
             max_inflight_size: self.max_inflight_size,
             connect_timeout: self.connect_timeout,
+            store: self.store,
+            registry: self.registry,
+            outbound: self.outbound,
+            metrics: self.metrics,
+            admission: self.admission,
+            rate_limit: self.rate_limit,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -246,6 +335,12 @@ where
                 max_topic_alias: self.max_topic_alias,
                 max_qos: self.max_qos,
                 connect_timeout: self.connect_timeout.into(),
+                store: self.store,
+                registry: self.registry,
+                outbound: self.outbound,
+                metrics: self.metrics,
+                admission: self.admission,
+                rate_limit: self.rate_limit,
                 pool: self.pool,
                 _t: PhantomData,
             },
@@ -281,6 +376,12 @@ where
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             config: self.config,
+            store: self.store,
+            registry: self.registry,
+            outbound: self.outbound,
+            metrics: self.metrics,
+            admission: self.admission,
+            rate_limit: self.rate_limit,
             _t: PhantomData,
         }
     }
@@ -293,6 +394,12 @@ struct HandshakeFactory<St, H> {
     max_topic_alias: u16,
     max_qos: QoS,
     connect_timeout: Millis,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    outbound: Option<OutboundQueue>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -317,6 +424,12 @@ where
         let max_qos = self.max_qos;
         let pool = self.pool.clone();
         let connect_timeout = self.connect_timeout;
+        let store = self.store.clone();
+        let registry = self.registry.clone();
+        let outbound = self.outbound.clone();
+        let metrics = self.metrics.clone();
+        let admission = self.admission.clone();
+        let rate_limit = self.rate_limit;
 
         Box::pin(async move {
             let service = fut.await?;
@@ -327,6 +440,12 @@ where
                 max_topic_alias,
                 max_qos,
                 connect_timeout,
+                store,
+                registry,
+                outbound,
+                metrics,
+                admission,
+                rate_limit,
                 pool,
                 _t: PhantomData,
             })
@@ -341,6 +460,12 @@ struct HandshakeService<St, H> {
     max_topic_alias: u16,
     max_qos: QoS,
     connect_timeout: Millis,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    outbound: Option<OutboundQueue>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -359,44 +484,72 @@ where
 
     fn call<'a>(&'a self, io: IoBoxed, ctx: ServiceCtx<'a, Self>) -> Self::Future<'a> {
         log::trace!("Starting mqtt v5 handshake");
+        let started = Instant::now();
 
         let codec = mqtt::Codec::default();
         codec.set_max_inbound_size(self.max_size);
+        codec.metrics().set(self.metrics.clone());
         let shared = Rc::new(MqttShared::new(io.get_ref(), codec, self.pool.clone()));
         shared.set_max_qos(self.max_qos);
         shared.set_receive_max(self.max_receive);
         shared.set_topic_alias_max(self.max_topic_alias);
 
-        Box::pin(async move {
+        let span = trace::handshake(shared.span());
+        let fut = async move {
+            // connect timeout covers whole handshake exchange
+            let mut deadline = Deadline::new(self.connect_timeout);
+
             // read first packet
-            let packet = timeout_checked(self.connect_timeout, io.recv(&shared.codec))
-                .await
-                .map_err(|_| MqttError::Handshake(HandshakeError::Timeout))?
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::Handshake(HandshakeError::from(err))
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Handshake(HandshakeError::Disconnected(None))
-                })?;
+            let packet = match select(&mut deadline, io.recv(&shared.codec)).await {
+                Either::Left(_) => Err(MqttError::Handshake(HandshakeError::Timeout)),
+                Either::Right(item) => Ok(item),
+            }?
+            .map_err(|err| {
+                log::trace!("Error is received during mqtt handshake: {:?}", err);
+                MqttError::Handshake(HandshakeError::from(err))
+            })?
+            .ok_or_else(|| {
+                log::trace!("Server mqtt is disconnected during handshake");
+                MqttError::Handshake(HandshakeError::Disconnected(None))
+            })?;
 
             match packet {
                 (mqtt::Packet::Connect(connect), size) => {
+                    // connection admission control
+                    let permit = match self.admission.as_ref().map(|adm| adm.acquire(&io)) {
+                        Some(Ok(permit)) => Some(permit),
+                        Some(Err(reason)) => return reject(&io, &shared.codec, reason).await,
+                        None => None,
+                    };
+
                     // set max outbound (encoder) packet size
                     if let Some(size) = connect.max_packet_size {
                         shared.codec.set_max_outbound_size(size.get());
                     }
+                    shared.set_peer_topic_alias_max(connect.topic_alias_max);
                     let keep_alive = connect.keep_alive;
                     let peer_receive_max =
                         connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize;
+                    let client_id = connect.client_id.clone();
+                    trace::record_client_id(shared.span(), &client_id);
+                    let clean_start = connect.clean_start;
+                    let expiry = connect.session_expiry_interval_secs;
+                    if let Some(limit) = self.rate_limit {
+                        shared.set_rate_limit(limit);
+                    }
 
                     // authenticate mqtt connection
+                    let mut hnd = Handshake::new(connect, size, io, shared, deadline);
+                    hnd.store = self.store.clone();
                     let mut ack = ctx
-                        .call(&self.service, Handshake::new(connect, size, io, shared))
+                        .call(&self.service, hnd)
                         .await
                         .map_err(|e| MqttError::Handshake(HandshakeError::Service(e)))?;
 
+                    if ack.session.is_some() {
+                        register_session(&self.registry, &ack, &client_id);
+                        open_session(&self.store, &mut ack, &client_id, clean_start, expiry);
+                    }
                     match ack.session {
                         Some(session) => {
                             log::trace!("Sending: {:#?}", ack.packet);
@@ -418,11 +571,25 @@ where
                                 ack.packet.server_keepalive_sec = Some(ack.keepalive);
                             }
                             shared.set_cap(peer_receive_max);
+                            if let Some(ref queue) = self.outbound {
+                                shared.set_outbound_queue(queue.clone());
+                            }
+                            if let Some(secs) = ack.packet.session_expiry_interval_secs {
+                                shared.set_session_expiry(secs);
+                            }
 
                             ack.io.encode(
                                 mqtt::Packet::ConnectAck(Box::new(ack.packet)),
                                 &shared.codec,
                             )?;
+                            if let Some(permit) = permit {
+                                permit.release_on(ack.io.on_disconnect());
+                            }
+                            shared
+                                .codec
+                                .metrics()
+                                .with(|m, p| m.handshake(p, started.elapsed()));
+                            shared.restore_session();
 
                             Ok((
                                 ack.io,
@@ -453,7 +620,73 @@ where
                     )))
                 }
             }
-        })
+        };
+        Box::pin(trace::instrument(fut, span))
+    }
+}
+
+/// Reject connection that is not admitted
+async fn reject<T, E>(
+    io: &IoBoxed,
+    codec: &mqtt::Codec,
+    reason: Rejected,
+) -> Result<T, MqttError<E>> {
+    log::trace!("Connection is rejected by admission control: {:?}", reason);
+    let reason_code = match reason {
+        Rejected::Busy => mqtt::ConnectAckReason::ServerBusy,
+        Rejected::Rate => mqtt::ConnectAckReason::ConnectionRateExceeded,
+    };
+    io.encode(
+        mqtt::Packet::ConnectAck(Box::new(mqtt::ConnectAck {
+            reason_code,
+            ..Default::default()
+        })),
+        codec,
+    )?;
+    let _ = io.shutdown().await;
+    Err(MqttError::Handshake(HandshakeError::Disconnected(None)))
+}
+
+/// Load persisted session for connection, or remove it for clean start
+///
+/// Session is opened only for accepted connections.
+fn open_session<St>(
+    store: &Option<Rc<dyn SessionStore>>,
+    ack: &mut HandshakeAck<St>,
+    client_id: &ByteString,
+    clean_start: bool,
+    expiry: u32,
+) {
+    if let Some(store) = store {
+        if !client_id.is_empty() {
+            ack.shared.open_session(store.clone(), client_id.clone(), clean_start, expiry);
+            ack.packet.session_present |= ack.shared.session_present();
+        }
+    }
+}
+
+/// Register connection, existing connection of the client is taken over.
+///
+/// Session state of taken over connection is persisted on close, so
+/// session must be opened after registration to inherit it.
+fn register_session<St>(
+    registry: &Option<SessionRegistry>,
+    ack: &HandshakeAck<St>,
+    client_id: &ByteString,
+) {
+    if let Some(registry) = registry {
+        let id = if client_id.is_empty() {
+            if let Some(ref id) = ack.packet.assigned_client_id {
+                id.clone()
+            } else {
+                return;
+            }
+        } else {
+            client_id.clone()
+        };
+
+        let sink = MqttSink::new(ack.shared.clone());
+        registry.register_v5(id, sink, ack.io.on_disconnect());
     }
 }
 
@@ -466,6 +699,12 @@ pub(crate) struct ServerSelector<St, C, T, F, R> {
     max_qos: QoS,
     max_topic_alias: u16,
     config: DispatcherConfig,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    outbound: Option<OutboundQueue>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -499,6 +738,12 @@ where
         let max_receive = self.max_receive;
         let max_qos = self.max_qos;
         let max_topic_alias = self.max_topic_alias;
+        let store = self.store.clone();
+        let registry = self.registry.clone();
+        let outbound = self.outbound.clone();
+        let metrics = self.metrics.clone();
+        let admission = self.admission.clone();
+        let rate_limit = self.rate_limit;
 
         // create connect service and then create service impl
         Box::pin(async move {
@@ -510,6 +755,12 @@ where
                 max_receive,
                 max_qos,
                 max_topic_alias,
+                store,
+                registry,
+                outbound,
+                metrics,
+                admission,
+                rate_limit,
                 connect: fut.await?,
                 _t: PhantomData,
             })
@@ -526,6 +777,12 @@ pub(crate) struct ServerSelectorImpl<St, C, T, F, R> {
     max_qos: QoS,
     max_topic_alias: u16,
     config: DispatcherConfig,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    outbound: Option<OutboundQueue>,
+    metrics: Option<Rc<dyn Metrics>>,
+    admission: Option<Admission>,
+    rate_limit: Option<RateLimit>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -551,16 +808,31 @@ where
     ntex::forward_poll_ready!(connect, MqttError::Service);
     ntex::forward_poll_shutdown!(connect);
 
-    fn call<'a>(&'a self, hnd: Handshake, ctx: ServiceCtx<'a, Self>) -> Self::Future<'a> {
-        Box::pin(async move {
+    fn call<'a>(&'a self, mut hnd: Handshake, ctx: ServiceCtx<'a, Self>) -> Self::Future<'a> {
+        let span = trace::handshake(hnd.shared.span());
+        let fut = async move {
             log::trace!("Start connection handshake");
+            let started = Instant::now();
 
             let result = (*self.check)(&hnd).await;
             if !result.map_err(|e| MqttError::Handshake(HandshakeError::Service(e)))? {
                 Ok(Either::Left(hnd))
             } else {
+                // connection admission control
+                let permit = match self.admission.as_ref().map(|adm| adm.acquire(hnd.io())) {
+                    Some(Ok(permit)) => Some(permit),
+                    Some(Err(reason)) => {
+                        return reject(hnd.io(), &hnd.shared.codec, reason).await
+                    }
+                    None => None,
+                };
+
                 // decoder config
                 hnd.shared.codec.set_max_inbound_size(self.max_size);
+                hnd.shared.codec.metrics().set(self.metrics.clone());
+                if let Some(limit) = self.rate_limit {
+                    hnd.shared.set_rate_limit(limit);
+                }
                 hnd.shared.set_max_qos(self.max_qos);
                 hnd.shared.set_receive_max(self.max_receive);
                 hnd.shared.set_topic_alias_max(self.max_topic_alias);
@@ -569,9 +841,15 @@ where
                 if let Some(size) = hnd.packet().max_packet_size {
                     hnd.shared.codec.set_max_outbound_size(size.get());
                 }
+                hnd.shared.set_peer_topic_alias_max(hnd.packet().topic_alias_max);
                 let keep_alive = hnd.packet().keep_alive;
                 let peer_receive_max =
                     hnd.packet().receive_max.map(|v| v.get()).unwrap_or(16) as usize;
+                let client_id = hnd.packet().client_id.clone();
+                trace::record_client_id(hnd.shared.span(), &client_id);
+                let clean_start = hnd.packet().clean_start;
+                let expiry = hnd.packet().session_expiry_interval_secs;
+                hnd.store = self.store.clone();
 
                 // authenticate mqtt connection
                 let mut ack = ctx.call(&self.connect, hnd).await.map_err(|e| {
@@ -579,6 +857,10 @@ where
                     MqttError::Handshake(HandshakeError::Service(e))
                 })?;
 
+                if ack.session.is_some() {
+                    register_session(&self.registry, &ack, &client_id);
+                    open_session(&self.store, &mut ack, &client_id, clean_start, expiry);
+                }
                 match ack.session {
                     Some(session) => {
                         log::trace!("Sending: {:#?}", ack.packet);
@@ -600,10 +882,21 @@ where
                             ack.packet.server_keepalive_sec = Some(ack.keepalive);
                         }
                         shared.set_cap(peer_receive_max);
+                        if let Some(ref queue) = self.outbound {
+                            shared.set_outbound_queue(queue.clone());
+                        }
+                        if let Some(secs) = ack.packet.session_expiry_interval_secs {
+                            shared.set_session_expiry(secs);
+                        }
                         ack.io.encode(
                             mqtt::Packet::ConnectAck(Box::new(ack.packet)),
                             &shared.codec,
                         )?;
+                        if let Some(permit) = permit {
+                            permit.release_on(ack.io.on_disconnect());
+                        }
+                        shared.codec.metrics().with(|m, p| m.handshake(p, started.elapsed()));
+                        shared.restore_session();
 
                         let session = Session::new(session, MqttSink::new(shared.clone()));
                         let handler = self.handler.create(session).await?;
@@ -626,6 +919,7 @@ where
                     }
                 }
             }
-        })
+        };
+        Box::pin(trace::instrument(fut, span))
     }
 }
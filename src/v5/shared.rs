@@ -1,16 +1,24 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, num::NonZeroU32};
+use std::{rc::Rc, time::Instant};
 
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashSet, PoolId, PoolRef};
-use ntex::{channel::pool, io::IoRef};
+use ntex::util::{ByteString, Bytes, BytesMut, HashMap, HashSet, PoolId, PoolRef};
+use ntex::{channel::oneshot, channel::pool, io::IoRef};
 
-use crate::{error, error::SendPacketError, types::packet_type, v5::codec, QoS};
+use crate::error::{self, AuthError, SendPacketError};
+use crate::store::{self, SessionPersist, SessionStore, StoredPublish};
+use crate::{trace, types::packet_type, utils, v5::codec, v5::Authenticator, QoS, RateLimit};
+
+use super::queue::{Outbound, OutboundQueue, Push};
+use super::{alias::TopicAliases, expiry, sink::InflightMessages};
 
 bitflags::bitflags! {
     #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
     struct Flags: u8 {
         const WRB_ENABLED    = 0b0100_0000; // write-backpressure
         const ON_PUBLISH_ACK = 0b0010_0000; // on-publish-ack callback
+        const TRACK_INFLIGHT = 0b0001_0000; // keep unacknowledged publishes
+        const TOPIC_ALIAS    = 0b0000_1000; // outbound topic aliases
     }
 }
 
@@ -25,12 +33,38 @@ pub struct MqttShared {
     flags: Cell<Flags>,
     pool: Rc<MqttSinkPool>,
     on_publish_ack: Cell<Option<Box<dyn Fn(codec::PublishAck, bool)>>>,
+    session: RefCell<Option<SessionPersist>>,
+    // copies of unacknowledged publishes with expiration time
+    unacked: RefCell<HashMap<NonZeroU16, (codec::Publish, Option<Instant>)>>,
+    // in-flight messages of closed connection
+    exported: Cell<Option<InflightMessages>>,
+    // connection tracing span
+    span: trace::Span,
+    // inbound rate limit, applied by dispatcher
+    rate_limit: Cell<Option<RateLimit>>,
+    aliases: RefCell<TopicAliases>,
+    auth: RefCell<Option<AuthState>>,
+    // pending requests, keyed by correlation data
+    requests: RefCell<HashMap<Bytes, (ByteString, oneshot::Sender<codec::Publish>)>>,
+    request_idx: Cell<u64>,
+    outbound: RefCell<Outbound>,
     pub(super) codec: codec::Codec,
 }
 
+/// Client enhanced authentication state
+struct AuthState {
+    authenticator: Rc<RefCell<dyn Authenticator>>,
+    // pending re-authentication
+    waiter: Option<oneshot::Sender<Result<(), AuthError>>>,
+}
+
 pub(super) struct MqttSharedQueues {
     inflight: VecDeque<(NonZeroU16, Option<pool::Sender<Ack>>, AckType)>,
     inflight_ids: HashSet<NonZeroU16>,
+    // QoS 2 publishes waiting for PUBCOMP
+    released: VecDeque<(NonZeroU16, Option<pool::Sender<Ack>>, codec::PublishAck)>,
+    // replayed publishes waiting for receive credit
+    replay: VecDeque<(codec::Publish, Option<Instant>)>,
     waiters: VecDeque<pool::Sender<()>>,
 }
 
@@ -52,6 +86,7 @@ impl Default for MqttSinkPool {
 
 impl MqttShared {
     pub(super) fn new(io: IoRef, codec: codec::Codec, pool: Rc<MqttSinkPool>) -> Self {
+        let span = trace::connection("v5", &io);
         Self {
             io,
             pool,
@@ -60,6 +95,8 @@ impl MqttShared {
             queues: RefCell::new(MqttSharedQueues {
                 inflight: VecDeque::with_capacity(8),
                 inflight_ids: HashSet::default(),
+                released: VecDeque::new(),
+                replay: VecDeque::new(),
                 waiters: VecDeque::new(),
             }),
             receive_max: Cell::new(0),
@@ -68,9 +105,32 @@ impl MqttShared {
             inflight_idx: Cell::new(0),
             flags: Cell::new(Flags::empty()),
             on_publish_ack: Cell::new(None),
+            session: RefCell::new(None),
+            unacked: RefCell::new(HashMap::default()),
+            exported: Cell::new(None),
+            span,
+            rate_limit: Cell::new(None),
+            aliases: RefCell::new(TopicAliases::default()),
+            auth: RefCell::new(None),
+            requests: RefCell::new(HashMap::default()),
+            request_idx: Cell::new(0),
+            outbound: RefCell::new(Outbound::default()),
         }
     }
 
+    /// Connection tracing span
+    pub(super) fn span(&self) -> &trace::Span {
+        &self.span
+    }
+
+    pub(super) fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.get()
+    }
+
+    pub(super) fn set_rate_limit(&self, limit: RateLimit) {
+        self.rate_limit.set(Some(limit));
+    }
+
     pub(super) fn receive_max(&self) -> u16 {
         self.receive_max.get()
     }
@@ -113,7 +173,7 @@ impl MqttShared {
     }
 
     pub(super) fn credit(&self) -> usize {
-        self.cap.get().saturating_sub(self.queues.borrow().inflight.len())
+        self.cap.get().saturating_sub(self.queues.borrow().len())
     }
 
     pub(super) fn is_ready(&self) -> bool {
@@ -134,6 +194,12 @@ impl MqttShared {
     }
 
     pub(super) fn set_cap(&self, cap: usize) {
+        self.cap.set(cap);
+
+        // replayed publishes use receive credit first
+        if !self.drain_replay() {
+            return;
+        }
         let mut queues = self.queues.borrow_mut();
 
         // wake up queued request (receive max limit)
@@ -145,7 +211,10 @@ impl MqttShared {
             }
             break;
         }
-        self.cap.set(cap);
+    }
+
+    pub(super) fn set_outbound_queue(&self, config: OutboundQueue) {
+        self.outbound.borrow_mut().set_config(config);
     }
 
     pub(super) fn set_publish_ack(&self, f: Box<dyn Fn(codec::PublishAck, bool)>) {
@@ -156,6 +225,131 @@ impl MqttShared {
     }
 
     pub(super) fn encode_packet(&self, pkt: codec::Packet) -> Result<(), error::EncodeError> {
+        self.encode(pkt)
+    }
+
+    /// Set peer's topic alias maximum
+    pub(super) fn set_peer_topic_alias_max(&self, val: u16) {
+        self.aliases.borrow_mut().set_peer_max(val);
+    }
+
+    /// Enable outbound topic aliases
+    pub(super) fn set_outbound_topic_alias_max(&self, val: u16) {
+        let mut flags = self.flags.get();
+        flags.set(Flags::TOPIC_ALIAS, val != 0);
+        self.flags.set(flags);
+        self.aliases.borrow_mut().set_max(val);
+    }
+
+    /// Set client authenticator
+    pub(super) fn set_authenticator(&self, authenticator: Rc<RefCell<dyn Authenticator>>) {
+        *self.auth.borrow_mut() = Some(AuthState { authenticator, waiter: None });
+    }
+
+    /// Start re-authentication
+    pub(super) fn reauth(&self) -> Result<oneshot::Receiver<Result<(), AuthError>>, AuthError> {
+        let mut auth = self.auth.borrow_mut();
+        let state = auth
+            .as_mut()
+            .ok_or_else(|| AuthError::new("Enhanced authentication is not configured"))?;
+        if state.waiter.is_some() {
+            return Err(AuthError::new("Re-authentication is in progress"));
+        }
+        if self.is_closed() {
+            return Err(AuthError::new("Peer is disconnected"));
+        }
+
+        let mut authenticator = state.authenticator.borrow_mut();
+        let pkt = codec::Auth {
+            reason_code: codec::AuthReasonCode::ReAuth,
+            auth_method: Some(authenticator.method()),
+            auth_data: authenticator.start()?,
+            reason_string: None,
+            user_properties: Vec::new(),
+        };
+        log::trace!("Start re-authentication: {:?}", pkt);
+        self.encode(codec::Packet::Auth(pkt))
+            .map_err(|_| AuthError::new("Cannot encode AUTH packet"))?;
+
+        let (tx, rx) = oneshot::channel();
+        drop(authenticator);
+        state.waiter = Some(tx);
+        Ok(rx)
+    }
+
+    /// Handle AUTH packet of re-authentication exchange
+    pub(super) fn auth_packet(
+        &self,
+        pkt: codec::Auth,
+    ) -> Result<Option<codec::Packet>, error::ProtocolError> {
+        let mut auth = self.auth.borrow_mut();
+        let state = match auth.as_mut() {
+            Some(state) if state.waiter.is_some() => state,
+            _ => {
+                return Err(error::ProtocolError::unexpected_packet(
+                    packet_type::AUTH,
+                    "AUTH packet is not expected, re-authentication is not started",
+                ))
+            }
+        };
+
+        let mut authenticator = state.authenticator.borrow_mut();
+        let method = authenticator.method();
+        if pkt.auth_method.as_ref() != Some(&method) {
+            return Err(error::ProtocolError::generic_violation(
+                "Authentication method of AUTH packet does not match",
+            ));
+        }
+
+        let result = match pkt.reason_code {
+            codec::AuthReasonCode::ContinueAuth => {
+                match authenticator.challenge(pkt.auth_data.as_ref()) {
+                    Ok(data) => {
+                        return Ok(Some(codec::Packet::Auth(codec::Auth {
+                            reason_code: codec::AuthReasonCode::ContinueAuth,
+                            auth_method: Some(method),
+                            auth_data: data,
+                            reason_string: None,
+                            user_properties: Vec::new(),
+                        })))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            codec::AuthReasonCode::Success => authenticator.complete(pkt.auth_data.as_ref()),
+            codec::AuthReasonCode::ReAuth => {
+                return Err(error::ProtocolError::generic_violation(
+                    "Server must not send AUTH packet with ReAuth reason code",
+                ))
+            }
+        };
+        drop(authenticator);
+
+        let failed = result.is_err();
+        let _ = state.waiter.take().unwrap().send(result);
+        drop(auth);
+        if failed {
+            log::trace!("Re-authentication failed, closing connection");
+            self.close(codec::Disconnect {
+                reason_code: codec::DisconnectReasonCode::NotAuthorized,
+                ..Default::default()
+            });
+        }
+        Ok(None)
+    }
+
+    fn encode(&self, mut pkt: codec::Packet) -> Result<(), error::EncodeError> {
+        if self.flags.get().contains(Flags::TOPIC_ALIAS) {
+            if let codec::Packet::Publish(ref mut publish) = pkt {
+                let mut aliases = self.aliases.borrow_mut();
+                let new = aliases.apply(publish);
+                self.io.encode(pkt, &self.codec)?;
+                if let Some(item) = new {
+                    aliases.register(item);
+                }
+                return Ok(());
+            }
+        }
         self.io.encode(pkt, &self.codec)
     }
 
@@ -167,7 +361,51 @@ impl MqttShared {
 
     fn clear_queues(&self) {
         let mut queues = self.queues.borrow_mut();
+        let queues = &mut *queues;
+
+        // keep unacknowledged messages
+        if self.flags.get().contains(Flags::TRACK_INFLIGHT) {
+            let mut unacked = self.unacked.take();
+            let now = Instant::now();
+            let msgs = InflightMessages {
+                publishes: queues
+                    .inflight
+                    .iter()
+                    .filter_map(|(id, _, _)| unacked.remove(id))
+                    // topic alias is not valid for new connection
+                    .filter(|(pkt, _)| !pkt.topic.is_empty())
+                    // replayed publishes are not sent yet
+                    .chain(queues.replay.drain(..))
+                    .map(|(mut pkt, deadline)| {
+                        expiry::refresh_inflight(&mut pkt.properties, deadline, now);
+                        pkt
+                    })
+                    .collect(),
+                released: queues.released.iter().map(|item| item.0).collect(),
+                taken: Some(now),
+            };
+
+            if let Some(persist) = self.session.take() {
+                let now = store::unix_time();
+                let publishes = msgs
+                    .publishes
+                    .into_iter()
+                    .filter_map(|pkt| stored_publish(pkt, now))
+                    .collect();
+                persist.save(publishes, msgs.released);
+            } else if !msgs.is_empty() {
+                self.exported.set(Some(msgs));
+            }
+        }
+
         queues.waiters.clear();
+        queues.released.clear();
+        queues.replay.clear();
+        if let Some(ref mut state) = *self.auth.borrow_mut() {
+            state.waiter.take();
+        }
+        self.requests.borrow_mut().clear();
+        self.outbound.borrow_mut().clear();
 
         if let Some(cb) = self.on_publish_ack.take() {
             for (idx, tx, _) in queues.inflight.drain(..) {
@@ -191,10 +429,16 @@ impl MqttShared {
         flags.remove(Flags::WRB_ENABLED);
         self.flags.set(flags);
 
+        // queued messages use receive credit first
+        self.drain_outbound();
+        if !self.outbound.borrow().is_empty() {
+            return;
+        }
+
         // check if there are waiters
         let mut queues = self.queues.borrow_mut();
-        if queues.inflight.len() < self.cap.get() {
-            let mut num = self.cap.get() - queues.inflight.len();
+        if queues.len() < self.cap.get() {
+            let mut num = self.cap.get() - queues.len();
             while num > 0 {
                 if let Some(tx) = queues.waiters.pop_front() {
                     if tx.send(()).is_ok() {
@@ -214,12 +458,43 @@ impl MqttShared {
                 ..Default::default()
             });
             e
-        })
+        })?;
+        self.inflight_metrics(self.queues.borrow().len());
+
+        // pass remaining credit to waiters if outbound queue got drained
+        if self.drain_outbound() && self.is_ready() {
+            self.wake_waiter();
+        }
+        Ok(())
     }
 
     fn pkt_ack_inner(&self, pkt: Ack) -> Result<(), error::ProtocolError> {
         let mut queues = self.queues.borrow_mut();
 
+        // PUBCOMP is not ordered with other acks
+        if let Ack::Complete(pkt) = pkt {
+            return if let Some(pos) =
+                queues.released.iter().position(|item| item.0 == pkt.packet_id)
+            {
+                log::trace!("Complete QoS 2 publish with id: {}", pkt.packet_id);
+
+                let (_, tx, rec) = queues.released.remove(pos).unwrap();
+                queues.inflight_ids.remove(&pkt.packet_id);
+                if let Some(tx) = tx {
+                    let _ = tx.send(Ack::Completed(rec, pkt));
+                }
+                if queues.replay.is_empty() && self.outbound.borrow().is_empty() {
+                    queues.wake_waiter();
+                }
+                Ok(())
+            } else {
+                log::trace!("Unexpected PublishComplete packet");
+                Err(error::ProtocolError::generic_violation(
+                    "Received PUBCOMP packet while there are no unreleased PUBLISH packets",
+                ))
+            };
+        }
+
         // check ack order
         if let Some((idx, tx, tp)) = queues.inflight.pop_front() {
             if idx != pkt.packet_id() {
@@ -233,23 +508,36 @@ impl MqttShared {
                 // get publish ack channel
                 log::trace!("Ack packet with id: {}", pkt.packet_id());
 
-                // cleanup ack queue
-                queues.inflight_ids.remove(&pkt.packet_id());
-
                 if pkt.is_match(tp) {
+                    self.unacked.borrow_mut().remove(&idx);
+
+                    // successful PUBREC, send PUBREL and wait for PUBCOMP
+                    if let Ack::Receive(ref rec) = pkt {
+                        if u8::from(rec.reason_code) < 0x80 {
+                            let rel = codec::PublishAck2 {
+                                packet_id: rec.packet_id,
+                                ..Default::default()
+                            };
+                            self.io.encode(codec::Packet::PublishRelease(rel), &self.codec)?;
+                            queues.released.push_back((idx, tx, pkt.publish()));
+                            return Ok(());
+                        }
+                    }
+
+                    // cleanup ack queue
+                    queues.inflight_ids.remove(&pkt.packet_id());
+
                     if let Some(tx) = tx {
                         let _ = tx.send(pkt);
-                    } else {
-                        let cb = self.on_publish_ack.take().unwrap();
+                    } else if let Some(cb) = self.on_publish_ack.take() {
                         (*cb)(pkt.publish(), false);
                         self.on_publish_ack.set(Some(cb));
                     }
 
-                    // wake up queued request (receive max limit)
-                    while let Some(tx) = queues.waiters.pop_front() {
-                        if tx.send(()).is_ok() {
-                            break;
-                        }
+                    // wake up queued request (receive max limit),
+                    // replayed publishes and outbound queue are drained first
+                    if queues.replay.is_empty() && self.outbound.borrow().is_empty() {
+                        queues.wake_waiter();
                     }
                     Ok(())
                 } else {
@@ -268,6 +556,10 @@ impl MqttShared {
         }
     }
 
+    fn inflight_metrics(&self, depth: usize) {
+        self.codec.metrics().with(|m, p| m.inflight(p, depth));
+    }
+
     /// Register ack in response channel
     pub(super) fn wait_response(
         &self,
@@ -281,6 +573,7 @@ impl MqttShared {
             let (tx, rx) = self.pool.queue.channel();
             queues.inflight.push_back((id, Some(tx), ack));
             queues.inflight_ids.insert(id);
+            self.inflight_metrics(queues.len());
             Ok(rx)
         }
     }
@@ -296,11 +589,16 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
-            match self.io.encode(pkt, &self.codec) {
+            let retained = self.retain_publish(&pkt);
+            match self.encode(pkt) {
                 Ok(_) => {
+                    if let Some(pkt) = retained {
+                        self.unacked.borrow_mut().insert(id, pkt);
+                    }
                     let (tx, rx) = self.pool.queue.channel();
                     queues.inflight.push_back((id, Some(tx), ack));
                     queues.inflight_ids.insert(id);
+                    self.inflight_metrics(queues.len());
                     Ok(rx)
                 }
                 Err(e) => Err(SendPacketError::Encode(e)),
@@ -318,10 +616,15 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
-            match self.io.encode(pkt, &self.codec) {
+            let retained = self.retain_publish(&pkt);
+            match self.encode(pkt) {
                 Ok(_) => {
+                    if let Some(pkt) = retained {
+                        self.unacked.borrow_mut().insert(id, pkt);
+                    }
                     queues.inflight.push_back((id, None, ack));
                     queues.inflight_ids.insert(id);
+                    self.inflight_metrics(queues.len());
                     Ok(())
                 }
                 Err(e) => Err(SendPacketError::Encode(e)),
@@ -329,12 +632,366 @@ impl MqttShared {
         }
     }
 
+    fn retain_publish(&self, pkt: &codec::Packet) -> Option<(codec::Publish, Option<Instant>)> {
+        match pkt {
+            codec::Packet::Publish(pkt) if self.flags.get().contains(Flags::TRACK_INFLIGHT) => {
+                Some((pkt.clone(), expiry::deadline(&pkt.properties, Instant::now())))
+            }
+            _ => None,
+        }
+    }
+
+    pub(super) fn track_inflight(&self) {
+        let mut flags = self.flags.get();
+        flags.insert(Flags::TRACK_INFLIGHT);
+        self.flags.set(flags);
+    }
+
+    pub(super) fn take_inflight(&self) -> InflightMessages {
+        self.exported.take().unwrap_or_default()
+    }
+
+    /// Re-send in-flight messages of previous connection
+    ///
+    /// Packet ids are checked before anything is sent. Publishes that
+    /// exceed receive credit are sent when credit is released.
+    pub(super) fn replay_inflight(
+        &self,
+        msgs: InflightMessages,
+    ) -> Result<(), SendPacketError> {
+        {
+            let mut queues = self.queues.borrow_mut();
+
+            let mut ids = HashSet::default();
+            let publish_ids = msgs.publishes.iter().filter_map(|pkt| pkt.packet_id);
+            for id in msgs.released.iter().copied().chain(publish_ids) {
+                if queues.inflight_ids.contains(&id) || !ids.insert(id) {
+                    return Err(SendPacketError::PacketIdInUse(id));
+                }
+            }
+            let max_id = ids.iter().map(|id| id.get()).max().unwrap_or(0);
+
+            // PUBREL packets must be re-sent before publishes
+            for id in msgs.released {
+                let rel = codec::PublishAck2 { packet_id: id, ..Default::default() };
+                self.io.encode(codec::Packet::PublishRelease(rel), &self.codec)?;
+
+                let rec = codec::PublishAck { packet_id: id, ..Default::default() };
+                queues.released.push_back((id, None, rec));
+                queues.inflight_ids.insert(id);
+            }
+
+            let now = Instant::now();
+            for mut pkt in msgs.publishes {
+                let id = if let Some(id) = pkt.packet_id {
+                    id
+                } else {
+                    continue;
+                };
+                // account time elapsed after connection is closed
+                let deadline = match msgs.taken {
+                    Some(taken) => expiry::deadline(&pkt.properties, taken),
+                    None => expiry::deadline(&pkt.properties, now),
+                };
+                pkt.dup = true;
+                pkt.properties.topic_alias = None;
+                queues.replay.push_back((pkt, deadline));
+                queues.inflight_ids.insert(id);
+            }
+
+            // do not reuse replayed packet ids
+            self.inflight_idx.set(self.inflight_idx.get().max(max_id));
+        }
+        self.drain_replay();
+        Ok(())
+    }
+
+    /// Send replayed publishes while there is receive credit
+    ///
+    /// Returns `true` if all replayed publishes are sent.
+    fn drain_replay(&self) -> bool {
+        let mut queues = self.queues.borrow_mut();
+        while !queues.replay.is_empty() {
+            if queues.len() >= self.cap.get() || self.flags.get().contains(Flags::WRB_ENABLED) {
+                return false;
+            }
+            let (mut pkt, deadline) = queues.replay.pop_front().unwrap();
+            let id = pkt.packet_id.unwrap();
+            expiry::refresh_inflight(&mut pkt.properties, deadline, Instant::now());
+            let tp =
+                if pkt.qos == QoS::ExactlyOnce { AckType::Receive } else { AckType::Publish };
+
+            let retained = self
+                .flags
+                .get()
+                .contains(Flags::TRACK_INFLIGHT)
+                .then(|| (pkt.clone(), deadline));
+            if let Err(err) = self.encode(codec::Packet::Publish(pkt)) {
+                log::trace!("Cannot send replayed publish: {:?}", err);
+                queues.inflight_ids.remove(&id);
+                continue;
+            }
+            if let Some(pkt) = retained {
+                self.unacked.borrow_mut().insert(id, pkt);
+            }
+            queues.inflight.push_back((id, None, tp));
+        }
+        true
+    }
+
+    /// Load persisted session state
+    pub(super) fn open_session(
+        &self,
+        store: Rc<dyn SessionStore>,
+        client_id: ByteString,
+        clean_start: bool,
+        expiry_secs: u32,
+    ) {
+        let mut persist = SessionPersist::open(store, client_id, clean_start);
+        persist.set_expiry_secs(expiry_secs);
+        *self.session.borrow_mut() = Some(persist);
+        self.track_inflight();
+    }
+
+    /// Check if persisted session state is restored
+    pub(super) fn session_present(&self) -> bool {
+        self.session.borrow().as_ref().map(|p| p.restored().is_some()).unwrap_or(false)
+    }
+
+    /// Update session expiry interval
+    pub(super) fn set_session_expiry(&self, secs: u32) {
+        if let Some(ref mut persist) = *self.session.borrow_mut() {
+            persist.set_expiry_secs(secs);
+        }
+    }
+
+    /// Resend unacknowledged packets of restored session
+    pub(super) fn restore_session(&self) {
+        let state = if let Some(ref mut persist) = *self.session.borrow_mut() {
+            persist.take_restored()
+        } else {
+            None
+        };
+
+        if let Some(state) = state {
+            let now = store::unix_time();
+            let msgs = InflightMessages {
+                publishes: state
+                    .publishes
+                    .into_iter()
+                    .map(|item| restored_publish(item, now))
+                    .collect(),
+                released: state.released,
+                taken: None,
+            };
+            if let Err(e) = self.replay_inflight(msgs) {
+                log::error!("Cannot re-send messages of restored session: {:?}", e);
+            }
+        }
+    }
+
+    /// Record client subscribe request
+    pub(super) fn session_subscribe(&self, pkt: &codec::Subscribe) {
+        if let Some(ref mut persist) = *self.session.borrow_mut() {
+            persist.subscribe(
+                pkt.packet_id,
+                pkt.topic_filters.iter().map(|(f, opts)| (f.clone(), opts.qos)).collect(),
+            );
+        }
+    }
+
+    /// Record client unsubscribe request
+    pub(super) fn session_unsubscribe(&self, pkt: &codec::Unsubscribe) {
+        if let Some(ref mut persist) = *self.session.borrow_mut() {
+            persist.unsubscribe(pkt.packet_id, pkt.topic_filters.clone());
+        }
+    }
+
+    /// Apply SUBACK or UNSUBACK to session subscriptions
+    pub(super) fn session_ack(&self, pkt: &codec::Packet) {
+        if let Some(ref mut persist) = *self.session.borrow_mut() {
+            match pkt {
+                codec::Packet::SubscribeAck(ack) => persist.subscribed(
+                    ack.packet_id,
+                    ack.status.iter().map(|st| match st {
+                        codec::SubscribeAckReason::GrantedQos0 => Some(QoS::AtMostOnce),
+                        codec::SubscribeAckReason::GrantedQos1 => Some(QoS::AtLeastOnce),
+                        codec::SubscribeAckReason::GrantedQos2 => Some(QoS::ExactlyOnce),
+                        _ => None,
+                    }),
+                ),
+                codec::Packet::UnsubscribeAck(ack) => persist.unsubscribed(
+                    ack.packet_id,
+                    ack.status.iter().map(|st| u8::from(*st) < 0x80),
+                ),
+                _ => (),
+            }
+        }
+    }
+
+    /// Register pending request, returns generated correlation data
+    pub(super) fn wait_request_response(
+        &self,
+        topic: ByteString,
+    ) -> (Bytes, oneshot::Receiver<codec::Publish>) {
+        let mut idx = self.request_idx.get();
+        if idx == 0 {
+            // random prefix, responses of previous connections must not match
+            idx = ((utils::random() * u32::MAX as f64) as u64) << 32;
+        }
+        let idx = idx.wrapping_add(1);
+        self.request_idx.set(idx);
+
+        let data = Bytes::copy_from_slice(&idx.to_be_bytes());
+        let (tx, rx) = oneshot::channel();
+        self.requests.borrow_mut().insert(data.clone(), (topic, tx));
+        (data, rx)
+    }
+
+    /// Remove pending request
+    pub(super) fn cancel_request(&self, data: &Bytes) {
+        self.requests.borrow_mut().remove(data);
+    }
+
+    /// Complete pending request with response publish.
+    ///
+    /// Returns publish back if it is not a response to pending request.
+    pub(super) fn request_response(&self, pkt: codec::Publish) -> Option<codec::Publish> {
+        let mut requests = self.requests.borrow_mut();
+        if requests.is_empty() {
+            return Some(pkt);
+        }
+
+        let is_response = pkt
+            .properties
+            .correlation_data
+            .as_ref()
+            .and_then(|data| requests.get(data))
+            .is_some_and(|(topic, _)| *topic == pkt.topic);
+        if is_response {
+            let data = pkt.properties.correlation_data.as_ref().unwrap();
+            let (_, tx) = requests.remove(data).unwrap();
+            log::trace!("Response for request {:?} is received", data);
+            let _ = tx.send(pkt);
+            None
+        } else {
+            Some(pkt)
+        }
+    }
+
+    /// Send publish or add it to outbound queue
+    ///
+    /// Returns publish back if queue is full and publisher must wait.
+    pub(super) fn enqueue(
+        &self,
+        pkt: codec::Publish,
+    ) -> Result<Option<(codec::Publish, oneshot::Receiver<()>)>, SendPacketError> {
+        self.enqueue_inner(pkt, Outbound::push)
+    }
+
+    #[cfg(feature = "broker")]
+    /// Send publish or add it to outbound queue, full queue is not waited for
+    pub(super) fn try_enqueue(&self, pkt: codec::Publish) -> Result<(), SendPacketError> {
+        self.enqueue_inner(pkt, Outbound::try_push).map(|_| ())
+    }
+
+    fn enqueue_inner(
+        &self,
+        pkt: codec::Publish,
+        push: fn(&mut Outbound, codec::Publish) -> Push,
+    ) -> Result<Option<(codec::Publish, oneshot::Receiver<()>)>, SendPacketError> {
+        if self.is_closed() {
+            return Err(SendPacketError::Disconnected);
+        }
+
+        let ready = if pkt.qos == QoS::AtMostOnce {
+            !self.flags.get().contains(Flags::WRB_ENABLED)
+        } else {
+            self.is_ready()
+        };
+        if ready && self.outbound.borrow().is_empty() {
+            return self.send_queued(pkt).map(|_| None);
+        }
+
+        let result = push(&mut self.outbound.borrow_mut(), pkt);
+        match result {
+            Push::Queued | Push::Dropped => Ok(None),
+            Push::Full => Err(SendPacketError::QueueFull),
+            Push::Blocked(pkt, rx) => Ok(Some((*pkt, rx))),
+            Push::Overflow => {
+                log::trace!("Outbound queue is full, closing connection");
+                self.close(codec::Disconnect {
+                    reason_code: codec::DisconnectReasonCode::QuotaExceeded,
+                    ..Default::default()
+                });
+                Err(SendPacketError::QueueFull)
+            }
+        }
+    }
+
+    fn send_queued(&self, mut pkt: codec::Publish) -> Result<(), SendPacketError> {
+        log::trace!("Publish ({:?}) to {:?}", pkt.qos, pkt.topic);
+        let ack = match pkt.qos {
+            QoS::AtMostOnce => {
+                return self
+                    .encode(codec::Packet::Publish(pkt))
+                    .map_err(SendPacketError::Encode)
+            }
+            QoS::AtLeastOnce => AckType::Publish,
+            QoS::ExactlyOnce => AckType::Receive,
+        };
+        let idx = if let Some(idx) = pkt.packet_id {
+            idx
+        } else {
+            let idx = self.next_id();
+            pkt.packet_id = Some(idx);
+            idx
+        };
+        self.wait_packet_response_no_block(idx, ack, codec::Packet::Publish(pkt))
+    }
+
+    /// Send queued messages while there is receive credit
+    ///
+    /// Returns `true` if outbound queue got emptied.
+    fn drain_outbound(&self) -> bool {
+        // replayed publishes are sent before queued
+        if !self.drain_replay() || self.outbound.borrow().is_empty() {
+            return false;
+        }
+
+        loop {
+            let item = {
+                let mut outbound = self.outbound.borrow_mut();
+                let ready = match outbound.next_qos() {
+                    Some(QoS::AtMostOnce) => !self.flags.get().contains(Flags::WRB_ENABLED),
+                    Some(_) => self.is_ready(),
+                    None => return true,
+                };
+                if !ready {
+                    return false;
+                }
+                outbound.pop()
+            };
+
+            if let Some((mut pkt, deadline)) = item {
+                if !expiry::refresh(&mut pkt.properties, deadline, Instant::now()) {
+                    log::trace!("Queued publish to {:?} is expired", pkt.topic);
+                } else if let Err(err) = self.send_queued(pkt) {
+                    log::trace!("Cannot send queued publish: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Pass unused readiness notification to next queued request
+    pub(super) fn wake_waiter(&self) {
+        self.queues.borrow_mut().wake_waiter();
+    }
+
     pub(super) fn wait_readiness(&self) -> Option<pool::Receiver<()>> {
         let mut queues = self.queues.borrow_mut();
 
-        if queues.inflight.len() >= self.cap.get()
-            || self.flags.get().contains(Flags::WRB_ENABLED)
-        {
+        if queues.len() >= self.cap.get() || self.flags.get().contains(Flags::WRB_ENABLED) {
             let (tx, rx) = self.pool.waiters.channel();
             queues.waiters.push_back(tx);
             Some(rx)
@@ -344,6 +1001,69 @@ impl MqttShared {
     }
 }
 
+fn stored_publish(pkt: codec::Publish, now: u64) -> Option<StoredPublish> {
+    pkt.packet_id.map(|packet_id| StoredPublish {
+        packet_id,
+        qos: pkt.qos,
+        retain: pkt.retain,
+        topic: pkt.topic,
+        payload: pkt.payload,
+        correlation_data: pkt.properties.correlation_data,
+        message_expiry: pkt
+            .properties
+            .message_expiry_interval
+            .map(|secs| now.saturating_add(secs.get() as u64)),
+        content_type: pkt.properties.content_type,
+        user_properties: pkt.properties.user_properties,
+        is_utf8_payload: pkt.properties.is_utf8_payload,
+        response_topic: pkt.properties.response_topic,
+    })
+}
+
+/// Convert stored publish
+///
+/// Stored publish is in-flight, so it is re-sent even if it is expired.
+fn restored_publish(item: StoredPublish, now: u64) -> codec::Publish {
+    let message_expiry_interval = item.message_expiry.map(|expires| {
+        let secs = expires.saturating_sub(now).clamp(1, u32::MAX as u64) as u32;
+        NonZeroU32::new(secs).unwrap()
+    });
+
+    codec::Publish {
+        dup: true,
+        retain: item.retain,
+        qos: item.qos,
+        packet_id: Some(item.packet_id),
+        topic: item.topic,
+        payload: item.payload,
+        properties: codec::PublishProperties {
+            topic_alias: None,
+            correlation_data: item.correlation_data,
+            message_expiry_interval,
+            content_type: item.content_type,
+            user_properties: item.user_properties,
+            is_utf8_payload: item.is_utf8_payload,
+            response_topic: item.response_topic,
+            subscription_ids: Vec::new(),
+        },
+    }
+}
+
+impl MqttSharedQueues {
+    /// Number of packets that use receive credit
+    fn len(&self) -> usize {
+        self.inflight.len() + self.released.len()
+    }
+
+    fn wake_waiter(&mut self) {
+        while let Some(tx) = self.waiters.pop_front() {
+            if tx.send(()).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
 impl Encoder for MqttShared {
     type Item = codec::Packet;
     type Error = error::EncodeError;
@@ -367,12 +1087,17 @@ impl Decoder for MqttShared {
 #[derive(Copy, Clone)]
 pub(super) enum AckType {
     Publish,
+    Receive,
     Subscribe,
     Unsubscribe,
 }
 
 pub(super) enum Ack {
     Publish(codec::PublishAck),
+    Receive(codec::PublishAck),
+    Complete(codec::PublishAck2),
+    /// PUBREC and PUBCOMP of completed QoS 2 publish
+    Completed(codec::PublishAck, codec::PublishAck2),
     Subscribe(codec::SubscribeAck),
     Unsubscribe(codec::UnsubscribeAck),
 }
@@ -381,6 +1106,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) | Ack::Completed(..) => packet_type::PUBCOMP,
             Ack::Subscribe(_) => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -389,16 +1116,18 @@ impl Ack {
     pub(super) fn packet_id(&self) -> NonZeroU16 {
         match self {
             Ack::Publish(ref pkt) => pkt.packet_id,
+            Ack::Receive(ref pkt) => pkt.packet_id,
+            Ack::Complete(ref pkt) => pkt.packet_id,
+            Ack::Completed(_, ref pkt) => pkt.packet_id,
             Ack::Subscribe(ref pkt) => pkt.packet_id,
             Ack::Unsubscribe(ref pkt) => pkt.packet_id,
         }
     }
 
     pub(super) fn publish(self) -> codec::PublishAck {
-        if let Ack::Publish(pkt) = self {
-            pkt
-        } else {
-            panic!()
+        match self {
+            Ack::Publish(pkt) | Ack::Receive(pkt) => pkt,
+            _ => panic!(),
         }
     }
 
@@ -421,6 +1150,7 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
             (Ack::Subscribe(_), AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -432,6 +1162,7 @@ impl AckType {
     pub(super) fn expected_str(&self) -> &'static str {
         match self {
             AckType::Publish => "Expected PUBACK packet",
+            AckType::Receive => "Expected PUBREC packet",
             AckType::Subscribe => "Expected SUBACK packet",
             AckType::Unsubscribe => "Expected UNSUBACK packet",
         }
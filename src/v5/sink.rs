@@ -1,11 +1,13 @@
+use std::time::Instant;
 use std::{fmt, future::ready, future::Future, num::NonZeroU16, num::NonZeroU32, rc::Rc};
 
 use ntex::util::{ByteString, Bytes, Either, Ready};
 
 use super::{
-    codec, codec::EncodeLtd, error::SendPacketError, shared::AckType, shared::MqttShared,
+    codec, codec::EncodeLtd, error::AuthError, error::SendPacketError, expiry, shared::Ack,
+    shared::AckType, shared::MqttShared,
 };
-use crate::types::QoS;
+use crate::{trace, types::QoS};
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -24,6 +26,12 @@ impl MqttSink {
         self.0.clone()
     }
 
+    #[cfg(feature = "broker")]
+    /// Check if both sinks belong to the same connection
+    pub(crate) fn is_same(&self, other: &MqttSink) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
     #[inline]
     /// Check if io stream is open
     pub fn is_open(&self) -> bool {
@@ -125,6 +133,64 @@ impl MqttSink {
         self.0.set_publish_ack(Box::new(f));
     }
 
+    /// Keep unacknowledged QoS 1 and QoS 2 messages
+    ///
+    /// After connection is closed, in-flight messages could be taken
+    /// with `MqttSink::take_inflight()` and re-sent on a new connection.
+    pub fn track_inflight(&self) {
+        self.0.track_inflight();
+    }
+
+    /// Take in-flight messages of closed connection
+    ///
+    /// Messages are tracked only if `MqttSink::track_inflight()` is called.
+    /// Message expiry interval of publishes is rewritten to remaining lifetime,
+    /// expired publishes are kept because they are already sent.
+    pub fn take_inflight(&self) -> InflightMessages {
+        self.0.take_inflight()
+    }
+
+    /// Re-send in-flight messages of previous connection
+    ///
+    /// PUBREL packets are sent first, then publish packets with `dup` flag set,
+    /// in the order they were originally sent. Original packet ids are used.
+    /// Acknowledgements of replayed publishes are delivered to publish ack callback
+    /// if it is set. Message expiry interval is rewritten to remaining lifetime,
+    /// but never less than one second, already sent publishes are not expired.
+    ///
+    /// Nothing is sent if any packet id is already in use. Publishes that exceed
+    /// peer's receive maximum are sent when in-flight publishes get acknowledged.
+    pub fn replay_inflight(&self, msgs: InflightMessages) -> Result<(), SendPacketError> {
+        if self.0.is_closed() {
+            Err(SendPacketError::Disconnected)
+        } else {
+            self.0.replay_inflight(msgs)
+        }
+    }
+
+    /// Enable automatic topic aliases for outgoing publishes.
+    ///
+    /// Up to `max` most recently used topics get aliases, within the peer's
+    /// topic alias maximum. Publish packet carries only topic alias once
+    /// alias is established. Set value to 0 to disable.
+    pub fn auto_topic_alias(&self, max: u16) {
+        self.0.set_outbound_topic_alias_max(max);
+    }
+
+    /// Start re-authentication with client's authenticator.
+    ///
+    /// Future resolves when server completes authentication exchange.
+    /// Connection is closed if authenticator rejects server's data.
+    pub fn reauthenticate(&self) -> impl Future<Output = Result<(), AuthError>> {
+        let rx = self.0.reauth();
+        async move {
+            match rx?.await {
+                Ok(res) => res,
+                Err(_) => Err(AuthError::new("Peer is disconnected")),
+            }
+        }
+    }
+
     #[inline]
     /// Create subscribe packet builder
     pub fn subscribe(&self, id: Option<NonZeroU32>) -> SubscribeBuilder {
@@ -223,6 +289,8 @@ impl PublishBuilder {
     #[inline]
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(mut self) -> Result<(), SendPacketError> {
+        let span = trace::send_publish(self.shared.span(), &self.packet.topic, QoS::AtMostOnce);
+        let _enter = span.enter();
         if !self.shared.is_closed() {
             log::trace!("Publish (QoS-0) to {:?}", self.packet.topic);
             self.packet.qos = QoS::AtMostOnce;
@@ -236,21 +304,76 @@ impl PublishBuilder {
         }
     }
 
+    /// Send publish packet via outbound queue
+    ///
+    /// Publish is sent immediately if peer has receive credit, otherwise it is
+    /// added to the outbound queue and sent when in-flight messages get acknowledged.
+    /// Future resolves after message is sent or queued, queue overflow is handled
+    /// according to `OutboundQueue` configuration of the server. Acknowledgements of
+    /// QoS 1 messages are passed to `publish_ack_cb`. Queued messages are dropped
+    /// when connection is closed.
+    pub fn enqueue(mut self, qos: QoS) -> impl Future<Output = Result<(), SendPacketError>> {
+        self.packet.qos = qos;
+        let shared = self.shared;
+        let mut packet = self.packet;
+
+        let span = trace::send_publish(shared.span(), &packet.topic, qos);
+        let fut = async move {
+            loop {
+                match shared.enqueue(packet)? {
+                    None => return Ok(()),
+                    Some((pkt, rx)) => {
+                        if rx.await.is_err() {
+                            return Err(SendPacketError::Disconnected);
+                        }
+                        packet = pkt;
+                    }
+                }
+            }
+        };
+        trace::instrument(fut, span)
+    }
+
+    #[cfg(feature = "broker")]
+    /// Send publish packet via outbound queue without waiting
+    ///
+    /// Same as `enqueue()`, but if queue is full and overflow policy is
+    /// `QueueOverflow::Block` message is rejected with `SendPacketError::QueueFull`.
+    pub(crate) fn try_enqueue(mut self, qos: QoS) -> Result<(), SendPacketError> {
+        self.packet.qos = qos;
+        let span = trace::send_publish(self.shared.span(), &self.packet.topic, qos);
+        let _enter = span.enter();
+        self.shared.try_enqueue(self.packet)
+    }
+
     /// Send publish packet with QoS 1
+    ///
+    /// If publish waits for peer's receive maximum, message expiry interval
+    /// is rewritten to remaining lifetime. Expired publish is not sent and
+    /// future resolves with `SendPacketError::Expired`.
     pub fn send_at_least_once(
         self,
     ) -> impl Future<Output = Result<codec::PublishAck, SendPacketError>> {
-        if !self.shared.is_closed() {
+        let span =
+            trace::send_publish(self.shared.span(), &self.packet.topic, QoS::AtLeastOnce);
+        let fut = if !self.shared.is_closed() {
             let shared = self.shared;
             let mut packet = self.packet;
             packet.qos = QoS::AtLeastOnce;
 
             // handle client receive maximum
             if let Some(rx) = shared.wait_readiness() {
+                let deadline = expiry::deadline(&packet.properties, Instant::now());
                 Either::Left(Either::Left(async move {
                     if rx.await.is_err() {
                         return Err(SendPacketError::Disconnected);
                     }
+                    // message expiry interval elapses while message is queued
+                    if !expiry::refresh(&mut packet.properties, deadline, Instant::now()) {
+                        log::trace!("Publish to {:?} is expired", packet.topic);
+                        shared.wake_waiter();
+                        return Err(SendPacketError::Expired);
+                    }
                     Self::send_at_least_once_inner(packet, shared).await
                 }))
             } else {
@@ -258,13 +381,17 @@ impl PublishBuilder {
             }
         } else {
             Either::Right(Ready::Err(SendPacketError::Disconnected))
-        }
+        };
+        trace::instrument(fut, span)
     }
 
     /// Non-blocking send publish packet with QoS 1
     ///
     /// Panics if sink is not ready or publish ack callback is not set
     pub fn send_at_least_once_no_block(self) -> Result<(), SendPacketError> {
+        let span =
+            trace::send_publish(self.shared.span(), &self.packet.topic, QoS::AtLeastOnce);
+        let _enter = span.enter();
         if !self.shared.is_closed() {
             let shared = self.shared;
 
@@ -315,6 +442,101 @@ impl PublishBuilder {
             shared.wait_packet_response(idx, AckType::Publish, codec::Packet::Publish(packet));
         async move { rx?.await.map(|pkt| pkt.publish()).map_err(|_| SendPacketError::Disconnected) }
     }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// Future resolves after PUBCOMP is received from the peer or if PUBREC
+    /// indicates failure. Message expiry is handled the same way as for
+    /// `send_at_least_once()`.
+    pub fn send_exactly_once(
+        self,
+    ) -> impl Future<Output = Result<ExactlyOnceAck, SendPacketError>> {
+        let span =
+            trace::send_publish(self.shared.span(), &self.packet.topic, QoS::ExactlyOnce);
+        let fut = if !self.shared.is_closed() {
+            let shared = self.shared;
+            let mut packet = self.packet;
+            packet.qos = QoS::ExactlyOnce;
+
+            // handle client receive maximum
+            if let Some(rx) = shared.wait_readiness() {
+                let deadline = expiry::deadline(&packet.properties, Instant::now());
+                Either::Left(Either::Left(async move {
+                    if rx.await.is_err() {
+                        return Err(SendPacketError::Disconnected);
+                    }
+                    // message expiry interval elapses while message is queued
+                    if !expiry::refresh(&mut packet.properties, deadline, Instant::now()) {
+                        log::trace!("Publish to {:?} is expired", packet.topic);
+                        shared.wake_waiter();
+                        return Err(SendPacketError::Expired);
+                    }
+                    Self::send_exactly_once_inner(packet, shared).await
+                }))
+            } else {
+                Either::Left(Either::Right(Self::send_exactly_once_inner(packet, shared)))
+            }
+        } else {
+            Either::Right(Ready::Err(SendPacketError::Disconnected))
+        };
+        trace::instrument(fut, span)
+    }
+
+    fn send_exactly_once_inner(
+        mut packet: codec::Publish,
+        shared: Rc<MqttShared>,
+    ) -> impl Future<Output = Result<ExactlyOnceAck, SendPacketError>> {
+        // packet id
+        let idx = if let Some(idx) = packet.packet_id {
+            idx
+        } else {
+            let idx = shared.next_id();
+            packet.packet_id = Some(idx);
+            idx
+        };
+
+        // send publish to client
+        log::trace!("Publish (QoS2) to {:#?}", packet);
+
+        let rx =
+            shared.wait_packet_response(idx, AckType::Receive, codec::Packet::Publish(packet));
+        async move {
+            match rx?.await.map_err(|_| SendPacketError::Disconnected)? {
+                Ack::Completed(rec, comp) => {
+                    Ok(ExactlyOnceAck { received: rec, completed: Some(comp) })
+                }
+                pkt => Ok(ExactlyOnceAck { received: pkt.publish(), completed: None }),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Result of QoS 2 publish
+pub struct ExactlyOnceAck {
+    /// PUBREC packet
+    pub received: codec::PublishAck,
+    /// PUBCOMP packet, it is not set if PUBREC reason code indicates failure
+    pub completed: Option<codec::PublishAck2>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// Unacknowledged messages of a connection
+pub struct InflightMessages {
+    /// QoS 1 and QoS 2 publishes without PUBACK or PUBREC
+    pub publishes: Vec<codec::Publish>,
+    /// Packet ids of QoS 2 publishes without PUBCOMP
+    pub released: Vec<NonZeroU16>,
+    /// Time when messages are taken from closed connection,
+    /// message expiry intervals of publishes are relative to it
+    pub(super) taken: Option<Instant>,
+}
+
+impl InflightMessages {
+    /// Check if there are no in-flight messages
+    pub fn is_empty(&self) -> bool {
+        self.publishes.is_empty() && self.released.is_empty()
+    }
 }
 
 /// Subscribe packet builder
@@ -364,6 +586,11 @@ impl SubscribeBuilder {
 
     /// Send subscribe packet
     pub async fn send(self) -> Result<codec::SubscribeAck, SendPacketError> {
+        let span = trace::send(self.shared.span(), "subscribe");
+        trace::instrument(self.send_inner(), span).await
+    }
+
+    async fn send_inner(self) -> Result<codec::SubscribeAck, SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
 
@@ -440,6 +667,11 @@ impl UnsubscribeBuilder {
 
     /// Send unsubscribe packet
     pub async fn send(self) -> Result<codec::UnsubscribeAck, SendPacketError> {
+        let span = trace::send(self.shared.span(), "unsubscribe");
+        trace::instrument(self.send_inner(), span).await
+    }
+
+    async fn send_inner(self) -> Result<codec::UnsubscribeAck, SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
 
@@ -0,0 +1,626 @@
+#![cfg(feature = "broker")]
+use std::{cell::RefCell, convert::TryFrom, rc::Rc};
+
+use ntex::server;
+use ntex::service::{fn_factory_with_config, fn_service};
+use ntex::time::{sleep, Millis};
+use ntex::util::{ByteString, Bytes, Ready};
+
+use ntex_mqtt::broker::InMemoryRetainedStore;
+use ntex_mqtt::broker::{Broker, SharedPolicy};
+use ntex_mqtt::{v3, v5, MqttServer, QoS};
+
+#[derive(Debug)]
+struct TestError;
+
+impl From<()> for TestError {
+    fn from(_: ()) -> Self {
+        TestError
+    }
+}
+
+impl TryFrom<TestError> for v5::PublishAck {
+    type Error = TestError;
+
+    fn try_from(err: TestError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+#[derive(Clone)]
+struct St(ByteString);
+
+#[ntex::test]
+async fn test_broker() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let broker = Broker::new().max_qos(QoS::AtLeastOnce);
+        let (b1, b2, b3) = (broker.clone(), broker.clone(), broker.clone());
+        let (b4, b5, b6) = (broker.clone(), broker.clone(), broker);
+
+        MqttServer::new()
+            .v3(v3::MqttServer::new(move |con: v3::Handshake| {
+                let id = con.packet().client_id.clone();
+                b1.connect(id.clone(), con.sink());
+                Ready::Ok::<_, TestError>(con.ack(St(id), false))
+            })
+            .publish(fn_factory_with_config(move |session: v3::Session<St>| {
+                let broker = b2.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: v3::Publish| {
+                    broker.publish_v3(&session.state().0, &p);
+                    Ready::Ok::<_, TestError>(())
+                }))
+            }))
+            .control(fn_factory_with_config(move |session: v3::Session<St>| {
+                let broker = b3.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                    v3::ControlMessage::Subscribe(mut msg) => {
+                        broker.subscribe_v3(&session.state().0, &mut msg);
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    v3::ControlMessage::Closed(msg) => {
+                        broker.closed(&session.state().0, session.sink().clone());
+                        Ready::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            })))
+            .v5(v5::MqttServer::new(move |con: v5::Handshake| {
+                let id = con.packet().client_id.clone();
+                b4.connect(id.clone(), con.sink());
+                Ready::Ok::<_, TestError>(con.ack(St(id)))
+            })
+            .publish(fn_factory_with_config(move |session: v5::Session<St>| {
+                let broker = b5.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: v5::Publish| {
+                    broker.publish_v5(&session.state().0, &p);
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .control(fn_factory_with_config(move |session: v5::Session<St>| {
+                let broker = b6.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                    v5::ControlMessage::Subscribe(mut msg) => {
+                        broker.subscribe_v5(&session.state().0, &mut msg);
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    v5::ControlMessage::Unsubscribe(mut msg) => {
+                        broker.unsubscribe_v5(&session.state().0, &mut msg);
+                        Ready::Ok(msg.ack())
+                    }
+                    v5::ControlMessage::Closed(msg) => {
+                        broker.closed(&session.state().0, session.sink().clone());
+                        Ready::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            })))
+    });
+
+    // v5 subscriber
+    let received5 = Rc::new(RefCell::new(Vec::new()));
+    let received = received5.clone();
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("v5").connect().await.unwrap();
+    let sink5 = client.sink();
+    let router = client.resource("a/b", move |p: v5::Publish| {
+        received.borrow_mut().push((
+            p.packet().topic.to_string(),
+            p.qos(),
+            p.packet().properties.subscription_ids.clone(),
+        ));
+        Ready::Ok::<_, TestError>(p.ack())
+    });
+    ntex::rt::spawn(router.start_default());
+
+    // v3 subscriber
+    let received3 = Rc::new(RefCell::new(Vec::new()));
+    let received = received3.clone();
+    let client =
+        v3::client::MqttConnector::new(srv.addr()).client_id("v3").connect().await.unwrap();
+    let sink3 = client.sink();
+    let router = client.resource(["a/b", "b/c"], move |p: v3::Publish| {
+        received.borrow_mut().push((p.packet().topic.to_string(), p.qos()));
+        Ready::Ok::<_, TestError>(())
+    });
+    ntex::rt::spawn(router.start_default());
+
+    let res = sink3
+        .subscribe()
+        .topic_filter("a/+".into(), QoS::ExactlyOnce)
+        .topic_filter("b/#".into(), QoS::AtMostOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res,
+        vec![
+            v3::codec::SubscribeReturnCode::Success(QoS::AtLeastOnce),
+            v3::codec::SubscribeReturnCode::Success(QoS::AtMostOnce)
+        ]
+    );
+
+    let res = sink5
+        .subscribe(std::num::NonZeroU32::new(7))
+        .topic_filter(
+            "a/b".into(),
+            v5::codec::SubscriptionOptions {
+                qos: QoS::ExactlyOnce,
+                no_local: true,
+                retain_as_published: false,
+                retain_handling: v5::codec::RetainHandling::AtSubscribe,
+            },
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status, vec![v5::codec::SubscribeAckReason::GrantedQos1]);
+
+    // publish from v5 session is routed to v3 session with granted qos,
+    // v5 session does not receive own publishes
+    sink5
+        .publish(ByteString::from_static("a/b"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*received3.borrow(), vec![("a/b".to_string(), QoS::AtLeastOnce)]);
+    assert!(received5.borrow().is_empty());
+
+    // publish with lower qos is not upgraded
+    sink3.publish(ByteString::from_static("a/b"), Bytes::new()).send_at_most_once().unwrap();
+    sink3.publish(ByteString::from_static("c"), Bytes::new()).send_at_most_once().unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(
+        *received3.borrow(),
+        vec![("a/b".to_string(), QoS::AtLeastOnce), ("a/b".to_string(), QoS::AtMostOnce)]
+    );
+
+    // qos is limited by subscription
+    sink5
+        .publish(ByteString::from_static("b/c"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(received3.borrow()[2], ("b/c".to_string(), QoS::AtMostOnce));
+    assert_eq!(
+        *received5.borrow(),
+        vec![("a/b".to_string(), QoS::AtMostOnce, vec![std::num::NonZeroU32::new(7).unwrap()])]
+    );
+
+    // unsubscribe
+    let res = sink5.unsubscribe().topic_filter("a/b".into()).send().await.unwrap();
+    assert_eq!(res.status, vec![v5::codec::UnsubscribeAckReason::Success]);
+    let res = sink5.unsubscribe().topic_filter("a/b".into()).send().await.unwrap();
+    assert_eq!(res.status, vec![v5::codec::UnsubscribeAckReason::NoSubscriptionExisted]);
+
+    sink3
+        .publish(ByteString::from_static("a/b"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(received3.borrow().len(), 4);
+    assert_eq!(received5.borrow().len(), 1);
+
+    sink3.close();
+    sink5.close();
+    Ok(())
+}
+
+/// v5 server with broker
+fn v5_server<F>(factory: F) -> server::TestServer
+where
+    F: Fn() -> Broker + Send + Clone + 'static,
+{
+    server::test_server(move || {
+        let broker = factory();
+        let (b1, b2, b3) = (broker.clone(), broker.clone(), broker);
+
+        v5::MqttServer::new(move |con: v5::Handshake| {
+            let id = con.packet().client_id.clone();
+            b1.connect(id.clone(), con.sink());
+            b1.will_v5(&id, con.packet());
+            Ready::Ok::<_, TestError>(con.ack(St(id)))
+        })
+        .publish(fn_factory_with_config(move |session: v5::Session<St>| {
+            let broker = b2.clone();
+            Ready::Ok::<_, TestError>(fn_service(move |p: v5::Publish| {
+                broker.publish_v5(&session.state().0, &p);
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .control(fn_factory_with_config(move |session: v5::Session<St>| {
+            let broker = b3.clone();
+            Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                v5::ControlMessage::Subscribe(mut msg) => {
+                    broker.subscribe_v5(&session.state().0, &mut msg);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                v5::ControlMessage::Disconnect(msg) => {
+                    broker.peer_disconnect_v5(&session.state().0, &msg);
+                    Ready::Ok(msg.ack())
+                }
+                v5::ControlMessage::Closed(msg) => {
+                    broker.closed(&session.state().0, session.sink().clone());
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            }))
+        }))
+        .finish()
+    })
+}
+
+#[ntex::test]
+async fn test_broker_shared() -> std::io::Result<()> {
+    let srv = v5_server(|| Broker::new().shared_policy(SharedPolicy::RoundRobin));
+
+    // two members of share group
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut sinks = Vec::new();
+    for id in ["s1", "s2"] {
+        let client =
+            v5::client::MqttConnector::new(srv.addr()).client_id(id).connect().await.unwrap();
+        let sink = client.sink();
+        let rcv = received.clone();
+        let router = client.resource("a/b", move |p: v5::Publish| {
+            rcv.borrow_mut().push(id);
+            Ready::Ok::<_, TestError>(p.ack())
+        });
+        ntex::rt::spawn(router.start_default());
+
+        let res = sink
+            .subscribe(None)
+            .topic_filter("$share/g/a/+".into(), v5::codec::SubscriptionOptions::default())
+            .topic_filter(
+                "$share/g/a/b".into(),
+                v5::codec::SubscriptionOptions { no_local: true, ..Default::default() },
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            res.status,
+            vec![
+                v5::codec::SubscribeAckReason::GrantedQos0,
+                v5::codec::SubscribeAckReason::TopicFilterInvalid
+            ]
+        );
+        sinks.push(sink);
+    }
+
+    // each message is delivered to one member
+    for _ in 0..4 {
+        sinks[0]
+            .publish(ByteString::from_static("a/b"), Bytes::new())
+            .send_at_most_once()
+            .unwrap();
+    }
+    sleep(Millis(50)).await;
+    let mut received = received.borrow().clone();
+    assert_eq!(received.len(), 4);
+    received.sort_unstable();
+    assert_eq!(received, vec!["s1", "s1", "s2", "s2"]);
+
+    for sink in sinks {
+        sink.close();
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_broker_retained() -> std::io::Result<()> {
+    let store = InMemoryRetainedStore::new();
+    let srv = v5_server(move || Broker::new().retained_store(store.clone()));
+
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("pub").connect().await.unwrap();
+    let publisher = client.sink();
+    ntex::rt::spawn(client.start_default());
+    for topic in ["r/a", "r/b"] {
+        publisher
+            .publish(ByteString::from_static(topic), Bytes::from_static(b"data"))
+            .retain(true)
+            .send_at_least_once()
+            .await
+            .unwrap();
+    }
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let rcv = received.clone();
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("sub").connect().await.unwrap();
+    let sink = client.sink();
+    let router = client.resource(["r/a", "r/b"], move |p: v5::Publish| {
+        let pkt = p.packet();
+        rcv.borrow_mut().push((pkt.topic.to_string(), pkt.retain, pkt.payload.len()));
+        Ready::Ok::<_, TestError>(p.ack())
+    });
+    ntex::rt::spawn(router.start_default());
+
+    let subscribe = |filter: &'static str, retain_handling| {
+        sink.subscribe(None)
+            .topic_filter(
+                ByteString::from_static(filter),
+                v5::codec::SubscriptionOptions {
+                    retain_handling,
+                    qos: QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                },
+            )
+            .send()
+    };
+    let take = || {
+        let mut items = received.borrow_mut().split_off(0);
+        items.sort();
+        items
+    };
+
+    // retained messages are delivered on subscribe
+    subscribe("r/+", v5::codec::RetainHandling::AtSubscribe).await.unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(take(), vec![("r/a".to_string(), true, 4), ("r/b".to_string(), true, 4)]);
+
+    // subscription exists
+    subscribe("r/+", v5::codec::RetainHandling::AtSubscribeNew).await.unwrap();
+    subscribe("r/#", v5::codec::RetainHandling::NoAtSubscribe).await.unwrap();
+    sleep(Millis(50)).await;
+    assert!(take().is_empty());
+    subscribe("r/+", v5::codec::RetainHandling::AtSubscribe).await.unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(take().len(), 2);
+
+    // empty payload clears retained message, routed message has no retain flag
+    publisher
+        .publish(ByteString::from_static("r/a"), Bytes::new())
+        .retain(true)
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(take(), vec![("r/a".to_string(), false, 0)]);
+    subscribe("r/a", v5::codec::RetainHandling::AtSubscribeNew).await.unwrap();
+    sleep(Millis(50)).await;
+    assert!(take().is_empty());
+    subscribe("r/b", v5::codec::RetainHandling::AtSubscribeNew).await.unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(take(), vec![("r/b".to_string(), true, 4)]);
+
+    sink.close();
+    publisher.close();
+    Ok(())
+}
+
+fn will(topic: &'static str, delay: Option<u32>) -> v5::codec::LastWill {
+    v5::codec::LastWill {
+        qos: QoS::AtMostOnce,
+        retain: false,
+        topic: ByteString::from_static(topic),
+        message: Bytes::from_static(b"will"),
+        will_delay_interval_sec: delay,
+        correlation_data: None,
+        message_expiry_interval: None,
+        content_type: None,
+        user_properties: Vec::new(),
+        is_utf8_payload: None,
+        response_topic: None,
+    }
+}
+
+#[ntex::test]
+async fn test_broker_will() -> std::io::Result<()> {
+    let srv = v5_server(Broker::new);
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let rcv = received.clone();
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("sub").connect().await.unwrap();
+    let sink = client.sink();
+    let router = client.resource(
+        ["will/1", "will/2", "will/3", "will/4", "will/5", "will/6"],
+        move |p: v5::Publish| {
+            rcv.borrow_mut().push(p.packet().topic.to_string());
+            Ready::Ok::<_, TestError>(p.ack())
+        },
+    );
+    ntex::rt::spawn(router.start_default());
+    sink.subscribe(None)
+        .topic_filter("will/#".into(), v5::codec::SubscriptionOptions::default())
+        .send()
+        .await
+        .unwrap();
+
+    let addr = srv.addr();
+    let connect = |id: &'static str, will: v5::codec::LastWill| async move {
+        let client = v5::client::MqttConnector::new(addr)
+            .client_id(id)
+            .last_will(will)
+            .packet(|pkt| pkt.session_expiry_interval_secs = 10)
+            .connect()
+            .await
+            .unwrap();
+        let sink = client.sink();
+        ntex::rt::spawn(client.start_default());
+        sink
+    };
+
+    // connection is lost
+    connect("c1", will("will/1", None)).await.force_close();
+    sleep(Millis(100)).await;
+    assert_eq!(received.borrow_mut().split_off(0), vec!["will/1".to_string()]);
+
+    // normal disconnect
+    connect("c2", will("will/2", None)).await.close();
+    sleep(Millis(100)).await;
+    assert!(received.borrow().is_empty());
+
+    // disconnect with will message
+    connect("c3", will("will/3", None)).await.close_with_reason(v5::codec::Disconnect {
+        reason_code: v5::codec::DisconnectReasonCode::DisconnectWithWillMessage,
+        ..Default::default()
+    });
+    sleep(Millis(100)).await;
+    assert_eq!(received.borrow_mut().split_off(0), vec!["will/3".to_string()]);
+
+    // will delay interval
+    connect("c4", will("will/4", Some(1))).await.force_close();
+    sleep(Millis(100)).await;
+    assert!(received.borrow().is_empty());
+    sleep(Millis(1100)).await;
+    assert_eq!(received.borrow_mut().split_off(0), vec!["will/4".to_string()]);
+
+    // reconnect within will delay interval
+    connect("c5", will("will/5", Some(1))).await.force_close();
+    sleep(Millis(100)).await;
+    let c5 = connect("c5", will("will/5", None)).await;
+    sleep(Millis(1200)).await;
+    assert!(received.borrow().is_empty());
+    c5.close();
+
+    // will delay interval is limited by session expiry interval
+    let client = v5::client::MqttConnector::new(addr)
+        .client_id("c6")
+        .last_will(will("will/6", Some(10)))
+        .connect()
+        .await
+        .unwrap();
+    let c6 = client.sink();
+    ntex::rt::spawn(client.start_default());
+    c6.force_close();
+    sleep(Millis(100)).await;
+    assert_eq!(received.borrow_mut().split_off(0), vec!["will/6".to_string()]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_broker_takeover() -> std::io::Result<()> {
+    let srv = v5_server(Broker::new);
+
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("sub").connect().await.unwrap();
+    let sink1 = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink1
+        .subscribe(None)
+        .topic_filter("a/b".into(), v5::codec::SubscriptionOptions::default())
+        .send()
+        .await
+        .unwrap();
+
+    // new connection takes over the session, subscriptions are preserved
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let rcv = received.clone();
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("sub").connect().await.unwrap();
+    let sink2 = client.sink();
+    let router = client.resource("a/b", move |p: v5::Publish| {
+        rcv.borrow_mut().push(p.packet().topic.to_string());
+        Ready::Ok::<_, TestError>(p.ack())
+    });
+    ntex::rt::spawn(router.start_default());
+
+    // closed connection does not affect session that took over
+    sink1.close();
+    sleep(Millis(100)).await;
+
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("pub").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish(ByteString::from_static("a/b"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(*received.borrow(), vec!["a/b".to_string()]);
+
+    sink.close();
+    sink2.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_broker_queue() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let broker = Broker::new().max_queue(2);
+        let (b1, b2, b3) = (broker.clone(), broker.clone(), broker);
+
+        MqttServer::new().v3(v3::MqttServer::new(move |con: v3::Handshake| {
+            let id = con.packet().client_id.clone();
+            b1.connect(id.clone(), con.sink());
+            Ready::Ok::<_, TestError>(con.ack(St(id), false).inflight(1))
+        })
+        .publish(fn_factory_with_config(move |session: v3::Session<St>| {
+            let broker = b2.clone();
+            Ready::Ok::<_, TestError>(fn_service(move |p: v3::Publish| {
+                broker.publish_v3(&session.state().0, &p);
+                Ready::Ok::<_, TestError>(())
+            }))
+        }))
+        .control(fn_factory_with_config(move |session: v3::Session<St>| {
+            let broker = b3.clone();
+            Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                v3::ControlMessage::Subscribe(mut msg) => {
+                    broker.subscribe_v3(&session.state().0, &mut msg);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                v3::ControlMessage::Closed(msg) => {
+                    broker.closed(&session.state().0, session.sink().clone());
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            }))
+        })))
+    });
+
+    // subscriber acknowledges publishes slowly
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let rcv = received.clone();
+    let client =
+        v3::client::MqttConnector::new(srv.addr()).client_id("sub").connect().await.unwrap();
+    let sink = client.sink();
+    let router = client.resource("a/b", move |p: v3::Publish| {
+        rcv.borrow_mut().push(p.packet().payload.clone());
+        async {
+            sleep(Millis(20)).await;
+            Ok::<_, TestError>(())
+        }
+    });
+    ntex::rt::spawn(router.start_default());
+    sink.subscribe().topic_filter("a/b".into(), QoS::AtLeastOnce).send().await.unwrap();
+
+    // one publish is in-flight, two are queued, others are dropped
+    let client =
+        v3::client::MqttConnector::new(srv.addr()).client_id("pub").connect().await.unwrap();
+    let pub_sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    for idx in 0..5u8 {
+        pub_sink
+            .publish(ByteString::from_static("a/b"), Bytes::from(vec![idx]))
+            .send_at_least_once()
+            .await
+            .unwrap();
+    }
+    sleep(Millis(200)).await;
+    assert_eq!(
+        *received.borrow(),
+        vec![Bytes::from(vec![0]), Bytes::from(vec![1]), Bytes::from(vec![2])]
+    );
+
+    // queue is drained, new publish is delivered
+    pub_sink
+        .publish(ByteString::from_static("a/b"), Bytes::from(vec![5]))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(received.borrow().last(), Some(&Bytes::from(vec![5])));
+
+    pub_sink.close();
+    sink.close();
+    Ok(())
+}
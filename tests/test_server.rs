@@ -1,4 +1,5 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
 use std::{cell::RefCell, future::Future, num::NonZeroU16, pin::Pin, rc::Rc, time::Duration};
 
 use ntex::service::{fn_service, Pipeline, ServiceFactory};
@@ -6,10 +7,14 @@ use ntex::time::{sleep, Millis, Seconds};
 use ntex::util::{join_all, lazy, ByteString, Bytes, BytesMut, Ready};
 use ntex::{codec::Encoder, server, service::chain_factory};
 
+use ntex_mqtt::auth::{Access, Acl, Identity, NotAuthorized};
+use ntex_mqtt::metrics::PrometheusMetrics;
+use ntex_mqtt::store::{InMemorySessionStore, SessionStore};
 use ntex_mqtt::v3::{
-    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, Authorization, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
+    Session,
 };
-use ntex_mqtt::{error::ProtocolError, QoS};
+use ntex_mqtt::{error::ProtocolError, Admission, QoS, RateLimit, SessionRegistry};
 
 struct St;
 
@@ -46,6 +51,130 @@ async fn test_simple() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_metrics() -> std::io::Result<()> {
+    let metrics = PrometheusMetrics::new();
+    let metrics2 = metrics.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .metrics(metrics2.clone())
+            .publish(|_t| Ready::Ok(()))
+            .finish()
+    });
+
+    let client_metrics = PrometheusMetrics::new();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .metrics(client_metrics.clone())
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+    sleep(Millis(150)).await;
+
+    let text = metrics.render();
+    for line in [
+        "mqtt_connections_opened_total{protocol=\"v3\"} 1",
+        "mqtt_connections_closed_total{protocol=\"v3\"} 1",
+        "mqtt_packets_received_total{protocol=\"v3\",type=\"connect\"} 1",
+        "mqtt_packets_received_total{protocol=\"v3\",type=\"publish\"} 1",
+        "mqtt_packets_sent_total{protocol=\"v3\",type=\"puback\"} 1",
+        "mqtt_handshake_duration_seconds_count{protocol=\"v3\"} 1",
+        "mqtt_connections_opened_total{protocol=\"v5\"} 0",
+    ] {
+        assert!(text.lines().any(|l| l == line), "{} not found in:\n{}", line, text);
+    }
+
+    let client_text = client_metrics.render();
+    for line in [
+        "mqtt_packets_sent_total{protocol=\"v3\",type=\"publish\"} 1",
+        "mqtt_packets_received_total{protocol=\"v3\",type=\"puback\"} 1",
+        "mqtt_inflight_messages_count{protocol=\"v3\"} 2",
+    ] {
+        assert!(client_text.lines().any(|l| l == line), "{} not found", line);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_rate_limit_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|packet: Handshake| {
+            let limit = RateLimit::new().messages(1).disconnect_after(2);
+            Ready::Ok::<_, ()>(packet.ack(St, false).rate_limit(limit))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    for _ in 0..3 {
+        let pkt = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from("test"),
+            packet_id: None,
+            payload: Bytes::new(),
+        };
+        io.send(pkt.into(), &codec).await.unwrap();
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_admission() -> std::io::Result<()> {
+    let admission = Admission::new().max_connections(1);
+    let admission2 = admission.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .admission(admission2.clone())
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("user2")
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    match err {
+        client::ClientError::Ack(ack) => {
+            assert_eq!(ack.return_code, codec::ConnectAckReason::ServiceUnavailable)
+        }
+        _ => panic!("{:?}", err),
+    }
+
+    // slot is released on disconnect
+    drop(io);
+    sleep(Millis(100)).await;
+    assert_eq!(admission.connections(), 0);
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_connect_fail() -> std::io::Result<()> {
     // bad user name or password
@@ -612,6 +741,364 @@ async fn test_sink_publish_noblock() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_qos2() -> std::io::Result<()> {
+    let server_received = Arc::new(AtomicBool::new(false));
+    let server_received2 = server_received.clone();
+    let server_sent = Arc::new(AtomicBool::new(false));
+    let server_sent2 = server_sent.clone();
+
+    let srv = server::test_server(move || {
+        let received = server_received2.clone();
+        let sent = server_sent2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            let sent = sent.clone();
+            ntex::rt::spawn(async move {
+                let res = sink
+                    .publish(ByteString::from_static("test"), Bytes::new())
+                    .send_exactly_once()
+                    .await;
+                sent.store(res.is_ok(), Relaxed);
+            });
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .max_qos(QoS::ExactlyOnce)
+        .publish(move |p: Publish| {
+            assert_eq!(p.qos(), QoS::ExactlyOnce);
+            received.store(true, Relaxed);
+            Ready::Ok(())
+        })
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    let client_received = Rc::new(RefCell::new(Vec::new()));
+    let client_received2 = client_received.clone();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+        client::ControlMessage::Publish(p) => {
+            client_received2.borrow_mut().push(p.packet().qos);
+            Ready::Ok(p.ack())
+        }
+        _ => Ready::Ok(msg.disconnect()),
+    }));
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_exactly_once().await;
+    assert!(res.is_ok());
+    assert!(server_received.load(Relaxed));
+
+    sleep(Millis(50)).await;
+    assert!(server_sent.load(Relaxed));
+    assert_eq!(*client_received.borrow(), vec![QoS::ExactlyOnce]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_exactly_once() -> std::io::Result<()> {
+    let completed = Arc::new(AtomicBool::new(false));
+    let completed2 = completed.clone();
+
+    let srv = server::test_server(move || {
+        let completed = completed2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            let completed = completed.clone();
+            ntex::rt::spawn(async move {
+                let res = sink
+                    .publish(ByteString::from_static("test"), Bytes::new())
+                    .send_exactly_once()
+                    .await;
+                completed.store(res.is_ok(), Relaxed);
+            });
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert_eq!(publish.qos, QoS::ExactlyOnce);
+            publish.packet_id.unwrap()
+        }
+        _ => panic!("{:?}", pkt),
+    };
+
+    io.send(codec::Packet::PublishReceived { packet_id }, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt.0, codec::Packet::PublishRelease { packet_id });
+    assert!(!completed.load(Relaxed));
+
+    io.send(codec::Packet::PublishComplete { packet_id }, &codec).await.unwrap();
+    sleep(Millis(50)).await;
+    assert!(completed.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_store() -> std::io::Result<()> {
+    let store = InMemorySessionStore::new();
+    let store2 = store.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| {
+            if let Some(state) = packet.session_state() {
+                assert_eq!(state.subscriptions.len(), 1);
+                assert_eq!(state.subscriptions[0].filter, "topic/#");
+            } else {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    let _ = sink
+                        .publish(ByteString::from_static("topic/1"), Bytes::new())
+                        .send_at_least_once()
+                        .await;
+                });
+            }
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .session_store(store2.clone())
+        .publish(|_| Ready::Ok(()))
+        .control(|msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    sub.subscribe(sub.qos());
+                }
+                Ready::Ok(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect =
+        codec::Connect { clean_session: false, ..Default::default() }.client_id("user");
+
+    // first connection, publish is not acknowledged
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        })
+    );
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert!(!publish.dup);
+            publish.packet_id.unwrap()
+        }
+        _ => panic!("{:?}", pkt),
+    };
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("topic/#"), QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Millis(100)).await;
+
+    let state = store.load("user").unwrap();
+    assert_eq!(state.subscriptions.len(), 1);
+    assert_eq!(state.publishes.len(), 1);
+
+    // reconnect, publish is re-sent with dup flag
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: true,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        })
+    );
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert!(publish.dup);
+            assert_eq!(publish.packet_id, Some(packet_id));
+            assert_eq!(publish.topic, "topic/1");
+        }
+        _ => panic!("{:?}", pkt),
+    }
+    io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+    sleep(Millis(50)).await;
+    drop(io);
+    sleep(Millis(100)).await;
+
+    let state = store.load("user").unwrap();
+    assert_eq!(state.subscriptions.len(), 1);
+    assert!(state.publishes.is_empty());
+
+    // clean session removes stored state
+    let io = srv.connect().await.unwrap();
+    let connect = codec::Connect { clean_session: true, ..connect };
+    io.send(connect.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        })
+    );
+    assert!(store.load("user").is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_takeover() -> std::io::Result<()> {
+    let store = InMemorySessionStore::new();
+    let connects = Arc::new(AtomicUsize::new(0));
+
+    let srv = server::test_server(move || {
+        let connects = connects.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // publish message to the first connection only
+            if connects.fetch_add(1, Relaxed) == 0 {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    let _ = sink
+                        .publish(ByteString::from_static("topic/1"), Bytes::new())
+                        .send_at_least_once()
+                        .await;
+                });
+            }
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .session_store(store.clone())
+        .registry(SessionRegistry::new())
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect =
+        codec::Connect { clean_session: false, ..Default::default() }.client_id("user");
+
+    // first connection, publish is not acknowledged
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => publish.packet_id.unwrap(),
+        _ => panic!("{:?}", pkt),
+    };
+
+    // second connection takes over the session, first connection get closed
+    let io2 = srv.connect().await.unwrap();
+    io2.send(connect.into(), &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: true,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        })
+    );
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert!(publish.dup);
+            assert_eq!(publish.packet_id.unwrap(), packet_id);
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_replay_inflight() -> std::io::Result<()> {
+    let dups = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let dups2 = dups.clone();
+
+    let srv = server::test_server(move || {
+        let dups = dups2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let dups = dups.clone();
+                async move {
+                    if p.dup() {
+                        dups.lock().unwrap().push(p.id().unwrap());
+                        Ok(())
+                    } else {
+                        // do not ack first delivery
+                        std::future::pending().await
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.track_inflight();
+    ntex::rt::spawn(client.start_default());
+
+    for _ in 0..2 {
+        let sink = sink.clone();
+        ntex::rt::spawn(async move {
+            let _ = sink
+                .publish(ByteString::from_static("test"), Bytes::new())
+                .send_at_least_once()
+                .await;
+        });
+    }
+    sleep(Millis(50)).await;
+    sink.close();
+
+    let msgs = sink.take_inflight();
+    assert_eq!(msgs.publishes.len(), 2);
+    let ids: Vec<_> = msgs.publishes.iter().map(|p| p.packet_id.unwrap()).collect();
+    assert!(sink.take_inflight().is_empty());
+
+    // replay on new connection
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let acks = Rc::new(RefCell::new(Vec::new()));
+    let acks2 = acks.clone();
+    sink.publish_ack_cb(move |id, _| acks2.borrow_mut().push(id));
+    ntex::rt::spawn(client.start_default());
+
+    sink.replay_inflight(msgs).unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*dups.lock().unwrap(), ids);
+    assert_eq!(*acks.borrow(), ids);
+
+    sink.close();
+    Ok(())
+}
+
 // Slow frame rate
 #[ntex::test]
 async fn test_frame_read_rate() -> std::io::Result<()> {
@@ -678,3 +1165,383 @@ async fn test_frame_read_rate() -> std::io::Result<()> {
 
     Ok(())
 }
+
+fn client_ack(msg: client::ControlMessage<()>) -> client::ControlResult {
+    match msg {
+        client::ControlMessage::Publish(msg) => msg.ack(),
+        client::ControlMessage::Closed(msg) => msg.ack(),
+        client::ControlMessage::Error(msg) => msg.ack(),
+        client::ControlMessage::ProtocolError(msg) => msg.ack(),
+        client::ControlMessage::PeerGone(msg) => msg.ack(),
+        client::ControlMessage::Connected(msg) => msg.ack(),
+        client::ControlMessage::Reconnecting(msg) => msg.ack(),
+    }
+}
+
+#[ntex::test]
+async fn test_reconnect() -> std::io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections2 = connections.clone();
+    let subscribes = Arc::new(AtomicUsize::new(0));
+    let subscribes2 = subscribes.clone();
+    let topics = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let connections = connections2.clone();
+        let subscribes = subscribes2.clone();
+        let topics = topics2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // drop first connection
+            if connections.fetch_add(1, Relaxed) == 0 {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(150)).await;
+                    sink.force_close();
+                });
+            }
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .control(move |msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                subscribes.fetch_add(1, Relaxed);
+                for mut sub in &mut msg {
+                    sub.subscribe(sub.qos());
+                }
+                Ready::Ok(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .publish(move |p: Publish| {
+            topics.lock().unwrap().push(p.topic().path().to_string());
+            Ready::Ok::<_, ()>(())
+        })
+        .finish()
+    });
+
+    let client =
+        client::ReconnectClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)))
+            .subscribe("topic1".into(), codec::QoS::AtLeastOnce)
+            .offline_queue(2, client::OverflowPolicy::DropOldest);
+    let sink = client.sink();
+
+    // queue publishes while disconnected
+    let mut deliveries = Vec::new();
+    for topic in ["t1", "t2", "t3"] {
+        deliveries.push(sink.publish(topic, Bytes::new(), codec::QoS::AtLeastOnce).unwrap());
+    }
+    assert_eq!(sink.queued(), 2);
+
+    let connects = Rc::new(RefCell::new(Vec::new()));
+    let connects2 = connects.clone();
+    ntex::rt::spawn(async move {
+        let res = client
+            .start(move |msg: client::ControlMessage<()>| {
+                if let client::ControlMessage::Connected(ref msg) = msg {
+                    connects2.borrow_mut().push(msg.session_present());
+                }
+                Ready::Ok(client_ack(msg))
+            })
+            .await;
+        assert!(res.is_ok());
+    });
+
+    assert!(sink.ready().await);
+    sleep(Millis(50)).await;
+    assert_eq!(sink.queued(), 0);
+    assert_eq!(*topics.lock().unwrap(), vec!["t2", "t3"]);
+    let res: Vec<_> = join_all(deliveries).await;
+    assert_eq!(res, vec![Err(client::error::SendPacketError::QueueFull), Ok(()), Ok(())]);
+    assert_eq!(subscribes.load(Relaxed), 1);
+
+    // server drops connection
+    sleep(Millis(150)).await;
+    assert!(sink.ready().await);
+    sleep(Millis(50)).await;
+    assert_eq!(connections.load(Relaxed), 2);
+    assert_eq!(subscribes.load(Relaxed), 2);
+    assert_eq!(*connects.borrow(), vec![false, false]);
+
+    sink.publish("t4", Bytes::new(), codec::QoS::AtMostOnce).unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*topics.lock().unwrap(), vec!["t2", "t3", "t4"]);
+
+    sink.close();
+    assert!(!sink.ready().await);
+    assert_eq!(
+        sink.publish("t5", Bytes::new(), codec::QoS::AtMostOnce).err(),
+        Some(client::error::SendPacketError::Disconnected)
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnect_protocol_error() -> std::io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections2 = connections.clone();
+
+    let srv = server::test_server(move || {
+        let connections = connections2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // first connection sends packet that is not expected by client
+            if connections.fetch_add(1, Relaxed) == 0 {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(50)).await;
+                    let _ = sink
+                        .subscribe()
+                        .topic_filter("topic".into(), codec::QoS::AtMostOnce)
+                        .send()
+                        .await;
+                });
+            }
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_| Ready::Ok::<_, ()>(()))
+        .finish()
+    });
+
+    let client =
+        client::ReconnectClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)));
+    let sink = client.sink();
+    ntex::rt::spawn(async move {
+        let _ = client
+            .start(move |msg: client::ControlMessage<()>| Ready::Ok(client_ack(msg)))
+            .await;
+    });
+
+    sleep(Millis(200)).await;
+    assert_eq!(connections.load(Relaxed), 2);
+    assert!(sink.is_connected());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnect_closed_after_connack() -> std::io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections2 = connections.clone();
+
+    let srv = server::test_server(move || {
+        let connections = connections2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // server closes every connection right after connack
+            connections.fetch_add(1, Relaxed);
+            let sink = packet.sink();
+            ntex::rt::spawn(async move {
+                sink.close();
+            });
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_| Ready::Ok::<_, ()>(()))
+        .finish()
+    });
+
+    let client =
+        client::ReconnectClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(20), Millis(100)).jitter(0.0));
+    let sink = client.sink();
+    ntex::rt::spawn(async move {
+        let _ = client
+            .start(move |msg: client::ControlMessage<()>| Ready::Ok(client_ack(msg)))
+            .await;
+    });
+
+    // reconnects after 20, 40, 80, 100 millis
+    sleep(Millis(300)).await;
+    let count = connections.load(Relaxed);
+    assert!((3..=6).contains(&count), "connections: {}", count);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnect_delivery() -> std::io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections2 = connections.clone();
+
+    let srv = server::test_server(move || {
+        let connections = connections2.clone();
+        let connections3 = connections2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // drop first two connections, keep session for second one
+            let num = connections.fetch_add(1, Relaxed) + 1;
+            if num < 3 {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(100)).await;
+                    sink.force_close();
+                });
+            }
+            Ready::Ok::<_, ()>(packet.ack(St, num == 2))
+        })
+        .max_qos(QoS::ExactlyOnce)
+        .publish(move |p: Publish| {
+            // do not ack publishes before connection is dropped
+            let slow = connections3.load(Relaxed) == 1 || p.topic().path() == "t3";
+            async move {
+                if slow {
+                    sleep(Millis(500)).await;
+                }
+                Ok::<_, ()>(())
+            }
+        })
+        .finish()
+    });
+
+    let client =
+        client::ReconnectClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)));
+    let sink = client.sink();
+    ntex::rt::spawn(async move {
+        let _ = client
+            .start(move |msg: client::ControlMessage<()>| Ready::Ok(client_ack(msg)))
+            .await;
+    });
+    assert!(sink.ready().await);
+
+    // in-flight publishes are re-sent on connection with session
+    let d1 = sink.publish("t1", Bytes::new(), codec::QoS::AtLeastOnce).unwrap();
+    let d2 = sink.publish("t2", Bytes::new(), codec::QoS::ExactlyOnce).unwrap();
+    assert_eq!(d1.await, Ok(()));
+    assert_eq!(d2.await, Ok(()));
+    assert_eq!(connections.load(Relaxed), 2);
+
+    // in-flight publish is lost on connection without session
+    let d3 = sink.publish("t3", Bytes::new(), codec::QoS::AtLeastOnce).unwrap();
+    assert_eq!(d3.await, Err(client::error::SendPacketError::Disconnected));
+    assert_eq!(connections.load(Relaxed), 3);
+
+    let d4 = sink.publish("t4", Bytes::new(), codec::QoS::ExactlyOnce).unwrap();
+    assert_eq!(d4.await, Ok(()));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnect_offline_queue() -> std::io::Result<()> {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+    let client = client::ReconnectClient::new(client::MqttConnector::new(addr))
+        .offline_queue(2, client::OverflowPolicy::Error);
+    let sink = client.sink();
+    sink.publish("t1", Bytes::new(), codec::QoS::AtMostOnce).unwrap();
+    sink.publish("t2", Bytes::new(), codec::QoS::AtMostOnce).unwrap();
+    assert_eq!(
+        sink.publish("t3", Bytes::new(), codec::QoS::AtMostOnce).err(),
+        Some(client::error::SendPacketError::QueueFull)
+    );
+
+    let client = client::ReconnectClient::new(client::MqttConnector::new(addr))
+        .backoff(client::Backoff::new(Millis(10), Millis(20)))
+        .offline_queue(2, client::OverflowPolicy::DropNewest);
+    let sink = client.sink();
+    for topic in ["t1", "t2", "t3"] {
+        sink.publish(topic, Bytes::new(), codec::QoS::AtMostOnce).unwrap();
+    }
+    assert_eq!(sink.queued(), 2);
+
+    let attempts = Rc::new(RefCell::new(Vec::new()));
+    let attempts2 = attempts.clone();
+    let sink2 = sink.clone();
+    let res = client
+        .start(move |msg: client::ControlMessage<()>| {
+            if let client::ControlMessage::Reconnecting(ref msg) = msg {
+                attempts2.borrow_mut().push(msg.attempt());
+                if msg.attempt() == 2 {
+                    sink2.close();
+                }
+            }
+            Ready::Ok(client_ack(msg))
+        })
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(*attempts.borrow(), vec![1, 2]);
+    assert_eq!(sink.queued(), 2);
+    Ok(())
+}
+
+struct User(ByteString);
+
+impl Identity for User {
+    fn client_id(&self) -> &str {
+        &self.0
+    }
+}
+
+#[ntex::test]
+async fn test_authorization() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        let auth = Authorization::new(Acl::new().allow(Access::All, "devices/%c/#"));
+
+        MqttServer::new(|packet: Handshake| {
+            let client_id = packet.packet().client_id.clone();
+            Ready::Ok::<_, NotAuthorized>(packet.ack(User(client_id), false))
+        })
+        .publish(auth.publish(ntex::service::fn_factory_with_config(|_: Session<User>| {
+            Ready::Ok::<_, NotAuthorized>(fn_service(|_: Publish| Ready::Ok(())))
+        })))
+        .control(auth.control(ntex::service::fn_factory_with_config(|_: Session<User>| {
+            Ready::Ok::<_, NotAuthorized>(fn_service(|msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            }))
+        })))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("dev1").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    // subscribe
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                (ByteString::from("devices/dev2/#"), codec::QoS::AtLeastOnce),
+                (ByteString::from("devices/dev1/#"), codec::QoS::AtLeastOnce),
+            ],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::SubscribeAck { status, .. } => assert_eq!(
+            status,
+            vec![
+                codec::SubscribeReturnCode::Failure,
+                codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)
+            ]
+        ),
+        _ => panic!("{:?}", pkt),
+    }
+
+    // not authorized publish closes connection
+    let pkt = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from("devices/dev2/temp"),
+        packet_id: None,
+        payload: Bytes::new(),
+    };
+    io.send(pkt.into(), &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
@@ -1,15 +1,21 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{cell::RefCell, rc::Rc};
-use std::{convert::TryFrom, future::Future, num::NonZeroU16, pin::Pin, time::Duration};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::time::Duration;
+use std::{cell::Cell, cell::RefCell, rc::Rc, sync::Arc};
+use std::{convert::TryFrom, future::Future, num::NonZeroU16, num::NonZeroU32, pin::Pin};
 
 use ntex::time::{sleep, Millis, Seconds};
 use ntex::util::{lazy, ByteString, Bytes, BytesMut, Ready};
 use ntex::{codec::Encoder, server, service::fn_service};
 
+use ntex_mqtt::auth::{Access, Acl, Identity};
+use ntex_mqtt::metrics::PrometheusMetrics;
+use ntex_mqtt::store::{InMemorySessionStore, SessionStore};
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, QoS, Session,
+    client, codec, error, Authorization, ControlMessage, Handshake, HandshakeAck,
+    InflightMessages, MqttServer, OutboundQueue, Publish, PublishAck, QoS, QueueMetrics,
+    QueueOverflow, Session,
 };
+use ntex_mqtt::{Admission, RateLimit, SessionRegistry};
 
 struct St;
 
@@ -74,6 +80,83 @@ async fn test_simple() -> std::io::Result<()> {
     Ok(())
 }
 
+fn metric(text: &str, name: &str) -> u64 {
+    text.lines()
+        .find_map(|line| line.strip_prefix(name).and_then(|v| v.trim().parse().ok()))
+        .unwrap_or(0)
+}
+
+#[ntex::test]
+async fn test_metrics() -> std::io::Result<()> {
+    let metrics = PrometheusMetrics::new();
+    let metrics2 = metrics.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .metrics(metrics2.clone())
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client_metrics = PrometheusMetrics::new();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .metrics(client_metrics.clone())
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+    sleep(Millis(150)).await;
+
+    let text = metrics.render();
+    assert_eq!(metric(&text, "mqtt_connections_opened_total{protocol=\"v5\"}"), 1);
+    assert_eq!(metric(&text, "mqtt_connections_closed_total{protocol=\"v5\"}"), 1);
+    assert_eq!(metric(&text, "mqtt_connections_active{protocol=\"v5\"}"), 0);
+    assert_eq!(
+        metric(&text, "mqtt_packets_received_total{protocol=\"v5\",type=\"connect\"}"),
+        1
+    );
+    assert_eq!(
+        metric(&text, "mqtt_packets_received_total{protocol=\"v5\",type=\"publish\"}"),
+        1
+    );
+    assert_eq!(metric(&text, "mqtt_packets_sent_total{protocol=\"v5\",type=\"puback\"}"), 1);
+    assert_eq!(metric(&text, "mqtt_handshake_duration_seconds_count{protocol=\"v5\"}"), 1);
+    assert_eq!(metric(&text, "mqtt_protocol_errors_total{protocol=\"v5\"}"), 0);
+
+    let client_text = client_metrics.render();
+    assert_eq!(
+        metric(&client_text, "mqtt_packets_received_total{protocol=\"v5\",type=\"connack\"}"),
+        1
+    );
+    assert_eq!(
+        metric(&client_text, "mqtt_handshake_duration_seconds_count{protocol=\"v5\"}"),
+        1
+    );
+    // publish and ack
+    assert_eq!(metric(&client_text, "mqtt_inflight_messages_count{protocol=\"v5\"}"), 2);
+    assert_eq!(
+        metric(&client_text, "mqtt_inflight_messages_bucket{protocol=\"v5\",le=\"0\"}"),
+        1
+    );
+
+    // both sides agree on traffic
+    let sent = metric(&client_text, "mqtt_sent_bytes_total{protocol=\"v5\"}");
+    assert!(sent > 0);
+    assert_eq!(metric(&text, "mqtt_received_bytes_total{protocol=\"v5\"}"), sent);
+    assert_eq!(
+        metric(&client_text, "mqtt_received_bytes_total{protocol=\"v5\"}"),
+        metric(&text, "mqtt_sent_bytes_total{protocol=\"v5\"}")
+    );
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_handshake_failed() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -1192,3 +1275,1574 @@ async fn test_frame_read_rate() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_sink_publish_exactly_once() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_qos(QoS::ExactlyOnce)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_exactly_once()
+        .await
+        .unwrap();
+    assert_eq!(res.received.reason_code, codec::PublishAckReason::Success);
+    assert_eq!(res.completed.unwrap().reason_code, codec::PublishAck2Reason::Success);
+    assert_eq!(sink.credit(), 15);
+
+    // failed PUBREC completes exchange
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_qos(QoS::ExactlyOnce)
+            .publish(|p: Publish| {
+                Ready::Ok::<_, TestError>(
+                    p.ack().reason_code(codec::PublishAckReason::NotAuthorized),
+                )
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_exactly_once()
+        .await
+        .unwrap();
+    assert_eq!(res.received.reason_code, codec::PublishAckReason::NotAuthorized);
+    assert!(res.completed.is_none());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_store() -> std::io::Result<()> {
+    let store = InMemorySessionStore::new();
+    let store2 = store.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| {
+            if packet.session_state().is_none() {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    let _ = sink
+                        .publish(ByteString::from_static("test"), Bytes::new())
+                        .send_exactly_once()
+                        .await;
+                });
+            }
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .session_store(store2.clone())
+        .max_qos(QoS::ExactlyOnce)
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect = codec::Connect {
+        clean_start: false,
+        session_expiry_interval_secs: 60,
+        ..Default::default()
+    }
+    .client_id("user");
+
+    // first connection, PUBCOMP is not received
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => assert!(!ack.session_present),
+        _ => panic!("{:?}", pkt),
+    }
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => publish.packet_id.unwrap(),
+        _ => panic!("{:?}", pkt),
+    };
+    io.send(
+        codec::Packet::PublishReceived(codec::PublishAck { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::PublishRelease(_)));
+    drop(io);
+    sleep(Millis(100)).await;
+
+    let state = store.load("user").unwrap();
+    assert!(state.publishes.is_empty());
+    assert_eq!(state.released, vec![packet_id]);
+
+    // reconnect, PUBREL is re-sent
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => assert!(ack.session_present),
+        _ => panic!("{:?}", pkt),
+    }
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::PublishRelease(rel) => assert_eq!(rel.packet_id, packet_id),
+        _ => panic!("{:?}", pkt),
+    }
+    io.send(
+        codec::Packet::PublishComplete(codec::PublishAck2 { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // zero session expiry interval on disconnect removes session
+    io.send(
+        codec::Disconnect { session_expiry_interval_secs: Some(0), ..Default::default() }
+            .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Millis(100)).await;
+    drop(io);
+    sleep(Millis(100)).await;
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_store_rejected() -> std::io::Result<()> {
+    let store = InMemorySessionStore::new();
+    store.save("user", Default::default(), None);
+    let store2 = store.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| {
+            Ready::Ok::<_, TestError>(
+                packet.failed::<St>(codec::ConnectAckReason::NotAuthorized),
+            )
+        })
+        .session_store(store2.clone())
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // rejected clean start connect does not remove session
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    let connect = codec::Connect { clean_start: true, ..Default::default() }.client_id("user");
+    io.send(connect.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::NotAuthorized)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+    drop(io);
+    sleep(Millis(100)).await;
+    assert!(store.load("user").is_some());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_takeover() -> std::io::Result<()> {
+    let store = InMemorySessionStore::new();
+    let store2 = store.clone();
+    let connects = Arc::new(AtomicUsize::new(0));
+
+    let srv = server::test_server(move || {
+        let connects = connects.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // publish message to the first connection only
+            if connects.fetch_add(1, Relaxed) == 0 {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    let _ = sink
+                        .publish(ByteString::from_static("test"), Bytes::new())
+                        .send_at_least_once()
+                        .await;
+                });
+            }
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .session_store(store2.clone())
+        .registry(SessionRegistry::new())
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect = codec::Connect {
+        clean_start: false,
+        session_expiry_interval_secs: 60,
+        ..Default::default()
+    }
+    .client_id("user");
+
+    // first connection, PUBACK is not sent
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => publish.packet_id.unwrap(),
+        _ => panic!("{:?}", pkt),
+    };
+
+    // second connection with the same client id takes over the session
+    let io2 = srv.connect().await.unwrap();
+    io2.send(connect.clone().into(), &codec).await.unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::SessionTakenOver)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => assert!(ack.session_present),
+        _ => panic!("{:?}", pkt),
+    }
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert!(publish.dup);
+            assert_eq!(publish.packet_id.unwrap(), packet_id);
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    // clean start discards inherited session
+    let io3 = srv.connect().await.unwrap();
+    let connect = codec::Connect { clean_start: true, ..Default::default() }.client_id("user");
+    io3.send(connect.into(), &codec).await.unwrap();
+    let pkt = io3.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => assert!(!ack.session_present),
+        _ => panic!("{:?}", pkt),
+    }
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::Disconnect(_)));
+    sleep(Millis(100)).await;
+    assert!(store.load("user").is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_message_expiry_queued() -> std::io::Result<()> {
+    let expired = Arc::new(AtomicBool::new(false));
+    let expired2 = expired.clone();
+
+    let srv = server::test_server(move || {
+        let expired = expired2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            let expired = expired.clone();
+            ntex::rt::spawn(async move {
+                let publish = |topic, secs| {
+                    sink.publish(ByteString::from_static(topic), Bytes::new()).properties(
+                        |props| props.message_expiry_interval = NonZeroU32::new(secs),
+                    )
+                };
+                let fut1 = publish("test/1", 0).send_at_least_once();
+                // wait for receive maximum credit
+                let fut2 = publish("test/2", 1).send_at_least_once();
+                let fut3 = publish("test/3", 10).send_at_least_once();
+                let (_, (res2, _)) = ntex::util::join(fut1, ntex::util::join(fut2, fut3)).await;
+                expired.store(res2 == Err(error::SendPacketError::Expired), Relaxed);
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    let connect = codec::Connect { receive_max: NonZeroU16::new(1), ..Default::default() }
+        .client_id("user");
+    io.send(connect.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert_eq!(publish.topic, "test/1");
+            publish.packet_id.unwrap()
+        }
+        _ => panic!("{:?}", pkt),
+    };
+
+    // second message expires while it waits for credit
+    sleep(Millis(1100)).await;
+    io.send(
+        codec::Packet::PublishAck(codec::PublishAck { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert_eq!(publish.topic, "test/3");
+            assert_eq!(publish.properties.message_expiry_interval, NonZeroU32::new(9));
+            publish.packet_id.unwrap()
+        }
+        _ => panic!("{:?}", pkt),
+    };
+    io.send(
+        codec::Packet::PublishAck(codec::PublishAck { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Millis(50)).await;
+    assert!(expired.load(Relaxed));
+
+    Ok(())
+}
+
+struct DropCounter(Arc<AtomicUsize>);
+
+impl QueueMetrics for DropCounter {
+    fn dropped(&self, _: QoS) {
+        self.0.fetch_add(1, Relaxed);
+    }
+}
+
+#[ntex::test]
+async fn test_outbound_queue() -> std::io::Result<()> {
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let dropped2 = dropped.clone();
+    let full = Arc::new(AtomicBool::new(false));
+    let full2 = full.clone();
+
+    let srv = server::test_server(move || {
+        let full = full2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            let full = full.clone();
+            ntex::rt::spawn(async move {
+                let publish =
+                    |topic| sink.publish(ByteString::from_static(topic), Bytes::new());
+                // first message uses receive credit, others are queued
+                publish("test/1").enqueue(QoS::AtLeastOnce).await.unwrap();
+                publish("test/2").enqueue(QoS::AtLeastOnce).await.unwrap();
+                publish("test/3").enqueue(QoS::AtMostOnce).await.unwrap();
+                // replaces queued QoS 0 message
+                publish("test/4").enqueue(QoS::AtLeastOnce).await.unwrap();
+                // dropped
+                publish("test/5").enqueue(QoS::AtMostOnce).await.unwrap();
+                let res = publish("test/6").enqueue(QoS::AtLeastOnce).await;
+                full.store(res == Err(error::SendPacketError::QueueFull), Relaxed);
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .outbound_queue(OutboundQueue::new().max_len(2).metrics(DropCounter(dropped2.clone())))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    let connect = codec::Connect { receive_max: NonZeroU16::new(1), ..Default::default() }
+        .client_id("user");
+    io.send(connect.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    for topic in ["test/1", "test/2", "test/4"] {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        let packet_id = match pkt.0 {
+            codec::Packet::Publish(publish) => {
+                assert_eq!(publish.topic, topic);
+                publish.packet_id.unwrap()
+            }
+            _ => panic!("{:?}", pkt),
+        };
+        sleep(Millis(50)).await;
+        io.send(
+            codec::Packet::PublishAck(codec::PublishAck { packet_id, ..Default::default() }),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    sleep(Millis(50)).await;
+    assert!(full.load(Relaxed));
+    assert_eq!(dropped.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_outbound_queue_overflow() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            ntex::rt::spawn(async move {
+                for topic in ["test/1", "test/2", "test/3"] {
+                    let _ = sink
+                        .publish(ByteString::from_static(topic), Bytes::new())
+                        .enqueue(QoS::AtLeastOnce)
+                        .await;
+                }
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .outbound_queue(OutboundQueue::new().max_len(1).overflow(QueueOverflow::Disconnect))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    let connect = codec::Connect { receive_max: NonZeroU16::new(1), ..Default::default() }
+        .client_id("user");
+    io.send(connect.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::Publish(ref p) if p.topic == "test/1"));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::QuotaExceeded)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_rate_limit() -> std::io::Result<()> {
+    let count = Arc::new(AtomicUsize::new(0));
+    let count2 = count.clone();
+
+    let srv = server::test_server(move || {
+        let count = count2.clone();
+        MqttServer::new(handshake)
+            .rate_limit(RateLimit::new().messages(5))
+            .publish(move |p: Publish| {
+                count.fetch_add(1, Relaxed);
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    for _ in 0..10 {
+        let pkt = codec::Publish { qos: QoS::AtMostOnce, packet_id: None, ..pkt_publish() };
+        io.send(pkt.into(), &codec).await.unwrap();
+    }
+
+    // reads are paused after burst
+    sleep(Millis(300)).await;
+    let received = count.load(Relaxed);
+    assert!((5..10).contains(&received), "{}", received);
+
+    sleep(Millis(1500)).await;
+    assert_eq!(count.load(Relaxed), 10);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_rate_limit_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| {
+            let limit = RateLimit::new().messages(1).disconnect_after(2);
+            Ready::Ok::<_, TestError>(packet.ack(St).rate_limit(limit))
+        })
+        .rate_limit(RateLimit::new().messages(1000))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .control(move |msg| match msg {
+            ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    for _ in 0..3 {
+        let pkt = codec::Publish { qos: QoS::AtMostOnce, packet_id: None, ..pkt_publish() };
+        io.send(pkt.into(), &codec).await.unwrap();
+    }
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::MessageRateTooHigh)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_admission() -> std::io::Result<()> {
+    let admission = Admission::new().max_connections(1);
+    let admission2 = admission.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .admission(admission2.clone())
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+    assert_eq!(admission.connections(), 1);
+
+    // connections limit is reached
+    let io2 = srv.connect().await.unwrap();
+    io2.send(codec::Connect::default().client_id("user2").into(), &codec).await.unwrap();
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::ServerBusy)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+    assert!(io2.recv(&codec).await.unwrap().is_none());
+
+    // slot is released on disconnect
+    drop(io);
+    sleep(Millis(100)).await;
+    assert_eq!(admission.connections(), 0);
+
+    let io3 = srv.connect().await.unwrap();
+    io3.send(codec::Connect::default().client_id("user3").into(), &codec).await.unwrap();
+    let pkt = io3.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_admission_connect_rate() -> std::io::Result<()> {
+    let admission = Admission::new().max_connect_rate(1);
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .admission(admission.clone())
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    let io2 = srv.connect().await.unwrap();
+    io2.send(codec::Connect::default().client_id("user2").into(), &codec).await.unwrap();
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::ConnectionRateExceeded)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_message_expiry_session() -> std::io::Result<()> {
+    let store = InMemorySessionStore::new();
+    let store2 = store.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| {
+            if packet.session_state().is_none() {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    for (topic, secs) in [("test/1", 1), ("test/2", 60)] {
+                        let _ = sink
+                            .publish(ByteString::from_static(topic), Bytes::new())
+                            .properties(|props| {
+                                props.message_expiry_interval = NonZeroU32::new(secs)
+                            })
+                            .send_at_least_once_no_block();
+                    }
+                });
+            }
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .session_store(store2.clone())
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect = codec::Connect {
+        clean_start: false,
+        session_expiry_interval_secs: 60,
+        ..Default::default()
+    }
+    .client_id("user");
+
+    // first connection, publishes are not acknowledged
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+    for _ in 0..2 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert!(matches!(pkt.0, codec::Packet::Publish(_)));
+    }
+    drop(io);
+    sleep(Millis(1100)).await;
+
+    // reconnect, in-flight publishes are re-sent even if expired
+    let io = srv.connect().await.unwrap();
+    io.send(connect.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => assert!(ack.session_present),
+        _ => panic!("{:?}", pkt),
+    }
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert!(publish.dup);
+            assert_eq!(publish.topic, "test/1");
+            assert_eq!(publish.properties.message_expiry_interval, NonZeroU32::new(1));
+        }
+        _ => panic!("{:?}", pkt),
+    }
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert!(publish.dup);
+            assert_eq!(publish.topic, "test/2");
+            let secs = publish.properties.message_expiry_interval.unwrap().get();
+            assert!((57..60).contains(&secs), "{}", secs);
+        }
+        _ => panic!("{:?}", pkt),
+    }
+    let res = ntex::time::timeout(Millis(100), io.recv(&codec)).await;
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_request_response() -> std::io::Result<()> {
+    let completed = Arc::new(AtomicBool::new(false));
+    let completed2 = completed.clone();
+
+    let srv = server::test_server(move || {
+        let completed = completed2.clone();
+        MqttServer::new(|packet: Handshake| {
+            let info = packet.packet().request_response_info;
+            Ready::Ok::<_, TestError>(packet.ack(St).with(|ack| {
+                if info {
+                    ack.response_info = Some(ByteString::from_static("responses/user"));
+                }
+            }))
+        })
+        .control(|msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                msg.iter_mut().for_each(|mut s| s.confirm(codec::QoS::AtLeastOnce));
+                Ready::Ok::<_, TestError>(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let completed = completed.clone();
+            Ready::Ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                let props = &p.packet().properties;
+                let builder = session
+                    .sink()
+                    .publish(props.response_topic.clone().unwrap(), "pong".into())
+                    .properties(|p| p.correlation_data = props.correlation_data.clone());
+                if p.publish_topic() == "request" {
+                    ntex::rt::spawn(builder.send_at_least_once());
+                } else if p.publish_topic() == "request/qos2" {
+                    let completed = completed.clone();
+                    ntex::rt::spawn(async move {
+                        let res = builder.send_exactly_once().await;
+                        completed.store(res.is_ok(), Relaxed);
+                    });
+                }
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .request_response_info()
+        .connect()
+        .await
+        .unwrap();
+    let requester = client.requester().unwrap().timeout(Millis(250));
+    assert_eq!(requester.topic(), "responses/user/response");
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let response = requester.request("request", "ping".into()).send().await.unwrap();
+    assert_eq!(response.topic, "responses/user/response");
+    assert_eq!(response.payload, Bytes::from_static(b"pong"));
+    assert!(response.properties.correlation_data.is_some());
+
+    // exactly once response is released
+    let response = requester.request("request/qos2", "ping".into()).send().await.unwrap();
+    assert_eq!(response.qos, QoS::ExactlyOnce);
+    sleep(Millis(50)).await;
+    assert!(completed.load(Relaxed));
+
+    // responder ignores request
+    let res = requester.request("ignored", "ping".into()).send().await;
+    assert_eq!(res, Err(error::RequestError::Timeout));
+    assert!(sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_replay_inflight() -> std::io::Result<()> {
+    let dups = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let dups2 = dups.clone();
+
+    let srv = server::test_server(move || {
+        let dups = dups2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let dups = dups.clone();
+                async move {
+                    if p.dup() {
+                        dups.lock().unwrap().push(p.id().unwrap());
+                        Ok::<_, TestError>(p.ack())
+                    } else {
+                        // do not ack first delivery
+                        std::future::pending().await
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.track_inflight();
+    ntex::rt::spawn(client.start_default());
+
+    for _ in 0..2 {
+        sink.publish(ByteString::from_static("test"), Bytes::new())
+            .send_at_least_once_no_block()
+            .unwrap();
+    }
+    sleep(Millis(50)).await;
+    sink.close();
+
+    let msgs = sink.take_inflight();
+    assert_eq!(msgs.publishes.len(), 2);
+    assert!(msgs.released.is_empty());
+    let ids: Vec<_> = msgs.publishes.iter().map(|p| p.packet_id.unwrap()).collect();
+
+    // replay on new connection
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let acks = Rc::new(RefCell::new(Vec::new()));
+    let acks2 = acks.clone();
+    sink.publish_ack_cb(move |ack, _| acks2.borrow_mut().push(ack.packet_id));
+    ntex::rt::spawn(client.start_default());
+
+    sink.replay_inflight(msgs).unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*dups.lock().unwrap(), ids);
+    assert_eq!(*acks.borrow(), ids);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_replay_inflight_receive_max() -> std::io::Result<()> {
+    let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .publish(move |p: Publish| {
+                ids.lock().unwrap().push(p.id().unwrap());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let publish = |id| codec::Publish { packet_id: NonZeroU16::new(id), ..pkt_publish() };
+
+    // packet ids are checked before anything is sent
+    let mut msgs = InflightMessages::default();
+    msgs.publishes = vec![publish(1), publish(1)];
+    let res = sink.replay_inflight(msgs);
+    assert_eq!(res, Err(error::SendPacketError::PacketIdInUse(NonZeroU16::new(1).unwrap())));
+
+    // publishes over peer's receive maximum wait for credit
+    let mut msgs = InflightMessages::default();
+    msgs.publishes = vec![publish(1), publish(2), publish(3)];
+    sink.replay_inflight(msgs).unwrap();
+    sleep(Millis(100)).await;
+    let expected: Vec<_> = (1..4).map(|id| NonZeroU16::new(id).unwrap()).collect();
+    assert_eq!(*ids.lock().unwrap(), expected);
+    assert!(sink.is_open());
+
+    sink.close();
+    Ok(())
+}
+
+fn client_ack(msg: client::ControlMessage<()>) -> client::ControlResult {
+    match msg {
+        client::ControlMessage::Publish(msg) => msg.ack(codec::PublishAckReason::Success),
+        client::ControlMessage::Disconnect(msg) => msg.ack(),
+        client::ControlMessage::Error(msg) => {
+            msg.ack(codec::DisconnectReasonCode::UnspecifiedError)
+        }
+        client::ControlMessage::ProtocolError(msg) => msg.ack(),
+        client::ControlMessage::Closed(msg) => msg.ack(),
+        client::ControlMessage::PeerGone(msg) => msg.ack(),
+        client::ControlMessage::Connected(msg) => msg.ack(),
+        client::ControlMessage::Reconnecting(msg) => msg.ack(),
+    }
+}
+
+#[ntex::test]
+async fn test_reconnect() -> std::io::Result<()> {
+    let subscribes = Arc::new(AtomicUsize::new(0));
+    let subscribes2 = subscribes.clone();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections2 = connections.clone();
+
+    let srv = server::test_server(move || {
+        let subscribes = subscribes2.clone();
+        let connections = connections2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // drop first connection
+            if connections.fetch_add(1, Relaxed) == 0 {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(100)).await;
+                    sink.force_close();
+                });
+            }
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .control(move |msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                subscribes.fetch_add(1, Relaxed);
+                msg.iter_mut().for_each(|mut s| s.confirm(codec::QoS::AtLeastOnce));
+                Ready::Ok::<_, TestError>(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client =
+        client::ReconnectClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)))
+            .subscribe(
+                "topic1".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            );
+    let sink = client.sink();
+    assert!(!sink.is_connected());
+
+    let connects = Rc::new(Cell::new(0));
+    let connects2 = connects.clone();
+    let stopped = Rc::new(Cell::new(false));
+    let stopped2 = stopped.clone();
+    ntex::rt::spawn(async move {
+        let res = client
+            .start(move |msg: client::ControlMessage<()>| {
+                if let client::ControlMessage::Connected(ref msg) = msg {
+                    assert!(!msg.session_present());
+                    connects2.set(connects2.get() + 1);
+                }
+                Ready::Ok(client_ack(msg))
+            })
+            .await;
+        assert!(res.is_ok());
+        stopped2.set(true);
+    });
+
+    assert!(sink.ready().await);
+    assert!(sink.is_connected());
+    sleep(Millis(50)).await;
+    assert_eq!(connects.get(), 1);
+    assert_eq!(subscribes.load(Relaxed), 1);
+
+    // server drops connection
+    sleep(Millis(100)).await;
+    assert!(sink.ready().await);
+    sleep(Millis(50)).await;
+    assert_eq!(connections.load(Relaxed), 2);
+    assert_eq!(connects.get(), 2);
+    assert_eq!(subscribes.load(Relaxed), 2);
+
+    let res = sink
+        .sink()
+        .unwrap()
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+
+    sink.close();
+    assert!(!sink.ready().await);
+    sleep(Millis(50)).await;
+    assert!(stopped.get());
+    assert!(!sink.is_connected());
+    assert_eq!(connects.get(), 2);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnect_backoff() -> std::io::Result<()> {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+    let client = client::ReconnectClient::new(client::MqttConnector::new(addr))
+        .backoff(client::Backoff::new(Millis(10), Millis(20)));
+    let sink = client.sink();
+
+    let attempts = Rc::new(RefCell::new(Vec::new()));
+    let attempts2 = attempts.clone();
+    let sink2 = sink.clone();
+    let res = client
+        .start(move |msg: client::ControlMessage<()>| {
+            if let client::ControlMessage::Reconnecting(ref msg) = msg {
+                assert!(msg.delay() <= Millis(20));
+                attempts2.borrow_mut().push(msg.attempt());
+                if msg.attempt() == 3 {
+                    sink2.close();
+                }
+            }
+            Ready::Ok(client_ack(msg))
+        })
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(*attempts.borrow(), vec![1, 2, 3]);
+    assert!(!sink.ready().await);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnect_protocol_error() -> std::io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections2 = connections.clone();
+
+    let srv = server::test_server(move || {
+        let connections = connections2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // first connection sends packet that is not expected by client
+            if connections.fetch_add(1, Relaxed) == 0 {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(50)).await;
+                    let _ = sink
+                        .subscribe(None)
+                        .topic_filter("topic".into(), Default::default())
+                        .send()
+                        .await;
+                });
+            }
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client =
+        client::ReconnectClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(10), Millis(50)));
+    let sink = client.sink();
+    ntex::rt::spawn(async move {
+        let _ = client
+            .start(move |msg: client::ControlMessage<()>| Ready::Ok(client_ack(msg)))
+            .await;
+    });
+
+    sleep(Millis(200)).await;
+    assert_eq!(connections.load(Relaxed), 2);
+    assert!(sink.is_connected());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnect_closed_after_connack() -> std::io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections2 = connections.clone();
+
+    let srv = server::test_server(move || {
+        let connections = connections2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            // server closes every connection right after connack
+            connections.fetch_add(1, Relaxed);
+            let sink = packet.sink();
+            ntex::rt::spawn(async move {
+                sink.close();
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client =
+        client::ReconnectClient::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .backoff(client::Backoff::new(Millis(20), Millis(100)).jitter(0.0));
+    let sink = client.sink();
+    ntex::rt::spawn(async move {
+        let _ = client
+            .start(move |msg: client::ControlMessage<()>| Ready::Ok(client_ack(msg)))
+            .await;
+    });
+
+    // reconnects after 20, 40, 80, 100 millis
+    sleep(Millis(300)).await;
+    let count = connections.load(Relaxed);
+    assert!((3..=6).contains(&count), "connections: {}", count);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_auto_topic_alias() -> std::io::Result<()> {
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(|packet: Handshake| {
+            let sink = packet.sink();
+            sink.auto_topic_alias(8);
+            ntex::rt::spawn(async move {
+                sleep(Millis(50)).await;
+                for _ in 0..2 {
+                    sink.publish(ByteString::from_static("topic"), Bytes::new())
+                        .send_at_most_once()
+                        .unwrap();
+                }
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(move |p: Publish| {
+            received
+                .lock()
+                .unwrap()
+                .push((p.topic().path().to_string(), p.packet().properties.topic_alias));
+            Ready::Ok::<_, TestError>(p.ack())
+        })
+        .finish()
+    });
+
+    // server assigns aliases within client's limit
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.topic_alias_max = 4;
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Publish(pkt) = pkt.0 {
+        assert_eq!(pkt.topic, "topic");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(1));
+    } else {
+        panic!("Publish packet expected");
+    }
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Publish(pkt) = pkt.0 {
+        assert_eq!(pkt.topic, "");
+        assert_eq!(pkt.properties.topic_alias, NonZeroU16::new(1));
+    } else {
+        panic!("Publish packet expected");
+    }
+    drop(io);
+
+    // client assigns aliases within server's limit
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.auto_topic_alias(8);
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["a", "b", "a"] {
+        sink.publish(ByteString::from_static(topic), Bytes::new())
+            .send_at_least_once()
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            ("a".to_string(), NonZeroU16::new(1)),
+            ("b".to_string(), NonZeroU16::new(2)),
+            ("a".to_string(), NonZeroU16::new(1)),
+        ]
+    );
+    sink.close();
+    Ok(())
+}
+
+fn pkt_auth(data: &'static str) -> codec::Auth {
+    codec::Auth {
+        reason_code: codec::AuthReasonCode::ContinueAuth,
+        auth_method: Some(ByteString::from_static("test")),
+        auth_data: Some(Bytes::from_static(data.as_bytes())),
+        reason_string: None,
+        user_properties: Vec::new(),
+    }
+}
+
+async fn auth_handshake(mut hnd: Handshake) -> Result<HandshakeAck<St>, TestError> {
+    let mut step = 0;
+    loop {
+        step += 1;
+        let challenge = codec::Auth {
+            auth_method: None,
+            auth_data: Some(Bytes::from(format!("challenge{}", step))),
+            ..pkt_auth("")
+        };
+        let res =
+            hnd.auth(challenge).await.map_err(|_: error::HandshakeError<()>| TestError)?;
+        match res.auth_data.as_deref() {
+            Some(b"next") => continue,
+            Some(b"done") => break,
+            _ => return Ok(hnd.failed(codec::ConnectAckReason::NotAuthorized)),
+        }
+    }
+    Ok(hnd.ack(St).with(|ack| ack.auth_method = Some(ByteString::from_static("test"))))
+}
+
+#[ntex::test]
+async fn test_handshake_auth() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(auth_handshake)
+            .connect_timeout(Seconds(1))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+    let codec = codec::Codec::default();
+    let connect = codec::Connect {
+        auth_method: Some(ByteString::from_static("test")),
+        ..codec::Connect::default().client_id("user")
+    };
+
+    // multi-step exchange
+    let io = srv.connect().await.unwrap();
+    io.encode(connect.clone().into(), &codec).unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap().0;
+    assert_eq!(pkt, codec::Packet::Auth(pkt_auth("challenge1")));
+    io.encode(codec::Packet::Auth(pkt_auth("next")), &codec).unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap().0;
+    assert_eq!(pkt, codec::Packet::Auth(pkt_auth("challenge2")));
+    io.encode(codec::Packet::Auth(pkt_auth("done")), &codec).unwrap();
+    if let codec::Packet::ConnectAck(ack) = io.recv(&codec).await.unwrap().unwrap().0 {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::Success);
+        assert_eq!(ack.auth_method, Some(ByteString::from_static("test")));
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+
+    // failed exchange
+    let io = srv.connect().await.unwrap();
+    io.encode(connect.clone().into(), &codec).unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.encode(codec::Packet::Auth(pkt_auth("wrong")), &codec).unwrap();
+    if let codec::Packet::ConnectAck(ack) = io.recv(&codec).await.unwrap().unwrap().0 {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::NotAuthorized);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+
+    // connect timeout applies to whole exchange
+    let io = srv.connect().await.unwrap();
+    io.encode(connect.into(), &codec).unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    sleep(Millis(500)).await;
+    io.encode(codec::Packet::Auth(pkt_auth("next")), &codec).unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    sleep(Millis(700)).await;
+    assert!(matches!(io.recv(&codec).await, Ok(None) | Err(_)));
+
+    Ok(())
+}
+
+struct TestAuth {
+    step: usize,
+    completed: Rc<Cell<usize>>,
+}
+
+impl client::Authenticator for TestAuth {
+    fn method(&self) -> ByteString {
+        ByteString::from_static("test")
+    }
+
+    fn start(&mut self) -> Result<Option<Bytes>, error::AuthError> {
+        self.step = 0;
+        Ok(Some(Bytes::from_static(b"start")))
+    }
+
+    fn challenge(&mut self, _: Option<&Bytes>) -> Result<Option<Bytes>, error::AuthError> {
+        self.step += 1;
+        Ok(Some(Bytes::from_static(if self.step == 1 { b"next" } else { b"done" })))
+    }
+
+    fn complete(&mut self, data: Option<&Bytes>) -> Result<(), error::AuthError> {
+        match data.map(|d| d.as_ref()) {
+            None | Some(b"verified") => {
+                self.completed.set(self.completed.get() + 1);
+                Ok(())
+            }
+            _ => Err(error::AuthError::new("Server is not verified")),
+        }
+    }
+}
+
+#[ntex::test]
+async fn test_client_auth() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        let reauths = Rc::new(Cell::new(0));
+        MqttServer::new(auth_handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Auth(msg) => {
+                    let pkt = msg.packet();
+                    let res = match (pkt.reason_code, pkt.auth_data.as_deref()) {
+                        (codec::AuthReasonCode::ReAuth, Some(b"start")) => {
+                            pkt_auth("re-challenge")
+                        }
+                        (codec::AuthReasonCode::ContinueAuth, Some(b"next")) => {
+                            reauths.set(reauths.get() + 1);
+                            codec::Auth {
+                                reason_code: codec::AuthReasonCode::Success,
+                                ..pkt_auth(if reauths.get() == 1 {
+                                    "verified"
+                                } else {
+                                    "wrong"
+                                })
+                            }
+                        }
+                        _ => panic!("Unexpected AUTH packet: {:?}", pkt),
+                    };
+                    Ready::Ok::<_, TestError>(msg.ack(res))
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let completed = Rc::new(Cell::new(0));
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .authenticator(TestAuth { step: 0, completed: completed.clone() })
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().auth_method, Some(ByteString::from_static("test")));
+    assert_eq!(completed.get(), 1);
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // re-authentication
+    sink.reauthenticate().await.unwrap();
+    assert_eq!(completed.get(), 2);
+    assert!(sink.is_open());
+
+    // server's final data is not verified
+    let fut = sink.reauthenticate();
+    assert_eq!(
+        sink.reauthenticate().await,
+        Err(error::AuthError::new("Re-authentication is in progress"))
+    );
+    assert_eq!(fut.await, Err(error::AuthError::new("Server is not verified")));
+    assert_eq!(completed.get(), 2);
+    sleep(Millis(50)).await;
+    assert!(!sink.is_open());
+
+    // server challenge without authenticator
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user2")
+        .packet(|pkt| pkt.auth_method = Some(ByteString::from_static("test")))
+        .connect()
+        .await;
+    assert!(matches!(client, Err(error::ClientError::Protocol(_))));
+
+    Ok(())
+}
+
+struct RejectAuth;
+
+impl client::Authenticator for RejectAuth {
+    fn method(&self) -> ByteString {
+        ByteString::from_static("test")
+    }
+
+    fn start(&mut self) -> Result<Option<Bytes>, error::AuthError> {
+        Ok(None)
+    }
+
+    fn challenge(&mut self, _: Option<&Bytes>) -> Result<Option<Bytes>, error::AuthError> {
+        Err(error::AuthError::new("Challenge is rejected"))
+    }
+}
+
+#[ntex::test]
+async fn test_client_auth_rejected() -> std::io::Result<()> {
+    let disconnected = Arc::new(AtomicBool::new(false));
+    let disconnected2 = disconnected.clone();
+
+    let srv = server::test_server(move || {
+        let disconnected = disconnected2.clone();
+        fn_service(move |io: ntex::io::Io| {
+            let disconnected = disconnected.clone();
+            async move {
+                let codec = codec::Codec::default();
+                io.recv(&codec).await.unwrap().unwrap();
+                io.encode(codec::Packet::Auth(pkt_auth("challenge")), &codec).unwrap();
+
+                if let Ok(Some((codec::Packet::Disconnect(_), _))) = io.recv(&codec).await {
+                    disconnected.store(true, Relaxed);
+                }
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .authenticator(RejectAuth)
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err, error::ClientError::Auth(_)));
+    sleep(Millis(50)).await;
+    assert!(disconnected.load(Relaxed));
+
+    Ok(())
+}
+
+#[cfg(feature = "scram")]
+#[ntex::test]
+async fn test_scram_auth() -> std::io::Result<()> {
+    use ntex_mqtt::v5::scram::{ScramClient, ScramCredentials, ScramServer};
+
+    let srv = server::test_server(move || {
+        let scram = Rc::new(ScramServer::new(|name: &str| {
+            let salt = Bytes::from_static(b"salt");
+            (name == "user").then(|| {
+                ScramCredentials::new("pencil", salt, std::num::NonZeroU32::new(4096).unwrap())
+            })
+        }));
+        MqttServer::new(move |mut hnd: Handshake| {
+            let scram = scram.clone();
+            async move {
+                match scram.authenticate(&mut hnd).await {
+                    Ok(res) => {
+                        assert_eq!(res.username(), "user");
+                        Ok::<_, TestError>(res.ack(hnd, St))
+                    }
+                    Err(err) => Ok(hnd.failed(err.reason_code())),
+                }
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .authenticator(ScramClient::new("user", "pencil"))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().reason_code, codec::ConnectAckReason::Success);
+    assert_eq!(client.packet().auth_method, Some(ByteString::from_static("SCRAM-SHA-256")));
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .authenticator(ScramClient::new("user", "wrong"))
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let error::ClientError::Ack(ack) = err {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::BadUserNameOrPassword);
+    } else {
+        panic!("Expected ConnectAck error, got {:?}", err);
+    }
+
+    Ok(())
+}
+
+struct User(ByteString);
+
+impl Identity for User {
+    fn client_id(&self) -> &str {
+        &self.0
+    }
+}
+
+#[ntex::test]
+async fn test_authorization() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        let auth = Authorization::new(Acl::new().allow(Access::All, "devices/%c/#"));
+
+        MqttServer::new(|packet: Handshake| {
+            let client_id = packet.packet().client_id.clone();
+            Ready::Ok::<_, TestError>(packet.ack(User(client_id)))
+        })
+        .publish(auth.publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack())))
+        .control(auth.control(|msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    sub.confirm(QoS::AtLeastOnce);
+                }
+                Ready::Ok::<_, TestError>(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        }))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("dev1").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    // allowed publish
+    let pkt = codec::Publish { topic: ByteString::from("devices/dev1/temp"), ..pkt_publish() };
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::PublishAck(ack) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::Success)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    // not authorized publish
+    let pkt = codec::Publish {
+        topic: ByteString::from("devices/dev2/temp"),
+        packet_id: Some(NonZeroU16::new(2).unwrap()),
+        ..pkt_publish()
+    };
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::PublishAck(ack) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::NotAuthorized)
+        }
+        _ => panic!("{:?}", pkt),
+    }
+
+    // subscribe
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    io.send(
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(3).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![
+                (ByteString::from("devices/dev2/#"), opts),
+                (ByteString::from("devices/dev1/#"), opts),
+            ],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::SubscribeAck(ack) => assert_eq!(
+            ack.status,
+            vec![
+                codec::SubscribeAckReason::NotAuthorized,
+                codec::SubscribeAckReason::GrantedQos1
+            ]
+        ),
+        _ => panic!("{:?}", pkt),
+    }
+
+    Ok(())
+}